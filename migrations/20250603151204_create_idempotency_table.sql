@@ -0,0 +1,15 @@
+CREATE TYPE header_pair AS (
+	name TEXT,
+	value BYTEA
+);
+
+-- The response columns are NULL while the first request with the key is still being processed.
+CREATE TABLE idempotency (
+	user_id UUID NOT NULL REFERENCES users (user_id),
+	idempotency_key TEXT NOT NULL,
+	response_status_code SMALLINT NULL,
+	response_headers header_pair[] NULL,
+	response_body BYTEA NULL,
+	created_at TIMESTAMPTZ NOT NULL,
+	PRIMARY KEY(user_id, idempotency_key)
+);
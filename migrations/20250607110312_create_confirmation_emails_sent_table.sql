@@ -0,0 +1,7 @@
+-- Used to cap the number of confirmation emails sent to a single address.
+CREATE TABLE confirmation_emails_sent (
+	subscriber_id UUID NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+	sent_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX confirmation_emails_sent_subscriber_id_idx ON confirmation_emails_sent (subscriber_id);
@@ -0,0 +1,8 @@
+-- Tokens that already exist are treated as if they were just created.
+ALTER TABLE subscription_tokens ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+-- Purging unconfirmed subscribers also removes their tokens.
+ALTER TABLE subscription_tokens
+	DROP CONSTRAINT subscription_tokens_subscriber_id_fkey,
+	ADD CONSTRAINT subscription_tokens_subscriber_id_fkey
+		FOREIGN KEY (subscriber_id) REFERENCES subscriptions (id) ON DELETE CASCADE;
@@ -0,0 +1,9 @@
+-- The author is kept as NULL if the user gets deleted.
+ALTER TABLE newsletter_issues
+	ADD COLUMN author_user_id UUID NULL REFERENCES users (user_id) ON DELETE SET NULL,
+	ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	ADD COLUMN n_recipients INTEGER NOT NULL DEFAULT 0,
+	ADD COLUMN n_delivered INTEGER NOT NULL DEFAULT 0,
+	ADD COLUMN n_failed INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX newsletter_issues_published_at_idx ON newsletter_issues (published_at DESC);
@@ -0,0 +1,10 @@
+-- Possible values: draft, scheduled, published
+-- Issues published through the API skip the draft state.
+ALTER TABLE newsletter_issues
+	ADD COLUMN status TEXT NOT NULL DEFAULT 'published',
+	ADD COLUMN scheduled_for TIMESTAMPTZ NULL,
+	ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	ALTER COLUMN published_at DROP NOT NULL;
+
+CREATE INDEX newsletter_issues_scheduled_for_idx ON newsletter_issues (scheduled_for)
+	WHERE status = 'scheduled';
@@ -0,0 +1,12 @@
+-- The address the password reset links are sent to.
+ALTER TABLE users ADD COLUMN email TEXT NULL UNIQUE;
+
+-- Only the SHA-256 hash of a token is stored, the token itself is only in the email.
+CREATE TABLE password_reset_tokens (
+	token_hash TEXT PRIMARY KEY,
+	user_id UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+	created_at TIMESTAMPTZ NOT NULL,
+	expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX password_reset_tokens_user_id_idx ON password_reset_tokens (user_id);
@@ -0,0 +1,17 @@
+-- The users that already exist keep all the permissions they had, the new ones get the least by default.
+ALTER TABLE users
+	ADD COLUMN role TEXT NOT NULL DEFAULT 'owner' CHECK (role IN ('owner', 'editor', 'viewer')),
+	ADD COLUMN deactivated_at TIMESTAMPTZ NULL;
+ALTER TABLE users ALTER COLUMN role SET DEFAULT 'viewer';
+
+-- Only the SHA-256 hash of a token is stored, the token itself is only in the invitation email.
+CREATE TABLE user_invitations (
+	token_hash TEXT PRIMARY KEY,
+	email TEXT NOT NULL,
+	role TEXT NOT NULL CHECK (role IN ('owner', 'editor', 'viewer')),
+	invited_by UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+	created_at TIMESTAMPTZ NOT NULL,
+	expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX user_invitations_email_idx ON user_invitations (email);
@@ -0,0 +1,14 @@
+-- The secret is encrypted, it's only in use once the user confirmed the enrolment with a code.
+ALTER TABLE users
+	ADD COLUMN totp_secret_encrypted TEXT NULL,
+	ADD COLUMN totp_confirmed_at TIMESTAMPTZ NULL,
+	-- The time step of the last accepted code, so a code can't be used twice.
+	ADD COLUMN totp_last_step BIGINT NULL;
+
+-- Only the SHA-256 hashes of the recovery codes are stored.
+CREATE TABLE totp_recovery_codes (
+	user_id UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+	code_hash TEXT NOT NULL,
+	used_at TIMESTAMPTZ NULL,
+	PRIMARY KEY (user_id, code_hash)
+);
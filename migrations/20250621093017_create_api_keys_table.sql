@@ -0,0 +1,15 @@
+-- Only the SHA-256 hash of a key is stored, the prefix is kept so the keys can be told apart.
+CREATE TABLE api_keys (
+	api_key_id UUID NOT NULL PRIMARY KEY,
+	user_id UUID NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+	name TEXT NOT NULL,
+	key_hash TEXT NOT NULL UNIQUE,
+	key_prefix TEXT NOT NULL,
+	scopes TEXT[] NOT NULL,
+	created_at TIMESTAMPTZ NOT NULL,
+	expires_at TIMESTAMPTZ NULL,
+	last_used_at TIMESTAMPTZ NULL,
+	revoked_at TIMESTAMPTZ NULL
+);
+
+CREATE INDEX api_keys_user_id_idx ON api_keys (user_id);
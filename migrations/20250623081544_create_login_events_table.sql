@@ -0,0 +1,10 @@
+-- The failed password logins and the lockouts they caused, the throttling itself is done in Redis.
+CREATE TABLE login_events (
+	event_id UUID NOT NULL PRIMARY KEY,
+	kind TEXT NOT NULL CHECK (kind IN ('failure', 'username_lockout', 'ip_lockout')),
+	username TEXT NOT NULL,
+	ip TEXT NOT NULL,
+	occurred_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX login_events_occurred_at_idx ON login_events (occurred_at);
@@ -0,0 +1,2 @@
+-- The admin list of the subscribers is paginated by the subscription time and the ID.
+CREATE INDEX subscriptions_subscribed_at_id_idx ON subscriptions (subscribed_at, id);
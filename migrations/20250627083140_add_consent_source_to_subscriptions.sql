@@ -0,0 +1,2 @@
+-- Where the consent of the subscribers that were imported as confirmed comes from.
+ALTER TABLE subscriptions ADD COLUMN consent_source TEXT NULL;
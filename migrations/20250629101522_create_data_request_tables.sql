@@ -0,0 +1,12 @@
+-- Used to cap the number of data access and erasure links sent to a single address.
+CREATE TABLE data_request_emails_sent (
+	subscriber_id UUID NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+	sent_at TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX data_request_emails_sent_subscriber_id_idx ON data_request_emails_sent (subscriber_id);
+-- The record of the erasures requested by the subscribers, it doesn't keep any of their personal data.
+CREATE TABLE data_erasures (
+	erasure_id UUID NOT NULL PRIMARY KEY,
+	subscriber_id UUID NOT NULL,
+	erased_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,14 @@
+-- The append-only history of every subscriber, the proof of when and how they subscribed and gave their consent.
+-- There's no foreign key, so the history outlives the subscriber. The erasures remove the IP addresses and user agents.
+CREATE TABLE subscription_events (
+	event_id UUID NOT NULL PRIMARY KEY,
+	subscriber_id UUID NOT NULL,
+	kind TEXT NOT NULL CHECK (kind IN ('subscribed', 'confirmation_sent', 'confirmed', 'unsubscribed', 'bounced', 'erased')),
+	source TEXT NOT NULL,
+	request_id TEXT,
+	ip TEXT,
+	user_agent TEXT,
+	occurred_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX subscription_events_subscriber_id_idx ON subscription_events (subscriber_id, occurred_at);
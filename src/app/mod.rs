@@ -6,14 +6,23 @@ pub use serve::serve;
 use std::{net::SocketAddr, sync::Arc};
 
 use anyhow::Context;
+use axum::http::HeaderName;
 use derive_more::Deref;
-use secrecy::{ExposeSecret, SecretSlice};
+use secrecy::{ExposeSecret, SecretSlice, SecretString};
 use tokio::net::TcpListener;
 use tracing::info;
 
 use crate::{
-    config::AppConfig, database::DbManager, redis_manager::RedisManager,
-    templ_manager::TemplateManager, utils, EmailClient, Result,
+    config::{AppConfig, SessionConfig},
+    database::DbManager,
+    redis_manager::RedisManager,
+    templ_manager::TemplateManager,
+    utils,
+    web::{
+        auth::{password, LoginThrottle},
+        routes::SessionIndex,
+    },
+    EmailClient, Result,
 };
 
 // ###################################
@@ -32,31 +41,55 @@ impl App {
     }
 
     pub async fn build_from_config(config: AppConfig) -> Result<Self> {
-        let email_addr = config.email_config.valid_sender()?;
-
         let dm = DbManager::init(&config).await?;
         let redis_manager = RedisManager::init(&config).await?;
         let tm = TemplateManager::init();
-        let email_timeout = config.email_config.timeout();
-        let email_client = EmailClient::new(
-            &config.email_config.url,
-            email_addr,
-            config.email_config.auth_token,
-            email_timeout,
-        )?;
-        let cookie_secret = SecretSlice::from(
-            utils::b64_decode(config.net_config.cookie_secret_b64enc.expose_secret())
-                .context("config: failed to decode cookie secret from base64")?,
+        let email_client = EmailClient::from_config(&config.email_config)?;
+        let cookie_secret = cookie_secret_decode(&config.net_config.cookie_secret_b64enc)?;
+        let previous_cookie_secrets = config
+            .net_config
+            .previous_cookie_secrets_b64enc
+            .iter()
+            .map(cookie_secret_decode)
+            .collect::<Result<Vec<_>>>()?;
+        let hmac_secret = SecretSlice::from(
+            utils::b64_decode(config.net_config.hmac_secret_b64enc.expose_secret())
+                .context("config: failed to decode hmac secret from base64")?,
         );
+        let totp_key = utils::b64_decode(config.totp_config.encryption_key_b64enc.expose_secret())
+            .context("config: failed to decode TOTP encryption key from base64")?;
+        if totp_key.len() != 32 {
+            return Err(
+                anyhow::anyhow!("config: the TOTP encryption key has to be 32 bytes long").into(),
+            );
+        }
+        let totp_key = SecretSlice::from(totp_key);
+        password::init(&config.password_config)
+            .context("config: invalid password hashing settings")?;
+        let client_ip_header = config
+            .net_config
+            .client_ip_header
+            .map(|header| HeaderName::try_from(header.as_str()))
+            .transpose()
+            .context("config: the client IP header isn't a valid header name")?;
+        let login_throttle = LoginThrottle::new(&redis_manager, config.login_throttle_config);
+        let session_index = SessionIndex::new(&redis_manager, config.session_config.expiry_secs);
 
-        let app_state = AppState::new(
-            dm,
-            tm,
+        let app_state = AppState::new(InternalState {
+            database_mgr: dm,
+            templ_mgr: tm,
             email_client,
             redis_manager,
-            config.net_config.base_url,
+            base_url: config.net_config.base_url,
             cookie_secret,
-        );
+            previous_cookie_secrets,
+            hmac_secret,
+            totp_key,
+            client_ip_header,
+            login_throttle,
+            session_config: config.session_config,
+            session_index,
+        });
 
         let addr = SocketAddr::from((config.net_config.host, config.net_config.app_port));
         let listener = TcpListener::bind(addr).await?;
@@ -68,13 +101,37 @@ impl App {
     }
 }
 
+/// Cookie keys have to be at least 64 bytes long, `Key::from` panics otherwise.
+fn cookie_secret_decode(secret_b64enc: &SecretString) -> Result<SecretSlice<u8>> {
+    let secret = utils::b64_decode(secret_b64enc.expose_secret())
+        .context("config: failed to decode cookie secret from base64")?;
+    if secret.len() < 64 {
+        return Err(
+            anyhow::anyhow!("config: a cookie secret has to be at least 64 bytes long").into(),
+        );
+    }
+    Ok(SecretSlice::from(secret))
+}
+
 pub struct InternalState {
     pub database_mgr: DbManager,
     pub templ_mgr: TemplateManager,
     pub email_client: EmailClient,
     pub redis_manager: RedisManager,
     pub base_url: String,
+    /// The primary cookie key.
     pub cookie_secret: SecretSlice<u8>,
+    /// The cookies signed with these keys are re-signed with the primary one, see `midware::cookies_resign`.
+    pub previous_cookie_secrets: Vec<SecretSlice<u8>>,
+    pub hmac_secret: SecretSlice<u8>,
+    /// Encrypts the TOTP secrets of the admins.
+    pub totp_key: SecretSlice<u8>,
+    /// The header the client IP address is read from, see `web::ClientIp`.
+    pub client_ip_header: Option<HeaderName>,
+    pub login_throttle: LoginThrottle,
+    pub session_config: SessionConfig,
+    /// The sessions of every admin, see `SessionIndex`.
+    pub session_index: SessionIndex,
 }
 
 /// Application state containing all global data.
@@ -84,21 +141,7 @@ pub struct InternalState {
 pub struct AppState(Arc<InternalState>);
 
 impl AppState {
-    pub fn new(
-        database_mgr: DbManager,
-        templ_mgr: TemplateManager,
-        email_client: EmailClient,
-        redis_manager: RedisManager,
-        base_url: String,
-        cookie_secret: SecretSlice<u8>,
-    ) -> Self {
-        AppState(Arc::new(InternalState {
-            templ_mgr,
-            database_mgr,
-            email_client,
-            redis_manager,
-            base_url,
-            cookie_secret,
-        }))
+    pub fn new(internal_state: InternalState) -> Self {
+        AppState(Arc::new(internal_state))
     }
 }
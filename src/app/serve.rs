@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{net::SocketAddr, time::Duration};
 
 use axum::{
     body::Body,
@@ -17,9 +17,9 @@ use tower_sessions::{Expiry, SessionManagerLayer};
 use tower_sessions_redis_store::RedisStore;
 use tracing::Span;
 
-use crate::{config::get_or_init_config, App};
+use crate::{cleanup_worker, issue_delivery_worker, issue_scheduler, App};
 
-use crate::web::{midware, routes::routes, REQUEST_ID_HEADER};
+use crate::web::{midware, routes::routes, REQUEST_ID_HEADER, SESSION_COOKIE_NAME};
 
 // ###################################
 // ->   ERROR
@@ -35,6 +35,7 @@ pub enum ServeError {
 /// The core async function returning a future that will serve this application.
 ///
 /// Accepts a `TcpListener` and the `AppState` and sets up a TraceLayer that provides console logging.
+/// Also spawns the `issue_delivery_worker`, the `issue_scheduler` and the `cleanup_worker` that run alongside the server.
 ///
 /// Current implementation might return an IO error from `axum::serve`
 // Allow unused vars otherwise the compiler complains because of the cfg macros
@@ -48,10 +49,22 @@ pub async fn serve(app: App) -> Result<()> {
 
     let trace_layer = build_trace_layer();
 
+    // Deliver the newsletter issues in the background
+    tokio::spawn(issue_delivery_worker::run_worker_until_stopped(
+        app_state.clone(),
+    ));
+    // Publish the scheduled newsletter issues once they are due
+    tokio::spawn(issue_scheduler::run_scheduler_until_stopped(
+        app_state.clone(),
+    ));
+    // Remove expired tokens and stale unconfirmed subscribers
+    tokio::spawn(cleanup_worker::run_worker_until_stopped(app_state.clone()));
+
     // TODO: check session settings for security, read all the `with_` methods
-    let session_config = &get_or_init_config().session_config;
+    let session_config = &app_state.session_config;
     let session_store = RedisStore::new(app_state.redis_manager.get_pool());
     let session_man_layer = SessionManagerLayer::new(session_store)
+        .with_name(SESSION_COOKIE_NAME)
         .with_signed(Key::from(app_state.cookie_secret.expose_secret()))
         .with_secure(session_config.secure)
         .with_expiry(Expiry::OnInactivity(cookie::time::Duration::seconds(
@@ -68,6 +81,11 @@ pub async fn serve(app: App) -> Result<()> {
             .layer(trace_layer)
             // cookie manager
             .layer(CookieManagerLayer::new())
+            // accept the cookies signed with the previous keys, before the session manager verifies them
+            .layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                midware::cookies_resign,
+            ))
             // session manager
             .layer(session_man_layer)
             // This has to be in front of the Propagation layer because while the request goes through
@@ -81,7 +99,12 @@ pub async fn serve(app: App) -> Result<()> {
             .layer(PropagateRequestIdLayer::new(x_request_id)),
     );
 
-    axum::serve(listener, app).await?;
+    // The address of the peer is the client IP address when there is no proxy in front of the app.
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
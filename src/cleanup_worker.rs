@@ -0,0 +1,147 @@
+//! A background worker that periodically cleans up stale subscription data.
+//!
+//! Deletes the subscription tokens some time after they expired, and purges subscribers that never confirmed
+//! their subscription within the retention window. Both are configured in `CleanupConfig` and `SubscriptionConfig`.
+//! Also prunes the login events that are older than their retention window, and the idempotency keys
+//! past their `IdempotencyConfig::key_expiry`.
+
+use chrono::Utc;
+use tracing::{info, Span};
+
+use crate::{config::get_or_init_config, AppState};
+
+/// The number of rows removed by a single cleanup run.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CleanupOutcome {
+    pub expired_tokens: u64,
+    pub purged_subscribers: u64,
+    pub pruned_login_events: u64,
+    pub pruned_idempotency_keys: u64,
+}
+
+/// Runs the cleanup worker in a loop that never returns.
+///
+/// The first cleanup runs immediately, failed runs are simply retried on the next tick.
+pub async fn run_worker_until_stopped(app_state: AppState) {
+    info!("{:<20} - Starting the cleanup worker", "cleanup_worker");
+    let mut interval = tokio::time::interval(get_or_init_config().cleanup_config.interval());
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        // Errors are already logged by the instrumented function.
+        let _ = try_execute_cleanup(&app_state).await;
+    }
+}
+
+/// Deletes expired subscription tokens and purges the stale unconfirmed subscribers, the old login events
+/// and the expired idempotency keys.
+///
+/// A pending subscriber is stale when it subscribed, and was last sent a confirmation link,
+/// before the retention window. Their tokens and send records are removed by the `ON DELETE CASCADE`,
+/// their history is kept without the IP addresses and the user agents, like after an erasure.
+#[tracing::instrument(
+    name = "Executing subscription cleanup",
+    skip_all,
+    fields(
+        expired_tokens = tracing::field::Empty,
+        purged_subscribers = tracing::field::Empty,
+        pruned_login_events = tracing::field::Empty,
+        pruned_idempotency_keys = tracing::field::Empty
+    ),
+    err
+)]
+pub async fn try_execute_cleanup(app_state: &AppState) -> Result<CleanupOutcome> {
+    let config = get_or_init_config();
+    let db_pool = app_state.database_mgr.db();
+    let now = Utc::now();
+
+    // Until then the expired links are answered with 410 Gone instead of looking like they never existed.
+    let token_cutoff = now
+        - config.subscription_config.token_expiry()
+        - config.cleanup_config.expired_tokens_retention();
+    let expired_tokens = sqlx::query(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE created_at < $1
+        "#,
+    )
+    .bind(token_cutoff)
+    .execute(db_pool)
+    .await?
+    .rows_affected();
+
+    let retention_cutoff = now - config.cleanup_config.unconfirmed_retention();
+    let purged_subscribers: i64 = sqlx::query_scalar(
+        r#"
+        WITH purged AS (
+            DELETE FROM subscriptions s
+            WHERE s.status = 'pending_confirmation'
+                AND s.subscribed_at < $1
+                AND NOT EXISTS (
+                    SELECT 1 FROM confirmation_emails_sent c
+                    WHERE c.subscriber_id = s.id AND c.sent_at >= $1
+                )
+            RETURNING s.id
+        ), events AS (
+            UPDATE subscription_events e SET ip = NULL, user_agent = NULL
+            FROM purged
+            WHERE e.subscriber_id = purged.id
+        )
+        SELECT count(*) FROM purged
+        "#,
+    )
+    .bind(retention_cutoff)
+    .fetch_one(db_pool)
+    .await?;
+    let purged_subscribers = purged_subscribers as u64;
+
+    let login_events_cutoff = now - config.cleanup_config.login_events_retention();
+    let pruned_login_events = sqlx::query(
+        r#"
+        DELETE FROM login_events
+        WHERE occurred_at < $1
+        "#,
+    )
+    .bind(login_events_cutoff)
+    .execute(db_pool)
+    .await?
+    .rows_affected();
+
+    // The replay window is over, a retry with an expired key would be processed as a new request anyway.
+    let idempotency_cutoff = now - config.idempotency_config.key_expiry();
+    let pruned_idempotency_keys = sqlx::query(
+        r#"
+        DELETE FROM idempotency
+        WHERE created_at < $1
+        "#,
+    )
+    .bind(idempotency_cutoff)
+    .execute(db_pool)
+    .await?
+    .rows_affected();
+
+    Span::current()
+        .record("expired_tokens", expired_tokens)
+        .record("purged_subscribers", purged_subscribers)
+        .record("pruned_login_events", pruned_login_events)
+        .record("pruned_idempotency_keys", pruned_idempotency_keys);
+    info!("Subscription cleanup finished!");
+
+    Ok(CleanupOutcome {
+        expired_tokens,
+        purged_subscribers,
+        pruned_login_events,
+        pruned_idempotency_keys,
+    })
+}
+
+// ###################################
+// ->   ERROR
+// ###################################
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+}
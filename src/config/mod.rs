@@ -17,7 +17,15 @@ use types::Environment;
 
 // Re-export config structs
 pub use error::{ConfigError, ConfigResult};
-pub use types::{AppConfig, DbConfig, EmailConfig, NetConfig};
+pub use types::{
+    AppConfig, CleanupConfig, DbConfig, EmailCircuitBreakerConfig, EmailConfig, EmailProvider,
+    EmailRetryConfig, InvitationConfig, LoginThrottleConfig, NetConfig, PasswordConfig,
+    PasswordResetConfig, SessionConfig, SmtpAuthMechanism, SmtpConfig, SmtpTls, SubscriptionConfig,
+    TotpConfig,
+};
+
+/// The secret that overrides the development TOTP encryption key from base.toml.
+const TOTP_KEY_ENV: &str = "CONFIG__TOTP_CONFIG__ENCRYPTION_KEY_B64ENC";
 
 /// Allocates a static `OnceLock` containing `AppConfig`.
 /// This ensures configuration only gets initialized the first time we call this function.
@@ -59,6 +67,11 @@ pub fn get_or_init_config() -> &'static AppConfig {
             });
             config.db_config = prod_db_config;
 
+            // The key in base.toml is public, the TOTP secrets encrypted with it wouldn't be secret.
+            if std::env::var_os(TOTP_KEY_ENV).is_none() {
+                panic!("Fatal Error: The TOTP encryption key must be provided with the {TOTP_KEY_ENV} secret");
+            }
+
             // TODO: redis on fly.io
         }
 
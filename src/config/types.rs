@@ -26,6 +26,15 @@ pub struct AppConfig {
     pub db_config: DbConfig,
     pub email_config: EmailConfig,
     pub session_config: SessionConfig,
+    pub cleanup_config: CleanupConfig,
+    pub subscription_config: SubscriptionConfig,
+    pub idempotency_config: IdempotencyConfig,
+    pub password_reset_config: PasswordResetConfig,
+    pub data_request_config: DataRequestConfig,
+    pub invitation_config: InvitationConfig,
+    pub totp_config: TotpConfig,
+    pub login_throttle_config: LoginThrottleConfig,
+    pub password_config: PasswordConfig,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -34,13 +43,108 @@ pub struct SessionConfig {
     pub expiry_secs: i64,
 }
 
+#[derive(Deserialize, Clone, Debug)]
+pub struct CleanupConfig {
+    /// How often the cleanup job runs.
+    pub interval_secs: u64,
+    /// How long unconfirmed subscribers are kept after their last confirmation email.
+    pub unconfirmed_retention_secs: i64,
+    /// How long the expired subscription tokens are kept, so their links still say that they expired.
+    pub expired_tokens_retention_secs: i64,
+    /// How long the failed logins and lockouts are kept for the admins to look at.
+    pub login_events_retention_secs: i64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SubscriptionConfig {
+    /// The maximum number of confirmation emails sent to a single address in 24 hours.
+    pub max_confirmation_emails_per_day: i64,
+    /// How long a confirmation link stays valid.
+    pub token_expiry_secs: i64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct IdempotencyConfig {
+    /// How long a request can hold its key unanswered, after that it's considered abandoned and the key can be claimed again.
+    pub in_progress_expiry_secs: i64,
+    /// How long the saved responses are replayed, the older keys are pruned by the cleanup worker.
+    pub key_expiry_secs: i64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct PasswordResetConfig {
+    /// How long a password reset link stays valid.
+    pub token_expiry_secs: i64,
+    /// The maximum number of reset emails sent to a single user in an hour.
+    pub max_emails_per_hour: i64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct DataRequestConfig {
+    /// How long a link to access or erase the data of a subscriber stays valid.
+    pub link_expiry_secs: i64,
+    /// The maximum number of data request emails sent to a single address in 24 hours.
+    pub max_emails_per_day: i64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct InvitationConfig {
+    /// How long an invitation link stays valid.
+    pub token_expiry_secs: i64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct TotpConfig {
+    /// Shown next to the account in the authenticator apps.
+    pub issuer: String,
+    /// The 32 byte AES-256-GCM key the TOTP secrets are encrypted with.
+    pub encryption_key_b64enc: SecretString,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct PasswordConfig {
+    /// The argon2id memory cost in KiB.
+    pub memory_kib: u32,
+    /// The argon2id time cost.
+    pub iterations: u32,
+    pub parallelism: u32,
+    /// The HMAC key the passwords are signed with before they are hashed, it's never stored in the database.
+    /// The passwords hashed with a different pepper can't be validated anymore.
+    pub pepper_b64enc: Option<SecretString>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct LoginThrottleConfig {
+    /// How long the failed logins are counted, starting with the first failure.
+    pub window_secs: i64,
+    /// The number of failures of a username after which every further attempt has to wait.
+    pub delay_after_failures: i64,
+    /// The first wait, it doubles with every further failure.
+    pub base_delay_secs: i64,
+    pub max_delay_secs: i64,
+    /// The number of failures that lock out a username.
+    pub username_lockout_after_failures: i64,
+    /// The number of failures that lock out an IP address, many users can share one.
+    pub ip_lockout_after_failures: i64,
+    /// The number of invalid TOTP codes that lock out a username, counted across all its logins.
+    pub totp_lockout_after_failures: i64,
+    pub lockout_secs: i64,
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct NetConfig {
     pub host: [u8; 4],
     pub app_port: u16,
     pub redis_uri: SecretString,
     pub base_url: String,
+    /// The primary cookie key, signs all the new cookies.
     pub cookie_secret_b64enc: SecretString,
+    /// The keys that were primary before, the cookies signed with them are still accepted and re-signed.
+    pub previous_cookie_secrets_b64enc: Vec<SecretString>,
+    pub hmac_secret_b64enc: SecretString,
+    /// The header the proxy in front of the app stores the client IP address in.
+    /// The address of the peer is used without it.
+    pub client_ip_header: Option<String>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -63,10 +167,68 @@ pub enum SslRequire {
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct EmailConfig {
+    pub provider: EmailProvider,
     pub sender_addr: String,
-    pub url: String,
-    pub auth_token: SecretString,
     pub timeout_millis: u64,
+    /// Postmark API url.
+    pub url: String,
+    /// Postmark server token, only required by the Postmark provider.
+    pub auth_token: Option<SecretString>,
+    /// Only required by the SMTP provider.
+    pub smtp: Option<SmtpConfig>,
+    /// Directory for the `.eml` files, only required by the file provider.
+    pub file_sink_dir: Option<String>,
+    pub retry: EmailRetryConfig,
+    pub circuit_breaker: EmailCircuitBreakerConfig,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct EmailRetryConfig {
+    /// The number of retries after the first attempt, 0 disables retrying.
+    pub max_retries: u32,
+    pub base_delay_millis: u64,
+    /// Also the longest `Retry-After` the client is willing to wait for.
+    pub max_delay_millis: u64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct EmailCircuitBreakerConfig {
+    /// The number of consecutive transient failures that open the circuit.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before requests are allowed again.
+    pub open_secs: u64,
+}
+
+/// Selects the `EmailTransport` used by the `EmailClient`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailProvider {
+    Postmark,
+    Smtp,
+    File,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// Defaults to 587 for STARTTLS and 465 for implicit TLS.
+    pub port: Option<u16>,
+    pub username: String,
+    pub password: SecretString,
+    pub tls: SmtpTls,
+    pub auth_mechanism: SmtpAuthMechanism,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpTls {
+    StartTls,
+    Implicit,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpAuthMechanism {
+    Plain,
+    Login,
 }
 // ###################################
 // ->   IMPLs
@@ -82,6 +244,54 @@ impl EmailConfig {
     }
 }
 
+impl CleanupConfig {
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.interval_secs)
+    }
+    pub fn unconfirmed_retention(&self) -> chrono::TimeDelta {
+        chrono::TimeDelta::seconds(self.unconfirmed_retention_secs)
+    }
+    pub fn expired_tokens_retention(&self) -> chrono::TimeDelta {
+        chrono::TimeDelta::seconds(self.expired_tokens_retention_secs)
+    }
+    pub fn login_events_retention(&self) -> chrono::TimeDelta {
+        chrono::TimeDelta::seconds(self.login_events_retention_secs)
+    }
+}
+
+impl SubscriptionConfig {
+    pub fn token_expiry(&self) -> chrono::TimeDelta {
+        chrono::TimeDelta::seconds(self.token_expiry_secs)
+    }
+}
+
+impl IdempotencyConfig {
+    pub fn in_progress_expiry(&self) -> chrono::TimeDelta {
+        chrono::TimeDelta::seconds(self.in_progress_expiry_secs)
+    }
+    pub fn key_expiry(&self) -> chrono::TimeDelta {
+        chrono::TimeDelta::seconds(self.key_expiry_secs)
+    }
+}
+
+impl PasswordResetConfig {
+    pub fn token_expiry(&self) -> chrono::TimeDelta {
+        chrono::TimeDelta::seconds(self.token_expiry_secs)
+    }
+}
+
+impl DataRequestConfig {
+    pub fn link_expiry(&self) -> chrono::TimeDelta {
+        chrono::TimeDelta::seconds(self.link_expiry_secs)
+    }
+}
+
+impl InvitationConfig {
+    pub fn token_expiry(&self) -> chrono::TimeDelta {
+        chrono::TimeDelta::seconds(self.token_expiry_secs)
+    }
+}
+
 impl DbConfig {
     pub fn connection_options(&self) -> PgConnectOptions {
         self.connection_options_without_db().database(&self.db_name)
@@ -0,0 +1,229 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use strum_macros::AsRefStr;
+use tracing::{info, warn};
+
+use super::{Error, Result};
+use crate::config::EmailCircuitBreakerConfig;
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_OPEN_DURATION: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum CircuitState {
+    /// The provider is working, requests go through.
+    Closed,
+    /// The provider is down, requests fail fast without reaching it.
+    Open,
+    /// The open period is over, a single trial request decides whether the circuit closes or opens again.
+    HalfOpen,
+}
+
+/// Stops sending requests to the email provider for a while after too many consecutive transient failures.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    inner: Mutex<BreakerInner>,
+    /// Set while the trial request of the half-open circuit is being sent.
+    trial_in_flight: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+struct BreakerInner {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(DEFAULT_FAILURE_THRESHOLD, DEFAULT_OPEN_DURATION)
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold,
+            open_duration,
+            inner: Mutex::new(BreakerInner::default()),
+            trial_in_flight: AtomicBool::new(false),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let inner = self.inner.lock().expect("circuit breaker lock poisoned");
+        self.state_of(&inner)
+    }
+
+    fn state_of(&self, inner: &BreakerInner) -> CircuitState {
+        match inner.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.open_duration => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Fails with `Error::CircuitOpen` while the circuit is open.
+    ///
+    /// Once it's half-open only the first caller gets through, the others keep failing until the trial
+    /// request is recorded or its permit is dropped, so a still failing provider gets a single request.
+    pub fn check(&self) -> Result<CircuitPermit<'_>> {
+        match self.state() {
+            CircuitState::Closed => Ok(CircuitPermit {
+                breaker: self,
+                is_trial: false,
+            }),
+            CircuitState::Open => Err(Error::CircuitOpen),
+            CircuitState::HalfOpen => {
+                if self.trial_in_flight.swap(true, Ordering::AcqRel) {
+                    return Err(Error::CircuitOpen);
+                }
+                Ok(CircuitPermit {
+                    breaker: self,
+                    is_trial: true,
+                })
+            }
+        }
+    }
+
+    /// The provider responded, so it's up, even if the response was an error.
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().expect("circuit breaker lock poisoned");
+        if inner.opened_at.is_some() {
+            info!("{:<20} - Email provider circuit closed", "circuit_breaker");
+        }
+        *inner = BreakerInner::default();
+        self.trial_in_flight.store(false, Ordering::Release);
+    }
+
+    /// Records a transient failure, opens the circuit once the threshold is reached
+    /// or if the trial request of a half-open circuit failed.
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().expect("circuit breaker lock poisoned");
+        inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+        let state = self.state_of(&inner);
+        let should_open = match state {
+            CircuitState::Closed => inner.consecutive_failures >= self.failure_threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if should_open {
+            inner.opened_at = Some(Instant::now());
+            warn!(
+                consecutive_failures = inner.consecutive_failures,
+                "{:<20} - Email provider circuit opened for {:?}",
+                "circuit_breaker",
+                self.open_duration
+            );
+        }
+        self.trial_in_flight.store(false, Ordering::Release);
+    }
+}
+
+/// Lets a request through the circuit breaker, the outcome of the request is recorded with it.
+#[must_use]
+#[derive(Debug)]
+pub struct CircuitPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    is_trial: bool,
+}
+
+impl CircuitPermit<'_> {
+    /// Whether this is the single trial request of a half-open circuit.
+    pub fn is_trial(&self) -> bool {
+        self.is_trial
+    }
+
+    pub fn record_success(mut self) {
+        self.is_trial = false;
+        self.breaker.record_success();
+    }
+
+    pub fn record_failure(mut self) {
+        self.is_trial = false;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    /// A trial request that never recorded its outcome, e.g. because it was cancelled, lets the next one through.
+    fn drop(&mut self) {
+        if self.is_trial {
+            self.breaker.trial_in_flight.store(false, Ordering::Release);
+        }
+    }
+}
+
+impl From<&EmailCircuitBreakerConfig> for CircuitBreaker {
+    fn from(config: &EmailCircuitBreakerConfig) -> Self {
+        CircuitBreaker::new(
+            config.failure_threshold,
+            Duration::from_secs(config.open_secs),
+        )
+    }
+}
+
+// ###################################
+// ->   TESTS
+// ###################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuit_opens_after_threshold_and_half_opens_after_duration() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(50));
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(matches!(breaker.check(), Err(Error::CircuitOpen)));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        let trial = breaker.check().expect("the trial request is let through");
+        assert!(trial.is_trial());
+
+        // A failed trial request opens the circuit again.
+        trial.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(60));
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn half_open_circuit_lets_a_single_trial_request_through() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(60));
+
+        let trial = breaker.check().expect("the trial request is let through");
+        assert!(trial.is_trial());
+        assert!(matches!(breaker.check(), Err(Error::CircuitOpen)));
+
+        // A cancelled trial lets the next request try.
+        drop(trial);
+        let trial = breaker
+            .check()
+            .expect("the next trial request is let through");
+        assert!(matches!(breaker.check(), Err(Error::CircuitOpen)));
+
+        trial.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        let permit = breaker
+            .check()
+            .expect("the closed circuit lets requests through");
+        assert!(!permit.is_trial());
+        assert!(breaker.check().is_ok());
+    }
+}
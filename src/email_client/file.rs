@@ -0,0 +1,76 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use lettre::{AsyncFileTransport, AsyncTransport, Tokio1Executor};
+use tracing::info;
+
+use super::{Email, EmailTransport, Result};
+
+/// Writes every email into its own `.eml` file in a directory, instead of sending it.
+///
+/// Meant for local development, the files can be opened with any email client.
+#[derive(Debug)]
+pub struct FileTransport {
+    dir: PathBuf,
+    inner: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileTransport {
+    /// Creates the directory if it doesn't exist yet.
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let inner = AsyncFileTransport::new(&dir);
+
+        Ok(FileTransport { dir, inner })
+    }
+}
+
+#[async_trait]
+impl EmailTransport for FileTransport {
+    async fn send(&self, email: &Email<'_>) -> Result<()> {
+        let id = self.inner.send(email.to_message()?).await?;
+        info!(
+            "{:<20} - {}",
+            "Email written to:",
+            self.dir.join(format!("{id}.eml")).display()
+        );
+
+        Ok(())
+    }
+}
+
+// ###################################
+// ->   TESTS
+// ###################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{email_client::EmailClient, web::types::ValidEmail};
+    use anyhow::Result;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn file_transport_writes_eml_files() -> Result<()> {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let sender = ValidEmail::parse("sender@example.com")?;
+        let recepient = ValidEmail::parse("recepient@example.com")?;
+        let email_client = EmailClient::new(sender, FileTransport::new(&dir)?);
+
+        email_client
+            .send_single_email(&recepient, "Subject", "<p>Html body</p>", "Text body")
+            .await?;
+
+        let files = std::fs::read_dir(&dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(files.len(), 1);
+        let path = files[0].path();
+        assert_eq!(path.extension().and_then(|e| e.to_str()), Some("eml"));
+        let eml = std::fs::read_to_string(&path)?;
+        assert!(eml.contains("To: recepient@example.com"));
+        assert!(eml.contains("Subject: Subject"));
+        assert!(eml.contains("Text body"));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,956 @@
+//! Sends the application emails through a pluggable `EmailTransport`.
+//!
+//! `EmailClient` builds the emails and hands them to the transport selected with `EmailConfig.provider`:
+//! - `PostmarkTransport` sends them through Postmark's JSON API.
+//! - `SmtpTransport` sends them through an SMTP server.
+//! - `FileTransport` writes them to `.eml` files, so local runs don't need an email provider.
+//!
+//! Transient failures are retried with a jittered exponential backoff (`RetryPolicy`), and a `CircuitBreaker`
+//! fails fast while the provider is down.
+
+mod circuit_breaker;
+mod file;
+mod postmark;
+mod retry;
+mod smtp;
+
+pub use circuit_breaker::{CircuitBreaker, CircuitState};
+pub use file::FileTransport;
+pub use postmark::PostmarkTransport;
+pub use retry::RetryPolicy;
+pub use smtp::SmtpTransport;
+
+use std::{borrow::Cow, time::Duration};
+
+use async_trait::async_trait;
+use lettre::message::{
+    header::{HeaderName, HeaderValue},
+    Mailbox, MultiPart,
+};
+use serde::Serialize;
+use strum_macros::AsRefStr;
+use tracing::warn;
+
+use crate::{
+    config::{EmailConfig, EmailProvider},
+    web::types::ValidEmail,
+};
+
+#[derive(Debug, Clone, Copy, AsRefStr)]
+pub enum MessageStream {
+    #[strum(serialize = "broadcast")]
+    Broadcast,
+    #[strum(serialize = "outbound")]
+    Outbound,
+}
+
+/// A recepient of a batch email (newsletter) with their personal unsubscribe link.
+#[derive(Debug, Clone)]
+pub struct BatchRecepient {
+    pub email: ValidEmail,
+    pub unsubscribe_link: String,
+}
+
+/// A transactional email with its own content, e.g. a confirmation email with the link of the recepient.
+#[derive(Debug, Clone)]
+pub struct PersonalEmail {
+    pub to: ValidEmail,
+    pub subject: String,
+    pub html_content: String,
+    pub text_content: String,
+}
+
+/// An email that is ready to be sent by any of the transports.
+#[derive(Debug, Clone)]
+pub struct Email<'a> {
+    pub from: &'a ValidEmail,
+    pub to: &'a ValidEmail,
+    pub subject: &'a str,
+    pub html_body: Cow<'a, str>,
+    pub text_body: Cow<'a, str>,
+    /// Only used by the providers that separate transactional and broadcast emails.
+    pub message_stream: MessageStream,
+    pub headers: Vec<EmailHeader<'a>>,
+}
+
+/// A custom email header, serialized in the format expected by Postmark.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmailHeader<'a> {
+    pub name: &'a str,
+    pub value: String,
+}
+
+/// The per-recepient outcome of sending a batch of emails.
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    pub sent: Vec<SentEmail>,
+    pub failed: Vec<FailedEmail>,
+}
+
+#[derive(Debug)]
+pub struct SentEmail {
+    pub to: ValidEmail,
+    /// The ID assigned to the message by the provider, if it has one.
+    pub message_id: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct FailedEmail {
+    pub to: ValidEmail,
+    pub reason: FailureReason,
+}
+
+#[derive(Debug, derive_more::Display)]
+pub enum FailureReason {
+    /// The provider received the message but refused to send it, e.g. because the recepient is inactive.
+    #[display("rejected by the provider with error code {error_code}: {message}")]
+    Rejected { error_code: i64, message: String },
+    /// The message never reached the provider, or the provider's response couldn't be understood.
+    #[display("request failed: {message}")]
+    Request {
+        message: String,
+        /// Whether sending the message again might succeed.
+        transient: bool,
+        retry_after: Option<Duration>,
+    },
+}
+
+impl FailureReason {
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            FailureReason::Request {
+                transient: true,
+                ..
+            }
+        )
+    }
+}
+
+impl From<&Error> for FailureReason {
+    fn from(error: &Error) -> Self {
+        FailureReason::Request {
+            message: error.to_string(),
+            transient: error.is_transient(),
+            retry_after: error.retry_after(),
+        }
+    }
+}
+
+impl BatchReport {
+    /// A report where every email failed with the same error, e.g. the whole request failed.
+    pub fn all_failed(emails: &[Email<'_>], error: &Error) -> Self {
+        BatchReport {
+            sent: vec![],
+            failed: emails
+                .iter()
+                .map(|email| FailedEmail {
+                    to: email.to.clone(),
+                    reason: error.into(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn merge(&mut self, other: BatchReport) {
+        self.sent.extend(other.sent);
+        self.failed.extend(other.failed);
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// The backend that actually delivers the emails built by the `EmailClient`.
+#[async_trait]
+pub trait EmailTransport: std::fmt::Debug + Send + Sync {
+    async fn send(&self, email: &Email<'_>) -> Result<()>;
+
+    /// Sends multiple emails at once and reports the outcome for every recepient.
+    /// By default the emails are sent one after another.
+    async fn send_batch(&self, emails: &[Email<'_>]) -> BatchReport {
+        let mut report = BatchReport::default();
+        for email in emails {
+            match self.send(email).await {
+                Ok(()) => report.sent.push(SentEmail {
+                    to: email.to.clone(),
+                    message_id: None,
+                }),
+                Err(e) => report.failed.push(FailedEmail {
+                    to: email.to.clone(),
+                    reason: (&e).into(),
+                }),
+            }
+        }
+        report
+    }
+}
+
+#[derive(Debug)]
+pub struct EmailClient {
+    pub sender: ValidEmail,
+    transport: Box<dyn EmailTransport>,
+    retry_policy: RetryPolicy,
+    circuit_breaker: CircuitBreaker,
+}
+
+impl EmailClient {
+    /// Creates a client that doesn't retry failed requests, see `with_retry_policy`.
+    pub fn new(sender: ValidEmail, transport: impl EmailTransport + 'static) -> Self {
+        EmailClient {
+            sender,
+            transport: Box::new(transport),
+            retry_policy: RetryPolicy::none(),
+            circuit_breaker: CircuitBreaker::default(),
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = circuit_breaker;
+        self
+    }
+
+    /// The state of the circuit breaker guarding the email provider.
+    pub fn circuit_state(&self) -> CircuitState {
+        self.circuit_breaker.state()
+    }
+
+    /// Builds the client with the transport selected by `EmailConfig.provider`.
+    pub fn from_config(config: &EmailConfig) -> Result<Self> {
+        let sender = config.valid_sender()?;
+        let timeout = config.timeout();
+
+        let client = match config.provider {
+            EmailProvider::Postmark => {
+                let auth_token = config
+                    .auth_token
+                    .clone()
+                    .ok_or(Error::MissingConfig("email_config.auth_token"))?;
+                Self::new(
+                    sender,
+                    PostmarkTransport::new(&config.url, auth_token, timeout)?,
+                )
+            }
+            EmailProvider::Smtp => {
+                let smtp_config = config
+                    .smtp
+                    .as_ref()
+                    .ok_or(Error::MissingConfig("email_config.smtp"))?;
+                Self::new(sender, SmtpTransport::new(smtp_config, timeout)?)
+            }
+            EmailProvider::File => {
+                let dir = config
+                    .file_sink_dir
+                    .as_ref()
+                    .ok_or(Error::MissingConfig("email_config.file_sink_dir"))?;
+                Self::new(sender, FileTransport::new(dir)?)
+            }
+        };
+
+        Ok(client
+            .with_retry_policy(RetryPolicy::from(&config.retry))
+            .with_circuit_breaker(CircuitBreaker::from(&config.circuit_breaker)))
+    }
+
+    pub async fn send_single_email<S>(
+        &self,
+        recepient: &ValidEmail,
+        subject: S,
+        html_content: S,
+        text_content: S,
+    ) -> Result<()>
+    where
+        S: AsRef<str>,
+    {
+        let email = Email {
+            from: &self.sender,
+            to: recepient,
+            subject: subject.as_ref(),
+            html_body: html_content.as_ref().into(),
+            text_body: text_content.as_ref().into(),
+            message_stream: MessageStream::Outbound,
+            headers: vec![],
+        };
+
+        let mut attempt = 0;
+        loop {
+            let permit = self.circuit_breaker.check()?;
+            let error = match self.transport.send(&email).await {
+                Ok(()) => {
+                    permit.record_success();
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            if !error.is_transient() {
+                permit.record_success();
+                return Err(error);
+            }
+            permit.record_failure();
+
+            let Some(delay) = self.retry_policy.delay(attempt, error.retry_after()) else {
+                return Err(error);
+            };
+            warn!(attempt, ?delay, error = %error, "retrying to send an email");
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Sends the same email to all the recepients.
+    ///
+    /// Every email gets an unsubscribe link appended to its body and the RFC 8058
+    /// `List-Unsubscribe` and `List-Unsubscribe-Post` headers, so the recepients can opt out with one click.
+    ///
+    /// Failing to send to some of the recepients is not an error, the returned `BatchReport`
+    /// says which of the recepients failed and why, so they can be retried.
+    pub async fn send_batch_emails<S>(
+        &self,
+        recepients: &[BatchRecepient],
+        subject: S,
+        html_content: S,
+        text_content: S,
+    ) -> Result<BatchReport>
+    where
+        S: AsRef<str>,
+    {
+        if recepients.is_empty() {
+            return Err(Error::EmptyRecepients);
+        }
+
+        let emails = recepients
+            .iter()
+            .map(|recepient| {
+                let link = &recepient.unsubscribe_link;
+                Email {
+                    from: &self.sender,
+                    to: &recepient.email,
+                    subject: subject.as_ref(),
+                    html_body: format!(
+                        "{}<hr/><p><a href=\"{link}\">Unsubscribe</a> from this newsletter.</p>",
+                        html_content.as_ref()
+                    )
+                    .into(),
+                    text_body: format!(
+                        "{}\n\n---\nUnsubscribe from this newsletter: {link}",
+                        text_content.as_ref()
+                    )
+                    .into(),
+                    message_stream: MessageStream::Broadcast,
+                    headers: vec![
+                        EmailHeader {
+                            name: "List-Unsubscribe",
+                            value: format!("<{link}>"),
+                        },
+                        EmailHeader {
+                            name: "List-Unsubscribe-Post",
+                            value: "List-Unsubscribe=One-Click".to_string(),
+                        },
+                    ],
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Ok(self.send_batch_with_retries(emails).await)
+    }
+
+    /// Sends the transactional emails in batches, every recepient gets its own email.
+    ///
+    /// Like with `send_batch_emails` failing to send to some of the recepients is not an error,
+    /// the returned `BatchReport` says which of them failed.
+    pub async fn send_personal_emails(&self, emails: &[PersonalEmail]) -> Result<BatchReport> {
+        if emails.is_empty() {
+            return Err(Error::EmptyRecepients);
+        }
+
+        let emails = emails
+            .iter()
+            .map(|email| Email {
+                from: &self.sender,
+                to: &email.to,
+                subject: &email.subject,
+                html_body: email.html_content.as_str().into(),
+                text_body: email.text_content.as_str().into(),
+                message_stream: MessageStream::Outbound,
+                headers: vec![],
+            })
+            .collect::<Vec<_>>();
+
+        Ok(self.send_batch_with_retries(emails).await)
+    }
+
+    /// Sends the batch and then retries only the emails that failed with a transient error.
+    ///
+    /// A half-open circuit is probed with a single email, the rest of the batch is sent once the probe succeeds.
+    async fn send_batch_with_retries(&self, mut pending: Vec<Email<'_>>) -> BatchReport {
+        let mut report = BatchReport::default();
+        let mut attempt = 0;
+        loop {
+            let permit = match self.circuit_breaker.check() {
+                Ok(permit) => permit,
+                Err(e) => {
+                    report.merge(BatchReport::all_failed(&pending, &e));
+                    return report;
+                }
+            };
+            let waiting = if permit.is_trial() {
+                pending.split_off(1.min(pending.len()))
+            } else {
+                vec![]
+            };
+
+            let attempt_report = self.transport.send_batch(&pending).await;
+            let (retryable, failed): (Vec<_>, Vec<_>) = attempt_report
+                .failed
+                .into_iter()
+                .partition(|f| f.reason.is_transient());
+            // The provider is down only if nothing got through.
+            if attempt_report.sent.is_empty() && !retryable.is_empty() {
+                permit.record_failure();
+            } else {
+                permit.record_success();
+            }
+            report.sent.extend(attempt_report.sent);
+            report.failed.extend(failed);
+            if retryable.is_empty() {
+                if waiting.is_empty() {
+                    return report;
+                }
+                pending = waiting;
+                continue;
+            }
+
+            let retry_after = retryable
+                .iter()
+                .filter_map(|f| match f.reason {
+                    FailureReason::Request { retry_after, .. } => retry_after,
+                    FailureReason::Rejected { .. } => None,
+                })
+                .max();
+            let Some(delay) = self.retry_policy.delay(attempt, retry_after) else {
+                report.failed.extend(retryable);
+                report.merge(BatchReport::all_failed(&waiting, &Error::CircuitOpen));
+                return report;
+            };
+            warn!(
+                attempt,
+                ?delay,
+                n_failed = retryable.len(),
+                "retrying to send a batch of emails"
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+
+            pending.retain(|email| retryable.iter().any(|f| f.to.as_ref() == email.to.as_ref()));
+            pending.extend(waiting);
+        }
+    }
+}
+
+impl Email<'_> {
+    /// Builds a MIME message with both the plain text and the HTML body,
+    /// used by the transports that don't go through an HTTP API.
+    fn to_message(&self) -> Result<lettre::Message> {
+        let mut message = lettre::Message::builder()
+            .from(Mailbox::new(None, self.from.as_ref().parse()?))
+            .to(Mailbox::new(None, self.to.as_ref().parse()?))
+            .subject(self.subject)
+            .multipart(MultiPart::alternative_plain_html(
+                self.text_body.to_string(),
+                self.html_body.to_string(),
+            ))?;
+
+        for header in &self.headers {
+            let name = HeaderName::new_from_ascii(header.name.to_string())
+                .map_err(|_| Error::InvalidHeader(header.name.to_string()))?;
+            message
+                .headers_mut()
+                .insert_raw(HeaderValue::new(name, header.value.clone()));
+        }
+
+        Ok(message)
+    }
+}
+
+// ###################################
+// ->   ERROR & RESULT
+// ###################################
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("trying to send batch email without recepients")]
+    EmptyRecepients,
+    #[error("url parsing error: {0}")]
+    UrlParsing(String),
+    #[error("missing email config: {0}")]
+    MissingConfig(&'static str),
+    #[error("invalid email header: {0}")]
+    InvalidHeader(String),
+    #[error("invalid email provider response: {0}")]
+    InvalidResponse(String),
+    #[error("rate limited by the email provider, retry after: {retry_after:?}")]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("the email provider circuit breaker is open")]
+    CircuitOpen,
+    #[error("config error: {0}")]
+    Config(#[from] crate::config::ConfigError),
+    #[error("http client error: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("email address error: {0}")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("email message error: {0}")]
+    Message(#[from] lettre::error::Error),
+    #[error("smtp error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("file sink error: {0}")]
+    FileSink(#[from] lettre::transport::file::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl Error {
+    /// Whether the same request might succeed if it's sent again later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::RateLimited { .. } | Error::CircuitOpen => true,
+            Error::Reqwest(e) => {
+                e.is_timeout() || e.is_connect() || e.status().is_some_and(|s| s.is_server_error())
+            }
+            Error::Smtp(e) => e.is_transient() || e.is_timeout(),
+            _ => false,
+        }
+    }
+
+    /// The delay requested by the provider before the next request.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::RateLimited { retry_after } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+// ###################################
+// ->   TESTS
+// ###################################
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use anyhow::Result;
+    use claims::assert_err;
+    use fake::{
+        faker::{internet::en::SafeEmail, lorem::en::Sentence},
+        Fake, Faker,
+    };
+    use secrecy::SecretString;
+    use wiremock::{
+        matchers::{any, header, header_exists, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    struct SendEmailBodyMatcher;
+
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &wiremock::Request) -> bool {
+            let res_body: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+            if let Ok(body) = res_body {
+                body.get("From").is_some()
+                    && body.get("To").is_some()
+                    && body.get("Subject").is_some()
+                    && body.get("HtmlBody").is_some()
+                    && body.get("TextBody").is_some()
+                    && body.get("MessageStream").is_some()
+            } else {
+                false
+            }
+        }
+    }
+
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    fn content() -> String {
+        Sentence(1..10).fake()
+    }
+
+    fn email() -> Result<ValidEmail> {
+        let out = ValidEmail::parse(SafeEmail().fake::<String>())?;
+        Ok(out)
+    }
+
+    fn email_client(url: String) -> Result<EmailClient> {
+        let auth: String = Faker.fake();
+        let transport =
+            PostmarkTransport::new(url, SecretString::from(auth), Duration::from_millis(200))?;
+        Ok(EmailClient::new(email()?, transport))
+    }
+
+    fn retrying_email_client(url: String) -> Result<EmailClient> {
+        let retry_policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(2),
+        };
+        Ok(email_client(url)?.with_retry_policy(retry_policy))
+    }
+
+    #[tokio::test]
+    async fn send_email_send_request_success() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri())?;
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        email_client
+            .send_single_email(&email()?, &subject(), &content(), &content())
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_batch_emails_contain_unsubscribe_headers() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri())?;
+        let unsubscribe_link = "https://example.com/api/subscribe/unsubscribe?token=abc";
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                serde_json::json!([{ "ErrorCode": 0, "Message": "OK", "MessageID": "id" }]),
+            ))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let recepients = [BatchRecepient {
+            email: email()?,
+            unsubscribe_link: unsubscribe_link.to_string(),
+        }];
+        let report = email_client
+            .send_batch_emails(&recepients, &subject(), &content(), &content())
+            .await?;
+        assert!(report.is_success());
+
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body)?;
+        let message = &body[0];
+        let headers = message["Headers"].as_array().unwrap();
+        assert_eq!(headers[0]["Name"], "List-Unsubscribe");
+        assert_eq!(headers[0]["Value"], format!("<{unsubscribe_link}>"));
+        assert_eq!(headers[1]["Name"], "List-Unsubscribe-Post");
+        assert_eq!(headers[1]["Value"], "List-Unsubscribe=One-Click");
+        assert!(message["HtmlBody"]
+            .as_str()
+            .unwrap()
+            .contains(unsubscribe_link));
+        assert!(message["TextBody"]
+            .as_str()
+            .unwrap()
+            .contains(unsubscribe_link));
+
+        Ok(())
+    }
+
+    #[test]
+    fn email_to_message_contains_both_bodies_and_custom_headers() -> Result<()> {
+        let (from, to) = (email()?, email()?);
+        let email = Email {
+            from: &from,
+            to: &to,
+            subject: "Subject",
+            html_body: "<p>Html body</p>".into(),
+            text_body: "Text body".into(),
+            message_stream: MessageStream::Broadcast,
+            headers: vec![EmailHeader {
+                name: "List-Unsubscribe",
+                value: "<https://example.com/unsubscribe>".to_string(),
+            }],
+        };
+
+        let message = String::from_utf8(email.to_message()?.formatted())?;
+        assert!(message.contains("List-Unsubscribe: <https://example.com/unsubscribe>"));
+        assert!(message.contains("Content-Type: multipart/alternative"));
+        assert!(message.contains("Text body"));
+        assert!(message.contains("<p>Html body</p>"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_email_send_request_fail_if_500() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri())?;
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let out = email_client
+            .send_single_email(&email()?, &subject(), &content(), &content())
+            .await;
+
+        assert_err!(out);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_email_timeout() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri())?;
+
+        let response = ResponseTemplate::new(200).set_delay(Duration::from_secs(180));
+
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let out = email_client
+            .send_single_email(&email()?, &subject(), &content(), &content())
+            .await;
+
+        assert_err!(out);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_transient_errors() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri())?;
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        email_client
+            .send_single_email(&email()?, &subject(), &content(), &content())
+            .await?;
+        assert_eq!(email_client.circuit_state(), CircuitState::Closed);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_retry_client_errors() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri())?;
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let out = email_client
+            .send_single_email(&email()?, &subject(), &content(), &content())
+            .await;
+        assert_err!(out);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_email_honours_retry_after() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri())?;
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let start = std::time::Instant::now();
+        email_client
+            .send_single_email(&email()?, &subject(), &content(), &content())
+            .await?;
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_fast_while_circuit_is_open() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri())?
+            .with_circuit_breaker(CircuitBreaker::new(1, Duration::from_secs(60)));
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let out = email_client
+            .send_single_email(&email()?, &subject(), &content(), &content())
+            .await;
+        assert!(matches!(out, Err(Error::Reqwest(_))));
+        assert_eq!(email_client.circuit_state(), CircuitState::Open);
+
+        let out = email_client
+            .send_single_email(&email()?, &subject(), &content(), &content())
+            .await;
+        assert!(matches!(out, Err(Error::CircuitOpen)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_batch_emails_retries_failed_requests() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri())?;
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                serde_json::json!([{ "ErrorCode": 0, "Message": "OK", "MessageID": "id" }]),
+            ))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let recepients = [BatchRecepient {
+            email: email()?,
+            unsubscribe_link: "https://example.com/unsubscribe".to_string(),
+        }];
+        let report = email_client
+            .send_batch_emails(&recepients, &subject(), &content(), &content())
+            .await?;
+        assert!(report.is_success());
+        assert_eq!(report.sent.len(), 1);
+
+        Ok(())
+    }
+
+    fn recepients(n: usize) -> Result<Vec<BatchRecepient>> {
+        (0..n)
+            .map(|i| {
+                Ok(BatchRecepient {
+                    email: ValidEmail::parse(format!("reader{i}@example.com"))?,
+                    unsubscribe_link: "https://example.com/unsubscribe".to_string(),
+                })
+            })
+            .collect()
+    }
+
+    /// A circuit that half-opens after 50ms.
+    fn half_open_circuit_breaker() -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(60));
+        breaker
+    }
+
+    #[tokio::test]
+    async fn half_open_circuit_is_probed_with_a_single_email() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let no_retries = RetryPolicy {
+            max_retries: 0,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(2),
+        };
+        let email_client = email_client(mock_server.uri())?
+            .with_retry_policy(no_retries)
+            .with_circuit_breaker(half_open_circuit_breaker());
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let report = email_client
+            .send_batch_emails(&recepients(3)?, &subject(), &content(), &content())
+            .await?;
+        assert_eq!(report.failed.len(), 3);
+        assert!(report.failed.iter().all(|f| f.reason.is_transient()));
+        assert_eq!(email_client.circuit_state(), CircuitState::Open);
+
+        let requests = mock_server.received_requests().await.unwrap_or_default();
+        let messages: Vec<serde_json::Value> = serde_json::from_slice(&requests[0].body)?;
+        assert_eq!(messages.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn half_open_circuit_sends_the_rest_after_a_successful_probe() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let email_client =
+            email_client(mock_server.uri())?.with_circuit_breaker(half_open_circuit_breaker());
+
+        Mock::given(path("/email/batch"))
+            .respond_with(|req: &wiremock::Request| {
+                let messages: Vec<serde_json::Value> =
+                    serde_json::from_slice(&req.body).unwrap_or_default();
+                let results = messages
+                    .iter()
+                    .map(|_| serde_json::json!({ "ErrorCode": 0, "Message": "OK", "MessageID": "id" }))
+                    .collect::<Vec<_>>();
+                ResponseTemplate::new(200).set_body_json(results)
+            })
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        let report = email_client
+            .send_batch_emails(&recepients(3)?, &subject(), &content(), &content())
+            .await?;
+        assert!(report.is_success());
+        assert_eq!(report.sent.len(), 3);
+        assert_eq!(email_client.circuit_state(), CircuitState::Closed);
+
+        let requests = mock_server.received_requests().await.unwrap_or_default();
+        let n_messages = requests
+            .iter()
+            .map(|req| serde_json::from_slice::<Vec<serde_json::Value>>(&req.body).map(|m| m.len()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        assert_eq!(n_messages, vec![1, 2]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+use std::borrow::Cow;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use reqwest::{header::RETRY_AFTER, Client, StatusCode};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    BatchReport, Email, EmailHeader, EmailTransport, Error, FailedEmail, FailureReason, Result,
+    SentEmail,
+};
+
+/// Postmark accepts at most 500 messages in a single batch request.
+const MAX_BATCH_SIZE: usize = 500;
+/// The maximum number of batch requests that are in flight at the same time.
+const MAX_CONCURRENT_REQUESTS: usize = 4;
+
+/// Sends the emails through Postmark's JSON API.
+#[derive(Debug)]
+pub struct PostmarkTransport {
+    pub http_client: Client,
+    pub url: reqwest::Url,
+    auth_token: SecretString,
+}
+
+impl PostmarkTransport {
+    pub fn new<S: AsRef<str>>(
+        url: S,
+        auth_token: SecretString,
+        timeout: std::time::Duration,
+    ) -> Result<Self> {
+        let url =
+            reqwest::Url::parse(url.as_ref()).map_err(|e| Error::UrlParsing(e.to_string()))?;
+
+        let http_client = Client::builder().timeout(timeout).build()?;
+
+        Ok(PostmarkTransport {
+            http_client,
+            url,
+            auth_token,
+        })
+    }
+
+    async fn post<T: Serialize + ?Sized>(&self, path: &str, body: &T) -> Result<reqwest::Response> {
+        let mut url = self.url.clone();
+        url.set_path(path);
+
+        let resp = self
+            .http_client
+            .post(url)
+            .header("X-Postmark-Server-Token", self.auth_token.expose_secret())
+            .json(body)
+            .send()
+            .await?;
+
+        if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+            // Only the delay in seconds is supported, not the HTTP date.
+            let retry_after = resp
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(std::time::Duration::from_secs);
+            return Err(Error::RateLimited { retry_after });
+        }
+
+        Ok(resp.error_for_status()?)
+    }
+
+    /// Sends a single batch request, the chunk must not be larger than `MAX_BATCH_SIZE`.
+    ///
+    /// Postmark responds with one result per message, in the same order as the messages were sent.
+    async fn send_chunk(&self, chunk: &[Email<'_>]) -> BatchReport {
+        let email_content = chunk.iter().map(EmailContent::from).collect::<Vec<_>>();
+        let results = match self.post("email/batch", &email_content).await {
+            Ok(resp) => resp.json::<Vec<BatchResult>>().await,
+            Err(e) => return BatchReport::all_failed(chunk, &e),
+        };
+        // The provider already accepted the request, so these errors aren't transient,
+        // retrying could send the emails twice.
+        let results = match results {
+            Ok(results) if results.len() == chunk.len() => results,
+            Ok(results) => {
+                let error = Error::InvalidResponse(format!(
+                    "expected {} results in the batch response, got {}",
+                    chunk.len(),
+                    results.len()
+                ));
+                return BatchReport::all_failed(chunk, &error);
+            }
+            Err(e) => {
+                let error = Error::InvalidResponse(e.to_string());
+                return BatchReport::all_failed(chunk, &error);
+            }
+        };
+
+        let mut report = BatchReport::default();
+        for (email, result) in chunk.iter().zip(results) {
+            let to = email.to.clone();
+            if result.error_code == 0 {
+                report.sent.push(SentEmail {
+                    to,
+                    message_id: result.message_id,
+                });
+            } else {
+                report.failed.push(FailedEmail {
+                    to,
+                    reason: FailureReason::Rejected {
+                        error_code: result.error_code,
+                        message: result.message,
+                    },
+                });
+            }
+        }
+        report
+    }
+}
+
+#[async_trait]
+impl EmailTransport for PostmarkTransport {
+    async fn send(&self, email: &Email<'_>) -> Result<()> {
+        self.post("email", &EmailContent::from(email)).await?;
+        Ok(())
+    }
+
+    /// Splits the emails into chunks of `MAX_BATCH_SIZE` and sends them concurrently.
+    async fn send_batch(&self, emails: &[Email<'_>]) -> BatchReport {
+        // Collecting the requests first avoids the higher-ranked lifetime errors of a closure in a `Send` future.
+        let requests = emails
+            .chunks(MAX_BATCH_SIZE)
+            .map(|chunk| self.send_chunk(chunk))
+            .collect::<Vec<_>>();
+        let chunk_reports = futures::stream::iter(requests)
+            .buffer_unordered(MAX_CONCURRENT_REQUESTS)
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut report = BatchReport::default();
+        for chunk_report in chunk_reports {
+            report.merge(chunk_report);
+        }
+        report
+    }
+}
+
+/// The body of a single email in the format expected by Postmark.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmailContent<'a> {
+    pub from: &'a str,
+    pub to: &'a str,
+    pub subject: &'a str,
+    pub html_body: Cow<'a, str>,
+    pub text_body: Cow<'a, str>,
+    pub message_stream: &'a str,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub headers: &'a [EmailHeader<'a>],
+}
+
+impl<'a> From<&'a Email<'a>> for EmailContent<'a> {
+    fn from(email: &'a Email<'a>) -> Self {
+        EmailContent {
+            from: email.from.as_ref(),
+            to: email.to.as_ref(),
+            subject: email.subject,
+            html_body: Cow::Borrowed(&email.html_body),
+            text_body: Cow::Borrowed(&email.text_body),
+            message_stream: email.message_stream.as_ref(),
+            headers: &email.headers,
+        }
+    }
+}
+
+/// The result of a single message in the batch response.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BatchResult {
+    error_code: i64,
+    #[serde(default)]
+    message: String,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+
+// ###################################
+// ->   TESTS
+// ###################################
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{email_client::MessageStream, web::types::ValidEmail};
+    use anyhow::Result;
+    use serde_json::{json, Value};
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, Request, Respond, ResponseTemplate,
+    };
+
+    /// Responds like Postmark, rejecting the messages sent to the `rejected` address.
+    struct BatchResponder {
+        rejected: Option<String>,
+    }
+
+    impl Respond for BatchResponder {
+        fn respond(&self, request: &Request) -> ResponseTemplate {
+            let messages: Vec<Value> = serde_json::from_slice(&request.body).unwrap();
+            let results = messages
+                .iter()
+                .map(|message| {
+                    if message["To"].as_str() == self.rejected.as_deref() {
+                        json!({ "ErrorCode": 406, "Message": "Inactive recipient" })
+                    } else {
+                        json!({ "ErrorCode": 0, "Message": "OK", "MessageID": "message-id" })
+                    }
+                })
+                .collect::<Vec<_>>();
+            ResponseTemplate::new(200).set_body_json(results)
+        }
+    }
+
+    fn transport(url: String) -> Result<PostmarkTransport> {
+        let transport =
+            PostmarkTransport::new(url, SecretString::from("token"), Duration::from_millis(500))?;
+        Ok(transport)
+    }
+
+    fn recepients(n: usize) -> Result<Vec<ValidEmail>> {
+        let out = (0..n)
+            .map(|i| ValidEmail::parse(format!("recepient_{i}@example.com")))
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(out)
+    }
+
+    fn emails<'a>(from: &'a ValidEmail, recepients: &'a [ValidEmail]) -> Vec<Email<'a>> {
+        recepients
+            .iter()
+            .map(|to| Email {
+                from,
+                to,
+                subject: "Subject",
+                html_body: "<p>Html body</p>".into(),
+                text_body: "Text body".into(),
+                message_stream: MessageStream::Broadcast,
+                headers: vec![],
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn send_batch_splits_the_emails_into_chunks() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let transport = transport(mock_server.uri())?;
+        let sender = ValidEmail::parse("sender@example.com")?;
+        let recepients = recepients(2 * MAX_BATCH_SIZE + 1)?;
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(BatchResponder { rejected: None })
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        let report = transport.send_batch(&emails(&sender, &recepients)).await;
+        assert!(report.is_success());
+        assert_eq!(report.sent.len(), recepients.len());
+
+        for request in mock_server.received_requests().await.unwrap() {
+            let messages: Vec<Value> = serde_json::from_slice(&request.body)?;
+            assert!(messages.len() <= MAX_BATCH_SIZE);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_the_rejected_recepients() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let transport = transport(mock_server.uri())?;
+        let sender = ValidEmail::parse("sender@example.com")?;
+        let recepients = recepients(3)?;
+
+        Mock::given(path("/email/batch"))
+            .respond_with(BatchResponder {
+                rejected: Some(recepients[1].as_ref().to_owned()),
+            })
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let report = transport.send_batch(&emails(&sender, &recepients)).await;
+        assert_eq!(report.sent.len(), 2);
+        assert_eq!(report.sent[0].message_id.as_deref(), Some("message-id"));
+        assert_eq!(report.failed.len(), 1);
+        let failed = &report.failed[0];
+        assert_eq!(failed.to.as_ref(), recepients[1].as_ref());
+        assert!(matches!(
+            failed.reason,
+            FailureReason::Rejected {
+                error_code: 406,
+                ..
+            }
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_batch_failed_request_fails_every_recepient() -> Result<()> {
+        let mock_server = MockServer::start().await;
+        let transport = transport(mock_server.uri())?;
+        let sender = ValidEmail::parse("sender@example.com")?;
+        let recepients = recepients(3)?;
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let report = transport.send_batch(&emails(&sender, &recepients)).await;
+        assert!(report.sent.is_empty());
+        assert_eq!(report.failed.len(), 3);
+        assert!(report.failed.iter().all(|f| f.reason.is_transient()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use crate::config::EmailRetryConfig;
+
+/// How many times, and how long apart, the `EmailClient` retries the transient failures.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        }
+    }
+
+    /// Returns the delay before the next attempt, or `None` if there are no retries left.
+    ///
+    /// A `retry_after` requested by the provider is honoured as long as it doesn't exceed `max_delay`,
+    /// longer waits are left to the caller (e.g. the delivery queue reschedules the task).
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempt >= self.max_retries {
+            return None;
+        }
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(self.backoff(attempt)),
+        }
+    }
+
+    /// Exponential backoff with jitter, the delay is randomly picked between half and the whole
+    /// exponential delay, so the clients that failed at the same time don't retry at the same time.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let half = exponential / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+}
+
+impl From<&EmailRetryConfig> for RetryPolicy {
+    fn from(config: &EmailRetryConfig) -> Self {
+        RetryPolicy {
+            max_retries: config.max_retries,
+            base_delay: Duration::from_millis(config.base_delay_millis),
+            max_delay: Duration::from_millis(config.max_delay_millis),
+        }
+    }
+}
+
+// ###################################
+// ->   TESTS
+// ###################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+        }
+    }
+
+    #[test]
+    fn backoff_is_exponential_jittered_and_capped() {
+        let policy = policy();
+        for (attempt, max) in [(0, 100), (1, 200), (2, 400), (3, 500), (10, 500)] {
+            let delay = policy.backoff(attempt);
+            assert!(delay >= Duration::from_millis(max / 2), "attempt {attempt}");
+            assert!(delay <= Duration::from_millis(max), "attempt {attempt}");
+        }
+    }
+
+    #[test]
+    fn delay_honours_retry_after_and_max_retries() {
+        let policy = policy();
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_millis(300))),
+            Some(Duration::from_millis(300))
+        );
+        assert_eq!(policy.delay(0, Some(Duration::from_secs(60))), None);
+        assert!(policy.delay(2, None).is_some());
+        assert_eq!(policy.delay(3, None), None);
+        assert_eq!(RetryPolicy::none().delay(0, None), None);
+    }
+}
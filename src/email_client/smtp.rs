@@ -0,0 +1,57 @@
+use async_trait::async_trait;
+use lettre::{
+    transport::smtp::authentication::{Credentials, Mechanism},
+    AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+use secrecy::ExposeSecret;
+
+use super::{Email, EmailTransport, Result};
+use crate::config::{SmtpAuthMechanism, SmtpConfig, SmtpTls};
+
+/// Sends the emails through an SMTP server.
+#[derive(Debug)]
+pub struct SmtpTransport {
+    inner: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpTransport {
+    pub fn new(config: &SmtpConfig, timeout: std::time::Duration) -> Result<Self> {
+        let builder = match config.tls {
+            SmtpTls::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+            }
+            SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        };
+        let mut builder = builder
+            .credentials(Credentials::new(
+                config.username.clone(),
+                config.password.expose_secret().to_string(),
+            ))
+            .authentication(vec![config.auth_mechanism.into()])
+            .timeout(Some(timeout));
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+
+        Ok(SmtpTransport {
+            inner: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl EmailTransport for SmtpTransport {
+    async fn send(&self, email: &Email<'_>) -> Result<()> {
+        self.inner.send(email.to_message()?).await?;
+        Ok(())
+    }
+}
+
+impl From<SmtpAuthMechanism> for Mechanism {
+    fn from(value: SmtpAuthMechanism) -> Self {
+        match value {
+            SmtpAuthMechanism::Plain => Mechanism::Plain,
+            SmtpAuthMechanism::Login => Mechanism::Login,
+        }
+    }
+}
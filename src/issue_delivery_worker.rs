@@ -0,0 +1,433 @@
+//! A background worker that drains the `issue_delivery_queue` table.
+//!
+//! Publishing a newsletter only persists the issue and one delivery task per confirmed subscriber,
+//! either right away through the API or once a scheduled issue is due (see `issue_scheduler`).
+//! The worker picks up the tasks of a single issue in batches and sends them with the `EmailClient`.
+//! Rows are claimed with `FOR UPDATE SKIP LOCKED` and leased for the time of the sending,
+//! so multiple app instances can safely share the queue.
+
+use std::time::Duration;
+
+use secrecy::ExposeSecret;
+use sqlx::{Executor, Postgres, Transaction};
+use tracing::{error, info, Span};
+use uuid::Uuid;
+
+use crate::{
+    email_client::{BatchRecepient, FailureReason},
+    web::{
+        subscription_events::{self, EventKind, EventSource},
+        types::{UnsubscribeToken, ValidEmail},
+    },
+    AppState,
+};
+
+/// The maximum number of delivery tasks that get sent in a single batch.
+const BATCH_SIZE: i64 = 100;
+/// The number of times a failed delivery task gets retried before it's dropped from the queue.
+const MAX_RETRIES: i16 = 5;
+/// How long the other workers skip the claimed tasks, longer than sending a batch with its retries can take.
+/// The tasks of a worker that stopped while sending are picked up again after it.
+const LEASE_SECS: i32 = 600;
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+/// A single row from the `issue_delivery_queue`
+struct DeliveryTask {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    /// `None` if the subscriber is no longer confirmed (e.g. they unsubscribed after the issue was published).
+    subscriber_id: Option<Uuid>,
+}
+
+/// A newsletter issue as it is stored in the `newsletter_issues` table.
+struct NewsletterIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+/// Runs the delivery worker in a loop that never returns.
+///
+/// Sleeps for a while when the queue is empty or when the last execution failed.
+pub async fn run_worker_until_stopped(app_state: AppState) {
+    info!(
+        "{:<20} - Starting the issue delivery worker",
+        "delivery_worker"
+    );
+    loop {
+        match try_execute_task(&app_state).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// Tries to dequeue and send a batch of delivery tasks belonging to a single newsletter issue.
+///
+/// The tasks are claimed with a lease in a short transaction and sent outside of it,
+/// the outcome is recorded in a second transaction.
+/// The tasks of the recepients the email couldn't be delivered to because of a transient failure are kept
+/// in the queue and rescheduled with an exponential backoff, until they run out of retries.
+/// The tasks that failed permanently are dropped and counted as failed,
+/// the recepients that the provider rejected get a `bounced` event in their history.
+#[tracing::instrument(
+    name = "Executing issue delivery task",
+    skip_all,
+    fields(newsletter_issue_id = tracing::field::Empty, n_tasks = tracing::field::Empty),
+    err
+)]
+pub async fn try_execute_task(app_state: &AppState) -> Result<ExecutionOutcome> {
+    let db_pool = app_state.database_mgr.db();
+    let mut transaction = db_pool.begin().await?;
+
+    let tasks = dequeue_tasks(&mut transaction).await?;
+    let Some(newsletter_issue_id) = tasks.first().map(|t| t.newsletter_issue_id) else {
+        transaction.commit().await?;
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    Span::current()
+        .record("newsletter_issue_id", newsletter_issue_id.to_string())
+        .record("n_tasks", tasks.len());
+
+    let base_url = &app_state.base_url;
+    let hmac_secret = app_state.hmac_secret.expose_secret();
+    let recepients = tasks
+        .iter()
+        .filter_map(|task| {
+            let subscriber_id = task.subscriber_id?;
+            let res = ValidEmail::parse(&task.subscriber_email);
+            // NOTE: this should never happen since we validate before we store to DB.
+            // But we still check it if implementation changes.
+            if let Err(e) = &res {
+                error!(
+                    error = ?e,
+                    "THIS IS A BUG: a confirmed subscriber is using an invalid email address - email: {}",
+                    task.subscriber_email
+                );
+            }
+            let token = UnsubscribeToken::generate(subscriber_id, hmac_secret);
+            res.ok().map(|email| BatchRecepient {
+                email,
+                unsubscribe_link: format!(
+                    "{base_url}/api/subscribe/unsubscribe?token={}",
+                    token.as_str()
+                ),
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut subscriber_emails = tasks
+        .iter()
+        .map(|t| t.subscriber_email.clone())
+        .collect::<Vec<_>>();
+
+    let issue = if recepients.is_empty() {
+        None
+    } else {
+        Some(get_issue(&mut transaction, newsletter_issue_id).await?)
+    };
+    lease_tasks(&mut transaction, newsletter_issue_id, &subscriber_emails).await?;
+    transaction.commit().await?;
+
+    // The claim is committed, so no connection and no row locks are held while the provider is slow.
+    let send_result = match &issue {
+        Some(issue) => Some(
+            app_state
+                .email_client
+                .send_batch_emails(
+                    &recepients,
+                    &issue.title,
+                    &issue.html_content,
+                    &issue.text_content,
+                )
+                .await,
+        ),
+        None => None,
+    };
+
+    let mut transaction = db_pool.begin().await?;
+    let mut failed_emails = vec![];
+    if let Some(send_result) = send_result {
+        let report = match send_result {
+            Ok(report) => report,
+            Err(e) => {
+                reschedule_tasks(&mut transaction, newsletter_issue_id, &subscriber_emails).await?;
+                transaction.commit().await?;
+                return Err(e.into());
+            }
+        };
+        // Retrying won't change the mind of the provider, e.g. about an inactive or invalid address.
+        let (retryable, permanent): (Vec<_>, Vec<_>) = report
+            .failed
+            .into_iter()
+            .partition(|failed| failed.reason.is_transient());
+        for failed in &permanent {
+            error!(
+                "dropping the delivery of the issue to {}, it failed permanently - {}",
+                failed.to.as_ref(),
+                failed.reason
+            );
+            // Only the provider refusing the address is a bounce, a failed request says nothing about it.
+            if !matches!(failed.reason, FailureReason::Rejected { .. }) {
+                continue;
+            }
+            let subscriber_id = tasks
+                .iter()
+                .find(|t| t.subscriber_email == failed.to.as_ref())
+                .and_then(|t| t.subscriber_id);
+            if let Some(subscriber_id) = subscriber_id {
+                subscription_events::record_without_request(
+                    &mut *transaction,
+                    subscriber_id,
+                    EventKind::Bounced,
+                    EventSource::Delivery,
+                )
+                .await?;
+            }
+        }
+        for failed in &retryable {
+            error!(
+                "failed to deliver the issue to {} - {}",
+                failed.to.as_ref(),
+                failed.reason
+            );
+        }
+        update_delivery_counts(
+            &mut transaction,
+            newsletter_issue_id,
+            report.sent.len() as i32,
+            permanent.len() as i32,
+        )
+        .await?;
+        failed_emails = retryable
+            .into_iter()
+            .map(|failed| failed.to.as_ref().to_owned())
+            .collect();
+    }
+
+    // Only the transient failures are retried, the rest of the tasks are done.
+    subscriber_emails.retain(|email| !failed_emails.contains(email));
+    delete_tasks(&mut transaction, newsletter_issue_id, &subscriber_emails).await?;
+    if !failed_emails.is_empty() {
+        reschedule_tasks(&mut transaction, newsletter_issue_id, &failed_emails).await?;
+        transaction.commit().await?;
+        return Err(Error::DeliveryFailed {
+            n_failed: failed_emails.len(),
+            n_completed: subscriber_emails.len(),
+        });
+    }
+    transaction.commit().await?;
+    info!("Batch email succesfully sent!");
+
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// Enqueues a delivery task for every subscriber that is eligible to receive the newsletter issue,
+/// and records the number of recipients on the issue.
+pub async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> core::result::Result<(), sqlx::Error> {
+    let query = sqlx::query(
+        r#"
+        INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email)
+        SELECT $1, email FROM subscriptions
+        WHERE status = 'confirmed'
+        "#,
+    )
+    .bind(newsletter_issue_id);
+    let n_recipients = transaction.execute(query).await?.rows_affected();
+
+    let query = sqlx::query(
+        r#"
+        UPDATE newsletter_issues SET n_recipients = $2
+        WHERE newsletter_issue_id = $1
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .bind(n_recipients as i32);
+    transaction.execute(query).await?;
+
+    Ok(())
+}
+
+/// Locks and returns up to `BATCH_SIZE` tasks that are ready to be executed.
+/// All the returned tasks belong to the same newsletter issue.
+async fn dequeue_tasks(transaction: &mut Transaction<'_, Postgres>) -> Result<Vec<DeliveryTask>> {
+    let rows: Vec<(Uuid, String, Option<Uuid>)> = sqlx::query_as(
+        r#"
+        WITH next_issue AS (
+            SELECT newsletter_issue_id FROM issue_delivery_queue
+            WHERE execute_after <= now()
+            FOR UPDATE SKIP LOCKED
+            LIMIT 1
+        )
+        SELECT q.newsletter_issue_id, q.subscriber_email, s.id
+        FROM issue_delivery_queue q
+        JOIN next_issue USING (newsletter_issue_id)
+        LEFT JOIN subscriptions s ON s.email = q.subscriber_email AND s.status = 'confirmed'
+        WHERE q.execute_after <= now()
+        FOR UPDATE OF q SKIP LOCKED
+        LIMIT $1
+        "#,
+    )
+    .bind(BATCH_SIZE)
+    .fetch_all(&mut **transaction)
+    .await?;
+
+    let tasks = rows
+        .into_iter()
+        .map(
+            |(newsletter_issue_id, subscriber_email, subscriber_id)| DeliveryTask {
+                newsletter_issue_id,
+                subscriber_email,
+                subscriber_id,
+            },
+        )
+        .collect();
+
+    Ok(tasks)
+}
+
+async fn get_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<NewsletterIssue> {
+    let (title, text_content, html_content): (String, String, String) = sqlx::query_as(
+        r#"
+        SELECT title, text_content, html_content
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .fetch_one(&mut **transaction)
+    .await?;
+
+    Ok(NewsletterIssue {
+        title,
+        text_content,
+        html_content,
+    })
+}
+
+async fn delete_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    subscriber_emails: &[String],
+) -> Result<()> {
+    let query = sqlx::query(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE newsletter_issue_id = $1 AND subscriber_email = ANY($2)
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .bind(subscriber_emails);
+    transaction.execute(query).await?;
+
+    Ok(())
+}
+
+/// Claims the tasks for the time of the sending, the other workers only pick up the tasks that are due.
+async fn lease_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    subscriber_emails: &[String],
+) -> Result<()> {
+    let query = sqlx::query(
+        r#"
+        UPDATE issue_delivery_queue
+        SET execute_after = now() + interval '1 second' * $3
+        WHERE newsletter_issue_id = $1 AND subscriber_email = ANY($2)
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .bind(subscriber_emails)
+    .bind(LEASE_SECS);
+    transaction.execute(query).await?;
+
+    Ok(())
+}
+
+/// Pushes the execution of the failed tasks back with an exponential backoff
+/// and drops the tasks that ran out of retries.
+async fn reschedule_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    subscriber_emails: &[String],
+) -> Result<()> {
+    let query = sqlx::query(
+        r#"
+        UPDATE issue_delivery_queue
+        SET n_retries = n_retries + 1,
+            execute_after = now() + interval '30 seconds' * power(2, n_retries)
+        WHERE newsletter_issue_id = $1 AND subscriber_email = ANY($2)
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .bind(subscriber_emails);
+    transaction.execute(query).await?;
+
+    let query = sqlx::query(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE newsletter_issue_id = $1 AND subscriber_email = ANY($2) AND n_retries > $3
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .bind(subscriber_emails)
+    .bind(MAX_RETRIES);
+    let dropped = transaction.execute(query).await?.rows_affected();
+    if dropped > 0 {
+        error!("dropped {dropped} delivery tasks that ran out of retries");
+        update_delivery_counts(transaction, newsletter_issue_id, 0, dropped as i32).await?;
+    }
+
+    Ok(())
+}
+
+/// Adds to the number of delivered and failed (permanently or out of retries) emails of the issue.
+async fn update_delivery_counts(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    n_delivered: i32,
+    n_failed: i32,
+) -> Result<()> {
+    let query = sqlx::query(
+        r#"
+        UPDATE newsletter_issues
+        SET n_delivered = n_delivered + $2, n_failed = n_failed + $3
+        WHERE newsletter_issue_id = $1
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .bind(n_delivered)
+    .bind(n_failed);
+    transaction.execute(query).await?;
+
+    Ok(())
+}
+
+// ###################################
+// ->   ERROR
+// ###################################
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("email client error: {0}")]
+    EmailClient(#[from] crate::email_client::Error),
+    #[error("failed to deliver {n_failed} emails, {n_completed} tasks were completed")]
+    DeliveryFailed { n_failed: usize, n_completed: usize },
+}
@@ -0,0 +1,86 @@
+//! A background task that publishes the scheduled newsletter issues once they are due.
+//!
+//! Publishing a scheduled issue works the same as publishing through the API: the delivery tasks
+//! are enqueued and the `issue_delivery_worker` sends the emails.
+//! Due issues are locked with `SKIP LOCKED`, so several instances can run the scheduler at the same time.
+
+use std::time::Duration;
+
+use sqlx::Executor;
+use tracing::{info, Span};
+use uuid::Uuid;
+
+use crate::{issue_delivery_worker::enqueue_delivery_tasks, AppState};
+
+/// How often the scheduler checks for due issues.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Runs the scheduler in a loop that never returns.
+pub async fn run_scheduler_until_stopped(app_state: AppState) {
+    info!("{:<20} - Starting the issue scheduler", "issue_scheduler");
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        // Errors are already logged by the instrumented function.
+        let _ = try_publish_due_issues(&app_state).await;
+    }
+}
+
+/// Publishes every scheduled issue whose send time has passed, returns the number of published issues.
+#[tracing::instrument(
+    name = "Publishing due newsletter issues",
+    skip_all,
+    fields(published = tracing::field::Empty),
+    err
+)]
+pub async fn try_publish_due_issues(app_state: &AppState) -> Result<u64> {
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+
+    let due_issues: Vec<Uuid> = sqlx::query_scalar(
+        r#"
+        SELECT newsletter_issue_id
+        FROM newsletter_issues
+        WHERE status = 'scheduled' AND scheduled_for <= now()
+        FOR UPDATE
+        SKIP LOCKED
+        "#,
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    for newsletter_issue_id in &due_issues {
+        enqueue_delivery_tasks(&mut transaction, *newsletter_issue_id).await?;
+
+        let query = sqlx::query(
+            r#"
+            UPDATE newsletter_issues
+            SET status = 'published', published_at = now(), updated_at = now()
+            WHERE newsletter_issue_id = $1
+            "#,
+        )
+        .bind(newsletter_issue_id);
+        transaction.execute(query).await?;
+    }
+
+    transaction.commit().await?;
+
+    let published = due_issues.len() as u64;
+    Span::current().record("published", published);
+    if published > 0 {
+        info!("Scheduled newsletter issues published!");
+    }
+
+    Ok(published)
+}
+
+// ###################################
+// ->   ERROR
+// ###################################
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+}
@@ -1,8 +1,11 @@
 mod app;
+pub mod cleanup_worker;
 pub mod config;
 pub mod database;
 pub mod email_client;
 mod error;
+pub mod issue_delivery_worker;
+pub mod issue_scheduler;
 pub mod redis_manager;
 pub mod templ_manager;
 pub mod utils;
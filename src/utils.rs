@@ -2,6 +2,10 @@ use base64::{
     engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
     Engine,
 };
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 // ###################################
 // ->   Base64 utils
 // ###################################
@@ -81,6 +85,63 @@ pub fn hex_decode(hex: impl AsRef<str>) -> Result<Vec<u8>> {
     Ok(res)
 }
 
+// ###################################
+// ->   SHA-256 utils
+// ###################################
+/// Hashes the data with SHA-256 and returns the hex encoded digest.
+pub fn sha256_hex(data: impl AsRef<[u8]>) -> String {
+    hex_encode(Sha256::digest(data.as_ref()))
+}
+
+// ###################################
+// ->   HMAC utils
+// ###################################
+/// Signs the data with HMAC-SHA256 and returns the 32 byte long tag.
+pub fn hmac_sha256_sign(key: impl AsRef<[u8]>, data: impl AsRef<[u8]>) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_ref()).expect("HMAC can take a key of any size");
+    mac.update(data.as_ref());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Verifies the HMAC-SHA256 tag of the data in constant time.
+pub fn hmac_sha256_verify(
+    key: impl AsRef<[u8]>,
+    data: impl AsRef<[u8]>,
+    tag: impl AsRef<[u8]>,
+) -> Result<()> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_ref()).expect("HMAC can take a key of any size");
+    mac.update(data.as_ref());
+    mac.verify_slice(tag.as_ref())
+        .map_err(|_| UtilsError::HmacInvalid)
+}
+
+// ###################################
+// ->   CSV utils
+// ###################################
+/// Quotes the CSV field if needed. The fields that a spreadsheet would run as a formula are prefixed with a quote.
+pub fn csv_field(value: &str) -> Cow<'_, str> {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        Cow::Owned(format!("'{value}"))
+    } else {
+        Cow::Borrowed(value)
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        value
+    }
+}
+
+// ###################################
+// ->   Date utils
+// ###################################
+/// Formats the date the way the admin pages show it, down to the second so the event logs stay precise.
+pub fn format_utc(date: DateTime<Utc>) -> String {
+    date.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
 // ###################################
 // ->   ERROR
 // ###################################
@@ -94,6 +155,8 @@ pub enum UtilsError {
     B64uDecode(String),
     #[error("hex decoding error: {0}")]
     HexDecode(String),
+    #[error("hmac signature verification failed")]
+    HmacInvalid,
 }
 
 #[cfg(test)]
@@ -189,9 +252,26 @@ mod test {
         assert!(hex_decode(input).is_err());
     }
 
+    #[test]
+    fn test_hmac_sign_and_verify() {
+        let tag = hmac_sha256_sign(b"key", b"data");
+        assert_eq!(tag.len(), 32);
+        assert!(hmac_sha256_verify(b"key", b"data", &tag).is_ok());
+        assert!(hmac_sha256_verify(b"other key", b"data", &tag).is_err());
+        assert!(hmac_sha256_verify(b"key", b"other data", &tag).is_err());
+    }
+
     #[test]
     fn test_hex_decode_invalid_chars() {
         let input = "zzzz";
         assert!(hex_decode(input).is_err());
     }
+
+    #[test]
+    fn csv_fields_are_quoted_and_defused() {
+        assert_eq!(csv_field("ursula@example.com"), "ursula@example.com");
+        assert_eq!(csv_field("Le Guin, Ursula"), "\"Le Guin, Ursula\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("=HYPERLINK(1)"), "'=HYPERLINK(1)");
+    }
 }
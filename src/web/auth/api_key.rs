@@ -0,0 +1,203 @@
+//! API keys for the JSON API, sent as `Authorization: Bearer <key>`.
+//!
+//! The admins create the keys for themselves and pick their scopes, a key is only shown when it's created.
+//! The keys are random, so only their SHA-256 hashes are stored and checking a key is a single lookup
+//! instead of a password verification. A key also carries the role of its user, a key with the
+//! `news:publish` scope still can't publish once its user is no longer an editor.
+
+use std::str::FromStr;
+
+use axum::http::{header, HeaderMap};
+use rand::{rng, RngCore};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{database::DbManager, utils};
+
+use super::{AuthError, AuthenticatedUser, Result, Role};
+
+/// Makes the keys easy to recognize, for example by secret scanners.
+const KEY_PREFIX: &str = "mlm_";
+/// The part of the key that is shown in the list of keys.
+const DISPLAY_PREFIX_LEN: usize = 8;
+
+/// What an API key can be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, strum_macros::AsRefStr)]
+pub enum Scope {
+    #[serde(rename = "news:publish")]
+    #[strum(serialize = "news:publish")]
+    NewsPublish,
+    #[serde(rename = "subscribers:read")]
+    #[strum(serialize = "subscribers:read")]
+    SubscribersRead,
+    #[serde(rename = "subscribers:write")]
+    #[strum(serialize = "subscribers:write")]
+    SubscribersWrite,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [
+        Scope::NewsPublish,
+        Scope::SubscribersRead,
+        Scope::SubscribersWrite,
+    ];
+
+    /// The role the user needs to use the scope.
+    pub fn required_role(self) -> Role {
+        match self {
+            Scope::NewsPublish => Role::Editor,
+            Scope::SubscribersRead => Role::Viewer,
+            Scope::SubscribersWrite => Role::Editor,
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = AuthError;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_ref() == value)
+            .ok_or_else(|| AuthError::ScopeUnknown(value.to_string()))
+    }
+}
+
+/// A random API key, a `mlm_` prefix followed by 32 Base64-URL encoded random bytes.
+#[derive(Debug)]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    pub fn generate() -> Self {
+        let mut rand_bytes = [0u8; 32];
+        rng().fill_bytes(&mut rand_bytes);
+
+        Self(format!("{KEY_PREFIX}{}", utils::b64u_encode(rand_bytes)))
+    }
+
+    pub fn parse(value: &str) -> Result<Self> {
+        let encoded = value
+            .strip_prefix(KEY_PREFIX)
+            .ok_or(AuthError::ApiKeyInvalid)?;
+        if !utils::b64u_decode(encoded).is_ok_and(|bytes| bytes.len() == 32) {
+            return Err(AuthError::ApiKeyInvalid);
+        }
+        Ok(Self(value.to_string()))
+    }
+
+    /// Returns the API key from the `Authorization` header, `None` if the header doesn't use the `Bearer` schema.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>> {
+        let Some(header_val) = headers.get(header::AUTHORIZATION) else {
+            return Ok(None);
+        };
+        let header_val = header_val
+            .to_str()
+            .map_err(|e| AuthError::InvalidUtf(e.to_string()))?;
+        header_val
+            .strip_prefix("Bearer ")
+            .map(|key| Self::parse(key.trim()))
+            .transpose()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The hex encoded SHA-256 hash of the key, as it's stored in the database.
+    pub fn hash(&self) -> String {
+        utils::sha256_hex(&self.0)
+    }
+
+    /// The start of the key, enough to tell the keys apart.
+    pub fn display_prefix(&self) -> &str {
+        &self.0[..KEY_PREFIX.len() + DISPLAY_PREFIX_LEN]
+    }
+
+    /// Authenticates the user the key belongs to, the key has to have the `scope`.
+    /// Revoked and expired keys and the keys of deactivated users are rejected. Records when the key was used.
+    pub async fn authenticate(&self, dm: &DbManager, scope: Scope) -> Result<AuthenticatedUser> {
+        let key: Option<(Uuid, Role, Vec<String>)> = sqlx::query_as(
+            r#"
+            UPDATE api_keys k SET last_used_at = now()
+            FROM users u
+            WHERE k.key_hash = $1
+                AND k.user_id = u.user_id
+                AND k.revoked_at IS NULL
+                AND (k.expires_at IS NULL OR k.expires_at > now())
+                AND u.deactivated_at IS NULL
+            RETURNING k.user_id, u.role, k.scopes
+            "#,
+        )
+        .bind(self.hash())
+        .fetch_optional(dm.db())
+        .await?;
+        let (user_id, role, scopes) = key.ok_or(AuthError::ApiKeyInvalid)?;
+
+        if !scopes
+            .iter()
+            .any(|s| Scope::from_str(s).is_ok_and(|s| s == scope))
+        {
+            return Err(AuthError::ScopeMissing { scope });
+        }
+        tracing::info!(%user_id, "Succesful API key authentication!");
+
+        Ok(AuthenticatedUser { user_id, role })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use claims::{assert_err, assert_none};
+
+    #[test]
+    fn api_key_parses_and_hashes() -> anyhow::Result<()> {
+        let key = ApiKey::generate();
+        assert!(key.as_str().starts_with(KEY_PREFIX));
+        assert_eq!(
+            key.display_prefix().len(),
+            KEY_PREFIX.len() + DISPLAY_PREFIX_LEN
+        );
+
+        let parsed = ApiKey::parse(key.as_str())?;
+        assert_eq!(parsed.hash(), key.hash());
+        assert_ne!(key.hash(), ApiKey::generate().hash());
+
+        assert_err!(ApiKey::parse(&key.as_str()[KEY_PREFIX.len()..]));
+        assert_err!(ApiKey::parse("mlm_too-short"));
+        Ok(())
+    }
+
+    #[test]
+    fn api_key_is_read_from_the_bearer_header() -> anyhow::Result<()> {
+        let key = ApiKey::generate();
+        let mut headers = HeaderMap::new();
+        assert_none!(ApiKey::from_headers(&headers)?);
+
+        headers.insert(header::AUTHORIZATION, "Basic dXNlcjpwYXNz".parse()?);
+        assert_none!(ApiKey::from_headers(&headers)?);
+
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", key.as_str()).parse()?,
+        );
+        let parsed = ApiKey::from_headers(&headers)?.expect("bearer header");
+        assert_eq!(parsed.hash(), key.hash());
+
+        headers.insert(header::AUTHORIZATION, "Bearer not-a-key".parse()?);
+        assert_err!(ApiKey::from_headers(&headers));
+        Ok(())
+    }
+
+    #[test]
+    fn scopes_parse_from_their_names() -> anyhow::Result<()> {
+        assert_eq!(Scope::from_str("news:publish")?, Scope::NewsPublish);
+        assert_eq!(Scope::SubscribersRead.as_ref(), "subscribers:read");
+        assert_eq!(
+            Scope::from_str("subscribers:write")?,
+            Scope::SubscribersWrite
+        );
+        assert_err!(Scope::from_str("news:delete"));
+        Ok(())
+    }
+}
@@ -3,14 +3,21 @@
 //! You can use `authenticate()` method to try and authenticate the user from the DB.
 
 use axum::http::HeaderMap;
-use secrecy::SecretString;
+use secrecy::{ExposeSecret, SecretString};
 use unicode_segmentation::UnicodeSegmentation;
 use uuid::Uuid;
 
 use crate::database::DbManager;
 use crate::utils::b64_decode_to_string;
 
-use super::{password, AuthError, Result};
+use super::{password, AuthError, Result, Role};
+
+/// A user that was successfully authenticated.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthenticatedUser {
+    pub user_id: Uuid,
+    pub role: Role,
+}
 
 /// User credentials
 #[derive(Debug, serde::Deserialize)]
@@ -25,10 +32,11 @@ impl Credentials {
     }
 
     /// Try to authenticate the user using the information from the `users` table in the DB.
-    pub async fn authenticate(self, dm: &DbManager) -> Result<Uuid> {
-        let user_id_n_pwd_hash: Option<(Uuid, String)> = sqlx::query_as(
+    /// Deactivated users can't authenticate, even with the right password.
+    pub async fn authenticate(self, dm: &DbManager) -> Result<AuthenticatedUser> {
+        let user_id_n_pwd_hash: Option<(Uuid, String, Role, bool)> = sqlx::query_as(
             r#"
-    SELECT user_id, password_hash FROM users
+    SELECT user_id, password_hash, role, deactivated_at IS NOT NULL FROM users
     WHERE username = $1
     "#,
         )
@@ -38,14 +46,17 @@ impl Credentials {
         .map_err(|er| anyhow::anyhow!("authenticating credentials: {}", er))?;
 
         // Validate Password
-        let mut hash = r#"$argon2id$v=19$m=19456,t=2,p=1$DqfdT4sWTiKO8R19hTTtyg$DWeO60WYNYRhAdju0/dzYNhrtmb0jZ6+/ceCHyNKNfk"#.to_string();
-        let (user_id, expected_pwd_hash) = user_id_n_pwd_hash.unwrap_or_default();
+        let (user_id, expected_pwd_hash, role, deactivated) =
+            user_id_n_pwd_hash.unwrap_or_default();
         // Uuid defaults to NIL - all zeroes.
-        // If user_id is NIL we will check against the default hash which should always fail.
-        if !user_id.is_nil() {
-            hash = expected_pwd_hash;
-        }
-        password::validate_async(self.password, SecretString::from(hash)).await?;
+        // If user_id is NIL we will check against the dummy hash which should always fail,
+        // it's made with the current settings so it takes as long as a real password.
+        let hash = if user_id.is_nil() {
+            password::dummy_hash()
+        } else {
+            SecretString::from(expected_pwd_hash)
+        };
+        password::validate_async(self.password.clone(), hash.clone()).await?;
         // This should theoretically never happen, since the password validation should fail if the
         // user doesn't exist.
         if user_id.is_nil() {
@@ -53,9 +64,16 @@ impl Credentials {
                 username: self.username,
             });
         }
+        if deactivated {
+            return Err(AuthError::UserDeactivated);
+        }
         tracing::info!("Succesful authentication!");
 
-        Ok(user_id)
+        if password::needs_rehash(&hash) {
+            password_hash_upgrade(dm, user_id, self.password, hash).await;
+        }
+
+        Ok(AuthenticatedUser { user_id, role })
     }
 
     pub async fn parse_headers_basic_schema(header_map: HeaderMap) -> Result<Self> {
@@ -90,6 +108,33 @@ impl Credentials {
     }
 }
 
+/// Replaces a hash made with outdated settings, the password was just validated against it.
+/// A failed upgrade doesn't fail the login, it's tried again on the next one.
+async fn password_hash_upgrade(
+    dm: &DbManager,
+    user_id: Uuid,
+    raw_password: SecretString,
+    old_hash: SecretString,
+) {
+    let upgrade = async {
+        let new_hash = password::hash_new_to_string_async(raw_password).await?;
+        // Unless the password was changed in the meantime.
+        sqlx::query(
+            "UPDATE users SET password_hash = $1 WHERE user_id = $2 AND password_hash = $3",
+        )
+        .bind(new_hash.expose_secret())
+        .bind(user_id)
+        .bind(old_hash.expose_secret())
+        .execute(dm.db())
+        .await?;
+        Ok::<_, AuthError>(())
+    };
+    match upgrade.await {
+        Ok(()) => tracing::info!(%user_id, "Upgraded the password hash!"),
+        Err(e) => tracing::warn!(%user_id, "Unable to upgrade the password hash: {e}"),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
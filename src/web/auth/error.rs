@@ -1,7 +1,10 @@
 use axum::http::StatusCode;
+use tower_sessions_redis_store::fred;
 
 use crate::web::error::ClientError;
 
+use super::{api_key::Scope, Role};
+
 pub type Result<T> = core::result::Result<T, AuthError>;
 
 #[derive(Debug, thiserror::Error)]
@@ -14,6 +17,20 @@ pub enum AuthError {
     PasswordInvalid,
     #[error("password too long")]
     PasswordTooLong,
+    #[error("password too weak: {0}")]
+    PasswordTooWeak(String),
+    #[error("the user is deactivated")]
+    UserDeactivated,
+    #[error("the role of the user is insufficient, required: {}", required.as_ref())]
+    RoleInsufficient { required: Role },
+    #[error("the API key is invalid, expired or revoked")]
+    ApiKeyInvalid,
+    #[error("the API key is missing the scope: {}", scope.as_ref())]
+    ScopeMissing { scope: Scope },
+    #[error("unknown API key scope: {0}")]
+    ScopeUnknown(String),
+    #[error("too many failed logins, retry after {retry_after_secs} seconds")]
+    Throttled { retry_after_secs: u64 },
 
     #[error("error parsing the user salt: {0}")]
     Salting(String),
@@ -29,6 +46,13 @@ pub enum AuthError {
     #[error("received the wrong authentication schema. expected: {schema}")]
     WrongAuthSchema { schema: String },
 
+    #[error("totp error: {0}")]
+    Totp(String),
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("redis error: {0}")]
+    Redis(#[from] fred::error::Error),
+
     #[error("password_hash error: {0}")]
     PasswordHash(#[from] argon2::password_hash::Error),
     #[error("unexpected error: {0}")]
@@ -43,12 +67,34 @@ impl AuthError {
                 StatusCode::UNAUTHORIZED,
                 ClientError::UsernameOrPasswordInvalid,
             ),
-            MissingAuthHeader
+            UserDeactivated => (StatusCode::UNAUTHORIZED, ClientError::UserDeactivated),
+            Throttled { retry_after_secs } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                ClientError::TooManyLoginAttempts {
+                    retry_after_secs: *retry_after_secs,
+                },
+            ),
+            RoleInsufficient { .. } | ScopeMissing { .. } => {
+                (StatusCode::FORBIDDEN, ClientError::Forbidden)
+            }
+            ScopeUnknown(scope) => (
+                StatusCode::BAD_REQUEST,
+                ClientError::InputInvalid(format!("unknown scope: {scope}")),
+            ),
+            PasswordTooWeak(reason) => (
+                StatusCode::BAD_REQUEST,
+                ClientError::InputInvalid(format!("the password is too weak, {reason}")),
+            ),
+            ApiKeyInvalid
+            | MissingAuthHeader
             | InvalidUtf(_)
             | MissingColon
             | WrongAuthSchema { .. }
             | Salting(_)
             | Hashing(_) => (StatusCode::UNAUTHORIZED, ClientError::Unauthorized),
+            Totp(_) | Sqlx(_) | Redis(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, ClientError::ServiceError)
+            }
             _ => (StatusCode::UNAUTHORIZED, ClientError::ServiceError),
         }
     }
@@ -1,6 +1,13 @@
+pub mod api_key;
 pub mod credentials;
 mod error;
 pub mod password;
+mod role;
+pub mod throttle;
+pub mod totp;
 
+pub use api_key::{ApiKey, Scope};
 pub use credentials::*;
 pub use error::{AuthError, Result};
+pub use role::Role;
+pub use throttle::LoginThrottle;
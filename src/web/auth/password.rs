@@ -1,21 +1,131 @@
+//! Hashing and validating the passwords with argon2id.
+//!
+//! The argon2 parameters come from `PasswordConfig`. With a pepper configured the password is signed with
+//! HMAC-SHA256 before it's hashed, and the hash records which pepper was used in its `keyid` parameter.
+//! The hashes made with older parameters or without the pepper still validate, `needs_rehash` tells
+//! `Credentials::authenticate` to replace them once the user logs in.
+
+use anyhow::anyhow;
 use argon2::{
     password_hash::{PasswordHasher, SaltString},
-    Argon2, PasswordHash, PasswordVerifier,
+    Algorithm, Argon2, KeyId, Params, ParamsBuilder, PasswordHash, PasswordVerifier, Version,
 };
-use secrecy::{ExposeSecret, SecretString};
+use secrecy::{ExposeSecret, SecretSlice, SecretString};
+use sha2::{Digest, Sha256};
 use std::sync::OnceLock;
+use unicode_segmentation::UnicodeSegmentation;
 use uuid::Uuid;
 
+use crate::{
+    config::{get_or_init_config, PasswordConfig},
+    utils,
+};
+
 use super::{AuthError, Result};
 
-pub fn get_argon2() -> &'static Argon2<'static> {
-    static AUTH_MAN: OnceLock<Argon2> = OnceLock::new();
-    AUTH_MAN.get_or_init(|| {
-        Argon2::new(
-            argon2::Algorithm::Argon2id,
-            argon2::Version::V0x13,
-            argon2::Params::DEFAULT,
-        )
+static HASHER: OnceLock<Hasher> = OnceLock::new();
+
+/// The argon2 hasher and the pepper built from `PasswordConfig`.
+struct Hasher {
+    argon2: Argon2<'static>,
+    params: Params,
+    pepper: Option<SecretSlice<u8>>,
+    /// A hash of a random password made with the current settings. It's validated when the username doesn't exist,
+    /// so a missing user takes as long as a wrong password.
+    dummy_hash: String,
+}
+
+impl Hasher {
+    fn from_config(config: &PasswordConfig) -> Result<Self> {
+        let pepper = config
+            .pepper_b64enc
+            .as_ref()
+            .map(|pepper| utils::b64_decode(pepper.expose_secret()))
+            .transpose()
+            .map_err(|e| anyhow!("failed to decode the password pepper from base64: {e}"))?
+            .map(SecretSlice::from);
+
+        let mut builder = ParamsBuilder::new();
+        builder
+            .m_cost(config.memory_kib)
+            .t_cost(config.iterations)
+            .p_cost(config.parallelism);
+        if let Some(pepper) = &pepper {
+            builder.keyid(
+                KeyId::new(&pepper_id(pepper)).map_err(|e| AuthError::Hashing(e.to_string()))?,
+            );
+        }
+        let params = builder
+            .build()
+            .map_err(|e| AuthError::Hashing(format!("invalid argon2 parameters: {e}")))?;
+
+        let mut hasher = Hasher {
+            argon2: Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone()),
+            params,
+            pepper,
+            dummy_hash: String::new(),
+        };
+        hasher.dummy_hash = hasher
+            .hash(&SecretString::from(Uuid::new_v4().to_string()))?
+            .expose_secret()
+            .to_string();
+
+        Ok(hasher)
+    }
+
+    /// The bytes that are hashed, the password signed with the pepper if the hash uses one.
+    fn input(&self, raw_password: &SecretString, keyid: &[u8]) -> Result<Vec<u8>> {
+        let raw_password = raw_password.expose_secret().as_bytes();
+        if keyid.is_empty() {
+            return Ok(raw_password.to_vec());
+        }
+        match &self.pepper {
+            Some(pepper) if keyid == self.params.keyid() => Ok(utils::hmac_sha256_sign(
+                pepper.expose_secret(),
+                raw_password,
+            )),
+            _ => Err(AuthError::Hashing(
+                "the password hash uses an unknown pepper".to_string(),
+            )),
+        }
+    }
+
+    fn hash(&self, raw_password: &SecretString) -> Result<SecretString> {
+        let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes())
+            .map_err(|e| AuthError::Salting(e.to_string()))?;
+        let input = self.input(raw_password, self.params.keyid())?;
+
+        let hashed = self
+            .argon2
+            .hash_password(&input, &salt)
+            .map_err(|e| AuthError::Hashing(e.to_string()))?
+            .to_string();
+
+        Ok(SecretString::from(hashed))
+    }
+}
+
+/// Identifies the pepper in the hashes without revealing it.
+fn pepper_id(pepper: &SecretSlice<u8>) -> [u8; 6] {
+    let digest = Sha256::digest(pepper.expose_secret());
+    let mut id = [0u8; 6];
+    id.copy_from_slice(&digest[..6]);
+    id
+}
+
+/// Builds the hasher from the config, so invalid settings fail when the app starts.
+/// Does nothing if the hasher is already built.
+pub fn init(config: &PasswordConfig) -> Result<()> {
+    if HASHER.get().is_none() {
+        let _ = HASHER.set(Hasher::from_config(config)?);
+    }
+    Ok(())
+}
+
+fn hasher() -> &'static Hasher {
+    HASHER.get_or_init(|| {
+        Hasher::from_config(&get_or_init_config().password_config)
+            .unwrap_or_else(|e| panic!("Unable to build the password hasher: {e}"))
     })
 }
 
@@ -30,17 +140,7 @@ pub async fn hash_new_to_string_async(raw_password: SecretString) -> Result<Secr
 }
 
 pub fn hash_new_to_string(raw_password: SecretString) -> Result<SecretString> {
-    let argon2 = get_argon2();
-
-    let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes())
-        .map_err(|e| AuthError::Salting(e.to_string()))?;
-
-    let hashed = argon2
-        .hash_password(raw_password.expose_secret().as_bytes(), &salt)
-        .map_err(|e| AuthError::Hashing(e.to_string()))?
-        .to_string();
-
-    Ok(SecretString::from(hashed))
+    hasher().hash(&raw_password)
 }
 
 /// Async wrapper around validate(), spawns a new blocking task.
@@ -53,17 +153,85 @@ pub async fn validate_async(raw_password: SecretString, pwd_hash: SecretString)
         .map_err(|er| anyhow::anyhow!("password hashing: {}", er))?
 }
 pub fn validate(raw_password: SecretString, pwd_hash_ref: SecretString) -> Result<()> {
-    let argon2 = get_argon2();
+    let hasher = hasher();
 
     let parsed_hash = PasswordHash::new(pwd_hash_ref.expose_secret())?;
+    let input = hasher.input(&raw_password, Params::try_from(&parsed_hash)?.keyid())?;
 
-    argon2
-        .verify_password(raw_password.expose_secret().as_bytes(), &parsed_hash)
+    // The parameters of the hash are used, not the configured ones.
+    hasher
+        .argon2
+        .verify_password(&input, &parsed_hash)
         .map_err(|_| AuthError::PasswordInvalid)?;
 
     Ok(())
 }
 
+/// Returns true if the hash wasn't made with the current parameters and pepper.
+pub fn needs_rehash(pwd_hash_ref: &SecretString) -> bool {
+    let current = &hasher().params;
+    let Ok(parsed_hash) = PasswordHash::new(pwd_hash_ref.expose_secret()) else {
+        return true;
+    };
+    let Ok(params) = Params::try_from(&parsed_hash) else {
+        return true;
+    };
+
+    parsed_hash.algorithm != Algorithm::Argon2id.ident()
+        || parsed_hash.version != Some(Version::V0x13.into())
+        || params.m_cost() != current.m_cost()
+        || params.t_cost() != current.t_cost()
+        || params.p_cost() != current.p_cost()
+        || params.keyid() != current.keyid()
+}
+
+/// The hash to validate against when the user doesn't exist, it never matches.
+pub fn dummy_hash() -> SecretString {
+    SecretString::from(hasher().dummy_hash.as_str())
+}
+
+/// The minimum number of characters (grapheme clusters) of a new password.
+pub const MIN_PASSWORD_LEN: usize = 12;
+/// The maximum number of characters (grapheme clusters) of a new password.
+pub const MAX_PASSWORD_LEN: usize = 128;
+
+/// Checks that a new password follows the password policy.
+///
+/// The password must have between `MIN_PASSWORD_LEN` and `MAX_PASSWORD_LEN` characters and mix at least two
+/// kinds of characters (letters, digits, other), so a long passphrase with spaces is fine but a PIN is not.
+pub fn check_strength(raw_password: &SecretString) -> Result<()> {
+    let raw_password = raw_password.expose_secret();
+
+    let len = raw_password.graphemes(true).count();
+    if len < MIN_PASSWORD_LEN {
+        return Err(AuthError::PasswordTooWeak(format!(
+            "it must have at least {MIN_PASSWORD_LEN} characters"
+        )));
+    }
+    if len > MAX_PASSWORD_LEN {
+        return Err(AuthError::PasswordTooWeak(format!(
+            "it can't have more than {MAX_PASSWORD_LEN} characters"
+        )));
+    }
+
+    let has_letters = raw_password.chars().any(char::is_alphabetic);
+    let has_digits = raw_password.chars().any(char::is_numeric);
+    let has_other = raw_password
+        .chars()
+        .any(|c| !c.is_alphabetic() && !c.is_numeric());
+    let kinds = [has_letters, has_digits, has_other]
+        .into_iter()
+        .filter(|&has| has)
+        .count();
+    if kinds < 2 {
+        return Err(AuthError::PasswordTooWeak(
+            "it must mix at least two of letters, digits and other characters".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -100,4 +268,65 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn pwd_hash_with_old_params_validates_and_needs_rehash() -> Result<()> {
+        let password = SecretString::from(fake_valid_pwd());
+        let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes())?;
+        let old_hash = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(8192, 1, 1, None)?,
+        )
+        .hash_password(password.expose_secret().as_bytes(), &salt)?
+        .to_string();
+        let old_hash = SecretString::from(old_hash);
+
+        validate(password.clone(), old_hash.clone())?;
+        assert!(needs_rehash(&old_hash));
+
+        let new_hash = hash_new_to_string(password.clone())?;
+        validate(password, new_hash.clone())?;
+        assert!(!needs_rehash(&new_hash));
+        assert!(!needs_rehash(&dummy_hash()));
+        Ok(())
+    }
+
+    #[test]
+    fn pwd_hash_with_an_unknown_pepper_is_rejected() -> Result<()> {
+        let password = SecretString::from(fake_valid_pwd());
+        let hasher = Hasher::from_config(&PasswordConfig {
+            memory_kib: 8192,
+            iterations: 1,
+            parallelism: 1,
+            pepper_b64enc: Some(SecretString::from(utils::b64_encode(Uuid::new_v4()))),
+        })?;
+        let hashed = hasher.hash(&password)?;
+
+        assert_err!(validate(password, hashed.clone()));
+        assert!(needs_rehash(&hashed));
+        Ok(())
+    }
+
+    #[test]
+    fn pwd_strength_policy() {
+        for weak in [
+            "short1!",
+            "onlylettersinthispassword",
+            "123456789012345",
+            &"a1".repeat(65),
+        ] {
+            assert_err!(check_strength(&SecretString::from(weak)), "{weak}");
+        }
+        for strong in [
+            "correct horse battery staple",
+            "s3cure-enough-pwd",
+            "ŠčŽ12345678ćđ",
+        ] {
+            assert!(
+                check_strength(&SecretString::from(strong)).is_ok(),
+                "{strong}"
+            );
+        }
+    }
 }
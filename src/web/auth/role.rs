@@ -0,0 +1,67 @@
+//! The roles of the admin users, stored in the `role` column of the `users` table.
+//!
+//! The roles are ordered by their permissions, every role can do everything the roles below it can:
+//! - `Viewer` can look around the admin area,
+//! - `Editor` can also write, schedule and publish newsletter issues,
+//! - `Owner` can also invite and deactivate users.
+
+use serde::{Deserialize, Serialize};
+
+use super::{AuthError, Result};
+
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    sqlx::Type,
+    strum_macros::AsRefStr,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Viewer,
+    Editor,
+    Owner,
+}
+
+impl Role {
+    /// Fails with `AuthError::RoleInsufficient` if this role doesn't have the permissions of the `required` role.
+    pub fn require(self, required: Role) -> Result<()> {
+        if self < required {
+            return Err(AuthError::RoleInsufficient { required });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use claims::{assert_err, assert_ok};
+
+    #[test]
+    fn roles_include_the_permissions_of_lower_roles() {
+        assert_ok!(Role::Owner.require(Role::Editor));
+        assert_ok!(Role::Owner.require(Role::Owner));
+        assert_ok!(Role::Editor.require(Role::Viewer));
+        assert_err!(Role::Editor.require(Role::Owner));
+        assert_err!(Role::Viewer.require(Role::Editor));
+    }
+
+    #[test]
+    fn roles_serialize_to_lowercase() -> anyhow::Result<()> {
+        assert_eq!(serde_json::to_string(&Role::Editor)?, r#""editor""#);
+        assert_eq!(serde_json::from_str::<Role>(r#""owner""#)?, Role::Owner);
+        assert_eq!(Role::Viewer.as_ref(), "viewer");
+        Ok(())
+    }
+}
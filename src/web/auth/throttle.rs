@@ -0,0 +1,248 @@
+//! Throttling of the password logins, on the login form and on the Basic auth path of the API.
+//!
+//! The failed logins are counted in Redis per username and per client IP address. Once a username
+//! failed a few times every further attempt has to wait, the wait doubles with every failure.
+//! Too many failures lock out the username or the IP address for a while. While a login is throttled
+//! the password isn't checked at all, so hammering the login doesn't cost an argon2 hash per request.
+//! The failures and the lockouts are also stored in the `login_events` table for the admins.
+//!
+//! The TOTP codes of the users with two-factor authentication are counted per username as well. The counts of
+//! a username only start over once the login is complete, a correct password alone doesn't give fresh guesses.
+
+use std::net::IpAddr;
+
+use tower_sessions_redis_store::fred::{
+    prelude::{KeysInterface, Pool},
+    types::Expiration,
+};
+use uuid::Uuid;
+
+use crate::{config::LoginThrottleConfig, database::DbManager, redis_manager::RedisManager, utils};
+
+use super::{AuthError, AuthenticatedUser, Credentials, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum_macros::AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum LoginEventKind {
+    Failure,
+    UsernameLockout,
+    IpLockout,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoginThrottle {
+    pool: Pool,
+    config: LoginThrottleConfig,
+}
+
+impl LoginThrottle {
+    pub fn new(redis_manager: &RedisManager, config: LoginThrottleConfig) -> Self {
+        LoginThrottle {
+            pool: redis_manager.get_pool(),
+            config,
+        }
+    }
+
+    /// The usernames are hashed, so the keys don't depend on what is typed into the login form.
+    fn username_key(kind: &str, username: &str) -> String {
+        format!("login_{kind}:username:{}", utils::sha256_hex(username))
+    }
+
+    fn ip_key(kind: &str, ip: IpAddr) -> String {
+        format!("login_{kind}:ip:{ip}")
+    }
+
+    /// Checks the credentials unless the username or the IP address is throttled.
+    /// The count of the username isn't started over here, call `reset` once the login is complete.
+    pub async fn authenticate(
+        &self,
+        dm: &DbManager,
+        creds: Credentials,
+        ip: IpAddr,
+    ) -> Result<AuthenticatedUser> {
+        let username = creds.username.clone();
+        self.check(&username, ip).await?;
+
+        match creds.authenticate(dm).await {
+            Ok(user) => Ok(user),
+            Err(e @ (AuthError::PasswordInvalid | AuthError::UsernameNotFound { .. })) => {
+                for kind in self.record_failure(&username, ip).await? {
+                    record_event(dm, kind, &username, ip).await?;
+                }
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Fails with `AuthError::Throttled` if the username or the IP address has to wait.
+    async fn check(&self, username: &str, ip: IpAddr) -> Result<()> {
+        let keys = [
+            Self::username_key("lockout", username),
+            Self::username_key("delay", username),
+            Self::ip_key("lockout", ip),
+        ];
+        let mut retry_after_secs = 0;
+        for key in keys {
+            // Negative if the key doesn't exist or doesn't expire.
+            let ttl: i64 = self.pool.ttl(key).await?;
+            retry_after_secs = retry_after_secs.max(ttl);
+        }
+        if retry_after_secs > 0 {
+            tracing::info!(retry_after_secs, "Login throttled!");
+            return Err(AuthError::Throttled {
+                retry_after_secs: retry_after_secs as u64,
+            });
+        }
+        Ok(())
+    }
+
+    /// Counts the failure and starts the wait or the lockout, returns the events to record.
+    async fn record_failure(&self, username: &str, ip: IpAddr) -> Result<Vec<LoginEventKind>> {
+        let config = &self.config;
+        let mut events = vec![LoginEventKind::Failure];
+
+        let failures = self.count(Self::username_key("failures", username)).await?;
+        if failures >= config.username_lockout_after_failures {
+            self.block(Self::username_key("lockout", username), config.lockout_secs)
+                .await?;
+            events.push(LoginEventKind::UsernameLockout);
+        } else if let Some(delay) = delay_secs(config, failures) {
+            self.block(Self::username_key("delay", username), delay)
+                .await?;
+        }
+
+        let failures = self.count(Self::ip_key("failures", ip)).await?;
+        if failures >= config.ip_lockout_after_failures {
+            self.block(Self::ip_key("lockout", ip), config.lockout_secs)
+                .await?;
+            events.push(LoginEventKind::IpLockout);
+        }
+
+        Ok(events)
+    }
+
+    /// Counts an attempt to enter the TOTP code of the username. It's counted before the code is checked,
+    /// so the concurrent attempts can't get more guesses. Too many attempts lock out the username,
+    /// the lockout also stops the password logins.
+    pub async fn totp_attempt(&self, dm: &DbManager, username: &str, ip: IpAddr) -> Result<()> {
+        self.check(username, ip).await?;
+
+        let attempts = self
+            .count(Self::username_key("totp_attempts", username))
+            .await?;
+        if attempts > self.config.totp_lockout_after_failures {
+            let lockout_secs = self.config.lockout_secs;
+            self.block(Self::username_key("lockout", username), lockout_secs)
+                .await?;
+            record_event(dm, LoginEventKind::UsernameLockout, username, ip).await?;
+            tracing::info!("Too many TOTP codes, the username is locked out");
+            return Err(AuthError::Throttled {
+                retry_after_secs: lockout_secs as u64,
+            });
+        }
+        Ok(())
+    }
+
+    /// A complete login starts the count of the username over, the IP address keeps its count.
+    pub async fn reset(&self, username: &str) -> Result<()> {
+        let _: i64 = self
+            .pool
+            .del(vec![
+                Self::username_key("failures", username),
+                Self::username_key("delay", username),
+                Self::username_key("totp_attempts", username),
+            ])
+            .await?;
+        Ok(())
+    }
+
+    /// Increments the counter, the counting window starts with the first failure.
+    async fn count(&self, key: String) -> Result<i64> {
+        let count: i64 = self.pool.incr(&key).await?;
+        if count == 1 {
+            let _: bool = self
+                .pool
+                .expire(&key, self.config.window_secs, None)
+                .await?;
+        }
+        Ok(count)
+    }
+
+    async fn block(&self, key: String, secs: i64) -> Result<()> {
+        let _: () = self
+            .pool
+            .set(key, 1, Some(Expiration::EX(secs)), None, false)
+            .await?;
+        Ok(())
+    }
+}
+
+/// The wait after the given number of failures of a username, `None` before the waits start.
+fn delay_secs(config: &LoginThrottleConfig, failures: i64) -> Option<i64> {
+    let doublings = failures - config.delay_after_failures;
+    if doublings < 0 {
+        return None;
+    }
+    let delay = config
+        .base_delay_secs
+        .saturating_mul(1 << doublings.min(62));
+    Some(delay.min(config.max_delay_secs))
+}
+
+async fn record_event(
+    dm: &DbManager,
+    kind: LoginEventKind,
+    username: &str,
+    ip: IpAddr,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO login_events (event_id, kind, username, ip, occurred_at)
+        VALUES ($1, $2, $3, $4, now())
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(kind.as_ref())
+    .bind(username)
+    .bind(ip.to_string())
+    .execute(dm.db())
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> LoginThrottleConfig {
+        LoginThrottleConfig {
+            window_secs: 900,
+            delay_after_failures: 3,
+            base_delay_secs: 2,
+            max_delay_secs: 60,
+            username_lockout_after_failures: 10,
+            ip_lockout_after_failures: 100,
+            totp_lockout_after_failures: 10,
+            lockout_secs: 900,
+        }
+    }
+
+    #[test]
+    fn delays_double_up_to_the_max() {
+        let config = config();
+        assert_eq!(delay_secs(&config, 1), None);
+        assert_eq!(delay_secs(&config, 2), None);
+        assert_eq!(delay_secs(&config, 3), Some(2));
+        assert_eq!(delay_secs(&config, 4), Some(4));
+        assert_eq!(delay_secs(&config, 7), Some(32));
+        assert_eq!(delay_secs(&config, 8), Some(60));
+        assert_eq!(delay_secs(&config, 1000), Some(60));
+    }
+
+    #[test]
+    fn event_kinds_are_snake_case() {
+        assert_eq!(LoginEventKind::UsernameLockout.as_ref(), "username_lockout");
+        assert_eq!(LoginEventKind::IpLockout.as_ref(), "ip_lockout");
+    }
+}
@@ -0,0 +1,358 @@
+//! Time-based one-time passwords (RFC 6238) for the second step of the admin login.
+//!
+//! The secrets are encrypted with AES-256-GCM before they're stored, the key comes from `TotpConfig`.
+//! The ID of the user is used as the associated data, so an encrypted secret only decrypts for its own user.
+//! The recovery codes are random and only their SHA-256 hashes are stored, like the email link tokens.
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use chrono::Utc;
+use qrcode::{render::svg, QrCode};
+use rand::{rng, Rng, RngCore};
+use reqwest::Url;
+use secrecy::{ExposeSecret, SecretSlice};
+use sqlx::{Executor, Postgres, Transaction};
+use totp_rs::{Algorithm, TOTP};
+use uuid::Uuid;
+
+use crate::{database::DbManager, utils};
+
+use super::{AuthError, Result};
+
+/// 160 bits, the length RFC 4226 recommends.
+const SECRET_LEN: usize = 20;
+const NONCE_LEN: usize = 12;
+const DIGITS: usize = 6;
+const STEP_SECS: u64 = 30;
+pub const RECOVERY_CODES_COUNT: usize = 10;
+/// The recovery codes skip the characters that are easy to mix up.
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const RECOVERY_CODE_LEN: usize = 10;
+
+/// A TOTP secret in the clear, it's only kept in memory.
+pub struct TotpSecret(SecretSlice<u8>);
+
+impl TotpSecret {
+    pub fn generate() -> Self {
+        let mut secret = vec![0u8; SECRET_LEN];
+        rng().fill_bytes(&mut secret);
+        TotpSecret(SecretSlice::from(secret))
+    }
+
+    /// Encrypts the secret for storage, returns the Base64-URL encoded nonce followed by the ciphertext.
+    pub fn encrypt(&self, key: &SecretSlice<u8>, user_id: Uuid) -> Result<String> {
+        let cipher = cipher(key)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        rng().fill_bytes(&mut nonce);
+
+        let payload = Payload {
+            msg: self.0.expose_secret(),
+            aad: user_id.as_bytes(),
+        };
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| AuthError::Totp("encrypting the secret failed".into()))?;
+
+        Ok(utils::b64u_encode([nonce.as_slice(), &ciphertext].concat()))
+    }
+
+    /// Decrypts a secret encrypted with `TotpSecret::encrypt` for the same user.
+    pub fn decrypt(encrypted: &str, key: &SecretSlice<u8>, user_id: Uuid) -> Result<Self> {
+        let cipher = cipher(key)?;
+        let bytes = utils::b64u_decode(encrypted)
+            .map_err(|e| AuthError::Totp(format!("decoding the secret failed: {e}")))?;
+        if bytes.len() <= NONCE_LEN {
+            return Err(AuthError::Totp("the encrypted secret is too short".into()));
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+
+        let payload = Payload {
+            msg: ciphertext,
+            aad: user_id.as_bytes(),
+        };
+        let secret = cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| AuthError::Totp("decrypting the secret failed".into()))?;
+
+        Ok(TotpSecret(SecretSlice::from(secret)))
+    }
+
+    fn totp(&self) -> TOTP {
+        // The length of the secret and the digits are fixed above, so nothing can be invalid.
+        TOTP::new_unchecked(
+            Algorithm::SHA1,
+            DIGITS,
+            0,
+            STEP_SECS,
+            self.0.expose_secret().to_vec(),
+        )
+    }
+
+    /// The secret as it's typed into an authenticator app by hand.
+    pub fn base32(&self) -> String {
+        self.totp().get_secret_base32()
+    }
+
+    /// The `otpauth://` URI the authenticator apps read from the QR code.
+    pub fn otpauth_uri(&self, issuer: &str, account: &str) -> Result<String> {
+        let mut uri = Url::parse("otpauth://totp/")
+            .map_err(|e| AuthError::Totp(format!("building the otpauth URI failed: {e}")))?;
+        uri.path_segments_mut()
+            .map_err(|_| AuthError::Totp("the otpauth URI can't have a path".into()))?
+            .push(&format!("{issuer}:{account}"));
+        uri.query_pairs_mut()
+            .append_pair("secret", &self.base32())
+            .append_pair("issuer", issuer)
+            .append_pair("algorithm", "SHA1")
+            .append_pair("digits", &DIGITS.to_string())
+            .append_pair("period", &STEP_SECS.to_string());
+
+        Ok(uri.to_string())
+    }
+
+    /// Returns the time step the code belongs to, or `None` if the code is invalid.
+    /// The codes of the previous and the next step are accepted as well, to allow for clock drift.
+    pub fn verify(&self, code: &str, unix_time: u64) -> Option<i64> {
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+        if code.len() != DIGITS {
+            return None;
+        }
+        let totp = self.totp();
+        let current_step = unix_time / STEP_SECS;
+        [
+            current_step.saturating_sub(1),
+            current_step,
+            current_step + 1,
+        ]
+        .into_iter()
+        .find(|step| totp.check(&code, step * STEP_SECS))
+        .map(|step| step as i64)
+    }
+
+    #[cfg(test)]
+    fn generate_code(&self, unix_time: u64) -> String {
+        self.totp().generate(unix_time)
+    }
+}
+
+fn cipher(key: &SecretSlice<u8>) -> Result<Aes256Gcm> {
+    Aes256Gcm::new_from_slice(key.expose_secret())
+        .map_err(|_| AuthError::Totp("the encryption key has to be 32 bytes long".into()))
+}
+
+/// Renders the data as an SVG QR code that can be embedded into a page.
+pub fn qr_code_svg(data: &str) -> Result<String> {
+    let code =
+        QrCode::new(data).map_err(|e| AuthError::Totp(format!("building QR code failed: {e}")))?;
+    Ok(code.render::<svg::Color>().min_dimensions(200, 200).build())
+}
+
+/// Generates new recovery codes formatted as `xxxxx-xxxxx`.
+pub fn recovery_codes_generate() -> Vec<String> {
+    let mut rng = rng();
+    (0..RECOVERY_CODES_COUNT)
+        .map(|_| {
+            let code: String = (0..RECOVERY_CODE_LEN)
+                .map(|_| {
+                    RECOVERY_CODE_ALPHABET[rng.random_range(0..RECOVERY_CODE_ALPHABET.len())]
+                        as char
+                })
+                .collect();
+            let (first, second) = code.split_at(RECOVERY_CODE_LEN / 2);
+            format!("{first}-{second}")
+        })
+        .collect()
+}
+
+/// The hash of the recovery code as it's stored, the code is case insensitive and the dashes are optional.
+pub fn recovery_code_hash(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    utils::sha256_hex(normalized)
+}
+
+/// Replaces the recovery codes of the user with new ones and returns them, they can only be shown once.
+pub async fn recovery_codes_replace(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+) -> Result<Vec<String>> {
+    let query = sqlx::query(
+        r#"
+        DELETE FROM totp_recovery_codes
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id);
+    transaction.execute(query).await?;
+
+    let codes = recovery_codes_generate();
+    let hashes = codes
+        .iter()
+        .map(|code| recovery_code_hash(code))
+        .collect::<Vec<_>>();
+    let query = sqlx::query(
+        r#"
+        INSERT INTO totp_recovery_codes (user_id, code_hash)
+        SELECT $1, code_hash FROM UNNEST($2::TEXT[]) AS code_hash
+        "#,
+    )
+    .bind(user_id)
+    .bind(&hashes);
+    transaction.execute(query).await?;
+
+    Ok(codes)
+}
+
+/// Whether the user confirmed the TOTP enrolment, the login then needs a code as well.
+pub async fn is_enabled(dm: &DbManager, user_id: Uuid) -> Result<bool> {
+    let enabled = sqlx::query_scalar(
+        r#"
+        SELECT totp_confirmed_at IS NOT NULL FROM users
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(dm.db())
+    .await?
+    .unwrap_or(false);
+    Ok(enabled)
+}
+
+/// Checks a code from the authenticator app or an unused recovery code of a user with TOTP enabled.
+///
+/// A TOTP code is only accepted once, the codes of the steps up to the last used one are rejected.
+/// A recovery code is used up.
+pub async fn check_code(
+    dm: &DbManager,
+    key: &SecretSlice<u8>,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool> {
+    let mut transaction = dm.db().begin().await?;
+    // Locking the user makes the concurrent attempts with the same code wait, so only one succeeds.
+    let user: Option<(String, Option<i64>)> = sqlx::query_as(
+        r#"
+        SELECT totp_secret_encrypted, totp_last_step FROM users
+        WHERE user_id = $1 AND totp_confirmed_at IS NOT NULL AND deactivated_at IS NULL
+        FOR UPDATE
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let Some((encrypted, last_step)) = user else {
+        return Ok(false);
+    };
+
+    let secret = TotpSecret::decrypt(&encrypted, key, user_id)?;
+    let unix_time = Utc::now().timestamp() as u64;
+    if let Some(step) = secret.verify(code, unix_time) {
+        if last_step.is_some_and(|last_step| step <= last_step) {
+            return Ok(false);
+        }
+        let query = sqlx::query(
+            r#"
+            UPDATE users SET totp_last_step = $2
+            WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id)
+        .bind(step);
+        transaction.execute(query).await?;
+        transaction.commit().await?;
+        return Ok(true);
+    }
+
+    let used = sqlx::query(
+        r#"
+        UPDATE totp_recovery_codes SET used_at = now()
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .bind(recovery_code_hash(code))
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    transaction.commit().await?;
+
+    Ok(used == 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key() -> SecretSlice<u8> {
+        SecretSlice::from(vec![7u8; 32])
+    }
+
+    #[test]
+    fn secret_decrypts_only_for_its_user() -> anyhow::Result<()> {
+        let secret = TotpSecret::generate();
+        let user_id = Uuid::new_v4();
+
+        let encrypted = secret.encrypt(&key(), user_id)?;
+        let decrypted = TotpSecret::decrypt(&encrypted, &key(), user_id)?;
+        assert_eq!(decrypted.base32(), secret.base32());
+        // A new nonce every time.
+        assert_ne!(secret.encrypt(&key(), user_id)?, encrypted);
+
+        assert!(TotpSecret::decrypt(&encrypted, &key(), Uuid::new_v4()).is_err());
+        let other_key = SecretSlice::from(vec![8u8; 32]);
+        assert!(TotpSecret::decrypt(&encrypted, &other_key, user_id).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn codes_are_accepted_within_one_step() {
+        let secret = TotpSecret::generate();
+        let now = 1_750_000_000;
+        let step = (now / STEP_SECS) as i64;
+
+        assert_eq!(secret.verify(&secret.generate_code(now), now), Some(step));
+        assert_eq!(
+            secret.verify(&secret.generate_code(now - STEP_SECS), now),
+            Some(step - 1)
+        );
+        assert_eq!(
+            secret.verify(&secret.generate_code(now + STEP_SECS), now),
+            Some(step + 1)
+        );
+        assert_eq!(
+            secret.verify(&secret.generate_code(now - 3 * STEP_SECS), now),
+            None
+        );
+        assert_eq!(secret.verify("12345", now), None);
+    }
+
+    #[test]
+    fn otpauth_uri_contains_the_secret_and_the_issuer() -> anyhow::Result<()> {
+        let secret = TotpSecret::generate();
+        let uri = secret.otpauth_uri("Mailomat", "jane doe")?;
+
+        assert!(uri.starts_with("otpauth://totp/Mailomat:jane%20doe?"));
+        assert!(uri.contains(&format!("secret={}", secret.base32())));
+        assert!(uri.contains("issuer=Mailomat"));
+        Ok(())
+    }
+
+    #[test]
+    fn recovery_codes_are_unique_and_normalized() {
+        let codes = recovery_codes_generate();
+        assert_eq!(codes.len(), RECOVERY_CODES_COUNT);
+        assert!(codes.iter().all(|code| code.len() == RECOVERY_CODE_LEN + 1));
+
+        let code = &codes[0];
+        assert_eq!(
+            recovery_code_hash(&code.to_uppercase().replace('-', "")),
+            recovery_code_hash(code)
+        );
+        assert_ne!(recovery_code_hash(code), recovery_code_hash(&codes[1]));
+    }
+}
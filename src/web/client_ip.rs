@@ -0,0 +1,42 @@
+//! The IP address of the client, used to throttle the logins.
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::anyhow;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+
+use crate::{web, AppState};
+
+/// Extracts the client IP address from the header configured in `NetConfig::client_ip_header`,
+/// the proxy in front of the app sets it. Falls back to the address of the peer.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = web::Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        // The header can hold a list of addresses, the first one is the client.
+        let from_header = state
+            .client_ip_header
+            .as_ref()
+            .and_then(|header| parts.headers.get(header))
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.split(',').next())
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+        if let Some(ip) = from_header {
+            return Ok(ClientIp(ip));
+        }
+
+        let ConnectInfo(addr) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| anyhow!("unable to get the client address: {e}"))?;
+        Ok(ClientIp(addr.ip()))
+    }
+}
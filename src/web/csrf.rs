@@ -0,0 +1,325 @@
+//! CSRF protection for the HTML forms.
+//!
+//! Every browser gets a random token in a signed cookie (the signed double-submit pattern), the forms send it back
+//! in the hidden `csrf_token` field (see the `csrf_field` macro in `templates/html/macros.html`) and other clients
+//! in the `x-csrf-token` header. The token isn't kept in the session, so a form left open for longer than
+//! the session expiry can still be submitted.
+//! The multipart forms send it in the `csrf_token` query parameter of their action, their body is streamed
+//! to the handler and isn't read here.
+//! `csrf_protect` rejects the unsafe requests without a matching token, and the requests whose `Origin` or `Referer`
+//! isn't the origin of the app. The JSON API isn't covered, it doesn't authenticate with the cookies.
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, Uri},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use rand::{rng, RngCore};
+use reqwest::Url;
+use secrecy::ExposeSecret;
+use subtle::ConstantTimeEq;
+use tower_cookies::{cookie::SameSite, Cookie, Cookies, Key};
+
+use crate::{
+    utils,
+    web::{self, flash, WebResult, FLASH_ERROR_MSG},
+    AppState,
+};
+
+/// The name of the hidden form field.
+pub const CSRF_FIELD: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+pub const CSRF_COOKIE_NAME: &str = "csrf";
+const FORM_EXPIRED_MSG: &str = "The form has expired, please submit it again.";
+/// The same as the default body limit of axum.
+const FORM_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+// ###################################
+// ->   ERROR
+// ###################################
+#[derive(Debug, thiserror::Error)]
+pub enum CsrfError {
+    #[error("the request came from another origin: {0}")]
+    OriginInvalid(String),
+    #[error("the request has no CSRF token")]
+    TokenMissing,
+    #[error("the CSRF token doesn't match the cookie")]
+    TokenInvalid,
+    #[error("unable to read the request body: {0}")]
+    Body(String),
+}
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+/// The CSRF token of the browser, created with its cookie if the browser doesn't have one yet.
+/// The handlers that render forms insert it into the template context as `csrf_token`.
+#[derive(Debug, Clone)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    fn generate() -> Self {
+        let mut rand_bytes = [0u8; 32];
+        rng().fill_bytes(&mut rand_bytes);
+        CsrfToken(utils::b64u_encode(rand_bytes))
+    }
+
+    /// The token of the signed cookie, `None` if there is no cookie or its signature is invalid.
+    fn from_cookies(cookies: &Cookies, key: &Key) -> Option<Self> {
+        let cookie = cookies.signed(key).get(CSRF_COOKIE_NAME)?;
+        Some(CsrfToken(cookie.value().to_string()))
+    }
+
+    pub fn get_or_create(cookies: &Cookies, key: &Key, secure: bool) -> Self {
+        if let Some(token) = Self::from_cookies(cookies, key) {
+            return token;
+        }
+        let token = Self::generate();
+        // Only lasts until the browser is closed. Lax, so the links in the emails that lead to a form work
+        // in the tabs that already have one open.
+        let cookie = Cookie::build((CSRF_COOKIE_NAME, token.0.clone()))
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(secure);
+        cookies.signed(key).add(cookie.into());
+        token
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Inserts the token into the template context, the `csrf_field` macro renders it.
+    pub fn insert_into(&self, ctx: &mut tera::Context) {
+        ctx.insert(CSRF_FIELD, &self.0);
+    }
+
+    fn matches(&self, token: &str) -> bool {
+        self.0.as_bytes().ct_eq(token.as_bytes()).into()
+    }
+}
+
+impl FromRequestParts<AppState> for CsrfToken {
+    type Rejection = web::Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let cookies = Cookies::from_request_parts(parts, state)
+            .await
+            .map_err(|(_, e)| anyhow::anyhow!("unable to extract the cookies: {e}"))?;
+        let key = Key::from(state.cookie_secret.expose_secret());
+        Ok(Self::get_or_create(
+            &cookies,
+            &key,
+            state.session_config.secure,
+        ))
+    }
+}
+
+// ###################################
+// ->   MIDDLEWARE
+// ###################################
+/// Checks the origin and the CSRF token of the requests that can change something.
+///
+/// A request without the cookie is sent back to its form with an error message, the browser was most likely
+/// closed and opened again while the form was open. The form gets a new token when it's shown again.
+pub async fn csrf_protect(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    req: Request,
+    next: Next,
+) -> WebResult<Response> {
+    if req.method().is_safe() {
+        return Ok(next.run(req).await);
+    }
+
+    origin_check(&app_state.base_url, req.headers())?;
+    let key = Key::from(app_state.cookie_secret.expose_secret());
+    let Some(expected) = CsrfToken::from_cookies(&cookies, &key) else {
+        tracing::warn!("The request has no CSRF cookie, sending it back to the form");
+        flash::add(&cookies, &key, FLASH_ERROR_MSG, FORM_EXPIRED_MSG);
+        let form_path = form_path(req.uri(), req.headers());
+        return Ok(Redirect::to(&form_path).into_response());
+    };
+
+    let header_token = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|val| val.to_str().ok())
+        .map(str::to_string);
+    let (req, token) = match header_token {
+        Some(token) => (req, Some(token)),
+        None if is_multipart(&req) => {
+            let token = query_token(&req);
+            (req, token)
+        }
+        None => form_token(req).await?,
+    };
+    let token = token.ok_or(CsrfError::TokenMissing)?;
+    if !expected.matches(&token) {
+        return Err(CsrfError::TokenInvalid.into());
+    }
+
+    Ok(next.run(req).await)
+}
+
+// ###################################
+// ->   HELPERS
+// ###################################
+/// Reads the token from an urlencoded form, the body is put back for the handler.
+async fn form_token(req: Request) -> Result<(Request, Option<String>), CsrfError> {
+    let is_form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|val| val.to_str().ok())
+        .is_some_and(|val| val.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok((req, None));
+    }
+
+    let (parts, body) = req.into_parts();
+    let bytes = to_bytes(body, FORM_BODY_LIMIT)
+        .await
+        .map_err(|e| CsrfError::Body(e.to_string()))?;
+    let token = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&bytes)
+        .map_err(|e| CsrfError::Body(e.to_string()))?
+        .into_iter()
+        .find_map(|(field, value)| (field == CSRF_FIELD).then_some(value));
+
+    Ok((Request::from_parts(parts, Body::from(bytes)), token))
+}
+
+/// The page the form was posted from, taken from the `Referer` because some forms are shown with the token
+/// of an email link in the query and post without it. Falls back to the path the form was posted to.
+/// Only the path of the page is used, so the redirect can't leave the app.
+fn form_path(uri: &Uri, headers: &HeaderMap) -> String {
+    headers
+        .get(header::REFERER)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|referer| Url::parse(referer).ok())
+        .filter(|referer| !referer.path().starts_with("//"))
+        .map(|referer| match referer.query() {
+            Some(query) => format!("{}?{query}", referer.path()),
+            None => referer.path().to_string(),
+        })
+        .unwrap_or_else(|| uri.path().to_string())
+}
+
+fn is_multipart(req: &Request) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|val| val.to_str().ok())
+        .is_some_and(|val| val.starts_with("multipart/form-data"))
+}
+
+fn query_token(req: &Request) -> Option<String> {
+    serde_urlencoded::from_str::<Vec<(String, String)>>(req.uri().query()?)
+        .ok()?
+        .into_iter()
+        .find_map(|(field, value)| (field == CSRF_FIELD).then_some(value))
+}
+
+/// The browsers send the `Origin` header with the form posts, some only the `Referer`.
+/// It has to be the origin of the base url, or the host the request was sent to (the base url of a local server
+/// has no port). A request without both is only checked by its token.
+fn origin_check(base_url: &str, headers: &HeaderMap) -> Result<(), CsrfError> {
+    let source = headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))
+        .map(|val| val.to_str().unwrap_or_default());
+    let Some(source) = source else {
+        return Ok(());
+    };
+    let Ok(source_url) = Url::parse(source) else {
+        return Err(CsrfError::OriginInvalid(source.to_string()));
+    };
+
+    let base_origin = Url::parse(base_url)
+        .map_err(|e| CsrfError::OriginInvalid(format!("the base url is invalid: {e}")))?
+        .origin();
+    let source_authority = match (source_url.host_str(), source_url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return Err(CsrfError::OriginInvalid(source.to_string())),
+    };
+    let same_host = headers
+        .get(header::HOST)
+        .is_some_and(|host| host.as_bytes() == source_authority.as_bytes());
+
+    if source_url.origin() == base_origin || same_host {
+        Ok(())
+    } else {
+        Err(CsrfError::OriginInvalid(source.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use claims::{assert_err, assert_ok};
+
+    #[test]
+    fn origin_must_match_the_base_url() -> anyhow::Result<()> {
+        let base_url = "https://mailomat.fly.dev";
+        let mut headers = HeaderMap::new();
+        assert_ok!(origin_check(base_url, &headers));
+
+        headers.insert(header::REFERER, "https://mailomat.fly.dev/login".parse()?);
+        assert_ok!(origin_check(base_url, &headers));
+        headers.insert(header::REFERER, "https://evil.example/login".parse()?);
+        assert_err!(origin_check(base_url, &headers));
+
+        // The Origin wins over the Referer.
+        headers.insert(header::ORIGIN, "https://mailomat.fly.dev".parse()?);
+        assert_ok!(origin_check(base_url, &headers));
+        for origin in [
+            "null",
+            "http://mailomat.fly.dev",
+            "https://mailomat.fly.dev:8443",
+        ] {
+            headers.insert(header::ORIGIN, origin.parse()?);
+            assert_err!(origin_check(base_url, &headers), "{origin}");
+        }
+
+        // The host the request was sent to.
+        headers.insert(header::HOST, "127.0.0.1:8080".parse()?);
+        headers.insert(header::ORIGIN, "http://127.0.0.1:8080".parse()?);
+        assert_ok!(origin_check(base_url, &headers));
+        headers.insert(header::ORIGIN, "http://127.0.0.1:9090".parse()?);
+        assert_err!(origin_check(base_url, &headers));
+        Ok(())
+    }
+
+    #[test]
+    fn forms_are_shown_again_on_the_page_they_were_posted_from() -> anyhow::Result<()> {
+        let uri: Uri = "/signup".parse()?;
+        let mut headers = HeaderMap::new();
+        assert_eq!(form_path(&uri, &headers), "/signup");
+
+        headers.insert(
+            header::REFERER,
+            "https://mailomat.fly.dev/signup?token=abc".parse()?,
+        );
+        assert_eq!(form_path(&uri, &headers), "/signup?token=abc");
+        // Never another host.
+        headers.insert(
+            header::REFERER,
+            "https://mailomat.fly.dev//evil.example".parse()?,
+        );
+        assert_eq!(form_path(&uri, &headers), "/signup");
+        Ok(())
+    }
+
+    #[test]
+    fn tokens_only_match_themselves() {
+        let token = CsrfToken::generate();
+        assert!(token.matches(&token.0.clone()));
+        assert!(!token.matches(CsrfToken::generate().as_str()));
+        assert!(!token.matches(""));
+    }
+}
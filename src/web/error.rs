@@ -24,10 +24,26 @@ pub enum Error {
     Subscribe(#[from] routes::SubscribeError),
     #[error("api subscribe confirm error: {0}")]
     SubscribeConfirm(#[from] routes::SubscribeConfirmError),
+    #[error("api subscribers error: {0}")]
+    Subscribers(#[from] routes::SubscribersError),
+    #[error("subscriber import error: {0}")]
+    Import(#[from] routes::ImportError),
+    #[error("api unsubscribe error: {0}")]
+    Unsubscribe(#[from] routes::UnsubscribeError),
+    #[error("archive error: {0}")]
+    Archive(#[from] routes::ArchiveError),
     #[error("login error: {0}")]
     Login(#[from] routes::LoginError),
+    #[error("password reset error: {0}")]
+    PasswordReset(#[from] routes::PasswordResetError),
+    #[error("privacy error: {0}")]
+    Privacy(#[from] routes::PrivacyError),
+    #[error("signup error: {0}")]
+    Signup(#[from] routes::SignupError),
     #[error("admin error: {0}")]
     Admin(#[from] routes::AdminError),
+    #[error("csrf error: {0}")]
+    Csrf(#[from] csrf::CsrfError),
 
     #[error("sqlx error: {0}")]
     Sqlx(#[from] sqlx::Error),
@@ -38,24 +54,79 @@ pub enum Error {
 
 impl Error {
     pub fn status_code_and_client_error(&self) -> (StatusCode, ClientError) {
-        use routes::{NewsError, SubscribeConfirmError, SubscribeError};
+        use routes::{
+            AdminError, ArchiveError, NewsError, PasswordResetError, SignupError,
+            SubscribeConfirmError, SubscribeError, SubscribersError, UnsubscribeError,
+        };
+        use types::DataParsingError;
         use Error::*;
 
         match self {
-            News(NewsError::Auth(e)) | Login(LoginError::Auth(e)) => {
+            News(NewsError::Auth(e)) | Login(LoginError::Auth(e)) | Admin(AdminError::Auth(e)) => {
                 e.status_code_and_client_error()
             }
-            SubscribeConfirm(SubscribeConfirmError::SubTokenInDbNotFound) => {
+            News(NewsError::Idempotency(e)) => e.status_code_and_client_error(),
+            Subscribers(SubscribersError::Auth(e)) => e.status_code_and_client_error(),
+            SubscribeConfirm(SubscribeConfirmError::SubTokenInDbNotFound)
+            | Unsubscribe(UnsubscribeError::DataParsing(DataParsingError::TokenSignatureInvalid)) => {
                 (StatusCode::UNAUTHORIZED, ClientError::Unauthorized)
             }
+            SubscribeConfirm(SubscribeConfirmError::SubTokenExpired) => {
+                (StatusCode::GONE, ClientError::LinkExpired)
+            }
+            PasswordReset(PasswordResetError::LinkInvalid) => {
+                (StatusCode::GONE, ClientError::PasswordResetLinkInvalid)
+            }
+            Privacy(routes::PrivacyError::LinkInvalid) => {
+                (StatusCode::GONE, ClientError::DataRequestLinkInvalid)
+            }
+            Signup(SignupError::LinkInvalid) => {
+                (StatusCode::GONE, ClientError::InvitationLinkInvalid)
+            }
             Subscribe(SubscribeError::ValidSubscriberParse(er))
-            | SubscribeConfirm(SubscribeConfirmError::DataParsing(er)) => (
+            | SubscribeConfirm(SubscribeConfirmError::DataParsing(er))
+            | Unsubscribe(UnsubscribeError::DataParsing(er)) => (
                 StatusCode::BAD_REQUEST,
                 ClientError::InputInvalid(er.to_string()),
             ),
+            Admin(AdminError::InvalidInput(msg))
+            | Subscribers(SubscribersError::InvalidInput(msg))
+            | Import(routes::ImportError::InvalidInput(msg) | routes::ImportError::Body(msg)) => (
+                StatusCode::BAD_REQUEST,
+                ClientError::InputInvalid(msg.to_string()),
+            ),
+            Archive(ArchiveError::IssueNotFound(_))
+            | Admin(
+                AdminError::IssueNotFound(_)
+                | AdminError::SessionNotFound
+                | AdminError::UserNotFound(_)
+                | AdminError::ApiKeyNotFound(_)
+                | AdminError::SubscriberNotFound(_),
+            ) => (StatusCode::NOT_FOUND, ClientError::NotFound),
+            Admin(AdminError::IssueNotEditable(_)) => (
+                StatusCode::CONFLICT,
+                ClientError::Conflict("the newsletter issue was already published".into()),
+            ),
+            Admin(AdminError::IssueNotScheduled(_)) => (
+                StatusCode::CONFLICT,
+                ClientError::Conflict("the newsletter issue isn't scheduled".into()),
+            ),
+            Csrf(
+                csrf::CsrfError::OriginInvalid(_)
+                | csrf::CsrfError::TokenMissing
+                | csrf::CsrfError::TokenInvalid,
+            ) => (StatusCode::FORBIDDEN, ClientError::CsrfCheckFailed),
             _ => (StatusCode::INTERNAL_SERVER_ERROR, ClientError::ServiceError),
         }
     }
+
+    /// The seconds the client has to wait before trying again, sent in the `Retry-After` header.
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        match self.status_code_and_client_error() {
+            (_, ClientError::TooManyLoginAttempts { retry_after_secs }) => Some(retry_after_secs),
+            _ => None,
+        }
+    }
 }
 
 impl IntoResponse for Error {
@@ -82,4 +153,26 @@ pub enum ClientError {
     UsernameOrPasswordInvalid,
     #[display("Unauthorized Access")]
     Unauthorized,
+    #[display("Too many failed login attempts, please try again in {retry_after_secs} seconds!")]
+    TooManyLoginAttempts { retry_after_secs: u64 },
+    #[display("You don't have the permission to do this!")]
+    Forbidden,
+    #[display("The form has expired, please reload the page and try again!")]
+    CsrfCheckFailed,
+    #[display("This account has been deactivated!")]
+    UserDeactivated,
+    #[display("A request with the same idempotency key is still being processed!")]
+    RequestInProgress,
+    #[display("This link has expired, please subscribe again to receive a new one!")]
+    LinkExpired,
+    #[display("This password reset link is invalid or has expired, please request a new one!")]
+    PasswordResetLinkInvalid,
+    #[display("This invitation link is invalid or has expired, please ask for a new invitation!")]
+    InvitationLinkInvalid,
+    #[display("This link is invalid or has expired, please request a new one!")]
+    DataRequestLinkInvalid,
+    #[display("The requested resource was not found!")]
+    NotFound,
+    #[display("The request can't be completed: {}", _0)]
+    Conflict(String),
 }
@@ -0,0 +1,55 @@
+//! One-time messages that survive a redirect, e.g. the outcome of a form post.
+//!
+//! The message is stored base64-url encoded in a signed cookie and removed as soon as it's read.
+
+use secrecy::ExposeSecret;
+use tower_cookies::{Cookie, Cookies, Key};
+
+use crate::{
+    utils::{self, b64u_decode_to_string, b64u_encode},
+    web::{FLASH_ERROR_MSG, FLASH_INFO_MSG},
+    AppState,
+};
+
+/// Adds a flash message, it's shown on the next page that reads the cookie with the same `name`.
+pub fn add(cookies: &Cookies, key: &Key, name: &'static str, message: impl AsRef<str>) {
+    // Without an explicit path the cookie would only be sent back to the paths under the current one.
+    let cookie = Cookie::build((name, b64u_encode(message.as_ref()))).path("/");
+    cookies.signed(key).add(cookie.into());
+}
+
+/// Same as [`add`], signed with the cookie secret of the app.
+pub fn add_for(
+    app_state: &AppState,
+    cookies: &Cookies,
+    name: &'static str,
+    message: impl AsRef<str>,
+) {
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    add(cookies, &secret_key, name, message);
+}
+
+/// Returns the flash message stored under `name` and removes it, so it's only shown once.
+pub fn take(cookies: &Cookies, key: &Key, name: &'static str) -> utils::Result<Option<String>> {
+    let signed_cookies = cookies.signed(key);
+    let Some(cookie) = signed_cookies.get(name) else {
+        return Ok(None);
+    };
+    signed_cookies.remove(Cookie::build((name, "")).path("/").into());
+
+    b64u_decode_to_string(cookie.value()).map(Some)
+}
+
+/// Takes the error and info flash messages and inserts them into the template context
+/// as `error_message` and `info_message`.
+pub fn insert_messages(cookies: &Cookies, key: &Key, ctx: &mut tera::Context) -> utils::Result<()> {
+    for (cookie_name, ctx_key) in [
+        (FLASH_ERROR_MSG, "error_message"),
+        (FLASH_INFO_MSG, "info_message"),
+    ] {
+        if let Some(message) = take(cookies, key, cookie_name)? {
+            ctx.insert(ctx_key, &message);
+        }
+    }
+    Ok(())
+}
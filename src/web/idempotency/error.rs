@@ -0,0 +1,39 @@
+use axum::http::StatusCode;
+
+use crate::web::error::ClientError;
+
+pub type Result<T> = core::result::Result<T, IdempotencyError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdempotencyError {
+    #[error("the idempotency key cannot be empty")]
+    KeyEmpty,
+    #[error("the idempotency key must be shorter than {max_len} characters")]
+    KeyTooLong { max_len: usize },
+    #[error("got invalid characters in 'Idempotency-Key' header: {0}")]
+    InvalidHeader(String),
+    #[error("a request with the same idempotency key is still being processed")]
+    RequestInProgress,
+
+    #[error("invalid saved response: {0}")]
+    InvalidSavedResponse(String),
+    #[error("error reading the response body: {0}")]
+    ResponseBody(#[from] axum::Error),
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+}
+
+impl IdempotencyError {
+    pub fn status_code_and_client_error(&self) -> (StatusCode, ClientError) {
+        use IdempotencyError::*;
+
+        match self {
+            KeyEmpty | KeyTooLong { .. } | InvalidHeader(_) => (
+                StatusCode::BAD_REQUEST,
+                ClientError::InputInvalid(self.to_string()),
+            ),
+            RequestInProgress => (StatusCode::CONFLICT, ClientError::RequestInProgress),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, ClientError::ServiceError),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use axum::http::HeaderMap;
+
+use super::{IdempotencyError, Result, IDEMPOTENCY_KEY_HEADER};
+
+/// A validated idempotency key provided by the client.
+#[derive(Debug, Clone)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    const MAX_LEN: usize = 50;
+
+    pub fn parse<S>(value: S) -> Result<Self>
+    where
+        S: AsRef<str>,
+    {
+        let value = value.as_ref();
+        if value.is_empty() {
+            return Err(IdempotencyError::KeyEmpty);
+        }
+        if value.chars().count() >= Self::MAX_LEN {
+            return Err(IdempotencyError::KeyTooLong {
+                max_len: Self::MAX_LEN,
+            });
+        }
+
+        Ok(Self(value.to_string()))
+    }
+
+    /// Tries to parse the key from the `Idempotency-Key` header.
+    /// Returns `Ok(None)` if the header is missing from the request.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>> {
+        headers
+            .get(IDEMPOTENCY_KEY_HEADER)
+            .map(|val| {
+                val.to_str()
+                    .map_err(|e| IdempotencyError::InvalidHeader(e.to_string()))
+                    .and_then(Self::parse)
+            })
+            .transpose()
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_err, assert_none, assert_ok};
+
+    #[test]
+    fn idempotency_key_empty_rejected() {
+        assert_err!(IdempotencyKey::parse(""));
+    }
+
+    #[test]
+    fn idempotency_key_too_long_rejected() {
+        assert_err!(IdempotencyKey::parse("a".repeat(50)));
+    }
+
+    #[test]
+    fn idempotency_key_valid_parsed() {
+        assert_ok!(IdempotencyKey::parse("a".repeat(49)));
+        assert_ok!(IdempotencyKey::parse(uuid::Uuid::new_v4().to_string()));
+    }
+
+    #[test]
+    fn idempotency_key_missing_header_is_none() -> anyhow::Result<()> {
+        let key = IdempotencyKey::from_headers(&HeaderMap::new())?;
+        assert_none!(key);
+        Ok(())
+    }
+}
@@ -0,0 +1,12 @@
+//! Support for the `Idempotency-Key` header.
+//! Responses are stored in the `idempotency` table per user and replayed when a request is retried.
+
+mod error;
+mod key;
+mod persistence;
+
+pub use error::{IdempotencyError, Result};
+pub use key::IdempotencyKey;
+pub use persistence::{delete_key, save_response, try_processing, NextAction};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
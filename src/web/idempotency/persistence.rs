@@ -0,0 +1,170 @@
+use axum::{
+    body::{to_bytes, Body},
+    http::{HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::config::get_or_init_config;
+
+use super::{IdempotencyError, IdempotencyKey, Result};
+
+#[derive(Debug, sqlx::Type)]
+#[sqlx(type_name = "header_pair")]
+struct HeaderPairRecord {
+    name: String,
+    value: Vec<u8>,
+}
+
+/// The saved response columns, they are all `NULL` while the request is still being processed.
+type SavedResponseRecord = (Option<i16>, Option<Vec<HeaderPairRecord>>, Option<Vec<u8>>);
+
+/// What the handler should do with the request after checking the idempotency key.
+pub enum NextAction {
+    /// This is the first request with this key, process it and save the response.
+    StartProcessing,
+    /// The request was already processed, return the saved response.
+    ReturnSavedResponse(Response),
+}
+
+/// Tries to claim the idempotency key for the user.
+///
+/// If the key was already claimed returns the saved response, or fails with
+/// `IdempotencyError::RequestInProgress` if the first request hasn't finished yet.
+/// Expired keys are claimed again, that is the ones past the `key_expiry` and the ones whose request
+/// was abandoned without a response for longer than the `in_progress_expiry`, e.g. when the server crashed.
+#[tracing::instrument(name = "Checking idempotency key", skip(pool, key), fields(key = key.as_ref()))]
+pub async fn try_processing(
+    pool: &PgPool,
+    key: &IdempotencyKey,
+    user_id: Uuid,
+) -> Result<NextAction> {
+    let config = &get_or_init_config().idempotency_config;
+    let now = Utc::now();
+    let n_claimed_rows = sqlx::query(
+        r#"
+        INSERT INTO idempotency (user_id, idempotency_key, created_at)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id, idempotency_key) DO UPDATE
+        SET created_at = EXCLUDED.created_at,
+            response_status_code = NULL,
+            response_headers = NULL,
+            response_body = NULL
+        WHERE idempotency.created_at < $4
+            OR (idempotency.response_status_code IS NULL AND idempotency.created_at < $5)
+        "#,
+    )
+    .bind(user_id)
+    .bind(key.as_ref())
+    .bind(now)
+    .bind(now - config.key_expiry())
+    .bind(now - config.in_progress_expiry())
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if n_claimed_rows > 0 {
+        return Ok(NextAction::StartProcessing);
+    }
+
+    let saved_response = get_saved_response(pool, key, user_id)
+        .await?
+        .ok_or(IdempotencyError::RequestInProgress)?;
+
+    Ok(NextAction::ReturnSavedResponse(saved_response))
+}
+
+async fn get_saved_response(
+    pool: &PgPool,
+    key: &IdempotencyKey,
+    user_id: Uuid,
+) -> Result<Option<Response>> {
+    let saved: Option<SavedResponseRecord> = sqlx::query_as(
+        r#"
+        SELECT response_status_code, response_headers, response_body
+        FROM idempotency
+        WHERE user_id = $1 AND idempotency_key = $2
+        "#,
+    )
+    .bind(user_id)
+    .bind(key.as_ref())
+    .fetch_optional(pool)
+    .await?;
+
+    let Some((Some(status_code), Some(headers), Some(body))) = saved else {
+        return Ok(None);
+    };
+
+    let status_code = u16::try_from(status_code)
+        .ok()
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| IdempotencyError::InvalidSavedResponse(status_code.to_string()))?;
+
+    let mut response = (status_code, body).into_response();
+    let response_headers = response.headers_mut();
+    response_headers.clear();
+    for HeaderPairRecord { name, value } in headers {
+        let name = HeaderName::try_from(name)
+            .map_err(|e| IdempotencyError::InvalidSavedResponse(e.to_string()))?;
+        let value = HeaderValue::from_bytes(&value)
+            .map_err(|e| IdempotencyError::InvalidSavedResponse(e.to_string()))?;
+        response_headers.append(name, value);
+    }
+
+    Ok(Some(response))
+}
+
+/// Stores the response for the idempotency key as part of the provided transaction,
+/// and returns an equivalent response that can be sent to the client.
+pub async fn save_response(
+    transaction: &mut Transaction<'_, Postgres>,
+    key: &IdempotencyKey,
+    user_id: Uuid,
+    response: Response,
+) -> Result<Response> {
+    let (parts, body) = response.into_parts();
+    let body = to_bytes(body, usize::MAX).await?;
+    let status_code = parts.status.as_u16() as i16;
+    let headers = parts
+        .headers
+        .iter()
+        .map(|(name, value)| HeaderPairRecord {
+            name: name.as_str().to_owned(),
+            value: value.as_bytes().to_owned(),
+        })
+        .collect::<Vec<_>>();
+
+    let query = sqlx::query(
+        r#"
+        UPDATE idempotency
+        SET response_status_code = $3, response_headers = $4, response_body = $5
+        WHERE user_id = $1 AND idempotency_key = $2
+        "#,
+    )
+    .bind(user_id)
+    .bind(key.as_ref())
+    .bind(status_code)
+    .bind(headers)
+    .bind(body.as_ref());
+    transaction.execute(query).await?;
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// Releases a claimed idempotency key, so that a failed request can be retried with it.
+pub async fn delete_key(pool: &PgPool, key: &IdempotencyKey, user_id: Uuid) -> Result<()> {
+    sqlx::query(
+        r#"
+        DELETE FROM idempotency
+        WHERE user_id = $1 AND idempotency_key = $2 AND response_status_code IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .bind(key.as_ref())
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
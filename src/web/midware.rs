@@ -2,7 +2,10 @@
 
 use crate::{
     utils::b64u_encode,
-    web::{self, routes::AdminError, WebResult, FLASH_ERROR_MSG, REQUEST_ID_HEADER},
+    web::{
+        self, csrf::CSRF_COOKIE_NAME, routes::AdminError, WebResult, FLASH_ERROR_MSG,
+        REQUEST_ID_HEADER, SESSION_COOKIE_NAME,
+    },
     AppState,
 };
 
@@ -10,15 +13,19 @@ use std::sync::Arc;
 
 use axum::{
     body::Body,
-    extract::State,
-    http::{self, header, StatusCode},
+    extract::{Request, State},
+    http::{self, header, HeaderValue, StatusCode},
+    middleware::Next,
     response::{IntoResponse, Response},
     Json,
 };
 use secrecy::ExposeSecret;
 use serde_json::json;
 
-use tower_cookies::{Cookie, Cookies, Key};
+use tower_cookies::{
+    cookie::{time::Duration, CookieJar, SameSite},
+    Cookie, Cookies, Key,
+};
 use web::routes::{LoginError, NewsError};
 
 #[derive(Debug, thiserror::Error)]
@@ -98,10 +105,12 @@ pub async fn error_handle_response_mapper(
                 }
             });
 
-            // Check if authentication error was encountered on the news path and insert appropriate
-            // headers if so.
+            // Check if authentication failed on the news path and insert appropriate headers if so,
+            // an insufficient role is a 403 and doesn't ask for the credentials again.
             let mut resp = (*status, Json(client_error_body)).into_response();
-            if matches!(er, web::Error::News(NewsError::Auth(_))) {
+            if matches!(er, web::Error::News(NewsError::Auth(_)))
+                && *status == StatusCode::UNAUTHORIZED
+            {
                 resp.headers_mut().insert(
                     header::WWW_AUTHENTICATE,
                     r#"Basic realm="publish""#.parse().expect("valid parse"),
@@ -112,9 +121,130 @@ pub async fn error_handle_response_mapper(
         None => None,
     };
 
+    // A throttled login tells the client how long to wait, also when it's redirected back to the login form.
+    let err_resp = err_resp.map(|mut err_resp| {
+        if let Some(secs) = web_error.and_then(web::Error::retry_after_secs) {
+            err_resp
+                .headers_mut()
+                .insert(header::RETRY_AFTER, secs.into());
+        }
+        err_resp
+    });
+
     Ok(err_resp.unwrap_or(resp))
 }
 
+/// Re-signs the cookies that were signed with one of the previous cookie keys with the primary key,
+/// so the keys can be rotated without logging everyone out.
+///
+/// The `SessionManagerLayer` reads the cookies from the request headers on its own, so the `Cookie` header
+/// is rewritten before it and the rest of the app only sees cookies signed with the primary key.
+/// The re-signed cookies are sent back, unless the response sets them anyway.
+pub async fn cookies_resign(
+    State(app_state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if app_state.previous_cookie_secrets.is_empty() {
+        return next.run(req).await;
+    }
+
+    // Parsed the same way as in `tower_cookies`.
+    let mut jar = CookieJar::new();
+    for header_val in req.headers().get_all(header::COOKIE) {
+        let Ok(header_str) = header_val.to_str() else {
+            continue;
+        };
+        for cookie_str in header_str.split(';') {
+            if let Ok(cookie) = Cookie::parse_encoded(cookie_str.to_owned()) {
+                jar.add_original(cookie);
+            }
+        }
+    }
+
+    let primary_key = Key::from(app_state.cookie_secret.expose_secret());
+    let previous_keys = app_state
+        .previous_cookie_secrets
+        .iter()
+        .map(|secret| Key::from(secret.expose_secret()))
+        .collect::<Vec<_>>();
+    let mut resigned = CookieJar::new();
+    for cookie in jar.iter() {
+        let name = cookie.name();
+        if jar.signed(&primary_key).get(name).is_some() {
+            continue;
+        }
+        let Some(verified) = previous_keys
+            .iter()
+            .find_map(|key| jar.signed(key).get(name))
+        else {
+            continue;
+        };
+
+        // The request only carries the values, the attributes are the ones the cookies are created with.
+        let mut cookie = Cookie::build((name.to_string(), verified.value().to_string())).path("/");
+        if name == SESSION_COOKIE_NAME {
+            let session_config = &app_state.session_config;
+            cookie = cookie
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .secure(session_config.secure)
+                .max_age(Duration::seconds(session_config.expiry_secs));
+        } else if name == CSRF_COOKIE_NAME {
+            cookie = cookie
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .secure(app_state.session_config.secure);
+        }
+        resigned.signed_mut(&primary_key).add(cookie);
+    }
+    if resigned.iter().next().is_none() {
+        return next.run(req).await;
+    }
+
+    let cookie_header = jar
+        .iter()
+        .map(|cookie| {
+            let cookie = resigned.get(cookie.name()).unwrap_or(cookie);
+            cookie.stripped().encoded().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+    match HeaderValue::from_str(&cookie_header) {
+        Ok(header_val) => {
+            req.headers_mut().insert(header::COOKIE, header_val);
+        }
+        Err(e) => {
+            tracing::error!("Unable to rewrite the cookie header: {e}");
+            return next.run(req).await;
+        }
+    }
+
+    let mut resp = next.run(req).await;
+
+    let set_cookies = resp
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|val| Cookie::parse_encoded(val.to_str().ok()?.to_owned()).ok())
+        .map(|cookie| cookie.name().to_string())
+        .collect::<Vec<_>>();
+    for cookie in resigned.iter() {
+        if set_cookies.iter().any(|name| name == cookie.name()) {
+            continue;
+        }
+        if let Ok(header_val) = HeaderValue::from_str(&cookie.encoded().to_string()) {
+            resp.headers_mut().append(header::SET_COOKIE, header_val);
+            tracing::debug!(
+                cookie = cookie.name(),
+                "Re-signed a cookie with the primary key"
+            );
+        }
+    }
+
+    resp
+}
+
 #[inline]
 fn redir_to_login_response() -> http::Response<Body> {
     let mut resp = StatusCode::SEE_OTHER.into_response();
@@ -1,10 +1,20 @@
 pub mod auth;
+mod client_ip;
+pub mod csrf;
 mod error;
+pub mod flash;
+pub mod idempotency;
 pub mod midware;
 pub mod routes;
+pub mod subscription_events;
 pub mod types;
 
+pub use client_ip::ClientIp;
+pub use csrf::CsrfToken;
 pub use error::{ClientError, Error, WebResult};
 
 pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// The name of the signed cookie with the session ID.
+pub const SESSION_COOKIE_NAME: &str = "id";
 pub const FLASH_ERROR_MSG: &str = "_flasherr";
+pub const FLASH_INFO_MSG: &str = "_flashinfo";
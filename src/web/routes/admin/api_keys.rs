@@ -0,0 +1,317 @@
+//! Creating and revoking the API keys of the logged in admin, see `auth::api_key`.
+//!
+//! A key is only shown once, right after it's created. The admin can only give a key the scopes
+//! their role allows, and a revoked key stays in the list so it's clear when it was last used.
+
+use std::str::FromStr;
+
+use axum::{
+    extract::{Path, State},
+    response::{Html, IntoResponse, Redirect, Response},
+    Form,
+};
+use chrono::{DateTime, Duration, Utc};
+use secrecy::ExposeSecret;
+use serde::Serialize;
+use tera::Context;
+use tower_cookies::{Cookies, Key};
+use uuid::Uuid;
+
+use crate::{
+    utils::format_utc,
+    web::{
+        auth::{ApiKey, Scope},
+        flash, CsrfToken, WebResult, FLASH_ERROR_MSG, FLASH_INFO_MSG,
+    },
+    AppState,
+};
+
+use super::{AdminError, AdminSession};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_EXPIRY_DAYS: i64 = 365;
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+/// The create form, built from the form fields because the `scope` checkboxes repeat the field name.
+#[derive(Debug, Default)]
+struct ApiKeyForm {
+    name: String,
+    scopes: Vec<Scope>,
+    expires_in_days: Option<i64>,
+}
+
+impl ApiKeyForm {
+    /// Returns the message that is shown to the admin if the form is invalid.
+    fn parse(fields: Vec<(String, String)>) -> Result<Self, &'static str> {
+        let mut form = ApiKeyForm::default();
+        for (field, value) in fields {
+            match field.as_str() {
+                "name" => form.name = value.trim().to_string(),
+                "scope" => {
+                    let scope = Scope::from_str(&value).map_err(|_| "Unknown scope!")?;
+                    if !form.scopes.contains(&scope) {
+                        form.scopes.push(scope);
+                    }
+                }
+                "expires_in_days" if !value.trim().is_empty() => {
+                    let days = value
+                        .trim()
+                        .parse::<i64>()
+                        .ok()
+                        .filter(|days| (1..=MAX_EXPIRY_DAYS).contains(days))
+                        .ok_or("The key has to expire in 1 to 365 days, or never!")?;
+                    form.expires_in_days = Some(days);
+                }
+                _ => {}
+            }
+        }
+
+        if form.name.is_empty() || form.name.chars().count() > MAX_NAME_LEN {
+            return Err("The name has to be between 1 and 100 characters long!");
+        }
+        if form.scopes.is_empty() {
+            return Err("Pick at least one scope!");
+        }
+        Ok(form)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct ApiKeyRecord {
+    api_key_id: Uuid,
+    name: String,
+    key_prefix: String,
+    scopes: Vec<String>,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+/// An API key as it's rendered in the template.
+#[derive(Serialize)]
+struct ApiKeyView {
+    id: Uuid,
+    name: String,
+    key_prefix: String,
+    scopes: Vec<String>,
+    created_at: String,
+    expires_at: Option<String>,
+    last_used_at: Option<String>,
+    revoked_at: Option<String>,
+    active: bool,
+}
+
+// ###################################
+// ->   HANDLERS
+// ###################################
+#[tracing::instrument(name = "admin_api_keys_list", skip_all)]
+pub async fn api_keys_list(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    csrf_token: CsrfToken,
+) -> WebResult<Html<String>> {
+    let keys: Vec<ApiKeyRecord> = sqlx::query_as(
+        r#"
+        SELECT api_key_id, name, key_prefix, scopes, created_at, expires_at, last_used_at, revoked_at
+        FROM api_keys
+        WHERE user_id = $1
+        ORDER BY revoked_at NULLS FIRST, created_at DESC
+        "#,
+    )
+    .bind(admin_session.user_id())
+    .fetch_all(app_state.database_mgr.db())
+    .await?;
+    let now = Utc::now();
+    let keys = keys
+        .into_iter()
+        .map(|key| ApiKeyView {
+            id: key.api_key_id,
+            active: key.revoked_at.is_none() && key.expires_at.is_none_or(|e| e > now),
+            name: key.name,
+            key_prefix: key.key_prefix,
+            scopes: key.scopes,
+            created_at: format_utc(key.created_at),
+            expires_at: key.expires_at.map(format_utc),
+            last_used_at: key.last_used_at.map(format_utc),
+            revoked_at: key.revoked_at.map(format_utc),
+        })
+        .collect::<Vec<_>>();
+    // Only the scopes the role of the admin allows can be picked.
+    let scopes = Scope::ALL
+        .into_iter()
+        .filter(|scope| admin_session.role() >= scope.required_role())
+        .collect::<Vec<_>>();
+
+    let mut ctx = Context::new();
+    csrf_token.insert_into(&mut ctx);
+    ctx.insert("keys", &keys);
+    ctx.insert("scopes", &scopes);
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    flash::insert_messages(&cookies, &secret_key, &mut ctx)
+        .map_err(|e| AdminError::Unexpected(e.into()))?;
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "admin_api_keys.html")
+        .map_err(AdminError::Tera)?;
+
+    Ok(Html(body))
+}
+
+/// Creates a new API key and shows it, this is the only time the key can be seen.
+#[tracing::instrument(name = "admin_api_key_create", skip_all, fields(user_id = %admin_session.user_id()))]
+pub async fn api_key_create(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    Form(fields): Form<Vec<(String, String)>>,
+) -> WebResult<Response> {
+    let redirect = Redirect::to("/admin/api-keys");
+    let form = match ApiKeyForm::parse(fields) {
+        Ok(form) => form,
+        Err(msg) => {
+            flash::add_for(&app_state, &cookies, FLASH_ERROR_MSG, msg);
+            return Ok(redirect.into_response());
+        }
+    };
+    if let Some(scope) = form
+        .scopes
+        .iter()
+        .find(|scope| admin_session.role() < scope.required_role())
+    {
+        flash::add_for(
+            &app_state,
+            &cookies,
+            FLASH_ERROR_MSG,
+            format!("Your role can't use the {} scope!", scope.as_ref()),
+        );
+        return Ok(redirect.into_response());
+    }
+
+    let key = ApiKey::generate();
+    let now = Utc::now();
+    let expires_at = form.expires_in_days.map(|days| now + Duration::days(days));
+    let scopes = form
+        .scopes
+        .iter()
+        .map(|scope| scope.as_ref().to_string())
+        .collect::<Vec<_>>();
+    sqlx::query(
+        r#"
+        INSERT INTO api_keys (api_key_id, user_id, name, key_hash, key_prefix, scopes, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(admin_session.user_id())
+    .bind(&form.name)
+    .bind(key.hash())
+    .bind(key.display_prefix())
+    .bind(&scopes)
+    .bind(now)
+    .bind(expires_at)
+    .execute(app_state.database_mgr.db())
+    .await?;
+    tracing::info!(scopes = ?scopes, "API key created!");
+
+    let mut ctx = Context::new();
+    ctx.insert("name", &form.name);
+    ctx.insert("key", key.as_str());
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "admin_api_key_created.html")
+        .map_err(AdminError::Tera)?;
+
+    Ok(Html(body).into_response())
+}
+
+/// Revokes one of the API keys of the admin, it stops working right away.
+#[tracing::instrument(name = "admin_api_key_revoke", skip(app_state, cookies, admin_session))]
+pub async fn api_key_revoke(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    Path(api_key_id): Path<Uuid>,
+) -> WebResult<Redirect> {
+    let name: String = sqlx::query_scalar(
+        r#"
+        UPDATE api_keys SET revoked_at = COALESCE(revoked_at, now())
+        WHERE api_key_id = $1 AND user_id = $2
+        RETURNING name
+        "#,
+    )
+    .bind(api_key_id)
+    .bind(admin_session.user_id())
+    .fetch_optional(app_state.database_mgr.db())
+    .await?
+    .ok_or(AdminError::ApiKeyNotFound(api_key_id))?;
+
+    flash::add_for(
+        &app_state,
+        &cookies,
+        FLASH_INFO_MSG,
+        format!("The API key {name} was revoked."),
+    );
+    Ok(Redirect::to("/admin/api-keys"))
+}
+
+// ###################################
+// ->   HELPERS
+// ###################################
+#[cfg(test)]
+mod test {
+    use super::*;
+    use claims::assert_err;
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn api_key_form_collects_the_scopes() -> anyhow::Result<()> {
+        let form = ApiKeyForm::parse(fields(&[
+            ("name", " deploy "),
+            ("scope", "news:publish"),
+            ("scope", "subscribers:read"),
+            ("scope", "news:publish"),
+            ("expires_in_days", ""),
+        ]))
+        .map_err(anyhow::Error::msg)?;
+        assert_eq!(form.name, "deploy");
+        assert_eq!(
+            form.scopes,
+            vec![Scope::NewsPublish, Scope::SubscribersRead]
+        );
+        assert_eq!(form.expires_in_days, None);
+
+        let form = ApiKeyForm::parse(fields(&[
+            ("name", "ci"),
+            ("scope", "news:publish"),
+            ("expires_in_days", "30"),
+        ]))
+        .map_err(anyhow::Error::msg)?;
+        assert_eq!(form.expires_in_days, Some(30));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_api_key_forms_are_rejected() {
+        assert_err!(ApiKeyForm::parse(fields(&[("scope", "news:publish")])));
+        assert_err!(ApiKeyForm::parse(fields(&[("name", "no scopes")])));
+        assert_err!(ApiKeyForm::parse(fields(&[
+            ("name", "unknown"),
+            ("scope", "news:delete")
+        ])));
+        assert_err!(ApiKeyForm::parse(fields(&[
+            ("name", "forever"),
+            ("scope", "news:publish"),
+            ("expires_in_days", "1000")
+        ])));
+    }
+}
@@ -3,7 +3,10 @@ use axum::{extract::State, response::Html};
 use sqlx::PgPool;
 use uuid::Uuid;
 
-use crate::{web::WebResult, AppState};
+use crate::{
+    web::{CsrfToken, WebResult},
+    AppState,
+};
 
 use super::{AdminError, AdminSession};
 
@@ -11,13 +14,16 @@ use super::{AdminError, AdminSession};
 pub async fn dashboard(
     State(app_state): State<AppState>,
     admin_session: AdminSession,
+    csrf_token: CsrfToken,
 ) -> WebResult<Html<String>> {
     let mut ctx = tera::Context::new();
+    csrf_token.insert_into(&mut ctx);
 
     // TODO: could this be stored in the session and retrieved from session ?
     let username = get_username(app_state.database_mgr.db(), admin_session.user_id()).await?;
 
     ctx.insert("username", &username);
+    ctx.insert("role", admin_session.role().as_ref());
     let html_body = app_state
         .templ_mgr
         .render_html_to_string(&ctx, "admin_dashboard.html")
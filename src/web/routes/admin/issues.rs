@@ -0,0 +1,439 @@
+//! Drafting, scheduling and cancelling newsletter issues from the admin area.
+//!
+//! An issue starts as a `draft`, it can be edited until it's `scheduled` for a send time, and a scheduled
+//! issue can be cancelled back to a draft. The `issue_scheduler` publishes it once the send time has passed.
+//! The send time is entered in the admin's time zone and stored in UTC.
+//! Viewers can only look at the issues, changing them takes an editor.
+
+use axum::{
+    extract::{Path, State},
+    response::{Html, Redirect},
+    Form,
+};
+use chrono::{DateTime, LocalResult, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    utils::format_utc,
+    web::{auth::Role, CsrfToken, WebResult},
+    AppState,
+};
+
+use super::{AdminError, AdminSession};
+
+/// The formats produced by a `datetime-local` input, with and without the seconds.
+const SEND_AT_FORMATS: [&str; 2] = ["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S"];
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum IssueStatus {
+    Draft,
+    Scheduled,
+    Published,
+}
+
+impl IssueStatus {
+    /// Only the issues that weren't published yet can be changed.
+    fn is_editable(self) -> bool {
+        matches!(self, IssueStatus::Draft | IssueStatus::Scheduled)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueForm {
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+impl IssueForm {
+    fn validate(&self) -> Result<(), AdminError> {
+        for (field, value) in [
+            ("title", &self.title),
+            ("text_content", &self.text_content),
+            ("html_content", &self.html_content),
+        ] {
+            if value.trim().is_empty() {
+                return Err(AdminError::InvalidInput(format!("{field} can't be empty")));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduleForm {
+    /// The local send time, as sent by a `datetime-local` input.
+    send_at: String,
+    /// An IANA time zone name, e.g. `Europe/Ljubljana`.
+    timezone: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct IssueRecord {
+    newsletter_issue_id: Uuid,
+    title: String,
+    text_content: String,
+    html_content: String,
+    status: IssueStatus,
+    scheduled_for: Option<DateTime<Utc>>,
+    published_at: Option<DateTime<Utc>>,
+    n_recipients: i32,
+    n_delivered: i32,
+    n_failed: i32,
+}
+
+/// The issue as it's rendered in the templates.
+#[derive(Serialize)]
+struct IssueView {
+    id: Uuid,
+    title: String,
+    text_content: String,
+    html_content: String,
+    status: IssueStatus,
+    editable: bool,
+    scheduled_for: Option<String>,
+    published_at: Option<String>,
+    n_recipients: i32,
+    n_delivered: i32,
+    n_failed: i32,
+}
+
+impl From<IssueRecord> for IssueView {
+    fn from(record: IssueRecord) -> Self {
+        IssueView {
+            id: record.newsletter_issue_id,
+            title: record.title,
+            text_content: record.text_content,
+            html_content: record.html_content,
+            status: record.status,
+            editable: record.status.is_editable(),
+            scheduled_for: record.scheduled_for.map(format_utc),
+            published_at: record.published_at.map(format_utc),
+            n_recipients: record.n_recipients,
+            n_delivered: record.n_delivered,
+            n_failed: record.n_failed,
+        }
+    }
+}
+
+// ###################################
+// ->   HANDLERS
+// ###################################
+#[tracing::instrument(name = "admin_issues_list", skip_all)]
+pub async fn issues_list(
+    State(app_state): State<AppState>,
+    admin_session: AdminSession,
+) -> WebResult<Html<String>> {
+    let records: Vec<IssueRecord> = sqlx::query_as(
+        r#"
+        SELECT newsletter_issue_id, title, text_content, html_content, status,
+            scheduled_for, published_at, n_recipients, n_delivered, n_failed
+        FROM newsletter_issues
+        ORDER BY updated_at DESC
+        "#,
+    )
+    .fetch_all(app_state.database_mgr.db())
+    .await?;
+    let issues = records.into_iter().map(IssueView::from).collect::<Vec<_>>();
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("issues", &issues);
+    ctx.insert("can_edit", &can_edit(&admin_session));
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "admin_issues.html")
+        .map_err(AdminError::Tera)?;
+
+    Ok(Html(body))
+}
+
+#[tracing::instrument(name = "admin_issue_new", skip_all)]
+pub async fn issue_new(
+    State(app_state): State<AppState>,
+    admin_session: AdminSession,
+    csrf_token: CsrfToken,
+) -> WebResult<Html<String>> {
+    admin_session.require_role(Role::Editor)?;
+    render_issue_form(&app_state, &admin_session, &csrf_token, None)
+}
+
+#[tracing::instrument(name = "admin_issue_create", skip_all, fields(title = form.title))]
+pub async fn issue_create(
+    State(app_state): State<AppState>,
+    admin_session: AdminSession,
+    Form(form): Form<IssueForm>,
+) -> WebResult<Redirect> {
+    admin_session.require_role(Role::Editor)?;
+    form.validate()?;
+
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query(
+        r#"
+        INSERT INTO newsletter_issues
+            (newsletter_issue_id, title, text_content, html_content, author_user_id, status, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, 'draft', $6, $6)
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .bind(&form.title)
+    .bind(&form.text_content)
+    .bind(&form.html_content)
+    .bind(admin_session.user_id())
+    .bind(Utc::now())
+    .execute(app_state.database_mgr.db())
+    .await?;
+
+    Ok(Redirect::to(&format!(
+        "/admin/issues/{newsletter_issue_id}"
+    )))
+}
+
+#[tracing::instrument(name = "admin_issue_get", skip(app_state, admin_session, csrf_token))]
+pub async fn issue_get(
+    State(app_state): State<AppState>,
+    admin_session: AdminSession,
+    csrf_token: CsrfToken,
+    Path(newsletter_issue_id): Path<Uuid>,
+) -> WebResult<Html<String>> {
+    let record: IssueRecord = sqlx::query_as(
+        r#"
+        SELECT newsletter_issue_id, title, text_content, html_content, status,
+            scheduled_for, published_at, n_recipients, n_delivered, n_failed
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .fetch_optional(app_state.database_mgr.db())
+    .await?
+    .ok_or(AdminError::IssueNotFound(newsletter_issue_id))?;
+
+    render_issue_form(&app_state, &admin_session, &csrf_token, Some(record.into()))
+}
+
+#[tracing::instrument(name = "admin_issue_update", skip(app_state, admin_session, form))]
+pub async fn issue_update(
+    State(app_state): State<AppState>,
+    admin_session: AdminSession,
+    Path(newsletter_issue_id): Path<Uuid>,
+    Form(form): Form<IssueForm>,
+) -> WebResult<Redirect> {
+    admin_session.require_role(Role::Editor)?;
+    form.validate()?;
+
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let status = lock_issue_status(&mut transaction, newsletter_issue_id).await?;
+    if !status.is_editable() {
+        return Err(AdminError::IssueNotEditable(newsletter_issue_id).into());
+    }
+
+    sqlx::query(
+        r#"
+        UPDATE newsletter_issues
+        SET title = $2, text_content = $3, html_content = $4, updated_at = now()
+        WHERE newsletter_issue_id = $1
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .bind(&form.title)
+    .bind(&form.text_content)
+    .bind(&form.html_content)
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(Redirect::to(&format!(
+        "/admin/issues/{newsletter_issue_id}"
+    )))
+}
+
+/// Schedules the issue for the submitted send time, an already scheduled issue is rescheduled.
+#[tracing::instrument(name = "admin_issue_schedule", skip(app_state, admin_session))]
+pub async fn issue_schedule(
+    State(app_state): State<AppState>,
+    admin_session: AdminSession,
+    Path(newsletter_issue_id): Path<Uuid>,
+    Form(form): Form<ScheduleForm>,
+) -> WebResult<Redirect> {
+    admin_session.require_role(Role::Editor)?;
+    let scheduled_for = parse_send_at(&form.send_at, &form.timezone)?;
+    if scheduled_for <= Utc::now() {
+        return Err(
+            AdminError::InvalidInput("the send time has to be in the future".into()).into(),
+        );
+    }
+
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let status = lock_issue_status(&mut transaction, newsletter_issue_id).await?;
+    if !status.is_editable() {
+        return Err(AdminError::IssueNotEditable(newsletter_issue_id).into());
+    }
+
+    sqlx::query(
+        r#"
+        UPDATE newsletter_issues
+        SET status = 'scheduled', scheduled_for = $2, updated_at = now()
+        WHERE newsletter_issue_id = $1
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .bind(scheduled_for)
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(Redirect::to("/admin/issues"))
+}
+
+/// Turns a scheduled issue back into a draft.
+#[tracing::instrument(name = "admin_issue_cancel", skip(app_state, admin_session))]
+pub async fn issue_cancel(
+    State(app_state): State<AppState>,
+    admin_session: AdminSession,
+    Path(newsletter_issue_id): Path<Uuid>,
+) -> WebResult<Redirect> {
+    admin_session.require_role(Role::Editor)?;
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let status = lock_issue_status(&mut transaction, newsletter_issue_id).await?;
+    if status != IssueStatus::Scheduled {
+        return Err(AdminError::IssueNotScheduled(newsletter_issue_id).into());
+    }
+
+    sqlx::query(
+        r#"
+        UPDATE newsletter_issues
+        SET status = 'draft', scheduled_for = NULL, updated_at = now()
+        WHERE newsletter_issue_id = $1
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(Redirect::to("/admin/issues"))
+}
+
+// ###################################
+// ->   HELPERS
+// ###################################
+/// Locks the issue until the end of the transaction, so the scheduler can't publish it in the meantime.
+async fn lock_issue_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<IssueStatus, AdminError> {
+    sqlx::query_scalar(
+        r#"
+        SELECT status FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        FOR UPDATE
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .fetch_optional(&mut **transaction)
+    .await?
+    .ok_or(AdminError::IssueNotFound(newsletter_issue_id))
+}
+
+/// Whether the admin may change the issues, the templates hide the forms otherwise.
+fn can_edit(admin_session: &AdminSession) -> bool {
+    admin_session.require_role(Role::Editor).is_ok()
+}
+
+fn render_issue_form(
+    app_state: &AppState,
+    admin_session: &AdminSession,
+    csrf_token: &CsrfToken,
+    issue: Option<IssueView>,
+) -> WebResult<Html<String>> {
+    let mut ctx = tera::Context::new();
+    csrf_token.insert_into(&mut ctx);
+    ctx.insert("can_edit", &can_edit(admin_session));
+    if let Some(issue) = issue {
+        ctx.insert("issue", &issue);
+    }
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "admin_issue_form.html")
+        .map_err(AdminError::Tera)?;
+
+    Ok(Html(body))
+}
+
+/// Interprets the local send time in the given time zone and converts it to UTC.
+///
+/// A time that occurs twice because of a DST change resolves to the earlier one,
+/// a time skipped by a DST change is rejected.
+fn parse_send_at(send_at: &str, timezone: &str) -> Result<DateTime<Utc>, AdminError> {
+    let tz: Tz = timezone
+        .trim()
+        .parse()
+        .map_err(|_| AdminError::InvalidInput(format!("unknown time zone: {timezone}")))?;
+    let local = SEND_AT_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(send_at.trim(), format).ok())
+        .ok_or_else(|| AdminError::InvalidInput(format!("invalid send time: {send_at}")))?;
+
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(send_at) | LocalResult::Ambiguous(send_at, _) => {
+            Ok(send_at.with_timezone(&Utc))
+        }
+        LocalResult::None => Err(AdminError::InvalidInput(format!(
+            "{send_at} doesn't exist in the {timezone} time zone"
+        ))),
+    }
+}
+
+// ###################################
+// ->   TESTS
+// ###################################
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_send_at_converts_the_local_time_to_utc() {
+        let send_at = parse_send_at("2030-01-15T09:30", "Europe/Ljubljana").unwrap();
+        assert_eq!(send_at.to_rfc3339(), "2030-01-15T08:30:00+00:00");
+
+        let send_at = parse_send_at("2030-07-15T09:30:00", "America/New_York").unwrap();
+        assert_eq!(send_at.to_rfc3339(), "2030-07-15T13:30:00+00:00");
+    }
+
+    #[test]
+    fn parse_send_at_handles_dst_transitions() {
+        // Clocks go from 02:00 to 03:00 on the last Sunday of March.
+        assert!(matches!(
+            parse_send_at("2030-03-31T02:30", "Europe/Ljubljana"),
+            Err(AdminError::InvalidInput(_))
+        ));
+        // 02:30 happens twice on the last Sunday of October, the earlier one is picked.
+        let send_at = parse_send_at("2030-10-27T02:30", "Europe/Ljubljana").unwrap();
+        assert_eq!(send_at.to_rfc3339(), "2030-10-27T00:30:00+00:00");
+    }
+
+    #[test]
+    fn parse_send_at_rejects_invalid_input() {
+        for (send_at, timezone) in [
+            ("2030-01-15T09:30", "Mars/Olympus_Mons"),
+            ("15.1.2030 09:30", "UTC"),
+            ("", "UTC"),
+        ] {
+            assert!(
+                matches!(
+                    parse_send_at(send_at, timezone),
+                    Err(AdminError::InvalidInput(_))
+                ),
+                "{send_at} {timezone}"
+            );
+        }
+    }
+}
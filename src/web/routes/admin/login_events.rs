@@ -0,0 +1,81 @@
+//! The failed logins and the lockouts recorded by `auth::throttle`, only the owners can see them.
+
+use axum::{extract::State, response::Html};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tera::Context;
+
+use crate::{
+    utils::format_utc,
+    web::{auth::Role, WebResult},
+    AppState,
+};
+
+use super::{AdminError, AdminSession};
+
+/// The number of the most recent events that are shown.
+const EVENTS_LIMIT: i64 = 200;
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+#[derive(sqlx::FromRow)]
+struct LoginEventRecord {
+    kind: String,
+    username: String,
+    ip: String,
+    occurred_at: DateTime<Utc>,
+}
+
+/// A login event as it's rendered in the template.
+#[derive(Serialize)]
+struct LoginEventView {
+    kind: String,
+    username: String,
+    ip: String,
+    occurred_at: String,
+}
+
+// ###################################
+// ->   HANDLERS
+// ###################################
+#[tracing::instrument(name = "admin_login_events_list", skip_all)]
+pub async fn login_events_list(
+    State(app_state): State<AppState>,
+    admin_session: AdminSession,
+) -> WebResult<Html<String>> {
+    admin_session.require_role(Role::Owner)?;
+
+    let events: Vec<LoginEventRecord> = sqlx::query_as(
+        r#"
+        SELECT kind, username, ip, occurred_at FROM login_events
+        ORDER BY occurred_at DESC
+        LIMIT $1
+        "#,
+    )
+    .bind(EVENTS_LIMIT)
+    .fetch_all(app_state.database_mgr.db())
+    .await?;
+    let events = events
+        .into_iter()
+        .map(|event| LoginEventView {
+            kind: event.kind.replace('_', " "),
+            username: event.username,
+            ip: event.ip,
+            occurred_at: format_utc(event.occurred_at),
+        })
+        .collect::<Vec<_>>();
+
+    let mut ctx = Context::new();
+    ctx.insert("events", &events);
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "admin_login_events.html")
+        .map_err(AdminError::Tera)?;
+
+    Ok(Html(body))
+}
+
+// ###################################
+// ->   HELPERS
+// ###################################
@@ -1,19 +1,49 @@
+mod api_keys;
 mod dashboard;
+mod issues;
+mod login_events;
 mod password;
+mod session_index;
+mod sessions;
+mod subscribers;
+mod totp;
+mod users;
 
 // re-exports
+pub use api_keys::{api_key_create, api_key_revoke, api_keys_list};
 pub use dashboard::dashboard;
-pub use password::{get_change_password, post_change_password};
+pub use issues::{
+    issue_cancel, issue_create, issue_get, issue_new, issue_schedule, issue_update, issues_list,
+};
+pub use login_events::login_events_list;
+pub use password::{
+    get_change_password, post_change_email, post_change_password, PasswordChangeError,
+};
+pub use session_index::SessionIndex;
+pub use sessions::{logout, session_revoke, sessions_list, sessions_revoke_all};
+pub use subscribers::{
+    subscriber_confirm, subscriber_delete, subscriber_events_list, subscriber_unsubscribe,
+    subscribers_import_get, subscribers_import_post, subscribers_list,
+};
+pub use totp::{totp_confirm, totp_disable, totp_enroll, totp_get, totp_recovery_codes_regenerate};
+pub use users::{user_deactivate, user_invite, users_list};
 
 use anyhow::anyhow;
 use axum::{extract::FromRequestParts, http::request::Parts};
 use serde::{Deserialize, Serialize};
 use tower_cookies::cookie::time::OffsetDateTime;
-use tower_sessions::Session;
+use tower_sessions::{session::Id, Session};
 use tracing::instrument;
 use uuid::Uuid;
 
-use crate::web;
+use crate::{
+    email_client,
+    web::{
+        self,
+        auth::{AuthError, Role},
+    },
+    AppState,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum AdminError {
@@ -21,6 +51,37 @@ pub enum AdminError {
     Unauthorized,
     #[error("tower_sessions error: {0}")]
     Session(#[from] tower_sessions::session::Error),
+    #[error("newsletter issue not found: {0}")]
+    IssueNotFound(Uuid),
+    #[error("newsletter issue was already published: {0}")]
+    IssueNotEditable(Uuid),
+    #[error("newsletter issue isn't scheduled: {0}")]
+    IssueNotScheduled(Uuid),
+    #[error("admin session not found")]
+    SessionNotFound,
+    #[error("user not found: {0}")]
+    UserNotFound(Uuid),
+    #[error("API key not found: {0}")]
+    ApiKeyNotFound(Uuid),
+    #[error("subscriber not found: {0}")]
+    SubscriberNotFound(Uuid),
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("password change error: {0}")]
+    PasswordChange(#[from] PasswordChangeError),
+    #[error("authentication error: {0}")]
+    Auth(#[from] AuthError),
+
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("redis error: {0}")]
+    Redis(#[from] tower_sessions_redis_store::fred::error::Error),
+    #[error("session store error: {0}")]
+    SessionStore(#[from] tower_sessions::session_store::Error),
+    #[error("tera template render error: {0}")]
+    Tera(#[from] tera::Error),
+    #[error("email client error: {0}")]
+    Email(#[from] email_client::Error),
 
     #[error("unexpected error: {0}")]
     Unexpected(#[from] anyhow::Error),
@@ -39,14 +100,18 @@ pub struct AdminSession {
 #[derive(Clone, Deserialize, Serialize)]
 pub struct AdminData {
     user_id: Uuid,
+    /// The sessions from before the roles were added get the least permissions.
+    #[serde(default)]
+    role: Role,
     first_seen: OffsetDateTime,
     last_seen: OffsetDateTime,
 }
 
 impl AdminData {
-    pub fn new(user_id: Uuid) -> Self {
+    pub fn new(user_id: Uuid, role: Role) -> Self {
         AdminData {
             user_id,
+            role,
             first_seen: OffsetDateTime::now_utc(),
             last_seen: OffsetDateTime::now_utc(),
         }
@@ -74,6 +139,16 @@ impl AdminSession {
         self.admin_data.user_id
     }
 
+    pub fn role(&self) -> Role {
+        self.admin_data.role
+    }
+
+    /// Fails with a 403 if the admin doesn't have the permissions of the `required` role.
+    /// The handlers of the routes that need more than a logged in viewer call this first.
+    pub fn require_role(&self, required: Role) -> Result<(), AdminError> {
+        Ok(self.admin_data.role.require(required)?)
+    }
+
     pub fn first_seen(&self) -> OffsetDateTime {
         self.admin_data.first_seen
     }
@@ -82,10 +157,26 @@ impl AdminSession {
         self.admin_data.last_seen
     }
 
+    /// The ID of the session, `None` until the session is saved for the first time.
+    pub fn id(&self) -> Option<Id> {
+        self.session.id()
+    }
+
     pub async fn cycle_id(&self) -> Result<(), AdminError> {
         self.session.cycle_id().await.map_err(AdminError::Session)
     }
 
+    /// Deletes the session from the store and clears its data, the session cookie is removed from the response.
+    pub async fn flush(&self) -> Result<(), AdminError> {
+        self.session.flush().await.map_err(AdminError::Session)
+    }
+
+    /// Saves the session to the store right away instead of at the end of the request,
+    /// a newly created session gets its ID this way.
+    pub async fn save(&self) -> Result<(), AdminError> {
+        self.session.save().await.map_err(AdminError::Session)
+    }
+
     /// updates the contained session with the contained data.
     /// `update_session` or `update_session_with` needs to be called when first building the AdminSession.
     pub async fn update_session(&self) -> Result<(), AdminError> {
@@ -101,14 +192,14 @@ impl AdminSession {
     }
 }
 
-impl<S> FromRequestParts<S> for AdminSession
-where
-    S: Send + Sync,
-{
+impl FromRequestParts<AppState> for AdminSession {
     type Rejection = web::Error;
 
     #[instrument(skip_all, name = "AdminSession from_request_parts")]
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
         let Ok(session) = Session::from_request_parts(parts, state).await else {
             return Err(anyhow!("unable to extract the session from request parts").into());
         };
@@ -121,12 +212,30 @@ where
             return Err(AdminError::Unauthorized.into());
         };
 
+        // The role could have changed and the user could have been deactivated since the login.
+        let user: Option<(Role, bool)> = sqlx::query_as(
+            r#"
+            SELECT role, deactivated_at IS NOT NULL FROM users
+            WHERE user_id = $1
+            "#,
+        )
+        .bind(admin_data.user_id)
+        .fetch_optional(state.database_mgr.db())
+        .await
+        .map_err(AdminError::Sqlx)?;
+        let Some((role, false)) = user else {
+            session.flush().await.map_err(AdminError::Session)?;
+            return Err(AdminError::Unauthorized.into());
+        };
+        admin_data.role = role;
         admin_data.last_seen = OffsetDateTime::now_utc();
 
         // build a new AdminSession from the changed data.
         let admin_session = Self::new(session, admin_data);
         // update with the contained data
         admin_session.update_session().await?;
+        // The request extends the session, the index of the sessions has to keep up with it.
+        state.session_index.touch(admin_session.user_id()).await?;
 
         Ok(admin_session)
     }
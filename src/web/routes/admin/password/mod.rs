@@ -1,20 +1,242 @@
+//! Changing the password and the email address of the logged in admin.
+//!
+//! The outcome is shown on the password page as a flash message. A successful password change logs out
+//! all the other sessions of the admin through the `SessionIndex`, the current one stays logged in.
+//! The email address is where the password reset links are sent to.
+
 use axum::{
     extract::State,
-    response::{Html, IntoResponse},
+    response::{Html, Redirect},
+    Form,
 };
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
 use tera::Context;
+use tower_cookies::{Cookies, Key};
+
+use crate::{
+    web::{
+        auth::{password, AuthError},
+        flash,
+        types::ValidEmail,
+        CsrfToken, WebResult, FLASH_ERROR_MSG, FLASH_INFO_MSG,
+    },
+    AppState,
+};
+
+use super::{AdminError, AdminSession};
+
+// ###################################
+// ->   ERROR
+// ###################################
+#[derive(Debug, thiserror::Error)]
+pub enum PasswordChangeError {
+    #[error("The current password is wrong!")]
+    CurrentPasswordInvalid,
+    #[error("The new passwords don't match!")]
+    NewPasswordMismatch,
+    #[error("The new password has to be different from the current one!")]
+    NewPasswordUnchanged,
+    #[error("The new password is too weak, {0}!")]
+    NewPasswordTooWeak(String),
+
+    #[error("authentication error: {0}")]
+    Auth(#[from] AuthError),
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+}
+
+impl PasswordChangeError {
+    /// The errors caused by the submitted form, they are shown to the admin instead of failing the request.
+    fn is_rejection(&self) -> bool {
+        use PasswordChangeError::*;
+        matches!(
+            self,
+            CurrentPasswordInvalid
+                | NewPasswordMismatch
+                | NewPasswordUnchanged
+                | NewPasswordTooWeak(_)
+        )
+    }
+}
+
+// ###################################
+// ->   HANDLERS
+// ###################################
+#[derive(Deserialize)]
+pub struct ChangePasswordForm {
+    current_password: SecretString,
+    new_password: SecretString,
+    new_password_check: SecretString,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeEmailForm {
+    email: String,
+}
+
+#[tracing::instrument(name = "admin_get_change_password", skip_all)]
+pub async fn get_change_password(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    csrf_token: CsrfToken,
+) -> WebResult<Html<String>> {
+    let mut ctx = Context::new();
+    csrf_token.insert_into(&mut ctx);
 
-use crate::{web::WebResult, AppState};
+    let email: Option<String> = sqlx::query_scalar(
+        r#"
+        SELECT email FROM users
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(admin_session.user_id())
+    .fetch_one(app_state.database_mgr.db())
+    .await?;
+    ctx.insert("email", &email);
+
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    flash::insert_messages(&cookies, &secret_key, &mut ctx)
+        .map_err(|e| AdminError::Unexpected(e.into()))?;
 
-pub async fn get_change_password(State(app_state): State<AppState>) -> WebResult<Html<String>> {
-    let ctx = Context::new();
     let html = app_state
         .templ_mgr
-        .render_html_to_string(&ctx, "change_admin_password.html");
+        .render_html_to_string(&ctx, "change_admin_password.html")
+        .map_err(AdminError::Tera)?;
+
+    Ok(Html(html))
+}
+
+#[tracing::instrument(name = "admin_post_change_password", skip_all, fields(user_id = %admin_session.user_id()))]
+pub async fn post_change_password(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    Form(form): Form<ChangePasswordForm>,
+) -> WebResult<Redirect> {
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+
+    match change_password(&app_state, &admin_session, form).await {
+        Ok(()) => {
+            // The password might have been changed because of a leak, log out everyone else.
+            let revoked = app_state
+                .session_index
+                .revoke_all_except(admin_session.user_id(), admin_session.id())
+                .await?;
+            tracing::info!(revoked_sessions = revoked, "Admin password changed!");
+            flash::add(
+                &cookies,
+                &secret_key,
+                FLASH_INFO_MSG,
+                "Your password was changed, all your other sessions were logged out.",
+            );
+        }
+        Err(e) if e.is_rejection() => {
+            tracing::info!("password change rejected: {e}");
+            flash::add(&cookies, &secret_key, FLASH_ERROR_MSG, e.to_string());
+        }
+        Err(e) => return Err(AdminError::from(e).into()),
+    }
+
+    Ok(Redirect::to("/admin/password"))
+}
 
-    todo!()
+#[tracing::instrument(name = "admin_post_change_email", skip_all, fields(user_id = %admin_session.user_id()))]
+pub async fn post_change_email(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    Form(form): Form<ChangeEmailForm>,
+) -> WebResult<Redirect> {
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    let redirect = Redirect::to("/admin/password");
+
+    let Ok(email) = ValidEmail::parse(form.email.trim()) else {
+        flash::add(
+            &cookies,
+            &secret_key,
+            FLASH_ERROR_MSG,
+            "The email address is invalid!",
+        );
+        return Ok(redirect);
+    };
+
+    let res = sqlx::query(
+        r#"
+        UPDATE users SET email = $2
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(admin_session.user_id())
+    .bind(email.as_ref())
+    .execute(app_state.database_mgr.db())
+    .await;
+    match res {
+        Ok(_) => flash::add(
+            &cookies,
+            &secret_key,
+            FLASH_INFO_MSG,
+            "Your email address was changed.",
+        ),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => flash::add(
+            &cookies,
+            &secret_key,
+            FLASH_ERROR_MSG,
+            "The email address is already used by another account!",
+        ),
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(redirect)
 }
 
-pub async fn post_change_password() -> impl IntoResponse {
-    todo!()
+/// Validates the form and stores the hash of the new password.
+async fn change_password(
+    app_state: &AppState,
+    admin_session: &AdminSession,
+    form: ChangePasswordForm,
+) -> Result<(), PasswordChangeError> {
+    let user_id = admin_session.user_id();
+
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        return Err(PasswordChangeError::NewPasswordMismatch);
+    }
+    if form.new_password.expose_secret() == form.current_password.expose_secret() {
+        return Err(PasswordChangeError::NewPasswordUnchanged);
+    }
+    match password::check_strength(&form.new_password) {
+        Err(AuthError::PasswordTooWeak(reason)) => {
+            return Err(PasswordChangeError::NewPasswordTooWeak(reason))
+        }
+        res => res?,
+    }
+
+    let current_hash: String = sqlx::query_scalar(
+        r#"
+        SELECT password_hash FROM users
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(app_state.database_mgr.db())
+    .await?;
+    match password::validate_async(form.current_password, SecretString::from(current_hash)).await {
+        Err(AuthError::PasswordInvalid) => return Err(PasswordChangeError::CurrentPasswordInvalid),
+        res => res?,
+    }
+
+    let new_hash = password::hash_new_to_string_async(form.new_password).await?;
+    sqlx::query(
+        r#"
+        UPDATE users SET password_hash = $2
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .bind(new_hash.expose_secret())
+    .execute(app_state.database_mgr.db())
+    .await?;
+
+    Ok(())
 }
@@ -0,0 +1,124 @@
+//! An index of the admin sessions per user, stored in Redis next to the sessions themselves.
+//!
+//! tower-sessions only knows how to find a session by its ID, the index keeps a set of the IDs
+//! for every user, so all the sessions of a user can be found and revoked.
+//! The index expires together with the most recently used session of the user, every request
+//! of a logged in admin extends it like it extends the session. IDs of the sessions that already expired
+//! are pruned whenever the index is read.
+
+use tower_sessions::{
+    session::{Id, Record},
+    SessionStore,
+};
+use tower_sessions_redis_store::{
+    fred::prelude::{KeysInterface, Pool, SetsInterface},
+    RedisStore,
+};
+use uuid::Uuid;
+
+use crate::redis_manager::RedisManager;
+
+use super::{AdminData, AdminError, AdminSession};
+
+#[derive(Debug, Clone)]
+pub struct SessionIndex {
+    pool: Pool,
+    store: RedisStore<Pool>,
+    /// The inactivity expiry of the sessions.
+    expiry_secs: i64,
+}
+
+impl SessionIndex {
+    pub fn new(redis_manager: &RedisManager, expiry_secs: i64) -> Self {
+        let pool = redis_manager.get_pool();
+        let store = RedisStore::new(pool.clone());
+        SessionIndex {
+            pool,
+            store,
+            expiry_secs,
+        }
+    }
+
+    fn key(user_id: Uuid) -> String {
+        format!("admin_sessions:{user_id}")
+    }
+
+    /// Adds the session to the index of its user.
+    pub async fn add(&self, user_id: Uuid, session_id: Id) -> Result<(), AdminError> {
+        let _: i64 = self
+            .pool
+            .sadd(Self::key(user_id), session_id.to_string())
+            .await?;
+        self.touch(user_id).await
+    }
+
+    /// Extends the index of the user, called whenever one of the sessions is used, so the index
+    /// lives as long as the most recently used session.
+    pub async fn touch(&self, user_id: Uuid) -> Result<(), AdminError> {
+        let _: bool = self
+            .pool
+            .expire(Self::key(user_id), self.expiry_secs, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the sessions of the user that are still in the store.
+    pub async fn sessions(&self, user_id: Uuid) -> Result<Vec<(Id, AdminData)>, AdminError> {
+        let key = Self::key(user_id);
+        let ids: Vec<String> = self.pool.smembers(&key).await?;
+
+        let mut sessions = Vec::with_capacity(ids.len());
+        for raw_id in ids {
+            let record = match raw_id.parse::<Id>() {
+                Ok(id) => self.store.load(&id).await?,
+                Err(_) => None,
+            };
+            let data = record.as_ref().and_then(admin_data);
+            match (record, data) {
+                (Some(record), Some(data)) if data.user_id == user_id => {
+                    sessions.push((record.id, data))
+                }
+                // Expired, flushed or unparsable, it doesn't belong in the index anymore.
+                _ => {
+                    let _: i64 = self.pool.srem(&key, raw_id).await?;
+                }
+            }
+        }
+
+        Ok(sessions)
+    }
+
+    /// Deletes the session from the store and removes it from the index.
+    pub async fn revoke(&self, user_id: Uuid, session_id: Id) -> Result<(), AdminError> {
+        self.store.delete(&session_id).await?;
+        let _: i64 = self
+            .pool
+            .srem(Self::key(user_id), session_id.to_string())
+            .await?;
+        Ok(())
+    }
+
+    /// Revokes all the sessions of the user except the `keep` session, returns the number of revoked sessions.
+    pub async fn revoke_all_except(
+        &self,
+        user_id: Uuid,
+        keep: Option<Id>,
+    ) -> Result<usize, AdminError> {
+        let mut revoked = 0;
+        for (session_id, _) in self.sessions(user_id).await? {
+            if Some(session_id) != keep {
+                self.revoke(user_id, session_id).await?;
+                revoked += 1;
+            }
+        }
+        Ok(revoked)
+    }
+}
+
+fn admin_data(record: &Record) -> Option<AdminData> {
+    record
+        .data
+        .get(AdminSession::ADMIN_DATA_KEY)
+        .cloned()
+        .and_then(|value| serde_json::from_value(value).ok())
+}
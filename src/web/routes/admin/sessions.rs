@@ -0,0 +1,181 @@
+//! Logging out and managing the active sessions of the logged in admin.
+//!
+//! The sessions are found through the `SessionIndex`. A session ID is as good as the session itself,
+//! so the pages refer to the sessions by an HMAC of the ID instead of the ID.
+
+use axum::{
+    extract::{Path, State},
+    response::{Html, Redirect},
+};
+use chrono::DateTime;
+use secrecy::ExposeSecret;
+use serde::Serialize;
+use tower_cookies::{cookie::time::OffsetDateTime, Cookies, Key};
+use tower_sessions::session::Id;
+
+use crate::{
+    utils::{b64u_encode, format_utc, hmac_sha256_sign},
+    web::{flash, CsrfToken, WebResult, FLASH_INFO_MSG},
+    AppState,
+};
+
+use super::{AdminError, AdminSession};
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+/// An active session as it's rendered in the template.
+#[derive(Serialize)]
+struct SessionView {
+    handle: String,
+    current: bool,
+    first_seen: String,
+    last_seen: String,
+}
+
+// ###################################
+// ->   HANDLERS
+// ###################################
+#[tracing::instrument(name = "admin_logout", skip_all, fields(user_id = %admin_session.user_id()))]
+pub async fn logout(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+) -> WebResult<Redirect> {
+    end_current_session(&app_state, &cookies, &admin_session).await?;
+    Ok(Redirect::to("/login"))
+}
+
+#[tracing::instrument(name = "admin_sessions_list", skip_all)]
+pub async fn sessions_list(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    csrf_token: CsrfToken,
+) -> WebResult<Html<String>> {
+    let mut sessions = app_state
+        .session_index
+        .sessions(admin_session.user_id())
+        .await?;
+    sessions.sort_by_key(|(_, data)| std::cmp::Reverse(data.last_seen));
+
+    let sessions = sessions
+        .into_iter()
+        .map(|(session_id, data)| SessionView {
+            handle: session_handle(&app_state, session_id),
+            current: Some(session_id) == admin_session.id(),
+            first_seen: format_offset(data.first_seen),
+            last_seen: format_offset(data.last_seen),
+        })
+        .collect::<Vec<_>>();
+
+    let mut ctx = tera::Context::new();
+    csrf_token.insert_into(&mut ctx);
+    ctx.insert("sessions", &sessions);
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    if let Some(msg) = flash::take(&cookies, &secret_key, FLASH_INFO_MSG)
+        .map_err(|e| AdminError::Unexpected(e.into()))?
+    {
+        ctx.insert("info_message", &msg);
+    }
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "admin_sessions.html")
+        .map_err(AdminError::Tera)?;
+
+    Ok(Html(body))
+}
+
+/// Revokes a single session, revoking the current session logs the admin out.
+#[tracing::instrument(name = "admin_session_revoke", skip(app_state, cookies, admin_session))]
+pub async fn session_revoke(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    Path(handle): Path<String>,
+) -> WebResult<Redirect> {
+    let user_id = admin_session.user_id();
+    let session_index = &app_state.session_index;
+
+    let session_id = session_index
+        .sessions(user_id)
+        .await?
+        .into_iter()
+        .map(|(session_id, _)| session_id)
+        .find(|session_id| session_handle(&app_state, *session_id) == handle)
+        .ok_or(AdminError::SessionNotFound)?;
+
+    if Some(session_id) == admin_session.id() {
+        end_current_session(&app_state, &cookies, &admin_session).await?;
+        return Ok(Redirect::to("/login"));
+    }
+
+    session_index.revoke(user_id, session_id).await?;
+    flash::add_for(
+        &app_state,
+        &cookies,
+        FLASH_INFO_MSG,
+        "The session was logged out.",
+    );
+
+    Ok(Redirect::to("/admin/sessions"))
+}
+
+/// Revokes all the sessions of the admin except the current one.
+#[tracing::instrument(name = "admin_sessions_revoke_all", skip_all, fields(user_id = %admin_session.user_id()))]
+pub async fn sessions_revoke_all(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+) -> WebResult<Redirect> {
+    let revoked = app_state
+        .session_index
+        .revoke_all_except(admin_session.user_id(), admin_session.id())
+        .await?;
+    flash::add_for(
+        &app_state,
+        &cookies,
+        FLASH_INFO_MSG,
+        format!("{revoked} other session(s) were logged out."),
+    );
+
+    Ok(Redirect::to("/admin/sessions"))
+}
+
+// ###################################
+// ->   HELPERS
+// ###################################
+/// Removes the current session from the index, deletes it from the store and clears the session cookie.
+async fn end_current_session(
+    app_state: &AppState,
+    cookies: &Cookies,
+    admin_session: &AdminSession,
+) -> Result<(), AdminError> {
+    if let Some(session_id) = admin_session.id() {
+        app_state
+            .session_index
+            .revoke(admin_session.user_id(), session_id)
+            .await?;
+    }
+    admin_session.flush().await?;
+    flash::add_for(
+        app_state,
+        cookies,
+        FLASH_INFO_MSG,
+        "You have been logged out.",
+    );
+    Ok(())
+}
+
+fn session_handle(app_state: &AppState, session_id: Id) -> String {
+    b64u_encode(hmac_sha256_sign(
+        app_state.hmac_secret.expose_secret(),
+        session_id.to_string(),
+    ))
+}
+
+fn format_offset(date: OffsetDateTime) -> String {
+    DateTime::from_timestamp(date.unix_timestamp(), 0)
+        .map(format_utc)
+        .unwrap_or_default()
+}
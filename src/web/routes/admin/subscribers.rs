@@ -0,0 +1,669 @@
+//! Managing the subscribers from the admin area.
+//!
+//! Every admin can look through the subscribers, the editors can confirm or unsubscribe them by hand
+//! and only the owners can delete them. The list is paginated by the subscription time and the ID,
+//! so the pages stay stable while new subscribers come in.
+
+use axum::{
+    extract::{multipart::MultipartError, Multipart, Path, Query, State},
+    response::{Html, Redirect},
+};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use tera::Context;
+use tower_cookies::{Cookies, Key};
+use uuid::Uuid;
+
+use crate::{
+    utils::format_utc,
+    web::{
+        auth::Role,
+        flash,
+        subscription_events::{self, EventKind, EventSource, RequestMeta},
+        CsrfToken, WebResult, FLASH_INFO_MSG,
+    },
+    AppState,
+};
+
+use super::{
+    super::subscriber_import::{ImportError, ImportReport, ImportStatus, SubscriberImport},
+    AdminError, AdminSession,
+};
+
+/// The number of subscribers on a page.
+const PAGE_SIZE: i64 = 50;
+/// The format of a `date` input.
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+pub enum SubscriberStatus {
+    PendingConfirmation,
+    Confirmed,
+    Unsubscribed,
+}
+
+impl SubscriberStatus {
+    fn parse(status: &str) -> Result<Self, AdminError> {
+        match status {
+            "pending_confirmation" => Ok(Self::PendingConfirmation),
+            "confirmed" => Ok(Self::Confirmed),
+            "unsubscribed" => Ok(Self::Unsubscribed),
+            other => Err(AdminError::InvalidInput(format!(
+                "unknown subscriber status: {other}"
+            ))),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::PendingConfirmation => "pending_confirmation",
+            Self::Confirmed => "confirmed",
+            Self::Unsubscribed => "unsubscribed",
+        }
+    }
+
+    /// The event recorded when an admin sets the status.
+    fn event_kind(self) -> Option<EventKind> {
+        match self {
+            Self::PendingConfirmation => None,
+            Self::Confirmed => Some(EventKind::Confirmed),
+            Self::Unsubscribed => Some(EventKind::Unsubscribed),
+        }
+    }
+}
+
+/// The query of the list, as sent by the filter form. The empty fields aren't used.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SubscribersQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+    /// The first day of the subscriptions, inclusive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from: Option<String>,
+    /// The last day of the subscriptions, inclusive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+    /// The cursor of the page, the subscribers after it are shown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    after: Option<String>,
+}
+
+impl SubscribersQuery {
+    /// Drops the empty fields, so they aren't carried over to the links of the other pages.
+    fn normalized(self) -> Self {
+        let non_empty = |field: Option<String>| {
+            field
+                .map(|val| val.trim().to_string())
+                .filter(|val| !val.is_empty())
+        };
+        SubscribersQuery {
+            q: non_empty(self.q),
+            status: non_empty(self.status),
+            from: non_empty(self.from),
+            to: non_empty(self.to),
+            after: non_empty(self.after),
+        }
+    }
+}
+
+/// The parsed filters of the list.
+#[derive(Debug, Default, PartialEq)]
+struct Filters {
+    search: Option<String>,
+    status: Option<SubscriberStatus>,
+    from: Option<DateTime<Utc>>,
+    /// Exclusive, the start of the day after the last day.
+    until: Option<DateTime<Utc>>,
+    after: Option<Cursor>,
+}
+
+impl TryFrom<&SubscribersQuery> for Filters {
+    type Error = AdminError;
+
+    fn try_from(query: &SubscribersQuery) -> Result<Self, Self::Error> {
+        Ok(Filters {
+            search: query.q.as_deref().map(like_pattern),
+            status: query
+                .status
+                .as_deref()
+                .map(SubscriberStatus::parse)
+                .transpose()?,
+            from: query.from.as_deref().map(day_start).transpose()?,
+            until: query
+                .to
+                .as_deref()
+                .map(|to| day_start(to).map(|start| start + Duration::days(1)))
+                .transpose()?,
+            after: query.after.as_deref().map(Cursor::parse).transpose()?,
+        })
+    }
+}
+
+/// The position in the list, the subscription time and the ID of the last subscriber on a page.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cursor {
+    subscribed_at: DateTime<Utc>,
+    id: Uuid,
+}
+
+impl Cursor {
+    fn parse(cursor: &str) -> Result<Self, AdminError> {
+        let invalid = || AdminError::InvalidInput("invalid page cursor".into());
+        let (micros, id) = cursor.split_once('_').ok_or_else(invalid)?;
+        let subscribed_at = micros
+            .parse()
+            .ok()
+            .and_then(DateTime::from_timestamp_micros)
+            .ok_or_else(invalid)?;
+        let id = Uuid::parse_str(id).map_err(|_| invalid())?;
+        Ok(Cursor { subscribed_at, id })
+    }
+
+    /// Postgres keeps the timestamps in microseconds, so the cursor is exact.
+    fn encode(&self) -> String {
+        format!("{}_{}", self.subscribed_at.timestamp_micros(), self.id)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct SubscriberRecord {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: SubscriberStatus,
+    subscribed_at: DateTime<Utc>,
+}
+
+/// A subscriber as it's rendered in the template.
+#[derive(Serialize)]
+struct SubscriberView {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: SubscriberStatus,
+    subscribed_at: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct SubscriberDetailsRecord {
+    email: String,
+    name: String,
+    status: SubscriberStatus,
+    subscribed_at: DateTime<Utc>,
+    consent_source: Option<String>,
+}
+
+/// The subscriber on the history page.
+#[derive(Serialize)]
+struct SubscriberDetailsView {
+    email: String,
+    name: String,
+    status: SubscriberStatus,
+    subscribed_at: String,
+    consent_source: Option<String>,
+}
+
+/// An event of the history as it's rendered in the template.
+#[derive(Serialize)]
+struct EventView {
+    kind: String,
+    source: String,
+    request_id: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    occurred_at: String,
+}
+
+// ###################################
+// ->   HANDLERS
+// ###################################
+#[tracing::instrument(name = "admin_subscribers_list", skip_all)]
+pub async fn subscribers_list(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    csrf_token: CsrfToken,
+    Query(query): Query<SubscribersQuery>,
+) -> WebResult<Html<String>> {
+    let query = query.normalized();
+    let filters = Filters::try_from(&query)?;
+
+    let mut records: Vec<SubscriberRecord> = sqlx::query_as(
+        r#"
+        SELECT id, email, name, status, subscribed_at FROM subscriptions
+        WHERE ($1::TEXT IS NULL OR email ILIKE $1 OR name ILIKE $1)
+            AND ($2::TEXT IS NULL OR status = $2)
+            AND ($3::TIMESTAMPTZ IS NULL OR subscribed_at >= $3)
+            AND ($4::TIMESTAMPTZ IS NULL OR subscribed_at < $4)
+            AND ($5::TIMESTAMPTZ IS NULL OR (subscribed_at, id) < ($5, $6))
+        ORDER BY subscribed_at DESC, id DESC
+        LIMIT $7
+        "#,
+    )
+    .bind(&filters.search)
+    .bind(filters.status.map(SubscriberStatus::as_str))
+    .bind(filters.from)
+    .bind(filters.until)
+    .bind(filters.after.map(|cursor| cursor.subscribed_at))
+    .bind(filters.after.map(|cursor| cursor.id))
+    // One more than a page, to know if there is a next page.
+    .bind(PAGE_SIZE + 1)
+    .fetch_all(app_state.database_mgr.db())
+    .await?;
+
+    let next_page = if records.len() as i64 > PAGE_SIZE {
+        records.truncate(PAGE_SIZE as usize);
+        records.last().map(|last| {
+            let cursor = Cursor {
+                subscribed_at: last.subscribed_at,
+                id: last.id,
+            };
+            page_link(&query, Some(cursor))
+        })
+    } else {
+        None
+    };
+    let subscribers = records
+        .into_iter()
+        .map(|record| SubscriberView {
+            id: record.id,
+            email: record.email,
+            name: record.name,
+            status: record.status,
+            subscribed_at: format_utc(record.subscribed_at),
+        })
+        .collect::<Vec<_>>();
+
+    let mut ctx = Context::new();
+    csrf_token.insert_into(&mut ctx);
+    ctx.insert("subscribers", &subscribers);
+    ctx.insert("query", &query);
+    ctx.insert("next_page", &next_page);
+    if query.after.is_some() {
+        ctx.insert("first_page", &page_link(&query, None));
+    }
+    ctx.insert(
+        "can_edit",
+        &admin_session.require_role(Role::Editor).is_ok(),
+    );
+    ctx.insert(
+        "can_delete",
+        &admin_session.require_role(Role::Owner).is_ok(),
+    );
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    flash::insert_messages(&cookies, &secret_key, &mut ctx)
+        .map_err(|e| AdminError::Unexpected(e.into()))?;
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "admin_subscribers.html")
+        .map_err(AdminError::Tera)?;
+
+    Ok(Html(body))
+}
+
+/// The history of the subscriber, it's still shown after the subscriber is deleted.
+#[tracing::instrument(name = "admin_subscriber_events_list", skip_all)]
+pub async fn subscriber_events_list(
+    State(app_state): State<AppState>,
+    // Every admin can see the history.
+    _admin_session: AdminSession,
+    Path(subscriber_id): Path<Uuid>,
+) -> WebResult<Html<String>> {
+    let db = app_state.database_mgr.db();
+    let subscriber: Option<SubscriberDetailsRecord> = sqlx::query_as(
+        r#"
+        SELECT email, name, status, subscribed_at, consent_source FROM subscriptions
+        WHERE id = $1
+        "#,
+    )
+    .bind(subscriber_id)
+    .fetch_optional(db)
+    .await?;
+    let events = subscription_events::history(db, subscriber_id).await?;
+    if subscriber.is_none() && events.is_empty() {
+        return Err(AdminError::SubscriberNotFound(subscriber_id).into());
+    }
+
+    let subscriber = subscriber.map(|record| SubscriberDetailsView {
+        email: record.email,
+        name: record.name,
+        status: record.status,
+        subscribed_at: format_utc(record.subscribed_at),
+        consent_source: record.consent_source,
+    });
+    let events = events
+        .into_iter()
+        .map(|event| EventView {
+            kind: event.kind.replace('_', " "),
+            source: event.source.replace('_', " "),
+            request_id: event.request_id,
+            ip: event.ip,
+            user_agent: event.user_agent,
+            occurred_at: format_utc(event.occurred_at),
+        })
+        .collect::<Vec<_>>();
+
+    let mut ctx = Context::new();
+    ctx.insert("subscriber_id", &subscriber_id);
+    ctx.insert("subscriber", &subscriber);
+    ctx.insert("events", &events);
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "admin_subscriber_events.html")
+        .map_err(AdminError::Tera)?;
+
+    Ok(Html(body))
+}
+
+/// Confirms the subscriber without the confirmation link, e.g. when the email got lost.
+#[tracing::instrument(
+    name = "admin_subscriber_confirm",
+    skip(app_state, cookies, admin_session, meta)
+)]
+pub async fn subscriber_confirm(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    meta: RequestMeta,
+    Path(subscriber_id): Path<Uuid>,
+) -> WebResult<Redirect> {
+    admin_session.require_role(Role::Editor)?;
+    let email = status_set(
+        &app_state,
+        &meta,
+        subscriber_id,
+        SubscriberStatus::Confirmed,
+    )
+    .await?;
+    tracing::info!(user_id = %admin_session.user_id(), "Subscriber confirmed by an admin!");
+
+    flash::add_for(
+        &app_state,
+        &cookies,
+        FLASH_INFO_MSG,
+        format!("{email} was confirmed."),
+    );
+    Ok(Redirect::to("/admin/subscribers"))
+}
+
+#[tracing::instrument(
+    name = "admin_subscriber_unsubscribe",
+    skip(app_state, cookies, admin_session, meta)
+)]
+pub async fn subscriber_unsubscribe(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    meta: RequestMeta,
+    Path(subscriber_id): Path<Uuid>,
+) -> WebResult<Redirect> {
+    admin_session.require_role(Role::Editor)?;
+    let email = status_set(
+        &app_state,
+        &meta,
+        subscriber_id,
+        SubscriberStatus::Unsubscribed,
+    )
+    .await?;
+    tracing::info!(user_id = %admin_session.user_id(), "Subscriber unsubscribed by an admin!");
+
+    flash::add_for(
+        &app_state,
+        &cookies,
+        FLASH_INFO_MSG,
+        format!("{email} was unsubscribed."),
+    );
+    Ok(Redirect::to("/admin/subscribers"))
+}
+
+/// Deletes the subscriber with its tokens, the issues that are being delivered skip the deleted subscribers.
+/// The history of the subscriber is kept without the personal data.
+#[tracing::instrument(
+    name = "admin_subscriber_delete",
+    skip(app_state, cookies, admin_session, meta)
+)]
+pub async fn subscriber_delete(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    meta: RequestMeta,
+    Path(subscriber_id): Path<Uuid>,
+) -> WebResult<Redirect> {
+    admin_session.require_role(Role::Owner)?;
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let email: String = sqlx::query_scalar(
+        r#"
+        DELETE FROM subscriptions
+        WHERE id = $1
+        RETURNING email
+        "#,
+    )
+    .bind(subscriber_id)
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(AdminError::SubscriberNotFound(subscriber_id))?;
+    subscription_events::erase(&mut transaction, subscriber_id, EventSource::Admin, &meta).await?;
+    transaction.commit().await?;
+    tracing::info!(user_id = %admin_session.user_id(), "Subscriber deleted by an admin!");
+
+    flash::add_for(
+        &app_state,
+        &cookies,
+        FLASH_INFO_MSG,
+        format!("{email} was deleted."),
+    );
+    Ok(Redirect::to("/admin/subscribers"))
+}
+
+#[tracing::instrument(name = "admin_subscribers_import_get", skip_all)]
+pub async fn subscribers_import_get(
+    State(app_state): State<AppState>,
+    admin_session: AdminSession,
+    csrf_token: CsrfToken,
+) -> WebResult<Html<String>> {
+    admin_session.require_role(Role::Editor)?;
+
+    let mut ctx = Context::new();
+    csrf_token.insert_into(&mut ctx);
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "admin_subscribers_import.html")
+        .map_err(AdminError::Tera)?;
+
+    Ok(Html(body))
+}
+
+/// Imports the subscribers from the uploaded CSV file, responds with the report as a CSV file.
+/// The options of the import have to come before the file in the form, the file is imported while it's uploaded.
+#[tracing::instrument(name = "admin_subscribers_import_post", skip_all)]
+pub async fn subscribers_import_post(
+    State(app_state): State<AppState>,
+    admin_session: AdminSession,
+    meta: RequestMeta,
+    mut multipart: Multipart,
+) -> WebResult<ImportReport> {
+    admin_session.require_role(Role::Editor)?;
+
+    let (mut status, mut consent_source) = (None, None);
+    let mut report = None;
+    while let Some(mut field) = multipart.next_field().await.map_err(multipart_err)? {
+        match field.name() {
+            Some("status") => status = Some(field.text().await.map_err(multipart_err)?),
+            Some("consent_source") => {
+                consent_source = Some(field.text().await.map_err(multipart_err)?)
+            }
+            Some("file") => {
+                let status = status.as_deref().ok_or_else(|| {
+                    ImportError::InvalidInput(
+                        "the import status has to come before the file".into(),
+                    )
+                })?;
+                let status = ImportStatus::parse(status, consent_source.as_deref())?;
+                let mut import = SubscriberImport::new(app_state.clone(), status, meta.clone());
+                while let Some(chunk) = field.chunk().await.map_err(multipart_err)? {
+                    import.feed(&chunk).await?;
+                    if import.is_stopped() {
+                        break;
+                    }
+                }
+                report = Some(import.finish().await?);
+                break;
+            }
+            _ => {}
+        }
+    }
+    let report = report.ok_or_else(|| ImportError::InvalidInput("no file was uploaded".into()))?;
+    tracing::info!(user_id = %admin_session.user_id(), "Subscribers imported by an admin!");
+
+    Ok(report)
+}
+
+// ###################################
+// ->   HELPERS
+// ###################################
+/// Sets the status of the subscriber, returns the email address of the subscriber.
+/// The change is recorded in the history, setting the same status again isn't.
+async fn status_set(
+    app_state: &AppState,
+    meta: &RequestMeta,
+    subscriber_id: Uuid,
+    status: SubscriberStatus,
+) -> Result<String, AdminError> {
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let (email, previous): (String, SubscriberStatus) = sqlx::query_as(
+        r#"
+        UPDATE subscriptions s SET status = $2
+        FROM (SELECT id, status FROM subscriptions WHERE id = $1 FOR UPDATE) previous
+        WHERE s.id = previous.id
+        RETURNING s.email, previous.status
+        "#,
+    )
+    .bind(subscriber_id)
+    .bind(status.as_str())
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(AdminError::SubscriberNotFound(subscriber_id))?;
+    if let Some(kind) = status.event_kind().filter(|_| previous != status) {
+        subscription_events::record(
+            &mut *transaction,
+            subscriber_id,
+            kind,
+            EventSource::Admin,
+            meta,
+        )
+        .await?;
+    }
+    // The old confirmation links must not subscribe them again, a new subscription sends a new link.
+    if status == SubscriberStatus::Unsubscribed {
+        sqlx::query("DELETE FROM subscription_tokens WHERE subscriber_id = $1")
+            .bind(subscriber_id)
+            .execute(&mut *transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+    Ok(email)
+}
+
+/// The `ILIKE` pattern that matches the text anywhere, the wildcards in the text are matched literally.
+fn like_pattern(text: &str) -> String {
+    let mut pattern = String::with_capacity(text.len() + 2);
+    pattern.push('%');
+    for ch in text.chars() {
+        if matches!(ch, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(ch);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// The start of the day (UTC) sent by a `date` input.
+fn day_start(date: &str) -> Result<DateTime<Utc>, AdminError> {
+    NaiveDate::parse_from_str(date, DATE_FORMAT)
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|start| start.and_utc())
+        .ok_or_else(|| AdminError::InvalidInput(format!("invalid date: {date}")))
+}
+
+/// The link to a page of the list with the same filters.
+fn page_link(query: &SubscribersQuery, after: Option<Cursor>) -> String {
+    let query = SubscribersQuery {
+        q: query.q.clone(),
+        status: query.status.clone(),
+        from: query.from.clone(),
+        to: query.to.clone(),
+        after: after.map(|cursor| cursor.encode()),
+    };
+    match serde_urlencoded::to_string(&query) {
+        Ok(params) if !params.is_empty() => format!("/admin/subscribers?{params}"),
+        _ => "/admin/subscribers".to_string(),
+    }
+}
+
+fn multipart_err(e: MultipartError) -> ImportError {
+    ImportError::Body(e.body_text())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use claims::assert_err;
+
+    #[test]
+    fn like_pattern_escapes_the_wildcards() {
+        assert_eq!(like_pattern("ursula"), "%ursula%");
+        assert_eq!(like_pattern("50%_off\\"), r"%50\%\_off\\%");
+    }
+
+    #[test]
+    fn cursor_roundtrips() -> anyhow::Result<()> {
+        let cursor = Cursor {
+            subscribed_at: DateTime::from_timestamp_micros(1_718_000_000_123_456).unwrap(),
+            id: Uuid::new_v4(),
+        };
+        assert_eq!(Cursor::parse(&cursor.encode())?, cursor);
+        assert_err!(Cursor::parse("1718000000123456"));
+        assert_err!(Cursor::parse("soon_not-a-uuid"));
+        Ok(())
+    }
+
+    #[test]
+    fn filters_are_parsed_from_the_query() -> anyhow::Result<()> {
+        let query = SubscribersQuery {
+            q: Some(" ".into()),
+            status: Some("confirmed".into()),
+            from: Some("2025-06-01".into()),
+            to: Some("2025-06-30".into()),
+            after: Some(String::new()),
+        }
+        .normalized();
+        let filters = Filters::try_from(&query)?;
+        assert_eq!(filters.search, None);
+        assert_eq!(filters.status, Some(SubscriberStatus::Confirmed));
+        assert_eq!(filters.from, Some(day_start("2025-06-01")?));
+        assert_eq!(filters.until, Some(day_start("2025-07-01")?));
+        assert_eq!(filters.after, None);
+
+        let query = SubscribersQuery {
+            status: Some("gone".into()),
+            ..Default::default()
+        };
+        assert_err!(Filters::try_from(&query));
+        let query = SubscribersQuery {
+            to: Some("30.6.2025".into()),
+            ..Default::default()
+        };
+        assert_err!(Filters::try_from(&query));
+        Ok(())
+    }
+}
@@ -0,0 +1,302 @@
+//! Two-factor authentication of the logged in admin with an authenticator app, see `auth::totp`.
+//!
+//! Enrolling stores a new encrypted secret that isn't used for the login yet. The page shows it as a QR code,
+//! the admin confirms it with a code from the app and gets the recovery codes, which are only shown once.
+//! Regenerating the recovery codes and turning two-factor authentication off need a valid code as well.
+
+use axum::{
+    extract::State,
+    response::{Html, IntoResponse, Redirect, Response},
+    Form,
+};
+use chrono::Utc;
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use sqlx::Executor;
+use tera::Context;
+use tower_cookies::{Cookies, Key};
+
+use crate::{
+    config::get_or_init_config,
+    web::{
+        auth::totp::{self, TotpSecret},
+        flash, CsrfToken, WebResult, FLASH_ERROR_MSG, FLASH_INFO_MSG,
+    },
+    AppState,
+};
+
+use super::{dashboard::get_username, AdminError, AdminSession};
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+#[derive(Deserialize)]
+pub struct TotpCodeForm {
+    code: SecretString,
+}
+
+#[derive(sqlx::FromRow)]
+struct TotpRecord {
+    totp_secret_encrypted: Option<String>,
+    enabled: bool,
+}
+
+// ###################################
+// ->   HANDLERS
+// ###################################
+#[tracing::instrument(name = "admin_totp_get", skip_all)]
+pub async fn totp_get(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    csrf_token: CsrfToken,
+) -> WebResult<Html<String>> {
+    let user_id = admin_session.user_id();
+    let db_pool = app_state.database_mgr.db();
+    let record: TotpRecord = sqlx::query_as(
+        r#"
+        SELECT totp_secret_encrypted, totp_confirmed_at IS NOT NULL AS enabled FROM users
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(db_pool)
+    .await?;
+
+    let mut ctx = Context::new();
+    csrf_token.insert_into(&mut ctx);
+    ctx.insert("enabled", &record.enabled);
+    if record.enabled {
+        let recovery_codes_left: i64 = sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*) FROM totp_recovery_codes
+            WHERE user_id = $1 AND used_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(db_pool)
+        .await?;
+        ctx.insert("recovery_codes_left", &recovery_codes_left);
+    } else if let Some(encrypted) = record.totp_secret_encrypted {
+        // The enrolment isn't confirmed yet, show the secret so it can be added to the app.
+        let secret = TotpSecret::decrypt(&encrypted, &app_state.totp_key, user_id)
+            .map_err(AdminError::Auth)?;
+        let username = get_username(db_pool, user_id).await?;
+        let uri = secret
+            .otpauth_uri(&get_or_init_config().totp_config.issuer, &username)
+            .map_err(AdminError::Auth)?;
+        ctx.insert(
+            "qr_code_svg",
+            &totp::qr_code_svg(&uri).map_err(AdminError::Auth)?,
+        );
+        ctx.insert("otpauth_uri", &uri);
+        ctx.insert("secret", &secret.base32());
+    }
+
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    flash::insert_messages(&cookies, &secret_key, &mut ctx)
+        .map_err(|e| AdminError::Unexpected(e.into()))?;
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "admin_totp.html")
+        .map_err(AdminError::Tera)?;
+
+    Ok(Html(body))
+}
+
+/// Stores a new secret for the admin, it replaces an unconfirmed one.
+#[tracing::instrument(name = "admin_totp_enroll", skip_all, fields(user_id = %admin_session.user_id()))]
+pub async fn totp_enroll(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+) -> WebResult<Redirect> {
+    let user_id = admin_session.user_id();
+    let encrypted = TotpSecret::generate()
+        .encrypt(&app_state.totp_key, user_id)
+        .map_err(AdminError::Auth)?;
+
+    let updated = sqlx::query(
+        r#"
+        UPDATE users SET totp_secret_encrypted = $2, totp_last_step = NULL
+        WHERE user_id = $1 AND totp_confirmed_at IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .bind(encrypted)
+    .execute(app_state.database_mgr.db())
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        flash::add_for(
+            &app_state,
+            &cookies,
+            FLASH_ERROR_MSG,
+            "Two-factor authentication is already enabled!",
+        );
+    }
+
+    Ok(Redirect::to("/admin/totp"))
+}
+
+/// Enables the pending secret once the admin proves the app has it, and shows the recovery codes.
+#[tracing::instrument(name = "admin_totp_confirm", skip_all, fields(user_id = %admin_session.user_id()))]
+pub async fn totp_confirm(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    Form(form): Form<TotpCodeForm>,
+) -> WebResult<Response> {
+    let user_id = admin_session.user_id();
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let encrypted: Option<String> = sqlx::query_scalar(
+        r#"
+        SELECT totp_secret_encrypted FROM users
+        WHERE user_id = $1 AND totp_confirmed_at IS NULL
+        FOR UPDATE
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *transaction)
+    .await?
+    .flatten();
+    let Some(encrypted) = encrypted else {
+        flash::add_for(
+            &app_state,
+            &cookies,
+            FLASH_ERROR_MSG,
+            "There is no pending enrolment to confirm!",
+        );
+        return Ok(Redirect::to("/admin/totp").into_response());
+    };
+
+    let secret =
+        TotpSecret::decrypt(&encrypted, &app_state.totp_key, user_id).map_err(AdminError::Auth)?;
+    let Some(step) = secret.verify(form.code.expose_secret(), Utc::now().timestamp() as u64) else {
+        flash::add_for(
+            &app_state,
+            &cookies,
+            FLASH_ERROR_MSG,
+            "The code is invalid!",
+        );
+        return Ok(Redirect::to("/admin/totp").into_response());
+    };
+
+    let query = sqlx::query(
+        r#"
+        UPDATE users SET totp_confirmed_at = now(), totp_last_step = $2
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .bind(step);
+    transaction.execute(query).await?;
+    let recovery_codes = totp::recovery_codes_replace(&mut transaction, user_id)
+        .await
+        .map_err(AdminError::Auth)?;
+    transaction.commit().await?;
+    tracing::info!("Two-factor authentication enabled!");
+
+    Ok(recovery_codes_page(&app_state, &recovery_codes)?.into_response())
+}
+
+/// Replaces all the recovery codes with new ones, the used up ones as well.
+#[tracing::instrument(name = "admin_totp_recovery_codes_regenerate", skip_all, fields(user_id = %admin_session.user_id()))]
+pub async fn totp_recovery_codes_regenerate(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    Form(form): Form<TotpCodeForm>,
+) -> WebResult<Response> {
+    let user_id = admin_session.user_id();
+    if !code_check(&app_state, &cookies, &admin_session, form).await? {
+        return Ok(Redirect::to("/admin/totp").into_response());
+    }
+
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let recovery_codes = totp::recovery_codes_replace(&mut transaction, user_id)
+        .await
+        .map_err(AdminError::Auth)?;
+    transaction.commit().await?;
+
+    Ok(recovery_codes_page(&app_state, &recovery_codes)?.into_response())
+}
+
+/// Turns off two-factor authentication, the secret and the recovery codes are deleted.
+#[tracing::instrument(name = "admin_totp_disable", skip_all, fields(user_id = %admin_session.user_id()))]
+pub async fn totp_disable(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    Form(form): Form<TotpCodeForm>,
+) -> WebResult<Redirect> {
+    let user_id = admin_session.user_id();
+    let redirect = Redirect::to("/admin/totp");
+    if !code_check(&app_state, &cookies, &admin_session, form).await? {
+        return Ok(redirect);
+    }
+
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let query = sqlx::query(
+        r#"
+        UPDATE users
+        SET totp_secret_encrypted = NULL, totp_confirmed_at = NULL, totp_last_step = NULL
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id);
+    transaction.execute(query).await?;
+    let query = sqlx::query(
+        r#"
+        DELETE FROM totp_recovery_codes
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id);
+    transaction.execute(query).await?;
+    transaction.commit().await?;
+    tracing::info!("Two-factor authentication disabled!");
+
+    flash::add_for(
+        &app_state,
+        &cookies,
+        FLASH_INFO_MSG,
+        "Two-factor authentication was turned off.",
+    );
+    Ok(redirect)
+}
+
+// ###################################
+// ->   HELPERS
+// ###################################
+/// Checks the code of an admin with two-factor authentication enabled, an invalid code is flashed.
+async fn code_check(
+    app_state: &AppState,
+    cookies: &Cookies,
+    admin_session: &AdminSession,
+    form: TotpCodeForm,
+) -> Result<bool, AdminError> {
+    let valid = totp::check_code(
+        &app_state.database_mgr,
+        &app_state.totp_key,
+        admin_session.user_id(),
+        form.code.expose_secret(),
+    )
+    .await?;
+    if !valid {
+        flash::add_for(app_state, cookies, FLASH_ERROR_MSG, "The code is invalid!");
+    }
+    Ok(valid)
+}
+
+fn recovery_codes_page(
+    app_state: &AppState,
+    recovery_codes: &[String],
+) -> Result<Html<String>, AdminError> {
+    let mut ctx = Context::new();
+    ctx.insert("recovery_codes", recovery_codes);
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "admin_totp_recovery_codes.html")?;
+    Ok(Html(body))
+}
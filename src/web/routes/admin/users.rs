@@ -0,0 +1,311 @@
+//! Managing the admin users, only owners can get here.
+//!
+//! New users are invited by email, the invitation link leads to the signup page where the user
+//! picks a username and a password, see `routes::signup`. The invitation decides the role of the user.
+//! Deactivated users can't log in anymore and all their sessions are logged out.
+
+use axum::{
+    extract::{Path, State},
+    response::{Html, Redirect},
+    Form,
+};
+use chrono::{DateTime, Utc};
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use sqlx::Executor;
+use tower_cookies::{Cookies, Key};
+use uuid::Uuid;
+
+use crate::{
+    config::get_or_init_config,
+    utils::format_utc,
+    web::{
+        auth::Role,
+        flash,
+        types::{EmailLinkToken, ValidEmail},
+        CsrfToken, WebResult, FLASH_ERROR_MSG, FLASH_INFO_MSG,
+    },
+    AppState,
+};
+
+use super::{dashboard::get_username, AdminError, AdminSession};
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+#[derive(Deserialize)]
+pub struct InviteForm {
+    email: String,
+    role: Role,
+}
+
+#[derive(sqlx::FromRow)]
+struct UserRecord {
+    user_id: Uuid,
+    username: String,
+    email: Option<String>,
+    role: Role,
+    deactivated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(sqlx::FromRow)]
+struct InvitationRecord {
+    email: String,
+    role: Role,
+    expires_at: DateTime<Utc>,
+}
+
+/// A user as it's rendered in the template.
+#[derive(Serialize)]
+struct UserView {
+    id: Uuid,
+    username: String,
+    email: Option<String>,
+    role: Role,
+    current: bool,
+    deactivated_at: Option<String>,
+}
+
+/// A pending invitation as it's rendered in the template.
+#[derive(Serialize)]
+struct InvitationView {
+    email: String,
+    role: Role,
+    expires_at: String,
+}
+
+// ###################################
+// ->   HANDLERS
+// ###################################
+#[tracing::instrument(name = "admin_users_list", skip_all)]
+pub async fn users_list(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    csrf_token: CsrfToken,
+) -> WebResult<Html<String>> {
+    admin_session.require_role(Role::Owner)?;
+    let db_pool = app_state.database_mgr.db();
+
+    let users: Vec<UserRecord> = sqlx::query_as(
+        r#"
+        SELECT user_id, username, email, role, deactivated_at FROM users
+        ORDER BY deactivated_at NULLS FIRST, username
+        "#,
+    )
+    .fetch_all(db_pool)
+    .await?;
+    let users = users
+        .into_iter()
+        .map(|user| UserView {
+            id: user.user_id,
+            current: user.user_id == admin_session.user_id(),
+            username: user.username,
+            email: user.email,
+            role: user.role,
+            deactivated_at: user.deactivated_at.map(format_utc),
+        })
+        .collect::<Vec<_>>();
+
+    let invitations: Vec<InvitationRecord> = sqlx::query_as(
+        r#"
+        SELECT email, role, expires_at FROM user_invitations
+        WHERE expires_at > now()
+        ORDER BY created_at DESC
+        "#,
+    )
+    .fetch_all(db_pool)
+    .await?;
+    let invitations = invitations
+        .into_iter()
+        .map(|invitation| InvitationView {
+            email: invitation.email,
+            role: invitation.role,
+            expires_at: format_utc(invitation.expires_at),
+        })
+        .collect::<Vec<_>>();
+
+    let mut ctx = tera::Context::new();
+    csrf_token.insert_into(&mut ctx);
+    ctx.insert("users", &users);
+    ctx.insert("invitations", &invitations);
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    flash::insert_messages(&cookies, &secret_key, &mut ctx)
+        .map_err(|e| AdminError::Unexpected(e.into()))?;
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "admin_users.html")
+        .map_err(AdminError::Tera)?;
+
+    Ok(Html(body))
+}
+
+/// Emails an invitation link to the address, a new invitation replaces the pending ones for the same address.
+#[tracing::instrument(name = "admin_user_invite", skip_all, fields(role = form.role.as_ref()))]
+pub async fn user_invite(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    Form(form): Form<InviteForm>,
+) -> WebResult<Redirect> {
+    admin_session.require_role(Role::Owner)?;
+    let redirect = Redirect::to("/admin/users");
+
+    let Ok(email) = ValidEmail::parse(form.email.trim()) else {
+        flash::add_for(
+            &app_state,
+            &cookies,
+            FLASH_ERROR_MSG,
+            "The email address is invalid!",
+        );
+        return Ok(redirect);
+    };
+
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let user_exists: bool = sqlx::query_scalar(
+        r#"
+        SELECT EXISTS (SELECT 1 FROM users WHERE email = $1)
+        "#,
+    )
+    .bind(email.as_ref())
+    .fetch_one(&mut *transaction)
+    .await?;
+    if user_exists {
+        flash::add_for(
+            &app_state,
+            &cookies,
+            FLASH_ERROR_MSG,
+            "A user with this email address already exists!",
+        );
+        return Ok(redirect);
+    }
+
+    let query = sqlx::query(
+        r#"
+        DELETE FROM user_invitations
+        WHERE email = $1
+        "#,
+    )
+    .bind(email.as_ref());
+    transaction.execute(query).await?;
+
+    let config = &get_or_init_config().invitation_config;
+    let token = EmailLinkToken::generate();
+    let now = Utc::now();
+    let query = sqlx::query(
+        r#"
+        INSERT INTO user_invitations (token_hash, email, role, invited_by, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+    )
+    .bind(token.hash())
+    .bind(email.as_ref())
+    .bind(form.role)
+    .bind(admin_session.user_id())
+    .bind(now)
+    .bind(now + config.token_expiry());
+    transaction.execute(query).await?;
+
+    let inviter = get_username(app_state.database_mgr.db(), admin_session.user_id()).await?;
+    let signup_link = format!("{}/signup?token={}", app_state.base_url, token.as_str());
+    let mut ctx = tera::Context::new();
+    ctx.insert("inviter", &inviter);
+    ctx.insert("role", form.role.as_ref());
+    ctx.insert("signup_link", &signup_link);
+    ctx.insert("expiry_hours", &config.token_expiry().num_hours());
+    let tera = app_state.templ_mgr.tera();
+    let html_email = tera
+        .render("emails/invitation.html", &ctx)
+        .map_err(AdminError::Tera)?;
+    let plain_email = tera
+        .render("emails/invitation.txt", &ctx)
+        .map_err(AdminError::Tera)?;
+
+    // The invitation is only stored once the email is on its way.
+    app_state
+        .email_client
+        .send_single_email(
+            &email,
+            "You are invited to the newsletter admin",
+            &html_email,
+            &plain_email,
+        )
+        .await
+        .map_err(AdminError::Email)?;
+    transaction.commit().await?;
+
+    flash::add_for(
+        &app_state,
+        &cookies,
+        FLASH_INFO_MSG,
+        format!("An invitation was sent to {}.", email.as_ref()),
+    );
+    Ok(redirect)
+}
+
+/// Deactivates the user and logs out all of its sessions, the owners can't deactivate themselves.
+#[tracing::instrument(
+    name = "admin_user_deactivate",
+    skip(app_state, cookies, admin_session)
+)]
+pub async fn user_deactivate(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    admin_session: AdminSession,
+    Path(user_id): Path<Uuid>,
+) -> WebResult<Redirect> {
+    admin_session.require_role(Role::Owner)?;
+    let redirect = Redirect::to("/admin/users");
+
+    if user_id == admin_session.user_id() {
+        flash::add_for(
+            &app_state,
+            &cookies,
+            FLASH_ERROR_MSG,
+            "You can't deactivate yourself!",
+        );
+        return Ok(redirect);
+    }
+
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let username: String = sqlx::query_scalar(
+        r#"
+        UPDATE users SET deactivated_at = COALESCE(deactivated_at, now())
+        WHERE user_id = $1
+        RETURNING username
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(AdminError::UserNotFound(user_id))?;
+
+    // A pending reset link would otherwise still work.
+    let query = sqlx::query(
+        r#"
+        DELETE FROM password_reset_tokens
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id);
+    transaction.execute(query).await?;
+    transaction.commit().await?;
+
+    let revoked = app_state
+        .session_index
+        .revoke_all_except(user_id, None)
+        .await?;
+    tracing::info!(revoked_sessions = revoked, "User deactivated!");
+
+    flash::add_for(
+        &app_state,
+        &cookies,
+        FLASH_INFO_MSG,
+        format!("{username} was deactivated and logged out."),
+    );
+    Ok(redirect)
+}
+
+// ###################################
+// ->   HELPERS
+// ###################################
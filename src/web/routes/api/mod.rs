@@ -1,7 +1,11 @@
 pub mod news;
 pub mod subscribe;
 pub mod subscribe_confirm;
+pub mod subscribers;
+pub mod unsubscribe;
 
 pub use news::news_publish;
 pub use subscribe::subscribe;
 pub use subscribe_confirm::subscribe_confirm;
+pub use subscribers::{subscribers_export, subscribers_import};
+pub use unsubscribe::{unsubscribe_get, unsubscribe_post};
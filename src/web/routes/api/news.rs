@@ -1,11 +1,24 @@
-use axum::{extract::State, http::HeaderMap, Json};
-use tracing::info;
+use std::net::IpAddr;
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use sqlx::{Executor, Postgres, Transaction};
+use tracing::{error, info};
+use uuid::Uuid;
 
 use crate::{
+    issue_delivery_worker,
     web::{
-        self, auth,
-        types::{News, ValidEmail},
-        WebResult,
+        self,
+        auth::{self, ApiKey, AuthenticatedUser, Role, Scope},
+        idempotency::{self, IdempotencyKey, NextAction},
+        types::News,
+        ClientIp, WebResult,
     },
     AppState,
 };
@@ -14,64 +27,120 @@ use crate::{
 pub enum NewsError {
     #[error("auth error: {0}")]
     Auth(#[from] web::auth::AuthError),
-    #[error("email client error: {0}")]
-    EmailClient(#[from] crate::email_client::Error),
+    #[error("idempotency error: {0}")]
+    Idempotency(#[from] web::idempotency::IdempotencyError),
 }
 
+/// Persists the newsletter issue and enqueues a delivery task for every confirmed subscriber.
+/// The emails are sent by the `issue_delivery_worker` so we can return immediately.
+///
+/// If the request contains an `Idempotency-Key` header the response is saved, and replayed
+/// when the same user retries the request with the same key.
 #[tracing::instrument(name = "Publishing newsletter issue", skip(headers, app_state, news))]
 pub async fn news_publish(
     headers: HeaderMap,
     State(app_state): State<AppState>,
+    ClientIp(ip): ClientIp,
     Json(news): Json<News>,
-) -> WebResult<()> {
-    let creds = auth::Credentials::parse_headers_basic_schema(headers)
-        .await
-        .map_err(NewsError::Auth)?;
-    creds
-        .authenticate(&app_state.database_mgr)
+) -> WebResult<Response> {
+    let idempotency_key = IdempotencyKey::from_headers(&headers);
+    let user = authenticate(&app_state, headers, ip)
         .await
         .map_err(NewsError::Auth)?;
+    // Viewers can't publish.
+    user.role.require(Role::Editor).map_err(NewsError::Auth)?;
+    let user_id = user.user_id;
+    let idempotency_key = idempotency_key.map_err(NewsError::Idempotency)?;
 
-    // Get all subscribers that are eligible to receive the newsletter
-    let emails: Vec<String> = sqlx::query_scalar(
-        r#"SELECT email FROM subscriptions
-    WHERE status = 'confirmed' "#,
-    )
-    .fetch_all(app_state.database_mgr.db())
-    .await?;
+    let db_pool = app_state.database_mgr.db();
+    if let Some(key) = &idempotency_key {
+        let next_action = idempotency::try_processing(db_pool, key, user_id)
+            .await
+            .map_err(NewsError::Idempotency)?;
+        if let NextAction::ReturnSavedResponse(saved_response) = next_action {
+            info!("Returning the saved response");
+            return Ok(saved_response);
+        }
+    }
+
+    let result = publish_issue(&app_state, &news, idempotency_key.as_ref(), user_id).await;
+    // Release the key so the client can retry the failed request
+    if let (Err(_), Some(key)) = (&result, &idempotency_key) {
+        if let Err(e) = idempotency::delete_key(db_pool, key, user_id).await {
+            error!(error = %e, "failed to release the idempotency key");
+        }
+    }
 
-    let subscribers = emails
-        .into_iter()
-        .filter_map(|email| {
-            let res = ValidEmail::parse(&email);
-            // NOTE: this should never happen since we validate before we store to DB. 
-            // But we still check it if implementation changes.
-            if let Err(e) = &res {
-                tracing::error!(
-                    error = ?e,
-                    "THIS IS A BUG: a confirmed subscriber is using an invalid email address - email: {email}"
-                );
-            }
-            res.ok()
-        })
-        .collect::<Vec<_>>();
+    result
+}
+
+/// Accepts an API key with the `news:publish` scope, or the username and password of the admin.
+/// The passwords are throttled like on the login form.
+async fn authenticate(
+    app_state: &AppState,
+    headers: HeaderMap,
+    ip: IpAddr,
+) -> auth::Result<AuthenticatedUser> {
+    if let Some(api_key) = ApiKey::from_headers(&headers)? {
+        return api_key
+            .authenticate(&app_state.database_mgr, Scope::NewsPublish)
+            .await;
+    }
+    let creds = auth::Credentials::parse_headers_basic_schema(headers).await?;
+    let username = creds.username.clone();
+    let user = app_state
+        .login_throttle
+        .authenticate(&app_state.database_mgr, creds, ip)
+        .await?;
+    app_state.login_throttle.reset(&username).await?;
+    Ok(user)
+}
 
-    tracing::debug!("{subscribers:?}");
+async fn publish_issue(
+    app_state: &AppState,
+    news: &News,
+    idempotency_key: Option<&IdempotencyKey>,
+    user_id: Uuid,
+) -> WebResult<Response> {
+    // BEGIN sql transaction
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let newsletter_issue_id = insert_newsletter_issue(&mut transaction, news, user_id).await?;
+    issue_delivery_worker::enqueue_delivery_tasks(&mut transaction, newsletter_issue_id).await?;
 
-    if !subscribers.is_empty() {
-        // Send batch email newsletter to the subscribers
-        app_state
-            .email_client
-            .send_batch_emails(
-                &subscribers,
-                news.title,
-                news.content.html,
-                news.content.text,
-            )
+    let mut response = StatusCode::ACCEPTED.into_response();
+    if let Some(key) = idempotency_key {
+        response = idempotency::save_response(&mut transaction, key, user_id, response)
             .await
-            .map_err(NewsError::EmailClient)?;
+            .map_err(NewsError::Idempotency)?;
     }
+    transaction.commit().await?;
+    // END sql transaction
+
+    info!(%newsletter_issue_id, "Newsletter issue accepted for delivery!");
+    Ok(response)
+}
+
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    news: &News,
+    author_user_id: Uuid,
+) -> WebResult<Uuid> {
+    let newsletter_issue_id = Uuid::new_v4();
+    let now = Utc::now();
+    let query = sqlx::query(
+        r#"
+        INSERT INTO newsletter_issues
+            (newsletter_issue_id, title, text_content, html_content, author_user_id, status, created_at, updated_at, published_at)
+        VALUES ($1, $2, $3, $4, $5, 'published', $6, $6, $6)
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .bind(&news.title)
+    .bind(&news.content.text)
+    .bind(&news.content.html)
+    .bind(author_user_id)
+    .bind(now);
+    transaction.execute(query).await?;
 
-    info!("Batch email succesfully sent!");
-    Ok(())
+    Ok(newsletter_issue_id)
 }
@@ -10,9 +10,12 @@ use tracing::info;
 use uuid::Uuid;
 
 use crate::{
+    config::get_or_init_config,
+    email_client::{BatchReport, PersonalEmail},
     web::{
         self,
-        types::{DeserSubscriber, SubscriptionToken, ValidSubscriber},
+        subscription_events::{self, EventKind, EventSource, RequestMeta},
+        types::{DeserSubscriber, SubscriptionToken, ValidName, ValidSubscriber},
         WebResult,
     },
     AppState,
@@ -43,7 +46,7 @@ pub enum SubscribeError {
 // ###################################
 #[tracing::instrument(
     name = "Saving new subscriber to the database",
-    skip(app_state, subscriber),
+    skip(app_state, meta, subscriber),
     fields(
         subscriber_name = %subscriber.name,
         subscriber_email = %subscriber.email
@@ -51,6 +54,7 @@ pub enum SubscribeError {
 )]
 pub async fn subscribe(
     State(app_state): State<AppState>,
+    meta: RequestMeta,
     Json(subscriber): Json<DeserSubscriber>,
 ) -> WebResult<(StatusCode, &'static str)> {
     // Spawn a blocking task to validate the subscriber info and generate subscription token.
@@ -68,11 +72,29 @@ pub async fn subscribe(
     let mut transaction = app_state.database_mgr.db().begin().await?;
     let (subscriber_id, was_subscribed) = insert_subscriber(&mut transaction, &subscriber).await?;
     // If the user was already subscribed we want to rollback the changes and fail silently.
+    // Subscribers that are still pending confirmation, or that unsubscribed, get a new confirmation email.
     if was_subscribed {
         transaction.rollback().await?;
+        // A failure would tell the pending addresses apart from the others, so the response is the same.
+        if let Err(e) =
+            resend_confirmation_email(app_state, &meta, &subscriber, &subscription_token).await
+        {
+            tracing::error!(error = %e, "Unable to resend the confirmation email");
+        }
         return Ok(standard_response);
     }
     insert_subscription_token(&mut transaction, &subscription_token, subscriber_id).await?;
+    record_confirmation_email(&mut transaction, subscriber_id).await?;
+    for kind in [EventKind::Subscribed, EventKind::ConfirmationSent] {
+        subscription_events::record(
+            &mut *transaction,
+            subscriber_id,
+            kind,
+            EventSource::Api,
+            &meta,
+        )
+        .await?;
+    }
     transaction.commit().await?;
     // END sql transaction
 
@@ -110,18 +132,40 @@ async fn insert_subscriber(
     Ok((subscriber_id, was_subscribed))
 }
 
-async fn insert_subscription_token(
+pub(crate) async fn insert_subscription_token(
     transaction: &mut Transaction<'_, Postgres>,
     subscription_token: &SubscriptionToken,
     subscriber_id: Uuid,
-) -> WebResult<()> {
+) -> Result<(), SubscribeError> {
     let subscription_token = subscription_token.deref();
     let query = sqlx::query(
-        r#"INSERT INTO subscription_tokens(subscription_token, subscriber_id)
-    VALUES ($1, $2)"#,
+        r#"INSERT INTO subscription_tokens(subscription_token, subscriber_id, created_at)
+    VALUES ($1, $2, $3)"#,
     )
     .bind(subscription_token)
-    .bind(subscriber_id);
+    .bind(subscriber_id)
+    .bind(Utc::now());
+
+    transaction
+        .execute(query)
+        .await
+        .map_err(SubscribeError::Insert)?;
+
+    Ok(())
+}
+
+/// Records that a confirmation email is about to be sent to the subscriber,
+/// so we can limit the number of emails sent to a single address.
+pub(crate) async fn record_confirmation_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), SubscribeError> {
+    let query = sqlx::query(
+        r#"INSERT INTO confirmation_emails_sent (subscriber_id, sent_at)
+    VALUES ($1, $2)"#,
+    )
+    .bind(subscriber_id)
+    .bind(Utc::now());
 
     transaction
         .execute(query)
@@ -131,17 +175,142 @@ async fn insert_subscription_token(
     Ok(())
 }
 
+/// Issues a new subscription token and sends a new confirmation email to a subscriber that is still pending confirmation,
+/// or that unsubscribed. The unsubscribed ones are moved back to pending confirmation with the new name,
+/// they are only subscribed again once they confirm.
+///
+/// Fails silently if the subscriber is confirmed, or if they already received the maximum
+/// number of confirmation emails in the last 24 hours, so we don't expose whether the address exists.
+#[tracing::instrument(
+    name = "Resending confirmation email",
+    skip(app_state, meta, subscription_token, subscriber)
+)]
+async fn resend_confirmation_email(
+    app_state: AppState,
+    meta: &RequestMeta,
+    subscriber: &ValidSubscriber,
+    subscription_token: &SubscriptionToken,
+) -> WebResult<()> {
+    let max_emails = get_or_init_config()
+        .subscription_config
+        .max_confirmation_emails_per_day;
+
+    // BEGIN sql transaction
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    // Lock the subscriber row so concurrent requests can't go over the limit.
+    let pending_subscriber: Option<(Uuid, String, String)> = sqlx::query_as(
+        r#"SELECT id, name, status FROM subscriptions
+    WHERE email = $1 AND status IN ('pending_confirmation', 'unsubscribed')
+    FOR UPDATE"#,
+    )
+    .bind(subscriber.email.as_ref())
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let Some((subscriber_id, mut name, status)) = pending_subscriber else {
+        info!("subscriber is already confirmed");
+        return Ok(());
+    };
+
+    let n_sent: i64 = sqlx::query_scalar(
+        r#"SELECT count(*) FROM confirmation_emails_sent
+    WHERE subscriber_id = $1 AND sent_at > now() - interval '24 hours'"#,
+    )
+    .bind(subscriber_id)
+    .fetch_one(&mut *transaction)
+    .await?;
+    if n_sent >= max_emails {
+        info!("confirmation email limit reached");
+        return Ok(());
+    }
+
+    if status == "unsubscribed" {
+        let query = sqlx::query(
+            r#"UPDATE subscriptions
+        SET status = 'pending_confirmation', name = $2, subscribed_at = $3, consent_source = NULL
+        WHERE id = $1"#,
+        )
+        .bind(subscriber_id)
+        .bind(subscriber.name.as_ref())
+        .bind(Utc::now());
+        transaction.execute(query).await?;
+        subscription_events::record(
+            &mut *transaction,
+            subscriber_id,
+            EventKind::Subscribed,
+            EventSource::Api,
+            meta,
+        )
+        .await?;
+        info!("unsubscribed subscriber is subscribing again");
+        name = subscriber.name.as_ref().to_string();
+    }
+    insert_subscription_token(&mut transaction, subscription_token, subscriber_id).await?;
+    record_confirmation_email(&mut transaction, subscriber_id).await?;
+    subscription_events::record(
+        &mut *transaction,
+        subscriber_id,
+        EventKind::ConfirmationSent,
+        EventSource::Api,
+        meta,
+    )
+    .await?;
+    transaction.commit().await?;
+    // END sql transaction
+
+    // Address the email with the name the subscriber first subscribed with, or subscribed again with.
+    let subscriber = ValidSubscriber {
+        email: subscriber.email.clone(),
+        name: ValidName::parse(name).map_err(SubscribeError::ValidSubscriberParse)?,
+    };
+    Ok(send_confirmation_email(app_state, &subscriber, subscription_token).await?)
+}
+
 #[tracing::instrument(
     name = "Sending confirmation email",
     skip(app_state, subscription_token, subscriber)
 )]
-async fn send_confirmation_email(
+pub(crate) async fn send_confirmation_email(
     app_state: AppState,
     subscriber: &ValidSubscriber,
     subscription_token: &SubscriptionToken,
-) -> WebResult<()> {
+) -> Result<(), SubscribeError> {
+    let email = confirmation_email(&app_state, subscriber, subscription_token)?;
+    app_state
+        .email_client
+        .send_single_email(
+            &email.to,
+            &email.subject,
+            &email.html_content,
+            &email.text_content,
+        )
+        .await
+        .map_err(SubscribeError::ConfirmationEmail)?;
+
+    info!("SUCCESS");
+    Ok(())
+}
+
+/// Sends the confirmation emails of many subscribers in batches, e.g. of an import.
+/// The returned report says which of the emails couldn't be sent.
+#[tracing::instrument(name = "Sending confirmation emails", skip_all, fields(n = pending.len()))]
+pub(crate) async fn send_confirmation_emails(
+    app_state: &AppState,
+    pending: &[(ValidSubscriber, SubscriptionToken)],
+) -> Result<BatchReport, SubscribeError> {
+    let emails = pending
+        .iter()
+        .map(|(subscriber, token)| confirmation_email(app_state, subscriber, token))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(app_state.email_client.send_personal_emails(&emails).await?)
+}
+
+fn confirmation_email(
+    app_state: &AppState,
+    subscriber: &ValidSubscriber,
+    subscription_token: &SubscriptionToken,
+) -> Result<PersonalEmail, SubscribeError> {
     let subscription_token = subscription_token.deref();
-    let email_client = &app_state.email_client;
     let base_url = &app_state.base_url;
     let tera = app_state.templ_mgr.tera();
 
@@ -162,18 +331,12 @@ async fn send_confirmation_email(
         &confirmation_link,
     )?;
 
-    email_client
-        .send_single_email(
-            &subscriber.email,
-            "Welcome to our newsletter!",
-            &html_email,
-            &plain_email,
-        )
-        .await
-        .map_err(SubscribeError::ConfirmationEmail)?;
-
-    info!("SUCCESS");
-    Ok(())
+    Ok(PersonalEmail {
+        to: subscriber.email.clone(),
+        subject: "Welcome to our newsletter!".to_string(),
+        html_content: html_email,
+        text_content: plain_email,
+    })
 }
 
 // ###################################
@@ -217,7 +380,7 @@ fn render_confirmation_email_from_template(
     tera: &Tera,
     subscriber: &ValidSubscriber,
     confirmation_link: &str,
-) -> WebResult<String> {
+) -> Result<String, SubscribeError> {
     let mut ctx = Context::new();
     ctx.insert("subscriber_name", subscriber.name.as_ref());
     ctx.insert("confirmation_link", confirmation_link);
@@ -4,11 +4,14 @@ use axum::{
     extract::{Query, State},
     http::StatusCode,
 };
+use chrono::{DateTime, Utc};
 use tracing::info;
 use uuid::Uuid;
 
+use crate::config::get_or_init_config;
 use crate::web::{
     self,
+    subscription_events::{self, EventKind, EventSource, RequestMeta},
     types::{SubscribeConfirmQuery, SubscriptionToken},
     WebResult,
 };
@@ -22,6 +25,9 @@ pub enum SubscribeConfirmError {
     #[error("subscriber token was not found in the database")]
     SubTokenInDbNotFound,
 
+    #[error("subscriber token expired")]
+    SubTokenExpired,
+
     #[error("data parsing error: {0}")]
     DataParsing(#[from] web::types::DataParsingError),
 
@@ -34,11 +40,12 @@ pub enum SubscribeConfirmError {
 // ###################################
 #[tracing::instrument(
     name = "Confirming a pending subscriber",
-    skip(app_state, subscription_token),
+    skip(app_state, meta, subscription_token),
     fields(sub_token = %subscription_token.subscription_token)
 )]
 pub async fn subscribe_confirm(
     State(app_state): State<AppState>,
+    meta: RequestMeta,
     Query(subscription_token): Query<SubscribeConfirmQuery>,
 ) -> WebResult<StatusCode> {
     let db_pool = app_state.database_mgr.db();
@@ -51,8 +58,8 @@ pub async fn subscribe_confirm(
     let subscription_token = subscription_token.deref();
 
     // Get the subscriber_id record from the database.
-    let subscriber_id: Uuid = sqlx::query_scalar(
-        r#"SELECT subscriber_id FROM subscription_tokens
+    let (subscriber_id, created_at): (Uuid, DateTime<Utc>) = sqlx::query_as(
+        r#"SELECT subscriber_id, created_at FROM subscription_tokens
     WHERE subscription_token = $1"#,
     )
     .bind(subscription_token)
@@ -60,15 +67,36 @@ pub async fn subscribe_confirm(
     .await?
     .ok_or_else(|| SubscribeConfirmError::SubTokenInDbNotFound)?;
 
+    // Reject the links that are older than the configured expiry.
+    let token_expiry = get_or_init_config().subscription_config.token_expiry();
+    if created_at + token_expiry < Utc::now() {
+        return Err(SubscribeConfirmError::SubTokenExpired.into());
+    }
+
     // Update the status of the subscriber - CONFIRM SUBSCRIBER
-    sqlx::query(
+    // Only the pending subscribers are confirmed, an old link must not bring back someone who unsubscribed.
+    let mut transaction = db_pool.begin().await?;
+    let confirmed = sqlx::query(
         r#"UPDATE subscriptions
-        SET status = 'confirmed' 
-        WHERE id = $1 AND status != 'confirmed'"#,
+        SET status = 'confirmed'
+        WHERE id = $1 AND status = 'pending_confirmation'"#,
     )
     .bind(subscriber_id)
-    .execute(db_pool)
-    .await?;
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    // Opening the link again doesn't confirm the subscriber again.
+    if confirmed > 0 {
+        subscription_events::record(
+            &mut *transaction,
+            subscriber_id,
+            EventKind::Confirmed,
+            EventSource::ConfirmationLink,
+            &meta,
+        )
+        .await?;
+    }
+    transaction.commit().await?;
     info!("SUCCESS!");
 
     Ok(StatusCode::OK)
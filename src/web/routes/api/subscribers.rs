@@ -0,0 +1,381 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use futures::{channel::mpsc, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    utils::csv_field,
+    web::{
+        self,
+        auth::{ApiKey, AuthError, AuthenticatedUser, Role, Scope},
+        subscription_events::RequestMeta,
+        WebResult,
+    },
+    AppState,
+};
+
+use super::super::subscriber_import::{ImportError, ImportReport, ImportStatus, SubscriberImport};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SubscribersError {
+    #[error("auth error: {0}")]
+    Auth(#[from] web::auth::AuthError),
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+}
+
+/// The export is sent to the client in chunks of about this size.
+const EXPORT_CHUNK_SIZE: usize = 16 * 1024;
+/// The chunks that wait for the client, the rows are only read as fast as the client downloads them.
+const EXPORT_BUFFERED_CHUNKS: usize = 4;
+const STATUSES: [&str; 3] = ["pending_confirmation", "confirmed", "unsubscribed"];
+
+#[derive(sqlx::FromRow)]
+struct SubscriberRecord {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+    consent_source: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SubscriberView {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: String,
+    consent_source: Option<String>,
+}
+
+impl From<SubscriberRecord> for SubscriberView {
+    fn from(sub: SubscriberRecord) -> Self {
+        SubscriberView {
+            id: sub.id,
+            email: sub.email,
+            name: sub.name,
+            status: sub.status,
+            subscribed_at: sub.subscribed_at.to_rfc3339(),
+            consent_source: sub.consent_source,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    /// `csv` (the default) or `ndjson`.
+    format: Option<String>,
+    status: Option<String>,
+    /// The first day of the subscriptions, inclusive.
+    from: Option<String>,
+    /// The last day of the subscriptions, inclusive.
+    to: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExportFormat {
+    Csv,
+    /// One JSON object per line.
+    Ndjson,
+}
+
+impl ExportFormat {
+    fn parse(format: Option<&str>) -> Result<Self, SubscribersError> {
+        match format.unwrap_or("csv") {
+            "csv" => Ok(ExportFormat::Csv),
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            other => Err(SubscribersError::InvalidInput(format!(
+                "unknown export format: {other}"
+            ))),
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+
+    fn header(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "id,email,name,status,subscribed_at,consent_source\r\n",
+            ExportFormat::Ndjson => "",
+        }
+    }
+
+    fn write_row(self, out: &mut String, sub: &SubscriberView) {
+        match self {
+            ExportFormat::Csv => {
+                let fields = [
+                    csv_field(&sub.id.to_string()).into_owned(),
+                    csv_field(&sub.email).into_owned(),
+                    csv_field(&sub.name).into_owned(),
+                    sub.status.clone(),
+                    sub.subscribed_at.clone(),
+                    csv_field(sub.consent_source.as_deref().unwrap_or_default()).into_owned(),
+                ];
+                out.push_str(&fields.join(","));
+                out.push_str("\r\n");
+            }
+            ExportFormat::Ndjson => {
+                out.push_str(&serde_json::to_string(sub).expect("a subscriber serializes to JSON"));
+                out.push('\n');
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct ExportFilters {
+    status: Option<String>,
+    from: Option<DateTime<Utc>>,
+    /// Exclusive, the start of the day after the last day.
+    until: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<&ExportParams> for ExportFilters {
+    type Error = SubscribersError;
+
+    fn try_from(params: &ExportParams) -> Result<Self, Self::Error> {
+        let non_empty = |field: &Option<String>| {
+            field
+                .as_deref()
+                .map(str::trim)
+                .filter(|val| !val.is_empty())
+                .map(str::to_string)
+        };
+        let status = non_empty(&params.status);
+        if let Some(status) = status.as_deref().filter(|s| !STATUSES.contains(s)) {
+            return Err(SubscribersError::InvalidInput(format!(
+                "unknown subscriber status: {status}"
+            )));
+        }
+        Ok(ExportFilters {
+            status,
+            from: non_empty(&params.from)
+                .map(|date| day_start(&date))
+                .transpose()?,
+            until: non_empty(&params.to)
+                .map(|date| day_start(&date).map(|start| start + Duration::days(1)))
+                .transpose()?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ImportParams {
+    status: String,
+    consent_source: Option<String>,
+}
+
+/// Exports the subscribers as a CSV or an NDJSON file, only with an API key that has the `subscribers:read` scope.
+/// The subscribers can be filtered by their status and the day they subscribed,
+/// e.g. `?format=ndjson&status=confirmed&from=2025-01-01&to=2025-03-31`.
+/// The rows are streamed from the database to the client, they aren't collected in memory.
+#[tracing::instrument(name = "Exporting subscribers", skip_all)]
+pub async fn subscribers_export(
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> WebResult<Response> {
+    let user = authenticate(&app_state, &headers, Scope::SubscribersRead).await?;
+    let format = ExportFormat::parse(params.format.as_deref())?;
+    let filters = ExportFilters::try_from(&params)?;
+    tracing::info!(user_id = %user.user_id, "Subscribers exported with an API key!");
+
+    let filename = format!(
+        "subscribers-{}.{}",
+        Utc::now().format("%Y%m%d-%H%M%S"),
+        format.extension()
+    );
+    let disposition = HeaderValue::from_str(&format!(r#"attachment; filename="{filename}""#))
+        .expect("valid header value");
+    let body = export_body(app_state.database_mgr.db().clone(), format, filters);
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(format.content_type()),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Imports the subscribers from the CSV file in the body, only with an API key that has the `subscribers:write` scope.
+/// The options of the import are in the query, e.g. `?status=confirmed&consent_source=...`.
+/// Responds with the report of the import as a CSV file.
+#[tracing::instrument(name = "Importing subscribers", skip_all)]
+pub async fn subscribers_import(
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    meta: RequestMeta,
+    Query(params): Query<ImportParams>,
+    body: Body,
+) -> WebResult<ImportReport> {
+    let user = authenticate(&app_state, &headers, Scope::SubscribersWrite).await?;
+    user.role
+        .require(Role::Editor)
+        .map_err(SubscribersError::Auth)?;
+
+    let status = ImportStatus::parse(&params.status, params.consent_source.as_deref())?;
+    let mut import = SubscriberImport::new(app_state, status, meta);
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| ImportError::Body(e.to_string()))?;
+        import.feed(&chunk).await?;
+        if import.is_stopped() {
+            break;
+        }
+    }
+    let report = import.finish().await?;
+    tracing::info!(user_id = %user.user_id, "Subscribers imported with an API key!");
+
+    Ok(report)
+}
+
+async fn authenticate(
+    app_state: &AppState,
+    headers: &HeaderMap,
+    scope: Scope,
+) -> Result<AuthenticatedUser, SubscribersError> {
+    let api_key =
+        ApiKey::from_headers(headers).and_then(|key| key.ok_or(AuthError::MissingAuthHeader))?;
+    Ok(api_key.authenticate(&app_state.database_mgr, scope).await?)
+}
+
+/// Streams the rows of the query into the body. The rows are read by a task that waits while the buffered
+/// chunks aren't downloaded, and stops when the client goes away.
+fn export_body(db: PgPool, format: ExportFormat, filters: ExportFilters) -> Body {
+    let (mut tx, rx) = mpsc::channel::<Result<Bytes, sqlx::Error>>(EXPORT_BUFFERED_CHUNKS);
+    tokio::spawn(async move {
+        let mut chunk = String::from(format.header());
+        let mut rows = sqlx::query_as::<_, SubscriberRecord>(
+            r#"
+            SELECT id, email, name, status, subscribed_at, consent_source FROM subscriptions
+            WHERE ($1::TEXT IS NULL OR status = $1)
+                AND ($2::TIMESTAMPTZ IS NULL OR subscribed_at >= $2)
+                AND ($3::TIMESTAMPTZ IS NULL OR subscribed_at < $3)
+            ORDER BY subscribed_at, id
+            "#,
+        )
+        .bind(filters.status)
+        .bind(filters.from)
+        .bind(filters.until)
+        .fetch(&db);
+
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(record) => format.write_row(&mut chunk, &SubscriberView::from(record)),
+                Err(e) => {
+                    // The body fails, so the client doesn't take a partial export for a complete one.
+                    tracing::error!(error = %e, "Subscribers export failed!");
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            }
+            if chunk.len() >= EXPORT_CHUNK_SIZE
+                && tx
+                    .send(Ok(Bytes::from(std::mem::take(&mut chunk))))
+                    .await
+                    .is_err()
+            {
+                tracing::info!("Subscribers export stopped, the client went away!");
+                return;
+            }
+        }
+        if !chunk.is_empty() {
+            let _ = tx.send(Ok(Bytes::from(chunk))).await;
+        }
+    });
+
+    Body::from_stream(rx)
+}
+
+/// The start of the day (UTC) of a `YYYY-MM-DD` date.
+fn day_start(date: &str) -> Result<DateTime<Utc>, SubscribersError> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|start| start.and_utc())
+        .ok_or_else(|| SubscribersError::InvalidInput(format!("invalid date: {date}")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use claims::assert_err;
+
+    fn params(status: &str, from: &str, to: &str) -> ExportParams {
+        ExportParams {
+            format: None,
+            status: Some(status.into()),
+            from: Some(from.into()),
+            to: Some(to.into()),
+        }
+    }
+
+    #[test]
+    fn export_filters_are_parsed_from_the_query() -> anyhow::Result<()> {
+        let filters = ExportFilters::try_from(&params("confirmed", "2025-06-01", "2025-06-30"))?;
+        assert_eq!(filters.status.as_deref(), Some("confirmed"));
+        assert_eq!(filters.from, Some(day_start("2025-06-01")?));
+        assert_eq!(filters.until, Some(day_start("2025-07-01")?));
+        assert_eq!(
+            ExportFilters::try_from(&params(" ", "", ""))?,
+            ExportFilters::default()
+        );
+
+        assert_err!(ExportFilters::try_from(&params("gone", "", "")));
+        assert_err!(ExportFilters::try_from(&params("", "01/06/2025", "")));
+        assert_err!(ExportFormat::parse(Some("xlsx")));
+        Ok(())
+    }
+
+    #[test]
+    fn export_rows_are_written_in_the_format() {
+        let sub = SubscriberView {
+            id: Uuid::nil(),
+            email: "ursula@example.com".into(),
+            name: "Le Guin, Ursula".into(),
+            status: "confirmed".into(),
+            subscribed_at: "2025-06-01T10:00:00+00:00".into(),
+            consent_source: None,
+        };
+
+        let mut csv = String::new();
+        ExportFormat::Csv.write_row(&mut csv, &sub);
+        assert_eq!(
+            csv,
+            "00000000-0000-0000-0000-000000000000,ursula@example.com,\"Le Guin, Ursula\",confirmed,\
+            2025-06-01T10:00:00+00:00,\r\n"
+        );
+
+        let mut ndjson = String::new();
+        ExportFormat::Ndjson.write_row(&mut ndjson, &sub);
+        assert!(ndjson.ends_with('\n'));
+        let value: serde_json::Value = serde_json::from_str(&ndjson).unwrap();
+        assert_eq!(value["name"], "Le Guin, Ursula");
+        assert_eq!(value["consent_source"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,107 @@
+//! Implementation for "api/subscribe/unsubscribe"
+//!
+//! The unsubscribe links are sent with every newsletter. `GET` shows a confirmation page
+//! and `POST` unsubscribes, which also supports the RFC 8058 one-click unsubscribe requests.
+
+use axum::{
+    extract::{Query, State},
+    response::Html,
+};
+use secrecy::ExposeSecret;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::web::{
+    self,
+    subscription_events::{self, EventKind, EventSource, RequestMeta},
+    types::{UnsubscribeQuery, UnsubscribeToken},
+    WebResult,
+};
+use crate::AppState;
+
+// ###################################
+// ->   ERROR
+// ###################################
+#[derive(Debug, thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("data parsing error: {0}")]
+    DataParsing(#[from] web::types::DataParsingError),
+    #[error("tera template render error: {0}")]
+    Tera(#[from] tera::Error),
+}
+
+// ###################################
+// ->   API
+// ###################################
+#[tracing::instrument(name = "Unsubscribe confirmation page", skip_all)]
+pub async fn unsubscribe_get(
+    State(app_state): State<AppState>,
+    Query(query): Query<UnsubscribeQuery>,
+) -> WebResult<Html<String>> {
+    // Only verify the token here, the subscriber is unsubscribed after they confirm.
+    verify_token(&app_state, &query)?;
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("token", &query.token);
+    ctx.insert("unsubscribed", &false);
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "unsubscribe.html")
+        .map_err(UnsubscribeError::Tera)?;
+
+    Ok(Html(body))
+}
+
+#[tracing::instrument(name = "Unsubscribing a subscriber", skip_all, fields(subscriber_id))]
+pub async fn unsubscribe_post(
+    State(app_state): State<AppState>,
+    meta: RequestMeta,
+    Query(query): Query<UnsubscribeQuery>,
+) -> WebResult<Html<String>> {
+    let subscriber_id = verify_token(&app_state, &query)?;
+    tracing::Span::current().record("subscriber_id", subscriber_id.to_string());
+
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let unsubscribed = sqlx::query(
+        r#"UPDATE subscriptions
+        SET status = 'unsubscribed'
+        WHERE id = $1 AND status != 'unsubscribed'"#,
+    )
+    .bind(subscriber_id)
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    if unsubscribed > 0 {
+        subscription_events::record(
+            &mut *transaction,
+            subscriber_id,
+            EventKind::Unsubscribed,
+            EventSource::UnsubscribeLink,
+            &meta,
+        )
+        .await?;
+    }
+    // The old confirmation links must not subscribe them again, a new subscription sends a new link.
+    sqlx::query("DELETE FROM subscription_tokens WHERE subscriber_id = $1")
+        .bind(subscriber_id)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    info!("SUCCESS!");
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("unsubscribed", &true);
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "unsubscribe.html")
+        .map_err(UnsubscribeError::Tera)?;
+
+    Ok(Html(body))
+}
+
+fn verify_token(app_state: &AppState, query: &UnsubscribeQuery) -> WebResult<Uuid> {
+    let subscriber_id =
+        UnsubscribeToken::verify(&query.token, app_state.hmac_secret.expose_secret())
+            .map_err(UnsubscribeError::DataParsing)?;
+    Ok(subscriber_id)
+}
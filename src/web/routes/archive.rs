@@ -0,0 +1,115 @@
+//! The public archive of the newsletter issues that were already sent.
+//!
+//! The HTML content of an issue is sanitized before it's rendered, so only a safe subset
+//! of the HTML written by the authors ends up on the page.
+
+use axum::{
+    extract::{Path, State},
+    response::Html,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{web::WebResult, AppState};
+
+// ###################################
+// ->   ERROR
+// ###################################
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("newsletter issue not found: {0}")]
+    IssueNotFound(Uuid),
+    #[error("tera template render error: {0}")]
+    Tera(#[from] tera::Error),
+}
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+#[derive(Serialize)]
+struct ArchiveEntry {
+    id: Uuid,
+    title: String,
+    published_at: String,
+}
+
+#[derive(Serialize)]
+struct ArchivedIssue {
+    title: String,
+    published_at: String,
+    /// Sanitized HTML content, safe to render as is.
+    html_content: String,
+}
+
+// ###################################
+// ->   HANDLERS
+// ###################################
+#[tracing::instrument(name = "Newsletter archive", skip_all)]
+pub async fn archive_list(State(app_state): State<AppState>) -> WebResult<Html<String>> {
+    let rows: Vec<(Uuid, String, DateTime<Utc>)> = sqlx::query_as(
+        r#"
+        SELECT newsletter_issue_id, title, published_at
+        FROM newsletter_issues
+        WHERE status = 'published' AND published_at <= now()
+        ORDER BY published_at DESC
+        "#,
+    )
+    .fetch_all(app_state.database_mgr.db())
+    .await?;
+
+    let issues = rows
+        .into_iter()
+        .map(|(id, title, published_at)| ArchiveEntry {
+            id,
+            title,
+            published_at: format_date(published_at),
+        })
+        .collect::<Vec<_>>();
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("issues", &issues);
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "archive.html")
+        .map_err(ArchiveError::Tera)?;
+
+    Ok(Html(body))
+}
+
+#[tracing::instrument(name = "Newsletter archive issue", skip(app_state))]
+pub async fn archive_issue(
+    State(app_state): State<AppState>,
+    Path(newsletter_issue_id): Path<Uuid>,
+) -> WebResult<Html<String>> {
+    let (title, html_content, published_at): (String, String, DateTime<Utc>) = sqlx::query_as(
+        r#"
+        SELECT title, html_content, published_at
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1 AND status = 'published' AND published_at <= now()
+        "#,
+    )
+    .bind(newsletter_issue_id)
+    .fetch_optional(app_state.database_mgr.db())
+    .await?
+    .ok_or(ArchiveError::IssueNotFound(newsletter_issue_id))?;
+
+    let issue = ArchivedIssue {
+        title,
+        published_at: format_date(published_at),
+        html_content: ammonia::clean(&html_content),
+    };
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("issue", &issue);
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "archive_issue.html")
+        .map_err(ArchiveError::Tera)?;
+
+    Ok(Html(body))
+}
+
+fn format_date(date: DateTime<Utc>) -> String {
+    date.format("%B %-d, %Y").to_string()
+}
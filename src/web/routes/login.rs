@@ -1,9 +1,10 @@
 use crate::{
-    utils::{self, b64u_decode_to_string},
+    utils,
     web::{
-        auth::{self, Credentials},
+        auth::{self, totp, Credentials, Role},
+        flash,
         routes::admin::{AdminData, AdminSession},
-        WebResult, FLASH_ERROR_MSG,
+        ClientIp, CsrfToken, WebResult, FLASH_ERROR_MSG, FLASH_INFO_MSG,
     },
     AppState,
 };
@@ -11,13 +12,25 @@ use anyhow::Context;
 use axum::{
     extract::State,
     http::StatusCode,
-    response::{Html, IntoResponse, Response},
+    response::{Html, IntoResponse, Redirect, Response},
     Form,
 };
-use secrecy::ExposeSecret;
-use tower_cookies::{Cookie, Cookies, Key};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use tower_cookies::{
+    cookie::time::{Duration, OffsetDateTime},
+    Cookies, Key,
+};
 use tower_sessions::Session;
-use tracing::debug;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+/// The session key of the login that is waiting for the TOTP code.
+const PENDING_LOGIN_KEY: &str = "pending_totp";
+/// The time the user has to enter the code after entering the password.
+const PENDING_LOGIN_TTL_SECS: i64 = 5 * 60;
+/// After this many invalid codes the user has to start with the password again.
+const PENDING_LOGIN_MAX_ATTEMPTS: u32 = 5;
 
 #[derive(Debug, thiserror::Error)]
 pub enum LoginError {
@@ -32,21 +45,43 @@ pub enum LoginError {
     Tera(#[from] tera::Error),
 }
 
+/// A user that entered the right password but still has to enter the TOTP code.
+/// It's kept in the session, the session is only turned into an admin session once the code is checked.
+#[derive(Deserialize, Serialize)]
+struct PendingLogin {
+    user_id: Uuid,
+    /// The TOTP attempts are throttled by the username, like the passwords.
+    username: String,
+    role: Role,
+    started_at: OffsetDateTime,
+    failed_attempts: u32,
+}
+
+#[derive(Deserialize)]
+pub struct TotpForm {
+    code: SecretString,
+}
+
 #[tracing::instrument(name = "login_get", skip(app_state, cookies))]
 pub async fn login_get(
     State(app_state): State<AppState>,
     cookies: Cookies,
+    csrf_token: CsrfToken,
 ) -> WebResult<Html<String>> {
     let mut ctx = tera::Context::new();
+    csrf_token.insert_into(&mut ctx);
 
     let secret_key = Key::from(app_state.cookie_secret.expose_secret());
-    let signed_cookies = cookies.signed(&secret_key);
-
-    if let Some(flash_err) = signed_cookies.get(FLASH_ERROR_MSG) {
-        let error_msg = b64u_decode_to_string(flash_err.value()).map_err(LoginError::Utils)?;
+    // the cookie is removed once we read it
+    if let Some(error_msg) =
+        flash::take(&cookies, &secret_key, FLASH_ERROR_MSG).map_err(LoginError::Utils)?
+    {
         ctx.insert("error_message", &error_msg);
-        // remove the cookie if we just used it
-        signed_cookies.remove(Cookie::new(FLASH_ERROR_MSG, ""));
+    }
+    if let Some(info_msg) =
+        flash::take(&cookies, &secret_key, FLASH_INFO_MSG).map_err(LoginError::Utils)?
+    {
+        ctx.insert("info_message", &info_msg);
     }
 
     let body = app_state
@@ -62,15 +97,194 @@ pub async fn login_post(
     State(app_state): State<AppState>,
     // untyped session
     session: Session,
+    ClientIp(ip): ClientIp,
     Form(user_creds): Form<Credentials>,
 ) -> WebResult<Response> {
     // If we get an authentication error redirect to `login_form` is inserted to headers in response mapper
     // alongside the client error message as a signed cookie.
-    let user_id = user_creds
-        .authenticate(&app_state.database_mgr)
+    let username = user_creds.username.clone();
+    let user = app_state
+        .login_throttle
+        .authenticate(&app_state.database_mgr, user_creds, ip)
+        .await
+        .map_err(LoginError::Auth)?;
+    let user_id = user.user_id;
+
+    // The users with two-factor authentication get the admin session once they enter the code.
+    if totp::is_enabled(&app_state.database_mgr, user_id)
+        .await
+        .map_err(LoginError::Auth)?
+    {
+        // The failures of the username are only reset once the code is checked.
+        let pending_login = PendingLogin {
+            user_id,
+            username,
+            role: user.role,
+            started_at: OffsetDateTime::now_utc(),
+            failed_attempts: 0,
+        };
+        session
+            .cycle_id()
+            .await
+            .context("couldn't cycle session IDs")?;
+        session
+            .insert(PENDING_LOGIN_KEY, pending_login)
+            .await
+            .map_err(LoginError::Session)?;
+        debug!(user_id = %user_id, "Waiting for the TOTP code");
+        return Ok(Redirect::to("/login/totp").into_response());
+    }
+
+    app_state
+        .login_throttle
+        .reset(&username)
+        .await
+        .map_err(LoginError::Auth)?;
+    start_admin_session(&app_state, session, user_id, user.role).await
+}
+
+#[tracing::instrument(name = "login_totp_get", skip_all)]
+pub async fn login_totp_get(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    session: Session,
+    csrf_token: CsrfToken,
+) -> WebResult<Response> {
+    if pending_login_get(&session).await?.is_none() {
+        return Ok(Redirect::to("/login").into_response());
+    }
+
+    let mut ctx = tera::Context::new();
+    csrf_token.insert_into(&mut ctx);
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    if let Some(error_msg) =
+        flash::take(&cookies, &secret_key, FLASH_ERROR_MSG).map_err(LoginError::Utils)?
+    {
+        ctx.insert("error_message", &error_msg);
+    }
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "login_totp.html")
+        .map_err(LoginError::Tera)?;
+
+    Ok(Html(body).into_response())
+}
+
+/// Checks the code from the authenticator app or a recovery code and finishes the login.
+#[tracing::instrument(name = "login_totp_post", skip_all)]
+pub async fn login_totp_post(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    session: Session,
+    ClientIp(ip): ClientIp,
+    Form(form): Form<TotpForm>,
+) -> WebResult<Response> {
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    let Some(mut pending_login) = pending_login_get(&session).await? else {
+        flash::add(
+            &cookies,
+            &secret_key,
+            FLASH_ERROR_MSG,
+            "The login expired, please log in again.",
+        );
+        return Ok(Redirect::to("/login").into_response());
+    };
+
+    if let Err(e) = app_state
+        .login_throttle
+        .totp_attempt(&app_state.database_mgr, &pending_login.username, ip)
+        .await
+    {
+        session
+            .remove::<PendingLogin>(PENDING_LOGIN_KEY)
+            .await
+            .map_err(LoginError::Session)?;
+        return Err(LoginError::Auth(e).into());
+    }
+    let code_valid = totp::check_code(
+        &app_state.database_mgr,
+        &app_state.totp_key,
+        pending_login.user_id,
+        form.code.expose_secret(),
+    )
+    .await
+    .map_err(LoginError::Auth)?;
+
+    if !code_valid {
+        pending_login.failed_attempts += 1;
+        if pending_login.failed_attempts >= PENDING_LOGIN_MAX_ATTEMPTS {
+            info!(user_id = %pending_login.user_id, "Too many invalid TOTP codes");
+            session
+                .remove::<PendingLogin>(PENDING_LOGIN_KEY)
+                .await
+                .map_err(LoginError::Session)?;
+            flash::add(
+                &cookies,
+                &secret_key,
+                FLASH_ERROR_MSG,
+                "Too many invalid codes, please log in again.",
+            );
+            return Ok(Redirect::to("/login").into_response());
+        }
+        session
+            .insert(PENDING_LOGIN_KEY, &pending_login)
+            .await
+            .map_err(LoginError::Session)?;
+        flash::add(
+            &cookies,
+            &secret_key,
+            FLASH_ERROR_MSG,
+            "The code is invalid!",
+        );
+        return Ok(Redirect::to("/login/totp").into_response());
+    }
+
+    session
+        .remove::<PendingLogin>(PENDING_LOGIN_KEY)
+        .await
+        .map_err(LoginError::Session)?;
+    app_state
+        .login_throttle
+        .reset(&pending_login.username)
         .await
         .map_err(LoginError::Auth)?;
+    start_admin_session(
+        &app_state,
+        session,
+        pending_login.user_id,
+        pending_login.role,
+    )
+    .await
+}
 
+/// Returns the pending login from the session, the expired ones are removed.
+async fn pending_login_get(session: &Session) -> WebResult<Option<PendingLogin>> {
+    let Some(pending_login) = session
+        .get::<PendingLogin>(PENDING_LOGIN_KEY)
+        .await
+        .map_err(LoginError::Session)?
+    else {
+        return Ok(None);
+    };
+    if pending_login.started_at + Duration::seconds(PENDING_LOGIN_TTL_SECS)
+        <= OffsetDateTime::now_utc()
+    {
+        session
+            .remove::<PendingLogin>(PENDING_LOGIN_KEY)
+            .await
+            .map_err(LoginError::Session)?;
+        return Ok(None);
+    }
+    Ok(Some(pending_login))
+}
+
+/// Turns the session into an admin session and redirects to the dashboard.
+async fn start_admin_session(
+    app_state: &AppState,
+    session: Session,
+    user_id: Uuid,
+    role: Role,
+) -> WebResult<Response> {
     // Succesfully logged-in: redirect admin user to the dashboard.
     let mut resp = StatusCode::SEE_OTHER.into_response();
     resp.headers_mut().insert(
@@ -79,7 +293,7 @@ pub async fn login_post(
     );
 
     // Build a typed admin session
-    let admin_session = AdminSession::new(session, AdminData::new(user_id));
+    let admin_session = AdminSession::new(session, AdminData::new(user_id, role));
     // Mitigate session fixation attacks
     // more: https://owasp.org/www-community/attacks/Session_fixation
     admin_session
@@ -89,6 +303,11 @@ pub async fn login_post(
 
     // Update the session with contained AdminData
     admin_session.update_session().await?;
+    // Save it right away to get the new session ID, so it can be indexed under the user
+    admin_session.save().await?;
+    if let Some(session_id) = admin_session.id() {
+        app_state.session_index.add(user_id, session_id).await?;
+    }
 
     debug!(user_id = %user_id);
 
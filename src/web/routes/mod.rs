@@ -2,45 +2,99 @@
 
 mod admin;
 mod api;
+mod archive;
 mod home;
 mod login;
+mod password_reset;
+mod privacy;
+mod signup;
+mod subscriber_import;
 
 // re-export errors
-pub use admin::AdminError;
+pub use admin::{AdminError, SessionIndex};
 pub use api::{
     news::NewsError, subscribe::SubscribeError, subscribe_confirm::SubscribeConfirmError,
+    subscribers::SubscribersError, unsubscribe::UnsubscribeError,
 };
+pub use archive::ArchiveError;
 pub use login::LoginError;
+pub use password_reset::PasswordResetError;
+pub use privacy::PrivacyError;
+pub use signup::SignupError;
+pub use subscriber_import::ImportError;
 
-use crate::AppState;
+use crate::{web::csrf, AppState};
+use archive::{archive_issue, archive_list};
 use home::home;
-use login::{login_get, login_post};
+use login::{login_get, login_post, login_totp_get, login_totp_post};
+use password_reset::{
+    forgot_password_get, forgot_password_post, reset_password_get, reset_password_post,
+};
+use privacy::{
+    privacy_data_erase, privacy_data_export, privacy_data_get, privacy_get, privacy_post,
+};
+use signup::{signup_get, signup_post};
 
 use axum::{
-    http::StatusCode,
+    extract::{DefaultBodyLimit, State},
+    middleware,
     routing::{get, post},
-    Router,
+    Json, Router,
 };
+use serde_json::{json, Value};
+
+/// The importer stops reading the file at its own limit, this leaves room for the rest of the form.
+const IMPORT_BODY_LIMIT: usize = subscriber_import::MAX_BYTES + 64 * 1024;
 
-async fn health_check() -> StatusCode {
-    StatusCode::OK
+/// Always responds with 200 OK while the server is up, the body reports the state of its dependencies.
+async fn health_check(State(app_state): State<AppState>) -> Json<Value> {
+    Json(json!({
+        "status": "ok",
+        "email_circuit": app_state.email_client.circuit_state().as_ref(),
+    }))
 }
 
-/// All the routes of the server
+/// All the routes of the server, the HTML pages and forms are protected against CSRF.
+/// The API isn't, it's authenticated by the headers and the unsubscribe links have to work in one click.
 pub fn routes(app_state: AppState) -> Router {
     Router::new()
         .route("/", get(home))
         .route("/login", get(login_get).post(login_post))
-        .with_state(app_state.clone())
-        .nest("/api", api_routes(app_state.clone()))
-        .nest("/admin", admin_routes(app_state))
+        .route("/login/totp", get(login_totp_get).post(login_totp_post))
+        .route(
+            "/login/forgot",
+            get(forgot_password_get).post(forgot_password_post),
+        )
+        .route(
+            "/login/reset",
+            get(reset_password_get).post(reset_password_post),
+        )
+        .route("/signup", get(signup_get).post(signup_post))
+        .route("/privacy", get(privacy_get).post(privacy_post))
+        .route("/privacy/data", get(privacy_data_get))
+        .route("/privacy/data/export", get(privacy_data_export))
+        .route("/privacy/data/erase", post(privacy_data_erase))
+        .route("/archive", get(archive_list))
+        .route("/archive/{id}", get(archive_issue))
         .route("/health-check", get(health_check))
+        .with_state(app_state.clone())
+        .nest("/admin", admin_routes(app_state.clone()))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            csrf::csrf_protect,
+        ))
+        .nest("/api", api_routes(app_state))
 }
 
 /// API - Routes nested under "/api" path
 fn api_routes(app_state: AppState) -> Router {
     Router::new()
         .route("/news", post(api::news_publish))
+        .route("/subscribers/export", get(api::subscribers_export))
+        .route(
+            "/subscribers/import",
+            post(api::subscribers_import).layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)),
+        )
         .with_state(app_state.clone())
         .nest("/subscribe", subscribe_routes(app_state))
 }
@@ -50,11 +104,66 @@ fn subscribe_routes(app_state: AppState) -> Router {
     Router::new()
         .route("/", post(api::subscribe))
         .route("/confirm", get(api::subscribe_confirm))
+        .route(
+            "/unsubscribe",
+            get(api::unsubscribe_get).post(api::unsubscribe_post),
+        )
         .with_state(app_state)
 }
 
 fn admin_routes(app_state: AppState) -> Router {
     Router::new()
         .route("/dashboard", get(admin::dashboard))
+        .route(
+            "/password",
+            get(admin::get_change_password).post(admin::post_change_password),
+        )
+        .route("/email", post(admin::post_change_email))
+        .route("/issues", get(admin::issues_list).post(admin::issue_create))
+        .route("/login-events", get(admin::login_events_list))
+        .route("/logout", post(admin::logout))
+        .route("/sessions", get(admin::sessions_list))
+        .route("/sessions/revoke-all", post(admin::sessions_revoke_all))
+        .route("/sessions/{handle}/revoke", post(admin::session_revoke))
+        .route("/issues/new", get(admin::issue_new))
+        .route(
+            "/issues/{id}",
+            get(admin::issue_get).post(admin::issue_update),
+        )
+        .route("/issues/{id}/schedule", post(admin::issue_schedule))
+        .route("/issues/{id}/cancel", post(admin::issue_cancel))
+        .route(
+            "/api-keys",
+            get(admin::api_keys_list).post(admin::api_key_create),
+        )
+        .route("/api-keys/{id}/revoke", post(admin::api_key_revoke))
+        .route("/totp", get(admin::totp_get))
+        .route("/totp/enroll", post(admin::totp_enroll))
+        .route("/totp/confirm", post(admin::totp_confirm))
+        .route(
+            "/totp/recovery-codes",
+            post(admin::totp_recovery_codes_regenerate),
+        )
+        .route("/totp/disable", post(admin::totp_disable))
+        .route("/subscribers", get(admin::subscribers_list))
+        .route(
+            "/subscribers/import",
+            get(admin::subscribers_import_get)
+                .post(admin::subscribers_import_post)
+                .layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)),
+        )
+        .route(
+            "/subscribers/{id}/events",
+            get(admin::subscriber_events_list),
+        )
+        .route("/subscribers/{id}/confirm", post(admin::subscriber_confirm))
+        .route(
+            "/subscribers/{id}/unsubscribe",
+            post(admin::subscriber_unsubscribe),
+        )
+        .route("/subscribers/{id}/delete", post(admin::subscriber_delete))
+        .route("/users", get(admin::users_list))
+        .route("/users/invite", post(admin::user_invite))
+        .route("/users/{id}/deactivate", post(admin::user_deactivate))
         .with_state(app_state)
 }
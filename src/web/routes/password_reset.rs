@@ -0,0 +1,365 @@
+//! Resetting a forgotten admin password through a single-use link sent by email.
+//!
+//! The forgot password form always responds the same way and the email is sent in the background,
+//! so neither the response nor its timing reveal whether the username exists.
+//! The reset tokens expire after `PasswordResetConfig::token_expiry_secs` and only their hashes are stored.
+
+use axum::{
+    extract::{Query, State},
+    response::{Html, Redirect},
+    Form,
+};
+use chrono::{DateTime, Utc};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use sqlx::Executor;
+use tera::Context;
+use tower_cookies::{Cookies, Key};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    config::get_or_init_config,
+    email_client, utils,
+    web::{
+        auth::{password, AuthError},
+        flash,
+        routes::admin::AdminError,
+        types::{DataParsingError, EmailLinkToken, ValidEmail},
+        CsrfToken, WebResult, FLASH_ERROR_MSG, FLASH_INFO_MSG,
+    },
+    AppState,
+};
+
+// ###################################
+// ->   ERROR
+// ###################################
+#[derive(Debug, thiserror::Error)]
+pub enum PasswordResetError {
+    #[error("the password reset link is invalid or expired")]
+    LinkInvalid,
+    #[error("The new passwords don't match!")]
+    NewPasswordMismatch,
+    #[error("The new password is too weak, {0}!")]
+    NewPasswordTooWeak(String),
+
+    #[error("authentication error: {0}")]
+    Auth(#[from] AuthError),
+    #[error("admin error: {0}")]
+    Admin(#[from] AdminError),
+    #[error("email client error: {0}")]
+    Email(#[from] email_client::Error),
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("tera template render error: {0}")]
+    Tera(#[from] tera::Error),
+    #[error("data parsing error: {0}")]
+    DataParsing(#[from] DataParsingError),
+    #[error("utils error: {0}")]
+    Utils(#[from] utils::UtilsError),
+}
+
+impl PasswordResetError {
+    /// The errors caused by the submitted form, they are shown on the reset page instead of failing the request.
+    fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            PasswordResetError::NewPasswordMismatch | PasswordResetError::NewPasswordTooWeak(_)
+        )
+    }
+}
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+#[derive(Deserialize)]
+pub struct ForgotPasswordForm {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordForm {
+    token: String,
+    new_password: SecretString,
+    new_password_check: SecretString,
+}
+
+// ###################################
+// ->   HANDLERS
+// ###################################
+#[tracing::instrument(name = "forgot_password_get", skip_all)]
+pub async fn forgot_password_get(
+    State(app_state): State<AppState>,
+    csrf_token: CsrfToken,
+) -> WebResult<Html<String>> {
+    let mut ctx = Context::new();
+    csrf_token.insert_into(&mut ctx);
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "forgot_password.html")
+        .map_err(PasswordResetError::Tera)?;
+
+    Ok(Html(body))
+}
+
+#[tracing::instrument(name = "forgot_password_post", skip_all)]
+pub async fn forgot_password_post(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    Form(form): Form<ForgotPasswordForm>,
+) -> WebResult<Redirect> {
+    // Errors are logged by the instrumented function, the response is the same either way.
+    tokio::spawn(try_send_reset_email(app_state.clone(), form.username));
+
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    flash::add(
+        &cookies,
+        &secret_key,
+        FLASH_INFO_MSG,
+        "If the account exists and has an email address, a password reset link was sent to it.",
+    );
+
+    Ok(Redirect::to("/login"))
+}
+
+#[tracing::instrument(name = "reset_password_get", skip_all)]
+pub async fn reset_password_get(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    csrf_token: CsrfToken,
+    Query(query): Query<ResetPasswordQuery>,
+) -> WebResult<Html<String>> {
+    let token = EmailLinkToken::parse(&query.token).map_err(|_| PasswordResetError::LinkInvalid)?;
+    let expires_at: Option<DateTime<Utc>> = sqlx::query_scalar(
+        r#"
+        SELECT expires_at FROM password_reset_tokens
+        WHERE token_hash = $1
+        "#,
+    )
+    .bind(token.hash())
+    .fetch_optional(app_state.database_mgr.db())
+    .await?;
+    if expires_at.is_none_or(|expires_at| expires_at <= Utc::now()) {
+        return Err(PasswordResetError::LinkInvalid.into());
+    }
+
+    let mut ctx = Context::new();
+    csrf_token.insert_into(&mut ctx);
+    ctx.insert("token", token.as_str());
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    if let Some(msg) =
+        flash::take(&cookies, &secret_key, FLASH_ERROR_MSG).map_err(PasswordResetError::Utils)?
+    {
+        ctx.insert("error_message", &msg);
+    }
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "reset_password.html")
+        .map_err(PasswordResetError::Tera)?;
+
+    Ok(Html(body))
+}
+
+#[tracing::instrument(name = "reset_password_post", skip_all)]
+pub async fn reset_password_post(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    Form(form): Form<ResetPasswordForm>,
+) -> WebResult<Redirect> {
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    let token = EmailLinkToken::parse(&form.token).map_err(|_| PasswordResetError::LinkInvalid)?;
+
+    // Validate the new password before the token is used up, so the admin can try again.
+    if let Err(e) = check_new_password(&form.new_password, &form.new_password_check) {
+        if !e.is_rejection() {
+            return Err(e.into());
+        }
+        flash::add(&cookies, &secret_key, FLASH_ERROR_MSG, e.to_string());
+        return Ok(Redirect::to(&format!(
+            "/login/reset?token={}",
+            token.as_str()
+        )));
+    }
+
+    let user_id = reset_password(&app_state, &token, form.new_password).await?;
+    // Whoever knew the old password shouldn't stay logged in.
+    let revoked = app_state
+        .session_index
+        .revoke_all_except(user_id, None)
+        .await?;
+    info!(%user_id, revoked_sessions = revoked, "Password reset!");
+
+    flash::add(
+        &cookies,
+        &secret_key,
+        FLASH_INFO_MSG,
+        "Your password was reset, you can log in with the new password.",
+    );
+
+    Ok(Redirect::to("/login"))
+}
+
+// ###################################
+// ->   HELPERS
+// ###################################
+fn check_new_password(
+    new_password: &SecretString,
+    new_password_check: &SecretString,
+) -> Result<(), PasswordResetError> {
+    if new_password.expose_secret() != new_password_check.expose_secret() {
+        return Err(PasswordResetError::NewPasswordMismatch);
+    }
+    match password::check_strength(new_password) {
+        Err(AuthError::PasswordTooWeak(reason)) => {
+            Err(PasswordResetError::NewPasswordTooWeak(reason))
+        }
+        res => Ok(res?),
+    }
+}
+
+/// Uses up the token and stores the hash of the new password, returns the ID of the user.
+///
+/// All the other reset tokens of the user are deleted as well.
+async fn reset_password(
+    app_state: &AppState,
+    token: &EmailLinkToken,
+    new_password: SecretString,
+) -> Result<Uuid, PasswordResetError> {
+    let new_hash = password::hash_new_to_string_async(new_password).await?;
+
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let (user_id, expires_at): (Uuid, DateTime<Utc>) = sqlx::query_as(
+        r#"
+        DELETE FROM password_reset_tokens
+        WHERE token_hash = $1
+        RETURNING user_id, expires_at
+        "#,
+    )
+    .bind(token.hash())
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(PasswordResetError::LinkInvalid)?;
+    if expires_at <= Utc::now() {
+        // Deleting the expired token is fine.
+        transaction.commit().await?;
+        return Err(PasswordResetError::LinkInvalid);
+    }
+
+    let query = sqlx::query(
+        r#"
+        UPDATE users SET password_hash = $2
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .bind(new_hash.expose_secret());
+    transaction.execute(query).await?;
+
+    let query = sqlx::query(
+        r#"
+        DELETE FROM password_reset_tokens
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id);
+    transaction.execute(query).await?;
+
+    transaction.commit().await?;
+
+    Ok(user_id)
+}
+
+/// Creates a reset token and emails the reset link to the user, if the user exists and has an email address.
+///
+/// At most `PasswordResetConfig::max_emails_per_hour` emails are sent to a single user in an hour.
+#[tracing::instrument(name = "Sending password reset email", skip(app_state), err)]
+async fn try_send_reset_email(
+    app_state: AppState,
+    username: String,
+) -> Result<(), PasswordResetError> {
+    let config = &get_or_init_config().password_reset_config;
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+
+    // Locking the user makes the concurrent requests wait for each other, so the limit holds.
+    let user: Option<(Uuid, Option<String>)> = sqlx::query_as(
+        r#"
+        SELECT user_id, email FROM users
+        WHERE username = $1 AND deactivated_at IS NULL
+        FOR UPDATE
+        "#,
+    )
+    .bind(&username)
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let Some((user_id, Some(email))) = user else {
+        info!("No user with an email address, no email was sent.");
+        return Ok(());
+    };
+    let email = ValidEmail::parse(email)?;
+
+    let now = Utc::now();
+    let query = sqlx::query(
+        r#"
+        DELETE FROM password_reset_tokens
+        WHERE user_id = $1 AND expires_at <= $2
+        "#,
+    )
+    .bind(user_id)
+    .bind(now);
+    transaction.execute(query).await?;
+
+    let n_recent: i64 = sqlx::query_scalar(
+        r#"
+        SELECT count(*) FROM password_reset_tokens
+        WHERE user_id = $1 AND created_at > $2 - interval '1 hour'
+        "#,
+    )
+    .bind(user_id)
+    .bind(now)
+    .fetch_one(&mut *transaction)
+    .await?;
+    if n_recent >= config.max_emails_per_hour {
+        info!("Too many password reset emails were sent recently, no email was sent.");
+        return Ok(());
+    }
+
+    let token = EmailLinkToken::generate();
+    let query = sqlx::query(
+        r#"
+        INSERT INTO password_reset_tokens (token_hash, user_id, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+    )
+    .bind(token.hash())
+    .bind(user_id)
+    .bind(now)
+    .bind(now + config.token_expiry());
+    transaction.execute(query).await?;
+    transaction.commit().await?;
+
+    let reset_link = format!(
+        "{}/login/reset?token={}",
+        app_state.base_url,
+        token.as_str()
+    );
+    let mut ctx = Context::new();
+    ctx.insert("username", &username);
+    ctx.insert("reset_link", &reset_link);
+    ctx.insert("expiry_minutes", &config.token_expiry().num_minutes());
+    let tera = app_state.templ_mgr.tera();
+    let html_email = tera.render("emails/password_reset.html", &ctx)?;
+    let plain_email = tera.render("emails/password_reset.txt", &ctx)?;
+
+    app_state
+        .email_client
+        .send_single_email(&email, "Reset your password", &html_email, &plain_email)
+        .await?;
+    info!("Password reset email sent!");
+
+    Ok(())
+}
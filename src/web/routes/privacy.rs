@@ -0,0 +1,444 @@
+//! Self-service access to and erasure of the data stored about a subscriber.
+//!
+//! The subscriber asks for a link with their email address. The form always responds the same way and the email
+//! is sent in the background, so neither the response nor its timing reveal whether the address is subscribed.
+//! The link is signed and expires after `DataRequestConfig::link_expiry_secs`, it leads to a page where
+//! the data can be downloaded as JSON or erased. An erasure deletes the subscriber with its tokens
+//! and pending deliveries, only a record without any personal data is kept. The history of the subscriber
+//! stays without the IP addresses and the user agents.
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderValue},
+    response::{Html, IntoResponse, Redirect, Response},
+    Form, Json,
+};
+use chrono::{DateTime, Utc};
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use sqlx::Executor;
+use tera::Context;
+use tower_cookies::{Cookies, Key};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    config::get_or_init_config,
+    email_client, utils,
+    web::{
+        flash,
+        subscription_events::{self, EventSource, RequestMeta},
+        types::{DataRequestToken, ValidEmail},
+        CsrfToken, WebResult, FLASH_INFO_MSG,
+    },
+    AppState,
+};
+
+// ###################################
+// ->   ERROR
+// ###################################
+#[derive(Debug, thiserror::Error)]
+pub enum PrivacyError {
+    #[error("the data request link is invalid or expired")]
+    LinkInvalid,
+
+    #[error("email client error: {0}")]
+    Email(#[from] email_client::Error),
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("tera template render error: {0}")]
+    Tera(#[from] tera::Error),
+    #[error("utils error: {0}")]
+    Utils(#[from] utils::UtilsError),
+}
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+#[derive(Deserialize)]
+pub struct DataRequestForm {
+    email: String,
+}
+
+/// The token of the link, in the query of the links and in the erasure form.
+#[derive(Deserialize)]
+pub struct DataRequestQuery {
+    token: String,
+}
+
+/// Everything that is stored about a subscriber.
+#[derive(Serialize)]
+struct SubscriberDataExport {
+    exported_at: String,
+    subscriber: SubscriberData,
+    /// When the confirmation links were created, the tokens themselves are left out.
+    confirmation_links_created_at: Vec<String>,
+    confirmation_emails_sent_at: Vec<String>,
+    data_request_emails_sent_at: Vec<String>,
+    /// The history of the subscription, with the requests that changed it.
+    events: Vec<EventData>,
+    /// The newsletter issues that are waiting to be delivered to the subscriber.
+    pending_deliveries: Vec<PendingDelivery>,
+}
+
+#[derive(Serialize)]
+struct SubscriberData {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: String,
+    consent_source: Option<String>,
+}
+
+#[derive(Serialize)]
+struct EventData {
+    kind: String,
+    source: String,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    occurred_at: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct SubscriberRecord {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+    consent_source: Option<String>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct PendingDelivery {
+    newsletter_issue_id: Uuid,
+    title: String,
+}
+
+// ###################################
+// ->   HANDLERS
+// ###################################
+#[tracing::instrument(name = "privacy_get", skip_all)]
+pub async fn privacy_get(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    csrf_token: CsrfToken,
+) -> WebResult<Html<String>> {
+    let mut ctx = Context::new();
+    csrf_token.insert_into(&mut ctx);
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    flash::insert_messages(&cookies, &secret_key, &mut ctx).map_err(PrivacyError::Utils)?;
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "privacy.html")
+        .map_err(PrivacyError::Tera)?;
+
+    Ok(Html(body))
+}
+
+#[tracing::instrument(name = "privacy_post", skip_all)]
+pub async fn privacy_post(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    Form(form): Form<DataRequestForm>,
+) -> WebResult<Redirect> {
+    // Errors are logged by the instrumented function, the response is the same either way.
+    tokio::spawn(try_send_data_request_email(app_state.clone(), form.email));
+
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    flash::add(
+        &cookies,
+        &secret_key,
+        FLASH_INFO_MSG,
+        "If the address is subscribed to the newsletter, a link to access its data was sent to it.",
+    );
+
+    Ok(Redirect::to("/privacy"))
+}
+
+/// The page the link leads to, the data can be downloaded or erased from here.
+#[tracing::instrument(name = "privacy_data_get", skip_all)]
+pub async fn privacy_data_get(
+    State(app_state): State<AppState>,
+    csrf_token: CsrfToken,
+    Query(query): Query<DataRequestQuery>,
+) -> WebResult<Html<String>> {
+    let subscriber_id = verify_token(&app_state, &query.token)?;
+    let email: String = sqlx::query_scalar("SELECT email FROM subscriptions WHERE id = $1")
+        .bind(subscriber_id)
+        .fetch_optional(app_state.database_mgr.db())
+        .await?
+        .ok_or(PrivacyError::LinkInvalid)?;
+
+    let mut ctx = Context::new();
+    csrf_token.insert_into(&mut ctx);
+    ctx.insert("token", &query.token);
+    ctx.insert("email", &email);
+    ctx.insert("erased", &false);
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "privacy_data.html")
+        .map_err(PrivacyError::Tera)?;
+
+    Ok(Html(body))
+}
+
+/// Downloads everything that is stored about the subscriber as a JSON file.
+#[tracing::instrument(name = "privacy_data_export", skip_all, fields(subscriber_id))]
+pub async fn privacy_data_export(
+    State(app_state): State<AppState>,
+    Query(query): Query<DataRequestQuery>,
+) -> WebResult<Response> {
+    let subscriber_id = verify_token(&app_state, &query.token)?;
+    tracing::Span::current().record("subscriber_id", subscriber_id.to_string());
+    let export = subscriber_data_export(&app_state, subscriber_id).await?;
+    info!("Subscriber data exported!");
+
+    let disposition = HeaderValue::from_static(r#"attachment; filename="newsletter-data.json""#);
+    Ok(([(header::CONTENT_DISPOSITION, disposition)], Json(export)).into_response())
+}
+
+#[tracing::instrument(name = "privacy_data_erase", skip_all, fields(subscriber_id))]
+pub async fn privacy_data_erase(
+    State(app_state): State<AppState>,
+    meta: RequestMeta,
+    Form(form): Form<DataRequestQuery>,
+) -> WebResult<Html<String>> {
+    let subscriber_id = verify_token(&app_state, &form.token)?;
+    tracing::Span::current().record("subscriber_id", subscriber_id.to_string());
+    subscriber_erase(&app_state, &meta, subscriber_id).await?;
+    info!("Subscriber data erased!");
+
+    let mut ctx = Context::new();
+    ctx.insert("erased", &true);
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "privacy_data.html")
+        .map_err(PrivacyError::Tera)?;
+
+    Ok(Html(body))
+}
+
+// ###################################
+// ->   HELPERS
+// ###################################
+fn verify_token(app_state: &AppState, token: &str) -> Result<Uuid, PrivacyError> {
+    DataRequestToken::verify(token, app_state.hmac_secret.expose_secret(), Utc::now()).map_err(
+        |e| {
+            info!(error = %e, "Invalid data request link.");
+            PrivacyError::LinkInvalid
+        },
+    )
+}
+
+async fn subscriber_data_export(
+    app_state: &AppState,
+    subscriber_id: Uuid,
+) -> Result<SubscriberDataExport, PrivacyError> {
+    let db = app_state.database_mgr.db();
+    // One snapshot of all the tables.
+    let mut transaction = db.begin().await?;
+    transaction
+        .execute("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+        .await?;
+
+    let subscriber: SubscriberRecord = sqlx::query_as(
+        r#"
+        SELECT id, email, name, status, subscribed_at, consent_source FROM subscriptions
+        WHERE id = $1
+        "#,
+    )
+    .bind(subscriber_id)
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(PrivacyError::LinkInvalid)?;
+    let timestamps =
+        |query: &'static str| sqlx::query_scalar::<_, DateTime<Utc>>(query).bind(subscriber_id);
+    let confirmation_links_created_at = timestamps(
+        "SELECT created_at FROM subscription_tokens WHERE subscriber_id = $1 ORDER BY created_at",
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let confirmation_emails_sent_at = timestamps(
+        "SELECT sent_at FROM confirmation_emails_sent WHERE subscriber_id = $1 ORDER BY sent_at",
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let data_request_emails_sent_at = timestamps(
+        "SELECT sent_at FROM data_request_emails_sent WHERE subscriber_id = $1 ORDER BY sent_at",
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let events = subscription_events::history(&mut *transaction, subscriber_id).await?;
+    let pending_deliveries: Vec<PendingDelivery> = sqlx::query_as(
+        r#"
+        SELECT q.newsletter_issue_id, i.title FROM issue_delivery_queue q
+        JOIN newsletter_issues i ON i.newsletter_issue_id = q.newsletter_issue_id
+        WHERE q.subscriber_email = $1
+        ORDER BY i.published_at
+        "#,
+    )
+    .bind(&subscriber.email)
+    .fetch_all(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    let rfc3339 = |dates: Vec<DateTime<Utc>>| {
+        dates
+            .into_iter()
+            .map(|date| date.to_rfc3339())
+            .collect::<Vec<_>>()
+    };
+    Ok(SubscriberDataExport {
+        exported_at: Utc::now().to_rfc3339(),
+        subscriber: SubscriberData {
+            id: subscriber.id,
+            email: subscriber.email,
+            name: subscriber.name,
+            status: subscriber.status,
+            subscribed_at: subscriber.subscribed_at.to_rfc3339(),
+            consent_source: subscriber.consent_source,
+        },
+        confirmation_links_created_at: rfc3339(confirmation_links_created_at),
+        confirmation_emails_sent_at: rfc3339(confirmation_emails_sent_at),
+        data_request_emails_sent_at: rfc3339(data_request_emails_sent_at),
+        events: events
+            .into_iter()
+            .map(|event| EventData {
+                kind: event.kind,
+                source: event.source,
+                ip: event.ip,
+                user_agent: event.user_agent,
+                occurred_at: event.occurred_at.to_rfc3339(),
+            })
+            .collect(),
+        pending_deliveries,
+    })
+}
+
+/// Deletes the subscriber with its tokens and pending deliveries, the email records are deleted with the subscriber.
+/// The erasure is recorded without the personal data.
+async fn subscriber_erase(
+    app_state: &AppState,
+    meta: &RequestMeta,
+    subscriber_id: Uuid,
+) -> Result<(), PrivacyError> {
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let query =
+        sqlx::query("DELETE FROM subscription_tokens WHERE subscriber_id = $1").bind(subscriber_id);
+    transaction.execute(query).await?;
+    let email: String = sqlx::query_scalar(
+        r#"
+        DELETE FROM subscriptions
+        WHERE id = $1
+        RETURNING email
+        "#,
+    )
+    .bind(subscriber_id)
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(PrivacyError::LinkInvalid)?;
+    let query =
+        sqlx::query("DELETE FROM issue_delivery_queue WHERE subscriber_email = $1").bind(&email);
+    transaction.execute(query).await?;
+
+    let query = sqlx::query(
+        r#"
+        INSERT INTO data_erasures (erasure_id, subscriber_id, erased_at)
+        VALUES ($1, $2, $3)
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(subscriber_id)
+    .bind(Utc::now());
+    transaction.execute(query).await?;
+    subscription_events::erase(&mut transaction, subscriber_id, EventSource::Privacy, meta).await?;
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Emails the link to the data of the subscriber, if the address is subscribed.
+///
+/// At most `DataRequestConfig::max_emails_per_day` emails are sent to a single address in 24 hours.
+#[tracing::instrument(name = "Sending data request email", skip_all, err)]
+async fn try_send_data_request_email(
+    app_state: AppState,
+    email: String,
+) -> Result<(), PrivacyError> {
+    let config = &get_or_init_config().data_request_config;
+    let Ok(email) = ValidEmail::parse(email) else {
+        info!("Invalid email address, no email was sent.");
+        return Ok(());
+    };
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+
+    // Locking the subscriber makes the concurrent requests wait for each other, so the limit holds.
+    let subscriber: Option<(Uuid, String)> = sqlx::query_as(
+        r#"
+        SELECT id, name FROM subscriptions
+        WHERE email = $1
+        FOR UPDATE
+        "#,
+    )
+    .bind(email.as_ref())
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let Some((subscriber_id, name)) = subscriber else {
+        info!("The address isn't subscribed, no email was sent.");
+        return Ok(());
+    };
+
+    let now = Utc::now();
+    let n_recent: i64 = sqlx::query_scalar(
+        r#"
+        SELECT count(*) FROM data_request_emails_sent
+        WHERE subscriber_id = $1 AND sent_at > $2 - interval '24 hours'
+        "#,
+    )
+    .bind(subscriber_id)
+    .bind(now)
+    .fetch_one(&mut *transaction)
+    .await?;
+    if n_recent >= config.max_emails_per_day {
+        info!("Too many data request emails were sent recently, no email was sent.");
+        return Ok(());
+    }
+    let query = sqlx::query(
+        r#"
+        INSERT INTO data_request_emails_sent (subscriber_id, sent_at)
+        VALUES ($1, $2)
+        "#,
+    )
+    .bind(subscriber_id)
+    .bind(now);
+    transaction.execute(query).await?;
+    transaction.commit().await?;
+
+    let token = DataRequestToken::generate(
+        subscriber_id,
+        now + config.link_expiry(),
+        app_state.hmac_secret.expose_secret(),
+    );
+    let data_link = format!(
+        "{}/privacy/data?token={}",
+        app_state.base_url,
+        token.as_str()
+    );
+    let mut ctx = Context::new();
+    ctx.insert("name", &name);
+    ctx.insert("data_link", &data_link);
+    ctx.insert("expiry_minutes", &config.link_expiry().num_minutes());
+    let tera = app_state.templ_mgr.tera();
+    let html_email = tera.render("emails/data_request.html", &ctx)?;
+    let plain_email = tera.render("emails/data_request.txt", &ctx)?;
+
+    app_state
+        .email_client
+        .send_single_email(&email, "Your newsletter data", &html_email, &plain_email)
+        .await?;
+    info!("Data request email sent!");
+
+    Ok(())
+}
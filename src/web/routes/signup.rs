@@ -0,0 +1,275 @@
+//! Creating an admin account through the link sent in an invitation email.
+//!
+//! The invitation is used up when the account is created, the new user gets the email address
+//! and the role the owner picked when sending the invitation. Only the hashes of the tokens are stored.
+
+use axum::{
+    extract::{Query, State},
+    response::{Html, Redirect},
+    Form,
+};
+use chrono::{DateTime, Utc};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use sqlx::Executor;
+use tera::Context;
+use tower_cookies::{Cookies, Key};
+use tracing::info;
+use unicode_segmentation::UnicodeSegmentation;
+use uuid::Uuid;
+
+use crate::{
+    utils,
+    web::{
+        auth::{password, AuthError, Role},
+        flash,
+        types::EmailLinkToken,
+        CsrfToken, WebResult, FLASH_ERROR_MSG, FLASH_INFO_MSG,
+    },
+    AppState,
+};
+
+/// The usernames are also sent in the `Authorization` header, where they can't be longer than this.
+const MAX_USERNAME_LEN: usize = 256;
+
+// ###################################
+// ->   ERROR
+// ###################################
+#[derive(Debug, thiserror::Error)]
+pub enum SignupError {
+    #[error("the invitation link is invalid or expired")]
+    LinkInvalid,
+    #[error("The username is invalid, {0}!")]
+    UsernameInvalid(&'static str),
+    #[error("The username is already taken!")]
+    UsernameTaken,
+    #[error("An account with this email address already exists!")]
+    EmailTaken,
+    #[error("The passwords don't match!")]
+    NewPasswordMismatch,
+    #[error("The password is too weak, {0}!")]
+    NewPasswordTooWeak(String),
+
+    #[error("authentication error: {0}")]
+    Auth(#[from] AuthError),
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("tera template render error: {0}")]
+    Tera(#[from] tera::Error),
+    #[error("utils error: {0}")]
+    Utils(#[from] utils::UtilsError),
+}
+
+impl SignupError {
+    /// The errors caused by the submitted form, they are shown on the signup page instead of failing the request.
+    fn is_rejection(&self) -> bool {
+        use SignupError::*;
+        matches!(
+            self,
+            UsernameInvalid(_)
+                | UsernameTaken
+                | EmailTaken
+                | NewPasswordMismatch
+                | NewPasswordTooWeak(_)
+        )
+    }
+}
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+#[derive(Deserialize)]
+pub struct SignupQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct SignupForm {
+    token: String,
+    username: String,
+    new_password: SecretString,
+    new_password_check: SecretString,
+}
+
+// ###################################
+// ->   HANDLERS
+// ###################################
+#[tracing::instrument(name = "signup_get", skip_all)]
+pub async fn signup_get(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    csrf_token: CsrfToken,
+    Query(query): Query<SignupQuery>,
+) -> WebResult<Html<String>> {
+    let token = EmailLinkToken::parse(&query.token).map_err(|_| SignupError::LinkInvalid)?;
+    let invitation: Option<(String, Role, DateTime<Utc>)> = sqlx::query_as(
+        r#"
+        SELECT email, role, expires_at FROM user_invitations
+        WHERE token_hash = $1
+        "#,
+    )
+    .bind(token.hash())
+    .fetch_optional(app_state.database_mgr.db())
+    .await?;
+    let Some((email, role, expires_at)) = invitation else {
+        return Err(SignupError::LinkInvalid.into());
+    };
+    if expires_at <= Utc::now() {
+        return Err(SignupError::LinkInvalid.into());
+    }
+
+    let mut ctx = Context::new();
+    csrf_token.insert_into(&mut ctx);
+    ctx.insert("token", token.as_str());
+    ctx.insert("email", &email);
+    ctx.insert("role", role.as_ref());
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    if let Some(msg) =
+        flash::take(&cookies, &secret_key, FLASH_ERROR_MSG).map_err(SignupError::Utils)?
+    {
+        ctx.insert("error_message", &msg);
+    }
+    let body = app_state
+        .templ_mgr
+        .render_html_to_string(&ctx, "signup.html")
+        .map_err(SignupError::Tera)?;
+
+    Ok(Html(body))
+}
+
+#[tracing::instrument(name = "signup_post", skip_all, fields(username = form.username))]
+pub async fn signup_post(
+    State(app_state): State<AppState>,
+    cookies: Cookies,
+    Form(form): Form<SignupForm>,
+) -> WebResult<Redirect> {
+    let secret_key = Key::from(app_state.cookie_secret.expose_secret());
+    let token = EmailLinkToken::parse(&form.token).map_err(|_| SignupError::LinkInvalid)?;
+
+    match create_user(&app_state, &token, form).await {
+        Ok(user_id) => {
+            info!(%user_id, "User signed up!");
+            flash::add(
+                &cookies,
+                &secret_key,
+                FLASH_INFO_MSG,
+                "Your account was created, you can log in now.",
+            );
+            Ok(Redirect::to("/login"))
+        }
+        // The invitation isn't used up, so the user can try again.
+        Err(e) if e.is_rejection() => {
+            flash::add(&cookies, &secret_key, FLASH_ERROR_MSG, e.to_string());
+            Ok(Redirect::to(&format!("/signup?token={}", token.as_str())))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// ###################################
+// ->   HELPERS
+// ###################################
+fn check_username(username: &str) -> Result<(), SignupError> {
+    if username.is_empty() {
+        return Err(SignupError::UsernameInvalid("it can't be empty"));
+    }
+    if username.graphemes(true).count() > MAX_USERNAME_LEN {
+        return Err(SignupError::UsernameInvalid("it's too long"));
+    }
+    // The `Authorization` header splits the username from the password on the first colon.
+    if username.contains(':') || username.chars().any(char::is_control) {
+        return Err(SignupError::UsernameInvalid(
+            "it can't contain colons or control characters",
+        ));
+    }
+    Ok(())
+}
+
+/// Validates the form, uses up the invitation and creates the user, returns the ID of the new user.
+async fn create_user(
+    app_state: &AppState,
+    token: &EmailLinkToken,
+    form: SignupForm,
+) -> Result<Uuid, SignupError> {
+    let username = form.username.trim();
+    check_username(username)?;
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        return Err(SignupError::NewPasswordMismatch);
+    }
+    match password::check_strength(&form.new_password) {
+        Err(AuthError::PasswordTooWeak(reason)) => {
+            return Err(SignupError::NewPasswordTooWeak(reason))
+        }
+        res => res?,
+    }
+    let password_hash = password::hash_new_to_string_async(form.new_password).await?;
+
+    let mut transaction = app_state.database_mgr.db().begin().await?;
+    let (email, role, expires_at): (String, Role, DateTime<Utc>) = sqlx::query_as(
+        r#"
+        DELETE FROM user_invitations
+        WHERE token_hash = $1
+        RETURNING email, role, expires_at
+        "#,
+    )
+    .bind(token.hash())
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(SignupError::LinkInvalid)?;
+    if expires_at <= Utc::now() {
+        // Deleting the expired invitation is fine.
+        transaction.commit().await?;
+        return Err(SignupError::LinkInvalid);
+    }
+
+    let user_id = Uuid::new_v4();
+    let query = sqlx::query(
+        r#"
+        INSERT INTO users (user_id, username, password_hash, email, role)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(user_id)
+    .bind(username)
+    .bind(password_hash.expose_secret())
+    .bind(&email)
+    .bind(role);
+    match transaction.execute(query).await {
+        Ok(_) => {}
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Err(match e.constraint() {
+                Some("users_email_key") => SignupError::EmailTaken,
+                _ => SignupError::UsernameTaken,
+            });
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let query = sqlx::query(
+        r#"
+        DELETE FROM user_invitations
+        WHERE email = $1
+        "#,
+    )
+    .bind(&email);
+    transaction.execute(query).await?;
+    transaction.commit().await?;
+
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use claims::{assert_err, assert_ok};
+
+    #[test]
+    fn usernames_are_checked() {
+        assert_ok!(check_username("editor"));
+        assert_ok!(check_username("Ana Novak"));
+        assert_err!(check_username(""));
+        assert_err!(check_username("with:colon"));
+        assert_err!(check_username("new\nline"));
+        assert_err!(check_username(&"a".repeat(MAX_USERNAME_LEN + 1)));
+    }
+}
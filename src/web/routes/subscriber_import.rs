@@ -0,0 +1,665 @@
+//! Importing subscribers from a CSV file, used by the admin upload and by the API.
+//!
+//! The file is parsed while it's streamed in, every row is validated like a subscription and imported on its own.
+//! The rows are imported either as confirmed, with a note on where their consent came from,
+//! or as pending confirmation, those get the usual confirmation email. The emails are queued once their rows
+//! are committed and sent in batches by a background task, the import doesn't wait for them.
+//! The addresses that are already
+//! in the list are skipped, the unsubscribed ones aren't subscribed again. The outcome of every row
+//! is collected in a report that is returned as a CSV file.
+
+use std::{borrow::Cow, collections::HashSet};
+
+use axum::{
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use csv_core::ReadRecordResult;
+use uuid::Uuid;
+
+use crate::{
+    utils::csv_field,
+    web::{
+        subscription_events::{self, EventKind, EventSource, RequestMeta},
+        types::{DeserSubscriber, SubscriptionToken, ValidSubscriber},
+    },
+    AppState,
+};
+
+use super::api::subscribe::{
+    insert_subscription_token, record_confirmation_email, send_confirmation_emails, SubscribeError,
+};
+
+/// The rows after this many aren't imported, a bigger list has to be split into more files.
+pub const MAX_ROWS: usize = 10_000;
+/// The bytes after this many aren't read.
+pub const MAX_BYTES: usize = 10 * 1024 * 1024;
+/// The longest row that is accepted, a name and an email address are much shorter.
+const MAX_ROW_BYTES: usize = 16 * 1024;
+const MAX_FIELDS: usize = 64;
+const MAX_CONSENT_SOURCE_LEN: usize = 500;
+/// The confirmation emails are queued in batches of this many, the rest once the import is done.
+const CONFIRMATIONS_BATCH_SIZE: usize = 500;
+
+// ###################################
+// ->   ERROR
+// ###################################
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error("invalid import: {0}")]
+    InvalidInput(String),
+    #[error("unable to read the file: {0}")]
+    Body(String),
+
+    #[error("subscribe error: {0}")]
+    Subscribe(#[from] SubscribeError),
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+}
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+/// The status the imported subscribers get.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportStatus {
+    /// The subscribers already agreed to the newsletter, `consent_source` records where and when.
+    Confirmed { consent_source: String },
+    /// The subscribers get a confirmation email, like when they subscribe themselves.
+    Pending,
+}
+
+impl ImportStatus {
+    /// Parses the options of the import, as sent by the upload form or in the query of the API.
+    pub fn parse(status: &str, consent_source: Option<&str>) -> Result<Self, ImportError> {
+        match status {
+            "confirmed" => {
+                let consent_source = consent_source.map(str::trim).unwrap_or_default();
+                if consent_source.is_empty() {
+                    return Err(ImportError::InvalidInput(
+                        "the confirmed subscribers need a consent source".into(),
+                    ));
+                }
+                if consent_source.chars().count() > MAX_CONSENT_SOURCE_LEN {
+                    return Err(ImportError::InvalidInput(format!(
+                        "the consent source can't be longer than {MAX_CONSENT_SOURCE_LEN} characters"
+                    )));
+                }
+                Ok(ImportStatus::Confirmed {
+                    consent_source: consent_source.to_string(),
+                })
+            }
+            "pending_confirmation" => Ok(ImportStatus::Pending),
+            other => Err(ImportError::InvalidInput(format!(
+                "unknown import status: {other}"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum_macros::AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum RowOutcome {
+    Accepted,
+    Skipped,
+    Invalid,
+}
+
+#[derive(Debug)]
+pub struct ReportRow {
+    /// The number of the row in the file, the header is the first row.
+    pub row: usize,
+    pub email: String,
+    pub name: String,
+    pub outcome: RowOutcome,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub rows: Vec<ReportRow>,
+}
+
+impl ImportReport {
+    pub fn count(&self, outcome: RowOutcome) -> usize {
+        self.rows
+            .iter()
+            .filter(|row| row.outcome == outcome)
+            .count()
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("row,email,name,outcome,reason\r\n");
+        for row in &self.rows {
+            let fields = [
+                Cow::Owned(row.row.to_string()),
+                csv_field(&row.email),
+                csv_field(&row.name),
+                Cow::Borrowed(row.outcome.as_ref()),
+                csv_field(&row.reason),
+            ];
+            csv.push_str(&fields.join(","));
+            csv.push_str("\r\n");
+        }
+        csv
+    }
+}
+
+/// The report is downloaded as a CSV file.
+impl IntoResponse for ImportReport {
+    fn into_response(self) -> Response {
+        let filename = format!(
+            "subscribers-import-{}.csv",
+            Utc::now().format("%Y%m%d-%H%M%S")
+        );
+        let disposition = HeaderValue::from_str(&format!(r#"attachment; filename="{filename}""#))
+            .expect("valid header value");
+        (
+            [
+                (
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("text/csv; charset=utf-8"),
+                ),
+                (header::CONTENT_DISPOSITION, disposition),
+            ],
+            self.to_csv(),
+        )
+            .into_response()
+    }
+}
+
+/// The positions of the name and the email address in the rows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Columns {
+    name: usize,
+    email: usize,
+}
+
+impl Columns {
+    /// Without a header the rows are `name,email`.
+    const DEFAULT: Columns = Columns { name: 0, email: 1 };
+
+    /// Returns the columns if the row is a header, a header has an `email` column.
+    fn from_header(fields: &[String]) -> Result<Option<Self>, ImportError> {
+        let position = |column: &str| {
+            fields
+                .iter()
+                .position(|field| field.trim().eq_ignore_ascii_case(column))
+        };
+        let Some(email) = position("email") else {
+            return Ok(None);
+        };
+        let name = position("name")
+            .ok_or_else(|| ImportError::InvalidInput("the header has no name column".into()))?;
+        Ok(Some(Columns { name, email }))
+    }
+}
+
+/// Imports the subscribers from a CSV file that is fed to it in chunks.
+pub struct SubscriberImport {
+    app_state: AppState,
+    status: ImportStatus,
+    /// The request of the upload, the events of the imported subscribers are recorded with it.
+    meta: RequestMeta,
+    parser: CsvParser,
+    columns: Option<Columns>,
+    /// The addresses from the earlier rows of the file.
+    seen: HashSet<String>,
+    /// The imported pending subscribers whose confirmation emails aren't queued yet.
+    confirmations: Vec<(ValidSubscriber, SubscriptionToken)>,
+    report: ImportReport,
+    rows: usize,
+    bytes: usize,
+    /// Set once a limit was reached, the rest of the file is ignored.
+    stopped: bool,
+}
+
+impl SubscriberImport {
+    pub fn new(app_state: AppState, status: ImportStatus, meta: RequestMeta) -> Self {
+        SubscriberImport {
+            app_state,
+            status,
+            meta,
+            parser: CsvParser::new(),
+            columns: None,
+            seen: HashSet::new(),
+            confirmations: Vec::new(),
+            report: ImportReport::default(),
+            rows: 0,
+            bytes: 0,
+            stopped: false,
+        }
+    }
+
+    /// Whether the rest of the file is ignored, the caller can stop reading it.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Imports the rows that end in this chunk of the file.
+    pub async fn feed(&mut self, chunk: &[u8]) -> Result<(), ImportError> {
+        if self.stopped {
+            return Ok(());
+        }
+        self.bytes += chunk.len();
+        if self.bytes > MAX_BYTES {
+            self.stop(format!(
+                "only the first {MAX_BYTES} bytes of a file are read"
+            ));
+            return Ok(());
+        }
+        // Spreadsheets like to start the UTF-8 files with a byte order mark.
+        let chunk = if self.rows == 0 && !self.parser.is_started() {
+            chunk.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(chunk)
+        } else {
+            chunk
+        };
+
+        let mut records = Vec::new();
+        let parsed = self.parser.parse(chunk, false, &mut records);
+        self.import_records(records).await?;
+        if self.confirmations.len() >= CONFIRMATIONS_BATCH_SIZE {
+            self.confirmation_emails_queue();
+        }
+        if let Err(reason) = parsed {
+            self.stop(reason);
+        }
+        Ok(())
+    }
+
+    /// Imports the last row and returns the report.
+    pub async fn finish(mut self) -> Result<ImportReport, ImportError> {
+        if !self.stopped {
+            let mut records = Vec::new();
+            let parsed = self.parser.parse(&[], true, &mut records);
+            self.import_records(records).await?;
+            if let Err(reason) = parsed {
+                self.stop(reason);
+            }
+        }
+
+        self.confirmation_emails_queue();
+        let report = std::mem::take(&mut self.report);
+        tracing::info!(
+            accepted = report.count(RowOutcome::Accepted),
+            skipped = report.count(RowOutcome::Skipped),
+            invalid = report.count(RowOutcome::Invalid),
+            "Subscribers imported!"
+        );
+        Ok(report)
+    }
+
+    /// Sends the confirmation emails of the committed rows in a background task.
+    /// The emails that can't be sent are only logged, the subscribers can ask for a new one by subscribing again.
+    fn confirmation_emails_queue(&mut self) {
+        if self.confirmations.is_empty() {
+            return;
+        }
+        let confirmations = std::mem::take(&mut self.confirmations);
+        let app_state = self.app_state.clone();
+        tokio::spawn(async move {
+            match send_confirmation_emails(&app_state, &confirmations).await {
+                Ok(report) => {
+                    for failed in &report.failed {
+                        tracing::warn!(
+                            "Unable to send the confirmation email of an imported subscriber to {} - {}",
+                            failed.to.as_ref(),
+                            failed.reason
+                        );
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Unable to send the confirmation emails of an import");
+                }
+            }
+        });
+    }
+
+    /// The reason is reported on a row after the last row that was read.
+    fn stop(&mut self, reason: impl Into<String>) {
+        self.stopped = true;
+        self.report.rows.push(ReportRow {
+            row: self.rows + 1,
+            email: String::new(),
+            name: String::new(),
+            outcome: RowOutcome::Skipped,
+            reason: format!("the import stopped, {}", reason.into()),
+        });
+    }
+
+    async fn import_records(&mut self, records: Vec<Vec<Vec<u8>>>) -> Result<(), ImportError> {
+        for record in records {
+            if self.stopped {
+                break;
+            }
+            self.rows += 1;
+            let row = self.rows;
+            let fields = match record
+                .into_iter()
+                .map(String::from_utf8)
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(fields) => fields,
+                Err(_) => {
+                    self.report_row(row, "", "", RowOutcome::Invalid, "the row isn't UTF-8");
+                    continue;
+                }
+            };
+            if row == 1 {
+                if let Some(columns) = Columns::from_header(&fields)? {
+                    self.columns = Some(columns);
+                    continue;
+                }
+            }
+            if row > MAX_ROWS {
+                self.rows -= 1;
+                self.stop(format!(
+                    "only the first {MAX_ROWS} rows of a file are imported"
+                ));
+                break;
+            }
+            self.import_row(row, fields).await?;
+        }
+        Ok(())
+    }
+
+    async fn import_row(&mut self, row: usize, fields: Vec<String>) -> Result<(), ImportError> {
+        let columns = self.columns.unwrap_or(Columns::DEFAULT);
+        let field = |index: usize| fields.get(index).map(|f| f.trim()).unwrap_or_default();
+        let (name, email) = (field(columns.name), field(columns.email));
+
+        let subscriber = match ValidSubscriber::try_from(DeserSubscriber::new(
+            name.to_string(),
+            email.to_string(),
+        )) {
+            Ok(subscriber) => subscriber,
+            Err(e) => {
+                self.report_row(row, email, name, RowOutcome::Invalid, e.to_string());
+                return Ok(());
+            }
+        };
+        if !self.seen.insert(subscriber.email.as_ref().to_string()) {
+            self.report_row(
+                row,
+                email,
+                name,
+                RowOutcome::Skipped,
+                "the address is on an earlier row",
+            );
+            return Ok(());
+        }
+
+        let (outcome, reason) = self.insert(&subscriber).await?;
+        self.report_row(row, email, name, outcome, reason);
+        Ok(())
+    }
+
+    /// Inserts the subscriber, the confirmation emails of the pending ones are queued once they are committed.
+    async fn insert(
+        &mut self,
+        subscriber: &ValidSubscriber,
+    ) -> Result<(RowOutcome, String), ImportError> {
+        let mut transaction = self.app_state.database_mgr.db().begin().await?;
+        let (status, consent_source) = match &self.status {
+            ImportStatus::Confirmed { consent_source } => ("confirmed", Some(consent_source)),
+            ImportStatus::Pending => ("pending_confirmation", None),
+        };
+        let subscriber_id: Option<Uuid> = sqlx::query_scalar(
+            r#"
+            INSERT INTO subscriptions (id, email, name, subscribed_at, status, consent_source)
+            VALUES ($1, $2, $3, now(), $4, $5)
+            ON CONFLICT (email) DO NOTHING
+            RETURNING id
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(subscriber.email.as_ref())
+        .bind(subscriber.name.as_ref())
+        .bind(status)
+        .bind(consent_source)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        let Some(subscriber_id) = subscriber_id else {
+            let existing: String =
+                sqlx::query_scalar("SELECT status FROM subscriptions WHERE email = $1")
+                    .bind(subscriber.email.as_ref())
+                    .fetch_one(&mut *transaction)
+                    .await?;
+            let reason = format!(
+                "the address is already in the list ({})",
+                existing.replace('_', " ")
+            );
+            return Ok((RowOutcome::Skipped, reason));
+        };
+
+        let kinds = match self.status {
+            ImportStatus::Confirmed { .. } => [EventKind::Subscribed, EventKind::Confirmed],
+            ImportStatus::Pending => [EventKind::Subscribed, EventKind::ConfirmationSent],
+        };
+        for kind in kinds {
+            subscription_events::record(
+                &mut *transaction,
+                subscriber_id,
+                kind,
+                EventSource::Import,
+                &self.meta,
+            )
+            .await?;
+        }
+        if self.status == ImportStatus::Pending {
+            let subscription_token = SubscriptionToken::generate();
+            insert_subscription_token(&mut transaction, &subscription_token, subscriber_id).await?;
+            record_confirmation_email(&mut transaction, subscriber_id).await?;
+            transaction.commit().await?;
+            self.confirmations
+                .push((subscriber.clone(), subscription_token));
+            return Ok((
+                RowOutcome::Accepted,
+                "the confirmation email was queued".into(),
+            ));
+        }
+        transaction.commit().await?;
+
+        Ok((RowOutcome::Accepted, "imported as confirmed".into()))
+    }
+
+    fn report_row(
+        &mut self,
+        row: usize,
+        email: &str,
+        name: &str,
+        outcome: RowOutcome,
+        reason: impl Into<String>,
+    ) {
+        self.report.rows.push(ReportRow {
+            row,
+            email: email.to_string(),
+            name: name.to_string(),
+            outcome,
+            reason: reason.into(),
+        });
+    }
+}
+
+/// The rows that were committed get their confirmation emails even if the import fails halfway.
+impl Drop for SubscriberImport {
+    fn drop(&mut self) {
+        self.confirmation_emails_queue();
+    }
+}
+
+/// A CSV parser that is fed the file in chunks, the rows can span the chunks.
+struct CsvParser {
+    reader: csv_core::Reader,
+    output: Vec<u8>,
+    output_len: usize,
+    ends: Vec<usize>,
+    ends_len: usize,
+    started: bool,
+}
+
+impl CsvParser {
+    fn new() -> Self {
+        CsvParser {
+            reader: csv_core::Reader::new(),
+            output: vec![0; 1024],
+            output_len: 0,
+            ends: vec![0; 8],
+            ends_len: 0,
+            started: false,
+        }
+    }
+
+    fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// Parses the chunk and adds the complete records to `records`, the last chunk of the file has to be `eof`.
+    /// Fails if a row is too long, the records before it are still added.
+    fn parse(
+        &mut self,
+        mut input: &[u8],
+        eof: bool,
+        records: &mut Vec<Vec<Vec<u8>>>,
+    ) -> Result<(), &'static str> {
+        if input.is_empty() && !eof {
+            return Ok(());
+        }
+        self.started = true;
+        loop {
+            let (result, n_in, n_out, n_ends) = self.reader.read_record(
+                input,
+                &mut self.output[self.output_len..],
+                &mut self.ends[self.ends_len..],
+            );
+            input = &input[n_in..];
+            self.output_len += n_out;
+            self.ends_len += n_ends;
+
+            match result {
+                ReadRecordResult::InputEmpty | ReadRecordResult::End => return Ok(()),
+                ReadRecordResult::OutputFull => {
+                    if self.output.len() >= MAX_ROW_BYTES {
+                        return Err("a row is too long");
+                    }
+                    self.output.resize(self.output.len() * 2, 0);
+                }
+                ReadRecordResult::OutputEndsFull => {
+                    if self.ends.len() >= MAX_FIELDS {
+                        return Err("a row has too many columns");
+                    }
+                    self.ends.resize(self.ends.len() * 2, 0);
+                }
+                ReadRecordResult::Record => {
+                    let mut start = 0;
+                    let fields = self.ends[..self.ends_len]
+                        .iter()
+                        .map(|&end| {
+                            let field = self.output[start..end].to_vec();
+                            start = end;
+                            field
+                        })
+                        .collect::<Vec<_>>();
+                    // The empty lines.
+                    if !(fields.len() == 1 && fields[0].is_empty()) {
+                        records.push(fields);
+                    }
+                    self.output_len = 0;
+                    self.ends_len = 0;
+                    // An empty input would be taken as the end of the file.
+                    if input.is_empty() && !eof {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use claims::assert_err;
+
+    fn parse_chunks(chunks: &[&[u8]]) -> Vec<Vec<String>> {
+        let mut parser = CsvParser::new();
+        let mut records = Vec::new();
+        for chunk in chunks {
+            parser.parse(chunk, false, &mut records).unwrap();
+        }
+        parser.parse(&[], true, &mut records).unwrap();
+        records
+            .into_iter()
+            .map(|fields| {
+                fields
+                    .into_iter()
+                    .map(|f| String::from_utf8(f).unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn records_can_span_the_chunks() {
+        let expected = vec![
+            vec!["name".to_string(), "email".to_string()],
+            vec![
+                "Le Guin, Ursula".to_string(),
+                "ursula@example.com".to_string(),
+            ],
+            vec![
+                "Octavia \"O\"\nButler".to_string(),
+                "octavia@example.com".to_string(),
+            ],
+        ];
+        let file = "name,email\r\n\"Le Guin, Ursula\",ursula@example.com\n\n\"Octavia \"\"O\"\"\nButler\",octavia@example.com";
+        assert_eq!(parse_chunks(&[file.as_bytes()]), expected);
+        // Split at every position.
+        for at in 0..file.len() {
+            let (first, second) = file.as_bytes().split_at(at);
+            assert_eq!(parse_chunks(&[first, second]), expected, "split at {at}");
+        }
+    }
+
+    #[test]
+    fn too_long_rows_are_rejected() {
+        let mut parser = CsvParser::new();
+        let mut records = Vec::new();
+        let row = format!("a,b\n{},c\n", "x".repeat(MAX_ROW_BYTES + 1));
+        assert_err!(parser.parse(row.as_bytes(), false, &mut records));
+        assert_eq!(records, vec![vec![b"a".to_vec(), b"b".to_vec()]]);
+    }
+
+    #[test]
+    fn header_picks_the_columns() -> anyhow::Result<()> {
+        let header = |fields: &[&str]| {
+            Columns::from_header(&fields.iter().map(|f| f.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            header(&["Email", " name ", "source"])?,
+            Some(Columns { name: 1, email: 0 })
+        );
+        assert_eq!(header(&["Ursula", "ursula@example.com"])?, None);
+        assert_err!(header(&["email", "full name"]));
+        Ok(())
+    }
+
+    #[test]
+    fn status_needs_a_consent_source_to_be_confirmed() -> anyhow::Result<()> {
+        assert_eq!(
+            ImportStatus::parse("pending_confirmation", None)?,
+            ImportStatus::Pending
+        );
+        assert_eq!(
+            ImportStatus::parse("confirmed", Some(" signup sheet, 2024 "))?,
+            ImportStatus::Confirmed {
+                consent_source: "signup sheet, 2024".into()
+            }
+        );
+        assert_err!(ImportStatus::parse("confirmed", Some("  ")));
+        assert_err!(ImportStatus::parse("confirmed", None));
+        assert_err!(ImportStatus::parse("unsubscribed", None));
+        Ok(())
+    }
+}
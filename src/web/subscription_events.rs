@@ -0,0 +1,208 @@
+//! The history of the subscribers, kept in the append-only `subscription_events` table.
+//!
+//! Every change of a subscription is recorded with the request that caused it: the request ID, the client IP address
+//! and the user agent, so we can prove when and how someone subscribed. The events stay after the subscriber
+//! is deleted, an erasure only removes the IP addresses and the user agents from them.
+
+use std::net::IpAddr;
+
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts},
+};
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Postgres};
+use uuid::Uuid;
+
+use crate::{
+    web::{self, ClientIp, REQUEST_ID_HEADER},
+    AppState,
+};
+
+/// The longer user agents are cut, they are only kept to tell the clients apart.
+const MAX_USER_AGENT_LEN: usize = 512;
+
+// ###################################
+// ->   STRUCTS
+// ###################################
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum_macros::AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum EventKind {
+    Subscribed,
+    ConfirmationSent,
+    Confirmed,
+    Unsubscribed,
+    Bounced,
+    Erased,
+}
+
+/// Where the change came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum_macros::AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum EventSource {
+    /// The `/api/subscribe` endpoint, the subscribe form posts to it.
+    Api,
+    ConfirmationLink,
+    /// The unsubscribe link in the newsletter, or the one-click unsubscribe of the email client.
+    UnsubscribeLink,
+    Import,
+    Admin,
+    /// The self-service data requests.
+    Privacy,
+    /// The newsletter delivery, e.g. the email provider rejected the address.
+    Delivery,
+}
+
+/// The details of the request that caused the event.
+#[derive(Debug, Clone)]
+pub struct RequestMeta {
+    pub request_id: Option<String>,
+    pub ip: IpAddr,
+    pub user_agent: Option<String>,
+}
+
+impl FromRequestParts<AppState> for RequestMeta {
+    type Rejection = web::Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|val| val.to_str().ok())
+                .map(str::to_string)
+        };
+        let request_id = header(REQUEST_ID_HEADER);
+        let user_agent = header(header::USER_AGENT.as_str())
+            .map(|agent| agent.chars().take(MAX_USER_AGENT_LEN).collect());
+        let ClientIp(ip) = ClientIp::from_request_parts(parts, state).await?;
+
+        Ok(RequestMeta {
+            request_id,
+            ip,
+            user_agent,
+        })
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct EventRecord {
+    pub kind: String,
+    pub source: String,
+    pub request_id: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+}
+
+// ###################################
+// ->   HELPERS
+// ###################################
+pub async fn record<'e, E>(
+    executor: E,
+    subscriber_id: Uuid,
+    kind: EventKind,
+    source: EventSource,
+    meta: &RequestMeta,
+) -> Result<(), sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO subscription_events
+            (event_id, subscriber_id, kind, source, request_id, ip, user_agent, occurred_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, clock_timestamp())
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(subscriber_id)
+    .bind(kind.as_ref())
+    .bind(source.as_ref())
+    .bind(&meta.request_id)
+    .bind(meta.ip.to_string())
+    .bind(&meta.user_agent)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Records an event that wasn't caused by a request, so it's recorded without the request details.
+pub async fn record_without_request<'e, E>(
+    executor: E,
+    subscriber_id: Uuid,
+    kind: EventKind,
+    source: EventSource,
+) -> Result<(), sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO subscription_events (event_id, subscriber_id, kind, source, occurred_at)
+        VALUES ($1, $2, $3, $4, clock_timestamp())
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(subscriber_id)
+    .bind(kind.as_ref())
+    .bind(source.as_ref())
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Removes the personal data from the history of the subscriber and records the erasure,
+/// the erasure itself is recorded without the IP address and the user agent.
+pub async fn erase(
+    transaction: &mut sqlx::Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    source: EventSource,
+    meta: &RequestMeta,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query(
+        r#"
+        UPDATE subscription_events SET ip = NULL, user_agent = NULL
+        WHERE subscriber_id = $1
+        "#,
+    )
+    .bind(subscriber_id);
+    transaction.execute(query).await?;
+
+    let query = sqlx::query(
+        r#"
+        INSERT INTO subscription_events (event_id, subscriber_id, kind, source, request_id, occurred_at)
+        VALUES ($1, $2, $3, $4, $5, clock_timestamp())
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(subscriber_id)
+    .bind(EventKind::Erased.as_ref())
+    .bind(source.as_ref())
+    .bind(&meta.request_id);
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+/// The history of the subscriber, the oldest event first.
+pub async fn history<'e, E>(
+    executor: E,
+    subscriber_id: Uuid,
+) -> Result<Vec<EventRecord>, sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_as(
+        r#"
+        SELECT kind, source, request_id, ip, user_agent, occurred_at FROM subscription_events
+        WHERE subscriber_id = $1
+        ORDER BY occurred_at, event_id
+        "#,
+    )
+    .bind(subscriber_id)
+    .fetch_all(executor)
+    .await
+}
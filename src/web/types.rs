@@ -1,10 +1,12 @@
 //! Most of the structs in `web` module and their implementations live here.
 //! Includes structs that need to be validated, their parsing implementations and tests for those
 
+use chrono::{DateTime, Utc};
 use derive_more::Deref;
 use rand::{rng, RngCore};
 use serde::Deserialize;
 use unicode_segmentation::UnicodeSegmentation;
+use uuid::Uuid;
 use validator::ValidateEmail;
 
 use crate::utils;
@@ -155,6 +157,139 @@ pub struct SubscribeConfirmQuery {
     pub subscription_token: String,
 }
 
+/// A Base64-URL encoded token containing the subscriber ID followed by its HMAC-SHA256 signature.
+/// It is used in the unsubscribe links, so it can't be forged for other subscribers.
+#[derive(Debug, Deref)]
+pub struct UnsubscribeToken(String);
+
+impl UnsubscribeToken {
+    /// Separates these signatures from other signatures created with the same key.
+    const PURPOSE: &'static [u8] = b"unsubscribe:";
+
+    pub fn generate(subscriber_id: Uuid, key: &[u8]) -> Self {
+        let tag = utils::hmac_sha256_sign(key, Self::signed_data(subscriber_id));
+        let token = utils::b64u_encode([subscriber_id.as_bytes().as_slice(), &tag].concat());
+
+        Self(token)
+    }
+
+    /// Parses the token and verifies its signature, returning the contained subscriber ID.
+    pub fn verify<S>(value: S, key: &[u8]) -> Result<Uuid, DataParsingError>
+    where
+        S: AsRef<str>,
+    {
+        let value = value.as_ref();
+        let decoded = utils::b64u_decode(value)
+            .map_err(|_| DataParsingError::UnsubscribeTokenInvalid(value.to_string()))?;
+        if decoded.len() != 16 + 32 {
+            return Err(DataParsingError::UnsubscribeTokenInvalid(value.to_string()));
+        }
+
+        let (id_bytes, tag) = decoded.split_at(16);
+        let subscriber_id = Uuid::from_slice(id_bytes)
+            .map_err(|_| DataParsingError::UnsubscribeTokenInvalid(value.to_string()))?;
+        utils::hmac_sha256_verify(key, Self::signed_data(subscriber_id), tag)
+            .map_err(|_| DataParsingError::TokenSignatureInvalid)?;
+
+        Ok(subscriber_id)
+    }
+
+    fn signed_data(subscriber_id: Uuid) -> Vec<u8> {
+        [Self::PURPOSE, subscriber_id.as_bytes()].concat()
+    }
+}
+
+/// A deserializable struct that contains the `token` used to unsubscribe, deserialized from the query
+#[derive(Debug, Deserialize, Deref)]
+pub struct UnsubscribeQuery {
+    pub token: String,
+}
+
+/// A Base64-URL encoded token with the subscriber ID, the expiry time and their HMAC-SHA256 signature.
+/// It is sent in the links of the data access and erasure requests, so only the owner of the address can use them.
+#[derive(Debug, Deref)]
+pub struct DataRequestToken(String);
+
+impl DataRequestToken {
+    /// A leaked unsubscribe token can't be used as a data request token and the other way around.
+    const PURPOSE: &'static [u8] = b"data-request:";
+
+    pub fn generate(subscriber_id: Uuid, expires_at: DateTime<Utc>, key: &[u8]) -> Self {
+        let expires_at = expires_at.timestamp().to_be_bytes();
+        let tag = utils::hmac_sha256_sign(key, Self::signed_data(subscriber_id, expires_at));
+        let token =
+            utils::b64u_encode([subscriber_id.as_bytes().as_slice(), &expires_at, &tag].concat());
+
+        Self(token)
+    }
+
+    /// Parses the token and verifies its signature and expiry time, returning the contained subscriber ID.
+    pub fn verify<S>(value: S, key: &[u8], now: DateTime<Utc>) -> Result<Uuid, DataParsingError>
+    where
+        S: AsRef<str>,
+    {
+        let value = value.as_ref();
+        let decoded =
+            utils::b64u_decode(value).map_err(|_| DataParsingError::DataRequestTokenInvalid)?;
+        if decoded.len() != 16 + 8 + 32 {
+            return Err(DataParsingError::DataRequestTokenInvalid);
+        }
+
+        let (id_bytes, rest) = decoded.split_at(16);
+        let (expires_at, tag) = rest.split_at(8);
+        let subscriber_id =
+            Uuid::from_slice(id_bytes).map_err(|_| DataParsingError::DataRequestTokenInvalid)?;
+        let expires_at: [u8; 8] = expires_at
+            .try_into()
+            .map_err(|_| DataParsingError::DataRequestTokenInvalid)?;
+        utils::hmac_sha256_verify(key, Self::signed_data(subscriber_id, expires_at), tag)
+            .map_err(|_| DataParsingError::TokenSignatureInvalid)?;
+        if i64::from_be_bytes(expires_at) <= now.timestamp() {
+            return Err(DataParsingError::DataRequestTokenExpired);
+        }
+
+        Ok(subscriber_id)
+    }
+
+    fn signed_data(subscriber_id: Uuid, expires_at: [u8; 8]) -> Vec<u8> {
+        [Self::PURPOSE, subscriber_id.as_bytes(), &expires_at].concat()
+    }
+}
+
+/// A random 43 character-long Base64-URL encoded token sent in the password reset and invitation links.
+/// Only its hash is stored in the database, see `EmailLinkToken::hash`.
+#[derive(Debug, Deref)]
+pub struct EmailLinkToken(String);
+
+impl EmailLinkToken {
+    /// Generates an array of 32 random bytes and encodes it to Base64-URL without padding
+    pub fn generate() -> Self {
+        let mut rand_bytes = [0u8; 32];
+        rng().fill_bytes(&mut rand_bytes);
+
+        Self(utils::b64u_encode(rand_bytes))
+    }
+
+    pub fn parse<S>(value: S) -> Result<Self, DataParsingError>
+    where
+        S: AsRef<str>,
+    {
+        let value = value.as_ref();
+
+        let decoded = utils::b64u_decode(value);
+        if decoded.is_err() || decoded.is_ok_and(|v| v.len() != 32) {
+            return Err(DataParsingError::EmailLinkTokenInvalid);
+        }
+
+        Ok(Self(value.to_string()))
+    }
+
+    /// The hex encoded SHA-256 hash of the token, as it's stored in the database.
+    pub fn hash(&self) -> String {
+        utils::sha256_hex(&self.0)
+    }
+}
+
 // ###################################
 // ->   ERROR
 // ###################################
@@ -174,6 +309,16 @@ pub enum DataParsingError {
 
     #[error("invalid subscriber token: {0}")]
     SubscriberTokenInvalid(String),
+    #[error("invalid unsubscribe token: {0}")]
+    UnsubscribeTokenInvalid(String),
+    #[error("token signature is invalid")]
+    TokenSignatureInvalid,
+    #[error("invalid email link token")]
+    EmailLinkTokenInvalid,
+    #[error("invalid data request token")]
+    DataRequestTokenInvalid,
+    #[error("data request token expired")]
+    DataRequestTokenExpired,
 
     #[error("utils error: {0}")]
     Utils(#[from] utils::UtilsError),
@@ -190,6 +335,23 @@ mod test {
     use super::*;
     use claims::{assert_err, assert_ok};
 
+    #[test]
+    fn email_link_token_parses_and_hashes() -> anyhow::Result<()> {
+        let token = EmailLinkToken::generate();
+        assert_eq!(token.len(), 43);
+
+        let parsed = EmailLinkToken::parse(token.as_str())?;
+        assert_eq!(parsed.hash(), token.hash());
+        assert_eq!(token.hash().len(), 64);
+        assert_ne!(token.hash(), EmailLinkToken::generate().hash());
+
+        assert_err!(EmailLinkToken::parse("too-short"));
+        assert_err!(EmailLinkToken::parse(
+            SubscriptionToken::generate().as_str()
+        ));
+        Ok(())
+    }
+
     #[test]
     fn subscription_token_is_86_chars_long() {
         for _ in 0..100 {
@@ -198,6 +360,80 @@ mod test {
         }
     }
 
+    #[test]
+    fn unsubscribe_token_verifies_with_the_same_key() -> anyhow::Result<()> {
+        let subscriber_id = Uuid::new_v4();
+        let token = UnsubscribeToken::generate(subscriber_id, b"key");
+        assert_eq!(
+            UnsubscribeToken::verify(token.as_str(), b"key")?,
+            subscriber_id
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unsubscribe_token_with_wrong_key_or_tampered_rejected() {
+        let token = UnsubscribeToken::generate(Uuid::new_v4(), b"key");
+        assert!(matches!(
+            UnsubscribeToken::verify(token.as_str(), b"other key"),
+            Err(DataParsingError::TokenSignatureInvalid)
+        ));
+
+        let mut tampered = utils::b64u_decode(token.as_str()).unwrap();
+        tampered[0] ^= 1;
+        assert_err!(UnsubscribeToken::verify(
+            utils::b64u_encode(tampered),
+            b"key"
+        ));
+        assert_err!(UnsubscribeToken::verify("not-a-token", b"key"));
+    }
+
+    #[test]
+    fn data_request_token_verifies_until_it_expires() -> anyhow::Result<()> {
+        let subscriber_id = Uuid::new_v4();
+        let now = Utc::now();
+        let token =
+            DataRequestToken::generate(subscriber_id, now + chrono::Duration::hours(1), b"key");
+        assert_eq!(
+            DataRequestToken::verify(token.as_str(), b"key", now)?,
+            subscriber_id
+        );
+        assert!(matches!(
+            DataRequestToken::verify(token.as_str(), b"key", now + chrono::Duration::hours(2)),
+            Err(DataParsingError::DataRequestTokenExpired)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn data_request_token_is_not_an_unsubscribe_token() {
+        let subscriber_id = Uuid::new_v4();
+        let now = Utc::now();
+        let token =
+            DataRequestToken::generate(subscriber_id, now + chrono::Duration::hours(1), b"key");
+        assert!(matches!(
+            DataRequestToken::verify(token.as_str(), b"other key", now),
+            Err(DataParsingError::TokenSignatureInvalid)
+        ));
+
+        // A later expiry time doesn't match the signature.
+        let mut tampered = utils::b64u_decode(token.as_str()).unwrap();
+        tampered[16] ^= 1;
+        assert!(matches!(
+            DataRequestToken::verify(utils::b64u_encode(tampered), b"key", now),
+            Err(DataParsingError::TokenSignatureInvalid)
+        ));
+
+        // The same key signs the unsubscribe links, their signatures don't verify here.
+        let unsubscribe_token = UnsubscribeToken::generate(subscriber_id, b"key");
+        assert_err!(DataRequestToken::verify(
+            unsubscribe_token.as_str(),
+            b"key",
+            now
+        ));
+        assert_err!(DataRequestToken::verify("not-a-token", b"key", now));
+    }
+
     #[test]
     fn name_a_256_grapheme_long_name_is_valid() {
         let name = "ё".repeat(256);
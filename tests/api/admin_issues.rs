@@ -0,0 +1,198 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use mailomat::issue_scheduler::try_publish_due_issues;
+use reqwest::StatusCode;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::helpers::{assert_resp_redir_to, TestApp};
+
+fn issue_form(title: &str) -> serde_json::Value {
+    json!({
+        "title": title,
+        "text_content": "Newsletter body as plain text",
+        "html_content": "<p>Newsletter body as HTML</p>",
+    })
+}
+
+/// Creates a draft through the admin area and returns its id.
+async fn draft_create(app: &TestApp, title: &str) -> Result<Uuid> {
+    let resp = app.admin_post("/issues", issue_form(title)).await?;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    let location = resp
+        .headers()
+        .get("Location")
+        .context("missing location header")?
+        .to_str()?;
+    let id = location
+        .strip_prefix("/admin/issues/")
+        .context("unexpected redirect")?;
+    Ok(id.parse()?)
+}
+
+async fn issue_status(app: &TestApp, id: Uuid) -> Result<(String, Option<DateTime<Utc>>)> {
+    let status = sqlx::query_as(
+        "SELECT status, scheduled_for FROM newsletter_issues WHERE newsletter_issue_id = $1",
+    )
+    .bind(id)
+    .fetch_one(app.dm.db())
+    .await?;
+    Ok(status)
+}
+
+#[tokio::test]
+async fn admin_issues_redirect_to_login_no_auth() -> Result<()> {
+    let app = TestApp::spawn().await?;
+
+    let resp = app.admin_get("/issues").await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    let resp = app.admin_post("/issues", issue_form("Title")).await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_can_edit_schedule_and_cancel_a_draft() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+
+    let id = draft_create(&app, "First draft").await?;
+    let resp = app
+        .admin_post(&format!("/issues/{id}"), issue_form("Edited draft"))
+        .await?;
+    assert_resp_redir_to(&resp, &format!("/admin/issues/{id}"));
+    let body = app.admin_get("/issues").await?.text().await?;
+    assert!(body.contains("Edited draft"));
+
+    // Schedule it in the admin's time zone.
+    let tz: Tz = "America/New_York".parse()?;
+    let send_at = (Utc::now() + Duration::days(2))
+        .with_timezone(&tz)
+        .format("%Y-%m-%dT%H:%M")
+        .to_string();
+    let resp = app
+        .admin_post(
+            &format!("/issues/{id}/schedule"),
+            json!({ "send_at": send_at, "timezone": "America/New_York" }),
+        )
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/issues");
+
+    let (status, scheduled_for) = issue_status(&app, id).await?;
+    assert_eq!(status, "scheduled");
+    let expected = tz
+        .from_local_datetime(&NaiveDateTime::parse_from_str(&send_at, "%Y-%m-%dT%H:%M")?)
+        .earliest()
+        .context("local time doesn't exist")?
+        .with_timezone(&Utc);
+    assert_eq!(scheduled_for, Some(expected));
+
+    // Drafts and scheduled issues aren't in the public archive.
+    let body = app.archive_get("/archive").await?.text().await?;
+    assert!(!body.contains("Edited draft"));
+
+    let resp = app
+        .admin_post(&format!("/issues/{id}/cancel"), json!({}))
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/issues");
+    assert_eq!(issue_status(&app, id).await?, ("draft".into(), None));
+
+    // Only a scheduled issue can be cancelled.
+    let resp = app
+        .admin_post(&format!("/issues/{id}/cancel"), json!({}))
+        .await?;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_schedule_rejects_invalid_send_times() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+    let id = draft_create(&app, "Draft").await?;
+
+    let past = (Utc::now() - Duration::hours(1))
+        .format("%Y-%m-%dT%H:%M")
+        .to_string();
+    let test_cases = [
+        (json!({ "send_at": past, "timezone": "UTC" }), "past time"),
+        (
+            json!({ "send_at": "2099-01-01T10:00", "timezone": "Not/AZone" }),
+            "unknown time zone",
+        ),
+        (
+            json!({ "send_at": "tomorrow", "timezone": "UTC" }),
+            "invalid format",
+        ),
+    ];
+    for (form, case) in test_cases {
+        let resp = app
+            .admin_post(&format!("/issues/{id}/schedule"), form)
+            .await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{case}");
+    }
+    assert_eq!(issue_status(&app, id).await?, ("draft".into(), None));
+
+    let resp = app
+        .admin_post(&format!("/issues/{}", Uuid::new_v4()), issue_form("Nope"))
+        .await?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn scheduler_publishes_due_issues() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.subscriber_confirmed_create().await?;
+    app.admin_login().await?;
+
+    let due = draft_create(&app, "Due issue").await?;
+    let later = draft_create(&app, "Later issue").await?;
+    let send_at = (Utc::now() + Duration::hours(1))
+        .format("%Y-%m-%dT%H:%M")
+        .to_string();
+    for id in [due, later] {
+        let resp = app
+            .admin_post(
+                &format!("/issues/{id}/schedule"),
+                json!({ "send_at": send_at, "timezone": "UTC" }),
+            )
+            .await?;
+        assert_resp_redir_to(&resp, "/admin/issues");
+    }
+    // Move the first issue into the past instead of waiting for it.
+    sqlx::query(
+        "UPDATE newsletter_issues SET scheduled_for = now() - interval '1 minute' WHERE newsletter_issue_id = $1",
+    )
+    .bind(due)
+    .execute(app.dm.db())
+    .await?;
+
+    assert_eq!(try_publish_due_issues(&app.app_state).await?, 1);
+    assert_eq!(try_publish_due_issues(&app.app_state).await?, 0);
+
+    assert_eq!(issue_status(&app, due).await?.0, "published");
+    assert_eq!(issue_status(&app, later).await?.0, "scheduled");
+    let queued: Vec<Uuid> =
+        sqlx::query_scalar("SELECT newsletter_issue_id FROM issue_delivery_queue")
+            .fetch_all(app.dm.db())
+            .await?;
+    assert_eq!(queued, vec![due]);
+
+    let body = app.archive_get("/archive").await?.text().await?;
+    assert!(body.contains("Due issue"));
+    assert!(!body.contains("Later issue"));
+
+    // A published issue can't be changed anymore.
+    let resp = app
+        .admin_post(&format!("/issues/{due}"), issue_form("Too late"))
+        .await?;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+    Ok(())
+}
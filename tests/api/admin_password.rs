@@ -0,0 +1,149 @@
+use anyhow::Result;
+use reqwest::StatusCode;
+use serde_json::json;
+
+use crate::helpers::{assert_resp_redir_to, http_client_build, TestApp};
+
+const NEW_PASSWORD: &str = "a brand new password 42";
+
+async fn password_page_html(app: &TestApp) -> Result<String> {
+    let resp = app.admin_get("/password").await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    Ok(resp.text().await?)
+}
+
+#[tokio::test]
+async fn change_password_redirect_to_login_no_auth() -> Result<()> {
+    let app = TestApp::spawn().await?;
+
+    let resp = app.admin_get("/password").await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    let resp = app
+        .admin_post(
+            "/password",
+            json!({
+                "current_password": app.test_user.password,
+                "new_password": NEW_PASSWORD,
+                "new_password_check": NEW_PASSWORD,
+            }),
+        )
+        .await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn change_password_rejects_invalid_forms() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+
+    let test_cases = [
+        (
+            json!({
+                "current_password": app.test_user.password,
+                "new_password": NEW_PASSWORD,
+                "new_password_check": "a different new password 42",
+            }),
+            "The new passwords don&#x27;t match!",
+        ),
+        (
+            json!({
+                "current_password": "the wrong password 42",
+                "new_password": NEW_PASSWORD,
+                "new_password_check": NEW_PASSWORD,
+            }),
+            "The current password is wrong!",
+        ),
+        (
+            json!({
+                "current_password": app.test_user.password,
+                "new_password": "short1",
+                "new_password_check": "short1",
+            }),
+            "The new password is too weak",
+        ),
+        (
+            json!({
+                "current_password": app.test_user.password,
+                "new_password": app.test_user.password,
+                "new_password_check": app.test_user.password,
+            }),
+            "The new password has to be different from the current one!",
+        ),
+    ];
+
+    for (form, expected_msg) in test_cases {
+        let resp = app.admin_post("/password", form).await?;
+        assert_resp_redir_to(&resp, "/admin/password");
+
+        let html = password_page_html(&app).await?;
+        assert!(html.contains(expected_msg), "{expected_msg}");
+        // The flash message is only shown once.
+        let html = password_page_html(&app).await?;
+        assert!(!html.contains(expected_msg), "{expected_msg}");
+    }
+
+    // The password didn't change.
+    let resp = app
+        .login_post(json!({
+            "username": app.test_user.username,
+            "password": app.test_user.password
+        }))
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn change_password_works_and_logs_out_other_sessions() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+
+    // Another session of the same admin, e.g. on a different device.
+    let other_client = http_client_build()?;
+    app.admin_login_with(&other_client).await?;
+    let dashboard_url = format!("http://{}/admin/dashboard", app.addr);
+    let resp = other_client.get(&dashboard_url).send().await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = app
+        .admin_post(
+            "/password",
+            json!({
+                "current_password": app.test_user.password,
+                "new_password": NEW_PASSWORD,
+                "new_password_check": NEW_PASSWORD,
+            }),
+        )
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/password");
+    let html = password_page_html(&app).await?;
+    assert!(html.contains("Your password was changed"));
+
+    // The current session stays logged in, the other one is logged out.
+    let resp = app.admin_dashboard_get().await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = other_client.get(&dashboard_url).send().await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    // Only the new password works from now on.
+    let resp = app
+        .login_post(json!({
+            "username": app.test_user.username,
+            "password": app.test_user.password
+        }))
+        .await?;
+    assert_resp_redir_to(&resp, "/login");
+    let resp = app
+        .login_post(json!({
+            "username": app.test_user.username,
+            "password": NEW_PASSWORD
+        }))
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use anyhow::Result;
+use reqwest::{Client, StatusCode};
+
+use crate::helpers::{assert_resp_redir_to, http_client_build, TestApp};
+
+/// Returns the revoke paths of the sessions listed on the sessions page, the current session is marked.
+async fn listed_sessions(app: &TestApp) -> Result<Vec<(String, bool)>> {
+    let resp = app.admin_get("/sessions").await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let html = resp.text().await?;
+
+    let sessions = html
+        .split("<tr>")
+        .filter_map(|row| {
+            let start = row.find("/admin/sessions/")?;
+            let end = start + row[start..].find("/revoke")?;
+            let path = row[start + "/admin".len()..end + "/revoke".len()].to_string();
+            Some((path, row.contains("(this session)")))
+        })
+        .collect();
+    Ok(sessions)
+}
+
+async fn dashboard_status(app: &TestApp, http_client: &Client) -> Result<StatusCode> {
+    let resp = http_client
+        .get(format!("http://{}/admin/dashboard", app.addr))
+        .send()
+        .await?;
+    Ok(resp.status())
+}
+
+#[tokio::test]
+async fn logout_ends_the_admin_session() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+
+    let resp = app.admin_post("/logout", ()).await?;
+    assert_resp_redir_to(&resp, "/login");
+    assert!(app
+        .login_get_html()
+        .await?
+        .contains("You have been logged out."));
+
+    let resp = app.admin_dashboard_get().await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sessions_page_lists_and_revokes_a_single_session() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let other_client = http_client_build()?;
+    app.admin_login_with(&other_client).await?;
+    app.admin_login().await?;
+
+    let sessions = listed_sessions(&app).await?;
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions.iter().filter(|(_, current)| *current).count(), 1);
+
+    let (other_path, _) = sessions
+        .iter()
+        .find(|(_, current)| !current)
+        .expect("two sessions");
+    let resp = app.admin_post(other_path, ()).await?;
+    assert_resp_redir_to(&resp, "/admin/sessions");
+
+    assert_eq!(
+        dashboard_status(&app, &other_client).await?,
+        StatusCode::SEE_OTHER
+    );
+    assert_eq!(
+        dashboard_status(&app, &app.http_client).await?,
+        StatusCode::OK
+    );
+    assert_eq!(listed_sessions(&app).await?.len(), 1);
+
+    // An unknown or already revoked session can't be found.
+    let resp = app.admin_post(other_path, ()).await?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn revoking_the_current_session_logs_out() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+
+    let sessions = listed_sessions(&app).await?;
+    assert_eq!(sessions.len(), 1);
+    let resp = app.admin_post(&sessions[0].0, ()).await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    let resp = app.admin_dashboard_get().await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn revoke_all_keeps_only_the_current_session() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let other_clients = [http_client_build()?, http_client_build()?];
+    for client in &other_clients {
+        app.admin_login_with(client).await?;
+    }
+    app.admin_login().await?;
+    assert_eq!(listed_sessions(&app).await?.len(), 3);
+
+    let resp = app.admin_post("/sessions/revoke-all", ()).await?;
+    assert_resp_redir_to(&resp, "/admin/sessions");
+
+    for client in &other_clients {
+        assert_eq!(dashboard_status(&app, client).await?, StatusCode::SEE_OTHER);
+    }
+    let sessions = listed_sessions(&app).await?;
+    assert_eq!(sessions.len(), 1);
+    assert!(sessions[0].1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sessions_used_after_the_expiry_are_still_revoked() -> Result<()> {
+    let app = TestApp::spawn_with(|c| c.session_config.expiry_secs = 2).await?;
+    let other_client = http_client_build()?;
+    app.admin_login_with(&other_client).await?;
+    // The requests keep the session alive for longer than the expiry of a single login.
+    for _ in 0..3 {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        assert_eq!(dashboard_status(&app, &other_client).await?, StatusCode::OK);
+    }
+
+    app.admin_login().await?;
+    assert_eq!(listed_sessions(&app).await?.len(), 2);
+    let resp = app.admin_post("/sessions/revoke-all", ()).await?;
+    assert_resp_redir_to(&resp, "/admin/sessions");
+    assert_eq!(
+        dashboard_status(&app, &other_client).await?,
+        StatusCode::SEE_OTHER
+    );
+
+    Ok(())
+}
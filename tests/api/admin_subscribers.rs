@@ -0,0 +1,312 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use mailomat::web::csrf::CSRF_HEADER;
+use reqwest::StatusCode;
+use uuid::Uuid;
+
+use crate::helpers::{assert_resp_redir_to, http_client_build, TestApp, TestUser};
+
+/// Inserts a subscriber directly, so the test controls the name, the status and the time.
+async fn subscriber_insert(
+    app: &TestApp,
+    email: &str,
+    name: &str,
+    status: &str,
+    subscribed_at: DateTime<Utc>,
+) -> Result<Uuid> {
+    let id = Uuid::new_v4();
+    sqlx::query(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(id)
+    .bind(email)
+    .bind(name)
+    .bind(subscribed_at)
+    .bind(status)
+    .execute(app.dm.db())
+    .await?;
+    Ok(id)
+}
+
+async fn subscriber_status(app: &TestApp, id: Uuid) -> Result<Option<String>> {
+    Ok(
+        sqlx::query_scalar("SELECT status FROM subscriptions WHERE id = $1")
+            .bind(id)
+            .fetch_optional(app.dm.db())
+            .await?,
+    )
+}
+
+/// Returns the link to the next page on the list page, Tera escapes the slashes and the ampersands.
+fn next_page_link(html: &str) -> Option<String> {
+    html.split(r#"<a href=""#)
+        .filter_map(|rest| rest.split_once(r#"">Next page</a>"#))
+        .map(|(link, _)| link.replace("&#x2F;", "/").replace("&amp;", "&"))
+        .next()
+}
+
+#[tokio::test]
+async fn subscribers_list_requires_login() -> Result<()> {
+    let app = TestApp::spawn().await?;
+
+    let resp = app.admin_get("/subscribers").await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn subscribers_list_can_be_searched_and_filtered() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let now = Utc::now();
+    subscriber_insert(
+        &app,
+        "ursula@example.com",
+        "Ursula Le Guin",
+        "confirmed",
+        now,
+    )
+    .await?;
+    subscriber_insert(
+        &app,
+        "le_guin@example.com",
+        "Someone Else",
+        "confirmed",
+        now - Duration::days(40),
+    )
+    .await?;
+    subscriber_insert(
+        &app,
+        "octavia@example.com",
+        "Octavia Butler",
+        "unsubscribed",
+        now - Duration::days(10),
+    )
+    .await?;
+    app.admin_login().await?;
+
+    let html = app.admin_get("/subscribers").await?.text().await?;
+    for email in ["ursula@", "le_guin@", "octavia@"] {
+        assert!(html.contains(email), "{email}");
+    }
+
+    // The name and the email address are searched, case insensitive.
+    let html = app
+        .admin_get("/subscribers?q=le+GUIN")
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains("ursula@"));
+    assert!(!html.contains("le_guin@"));
+    assert!(!html.contains("octavia@"));
+
+    // The underscore isn't a wildcard.
+    let html = app.admin_get("/subscribers?q=le_g").await?.text().await?;
+    assert!(html.contains("le_guin@"));
+    assert!(!html.contains("ursula@"));
+
+    let html = app
+        .admin_get("/subscribers?q=&status=unsubscribed&from=&to=")
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains("octavia@"));
+    assert!(!html.contains("ursula@"));
+
+    let from = (now - Duration::days(30)).format("%Y-%m-%d");
+    let to = (now - Duration::days(1)).format("%Y-%m-%d");
+    let html = app
+        .admin_get(&format!("/subscribers?from={from}&to={to}"))
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains("octavia@"));
+    assert!(!html.contains("ursula@"));
+    assert!(!html.contains("le_guin@"));
+
+    let resp = app.admin_get("/subscribers?status=gone").await?;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = app.admin_get("/subscribers?from=yesterday").await?;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn subscribers_list_is_paginated() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    // Some of them subscribed at the same time, the ID decides their order.
+    let now = Utc::now();
+    for n in 0..60 {
+        let subscribed_at = now - Duration::minutes(n / 3);
+        subscriber_insert(
+            &app,
+            &format!("reader{n:02}@example.com"),
+            "Reader",
+            "confirmed",
+            subscribed_at,
+        )
+        .await?;
+    }
+    app.admin_login().await?;
+
+    let mut seen = Vec::new();
+    let mut path = "/subscribers?status=confirmed".to_string();
+    let mut pages = 0;
+    loop {
+        pages += 1;
+        let html = app.admin_get(&path).await?.text().await?;
+        seen.extend(
+            (0..60)
+                .map(|n| format!("reader{n:02}@example.com"))
+                .filter(|email| html.contains(email.as_str())),
+        );
+        match next_page_link(&html) {
+            Some(link) => {
+                assert!(link.contains("status=confirmed"));
+                path = link
+                    .strip_prefix("/admin")
+                    .context("the link isn't under /admin")?
+                    .to_string();
+            }
+            None => break,
+        }
+    }
+    assert_eq!(pages, 2);
+    // Every subscriber is on exactly one page.
+    assert_eq!(seen.len(), 60);
+    seen.sort();
+    seen.dedup();
+    assert_eq!(seen.len(), 60);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admins_can_confirm_unsubscribe_and_delete_subscribers() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let id = subscriber_insert(
+        &app,
+        "reader@example.com",
+        "Reader",
+        "pending_confirmation",
+        Utc::now(),
+    )
+    .await?;
+    app.admin_login().await?;
+
+    let resp = app
+        .admin_post(&format!("/subscribers/{id}/confirm"), ())
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/subscribers");
+    assert_eq!(
+        subscriber_status(&app, id).await?.as_deref(),
+        Some("confirmed")
+    );
+    let html = app.admin_get("/subscribers").await?.text().await?;
+    assert!(html.contains("reader@example.com was confirmed."));
+
+    let resp = app
+        .admin_post(&format!("/subscribers/{id}/unsubscribe"), ())
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/subscribers");
+    assert_eq!(
+        subscriber_status(&app, id).await?.as_deref(),
+        Some("unsubscribed")
+    );
+
+    let resp = app
+        .admin_post(&format!("/subscribers/{id}/delete"), ())
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/subscribers");
+    assert_eq!(subscriber_status(&app, id).await?, None);
+
+    let resp = app
+        .admin_post(&format!("/subscribers/{id}/confirm"), ())
+        .await?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn confirmation_link_doesnt_undo_an_admin_unsubscribe() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let (confirmation_link, _) = app.subscriber_unconfirmed_create().await?;
+    let id: Uuid = sqlx::query_scalar("SELECT id FROM subscriptions")
+        .fetch_one(app.dm.db())
+        .await?;
+    app.admin_login().await?;
+
+    let resp = app
+        .admin_post(&format!("/subscribers/{id}/unsubscribe"), ())
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/subscribers");
+
+    let resp = app.http_client.get(confirmation_link.html).send().await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        subscriber_status(&app, id).await?.as_deref(),
+        Some("unsubscribed")
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn subscriber_actions_are_limited_by_the_role() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let id = subscriber_insert(
+        &app,
+        "reader@example.com",
+        "Reader",
+        "pending_confirmation",
+        Utc::now(),
+    )
+    .await?;
+
+    // A viewer can see the subscribers but can't change them.
+    let viewer = TestUser::create(&app.dm, "viewer").await?;
+    let viewer_client = http_client_build()?;
+    app.admin_login_as(&viewer_client, &viewer).await?;
+    let html = viewer_client
+        .get(format!("http://{}/admin/subscribers", app.addr))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains("reader@example.com"));
+    assert!(!html.contains("/confirm"));
+    let resp = viewer_client
+        .post(format!(
+            "http://{}/admin/subscribers/{id}/confirm",
+            app.addr
+        ))
+        .header(CSRF_HEADER, app.csrf_token(&viewer_client).await?)
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // An editor can confirm but can't delete.
+    let editor = TestUser::create(&app.dm, "editor").await?;
+    app.admin_login_as(&app.http_client, &editor).await?;
+    let html = app.admin_get("/subscribers").await?.text().await?;
+    assert!(!html.contains("/delete"));
+    let resp = app
+        .admin_post(&format!("/subscribers/{id}/confirm"), ())
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/subscribers");
+    let resp = app
+        .admin_post(&format!("/subscribers/{id}/delete"), ())
+        .await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        subscriber_status(&app, id).await?.as_deref(),
+        Some("confirmed")
+    );
+
+    Ok(())
+}
@@ -0,0 +1,281 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use futures::future::try_join_all;
+use mailomat::web::csrf::CSRF_HEADER;
+use reqwest::{header, Client, StatusCode};
+use serde_json::json;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::helpers::{assert_resp_redir_to, http_client_build, TestApp};
+
+/// Enrolls the test user, returns the secret from the page and the recovery codes.
+async fn totp_enable(app: &TestApp) -> Result<(TOTP, Vec<String>)> {
+    let resp = app.admin_post("/totp/enroll", ()).await?;
+    assert_resp_redir_to(&resp, "/admin/totp");
+    let html = app.admin_get("/totp").await?.text().await?;
+    assert!(html.contains("<svg"));
+    assert!(html.contains("otpauth:"));
+    let secret = html
+        .split("Key: <code>")
+        .nth(1)
+        .and_then(|rest| rest.split("</code>").next())
+        .context("no key on the page")?;
+    let totp = TOTP::new_unchecked(
+        Algorithm::SHA1,
+        6,
+        0,
+        30,
+        Secret::Encoded(secret.to_string()).to_bytes()?,
+    );
+
+    // A wrong code doesn't enable anything.
+    let resp = app
+        .admin_post("/totp/confirm", json!({ "code": "000000" }))
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/totp");
+    let html = app.admin_get("/totp").await?.text().await?;
+    assert!(html.contains("The code is invalid!"));
+
+    // The code of the previous step, so the later steps are still unused.
+    let resp = app
+        .admin_post("/totp/confirm", json!({ "code": code_at(&totp, -30) }))
+        .await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let recovery_codes = resp
+        .text()
+        .await?
+        .split("<li><code>")
+        .skip(1)
+        .filter_map(|rest| rest.split("</code>").next())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    assert_eq!(recovery_codes.len(), 10);
+
+    Ok((totp, recovery_codes))
+}
+
+/// The code for the current time moved by the offset.
+fn code_at(totp: &TOTP, offset_secs: i64) -> String {
+    totp.generate((Utc::now().timestamp() + offset_secs) as u64)
+}
+
+async fn login_password(app: &TestApp, client: &Client) -> Result<reqwest::Response> {
+    Ok(client
+        .post(format!("http://{}/login", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(client).await?)
+        .form(&json!({
+            "username": app.test_user.username,
+            "password": app.test_user.password,
+        }))
+        .send()
+        .await?)
+}
+
+async fn login_totp(app: &TestApp, client: &Client, code: &str) -> Result<reqwest::Response> {
+    Ok(client
+        .post(format!("http://{}/login/totp", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(client).await?)
+        .form(&json!({ "code": code }))
+        .send()
+        .await?)
+}
+
+async fn dashboard_status(app: &TestApp, client: &Client) -> Result<StatusCode> {
+    Ok(client
+        .get(format!("http://{}/admin/dashboard", app.addr))
+        .send()
+        .await?
+        .status())
+}
+
+#[tokio::test]
+async fn enabled_totp_requires_a_code_to_log_in() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+    let (totp, _) = totp_enable(&app).await?;
+    let html = app.admin_get("/totp").await?.text().await?;
+    assert!(html.contains("You have 10 unused recovery codes left."));
+
+    // The secret is only stored encrypted.
+    let encrypted: String =
+        sqlx::query_scalar("SELECT totp_secret_encrypted FROM users WHERE user_id = $1")
+            .bind(app.test_user.user_id)
+            .fetch_one(app.dm.db())
+            .await?;
+    assert!(!encrypted.contains(&totp.get_secret_base32()));
+
+    let client = http_client_build()?;
+    let resp = login_password(&app, &client).await?;
+    assert_resp_redir_to(&resp, "/login/totp");
+    // The password alone isn't a login.
+    assert_eq!(
+        dashboard_status(&app, &client).await?,
+        StatusCode::SEE_OTHER
+    );
+
+    let resp = login_totp(&app, &client, "000000").await?;
+    assert_resp_redir_to(&resp, "/login/totp");
+    let html = client
+        .get(format!("http://{}/login/totp", app.addr))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains("The code is invalid!"));
+
+    let code = code_at(&totp, 0);
+    let resp = login_totp(&app, &client, &code).await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+    assert_eq!(dashboard_status(&app, &client).await?, StatusCode::OK);
+
+    // The same code can't be used twice.
+    let other_client = http_client_build()?;
+    login_password(&app, &other_client).await?;
+    let resp = login_totp(&app, &other_client, &code).await?;
+    assert_resp_redir_to(&resp, "/login/totp");
+    assert_eq!(
+        dashboard_status(&app, &other_client).await?,
+        StatusCode::SEE_OTHER
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn recovery_code_logs_in_only_once() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+    let (_, recovery_codes) = totp_enable(&app).await?;
+
+    let client = http_client_build()?;
+    login_password(&app, &client).await?;
+    // The recovery codes are case insensitive.
+    let resp = login_totp(&app, &client, &recovery_codes[0].to_uppercase()).await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+    let html = app.admin_get("/totp").await?.text().await?;
+    assert!(html.contains("You have 9 unused recovery codes left."));
+
+    let client = http_client_build()?;
+    login_password(&app, &client).await?;
+    let resp = login_totp(&app, &client, &recovery_codes[0]).await?;
+    assert_resp_redir_to(&resp, "/login/totp");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn too_many_invalid_codes_restart_the_login() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+    let (totp, _) = totp_enable(&app).await?;
+
+    let client = http_client_build()?;
+    login_password(&app, &client).await?;
+    for _ in 0..4 {
+        let resp = login_totp(&app, &client, "000000").await?;
+        assert_resp_redir_to(&resp, "/login/totp");
+    }
+    let resp = login_totp(&app, &client, "000000").await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    // The pending login is gone, a valid code doesn't help anymore.
+    let resp = login_totp(&app, &client, &code_at(&totp, 0)).await?;
+    assert_resp_redir_to(&resp, "/login");
+    assert_eq!(
+        dashboard_status(&app, &client).await?,
+        StatusCode::SEE_OTHER
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn disabled_totp_logs_in_with_the_password() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+    let (totp, _) = totp_enable(&app).await?;
+
+    let resp = app
+        .admin_post("/totp/disable", json!({ "code": "000000" }))
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/totp");
+    assert!(app
+        .admin_get("/totp")
+        .await?
+        .text()
+        .await?
+        .contains("The code is invalid!"));
+
+    let resp = app
+        .admin_post("/totp/disable", json!({ "code": code_at(&totp, 0) }))
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/totp");
+    let html = app.admin_get("/totp").await?.text().await?;
+    assert!(html.contains("Two-factor authentication was turned off."));
+
+    let recovery_codes: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM totp_recovery_codes")
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(recovery_codes, 0);
+    app.admin_login_with(&http_client_build()?).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_codes_are_counted_across_logins() -> Result<()> {
+    let app =
+        TestApp::spawn_with(|c| c.login_throttle_config.totp_lockout_after_failures = 3).await?;
+    app.admin_login().await?;
+    let (totp, _) = totp_enable(&app).await?;
+
+    // Entering the right password again doesn't give fresh guesses.
+    for _ in 0..3 {
+        let client = http_client_build()?;
+        let resp = login_password(&app, &client).await?;
+        assert_resp_redir_to(&resp, "/login/totp");
+        let resp = login_totp(&app, &client, "000000").await?;
+        assert_resp_redir_to(&resp, "/login/totp");
+    }
+    let client = http_client_build()?;
+    login_password(&app, &client).await?;
+    let resp = login_totp(&app, &client, &code_at(&totp, 0)).await?;
+    assert_resp_redir_to(&resp, "/login");
+    assert_eq!(
+        dashboard_status(&app, &client).await?,
+        StatusCode::SEE_OTHER
+    );
+
+    // The username is locked out, for the password as well.
+    let client = http_client_build()?;
+    let resp = login_password(&app, &client).await?;
+    assert_resp_redir_to(&resp, "/login");
+    let html = client
+        .get(format!("http://{}/login", app.addr))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains("Too many failed login attempts, please try again in"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn concurrent_codes_dont_get_more_guesses() -> Result<()> {
+    let app =
+        TestApp::spawn_with(|c| c.login_throttle_config.totp_lockout_after_failures = 3).await?;
+    app.admin_login().await?;
+    totp_enable(&app).await?;
+
+    let client = http_client_build()?;
+    login_password(&app, &client).await?;
+    let resps = try_join_all((0..10).map(|_| login_totp(&app, &client, "000000"))).await?;
+    let checked = resps
+        .iter()
+        .filter(|resp| resp.headers()[header::LOCATION] == "/login/totp")
+        .count();
+    assert!(checked <= 3, "{checked} codes were checked");
+
+    Ok(())
+}
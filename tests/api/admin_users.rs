@@ -0,0 +1,287 @@
+use anyhow::{Context, Result};
+use mailomat::web::csrf::CSRF_HEADER;
+use reqwest::{header, StatusCode};
+use serde_json::json;
+use wiremock::{matchers::path, Mock, ResponseTemplate};
+
+use crate::helpers::{
+    api_news_post_appless, assert_resp_redir_to, http_client_build, TestApp, TestUser,
+};
+
+const NEW_PASSWORD: &str = "a brand new password 42";
+const INVITED_EMAIL: &str = "editor@example.com";
+
+fn issue_form() -> serde_json::Value {
+    json!({
+        "title": "Title",
+        "text_content": "Newsletter body as plain text",
+        "html_content": "<p>Newsletter body as HTML</p>",
+    })
+}
+
+async fn signup_get(app: &TestApp, token: &str) -> Result<reqwest::Response> {
+    Ok(app
+        .http_client
+        .get(format!("http://{}/signup", app.addr))
+        .query(&[("token", token)])
+        .send()
+        .await?)
+}
+
+async fn signup_post(
+    app: &TestApp,
+    token: &str,
+    username: &str,
+    new_password_check: &str,
+) -> Result<reqwest::Response> {
+    Ok(app
+        .http_client
+        .post(format!("http://{}/signup", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&app.http_client).await?)
+        .form(&json!({
+            "token": token,
+            "username": username,
+            "new_password": NEW_PASSWORD,
+            "new_password_check": new_password_check,
+        }))
+        .send()
+        .await?)
+}
+
+/// Extracts the token from the signup link in the invitation email.
+fn invitation_token_get(app: &TestApp, email_req: &wiremock::Request) -> Result<String> {
+    let links = app.confirmation_link_get(email_req)?;
+    assert_eq!(links.html, links.plain_text);
+    assert_eq!(links.html.path(), "/signup");
+    let token = links
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "token")
+        .context("no token in the signup link")?
+        .1
+        .into_owned();
+    Ok(token)
+}
+
+#[tokio::test]
+async fn viewers_and_editors_are_limited_by_their_role() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let viewer = TestUser::create(&app.dm, "viewer").await?;
+    let editor = TestUser::create(&app.dm, "editor").await?;
+
+    // A viewer can't publish through the API, an editor can.
+    let resp = api_news_post_appless(&viewer, &app.addr, http_client_build()?).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(resp.headers().get(header::WWW_AUTHENTICATE).is_none());
+    let resp = api_news_post_appless(&editor, &app.addr, http_client_build()?).await?;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+
+    // A viewer can look at the issues but can't change them.
+    app.admin_login_as(&app.http_client, &viewer).await?;
+    let resp = app.admin_get("/issues").await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(!resp.text().await?.contains("New draft"));
+    let resp = app.admin_post("/issues", issue_form()).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = app.admin_get("/issues/new").await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = app.admin_get("/users").await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // An editor can write drafts but can't manage the users.
+    let editor_client = http_client_build()?;
+    app.admin_login_as(&editor_client, &editor).await?;
+    let resp = editor_client
+        .post(format!("http://{}/admin/issues", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&editor_client).await?)
+        .form(&issue_form())
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    let resp = editor_client
+        .post(format!("http://{}/admin/users/invite", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&editor_client).await?)
+        .form(&json!({ "email": INVITED_EMAIL, "role": "owner" }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn invited_user_signs_up_with_the_invited_role() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.admin_login().await?;
+
+    let resp = app
+        .admin_post(
+            "/users/invite",
+            json!({ "email": INVITED_EMAIL, "role": "editor" }),
+        )
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/users");
+    let html = app.admin_get("/users").await?.text().await?;
+    assert!(html.contains("An invitation was sent to editor@example.com."));
+
+    let email_req = &app.email_server.received_requests().await.unwrap()[0];
+    let token = invitation_token_get(&app, email_req)?;
+    let resp = signup_get(&app, &token).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.text().await?.contains("You were invited as editor"));
+
+    // A rejected form doesn't use up the invitation.
+    let resp = signup_post(&app, &token, "new-editor", "a different password 42").await?;
+    assert_resp_redir_to(&resp, &format!("/signup?token={token}"));
+    let html = signup_get(&app, &token).await?.text().await?;
+    assert!(html.contains("The passwords don&#x27;t match!"));
+    let resp = signup_post(&app, &token, &app.test_user.username, NEW_PASSWORD).await?;
+    assert_resp_redir_to(&resp, &format!("/signup?token={token}"));
+    let html = signup_get(&app, &token).await?.text().await?;
+    assert!(html.contains("The username is already taken!"));
+
+    let resp = signup_post(&app, &token, "new-editor", NEW_PASSWORD).await?;
+    assert_resp_redir_to(&resp, "/login");
+    assert!(app
+        .login_get_html()
+        .await?
+        .contains("Your account was created"));
+
+    let (role, email): (String, Option<String>) =
+        sqlx::query_as("SELECT role, email FROM users WHERE username = 'new-editor'")
+            .fetch_one(app.dm.db())
+            .await?;
+    assert_eq!(role, "editor");
+    assert_eq!(email.as_deref(), Some(INVITED_EMAIL));
+    let client = http_client_build()?;
+    let resp = client
+        .post(format!("http://{}/login", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&client).await?)
+        .form(&json!({ "username": "new-editor", "password": NEW_PASSWORD }))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+
+    // The invitation can only be used once.
+    let resp = signup_get(&app, &token).await?;
+    assert_eq!(resp.status(), StatusCode::GONE);
+    let resp = signup_post(&app, &token, "another-editor", NEW_PASSWORD).await?;
+    assert_eq!(resp.status(), StatusCode::GONE);
+
+    // The address belongs to a user now.
+    let resp = app
+        .admin_post(
+            "/users/invite",
+            json!({ "email": INVITED_EMAIL, "role": "viewer" }),
+        )
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/users");
+    let html = app.admin_get("/users").await?.text().await?;
+    assert!(html.contains("A user with this email address already exists!"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn deactivated_user_is_logged_out_and_cant_log_in() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let editor = TestUser::create(&app.dm, "editor").await?;
+    let editor_client = http_client_build()?;
+    app.admin_login_as(&editor_client, &editor).await?;
+    app.admin_login().await?;
+
+    let resp = app
+        .admin_post(&format!("/users/{}/deactivate", editor.user_id), ())
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/users");
+    let html = app.admin_get("/users").await?.text().await?;
+    assert!(html.contains("was deactivated and logged out."));
+
+    let resp = editor_client
+        .get(format!("http://{}/admin/dashboard", app.addr))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    let resp = editor_client
+        .post(format!("http://{}/login", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&editor_client).await?)
+        .form(&json!({ "username": editor.username, "password": editor.password }))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/login");
+    let html = editor_client
+        .get(format!("http://{}/login", app.addr))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains("This account has been deactivated!"));
+
+    let resp = api_news_post_appless(&editor, &app.addr, http_client_build()?).await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // Owners can't lock themselves out.
+    let resp = app
+        .admin_post(&format!("/users/{}/deactivate", app.test_user.user_id), ())
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/users");
+    let html = app.admin_get("/users").await?.text().await?;
+    assert!(html.contains("You can&#x27;t deactivate yourself!"));
+
+    let resp = app
+        .admin_post(&format!("/users/{}/deactivate", uuid::Uuid::new_v4()), ())
+        .await?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sessions_follow_the_current_role_and_deactivation_of_the_user() -> Result<()> {
+    use tower_sessions_redis_store::fred::prelude::KeysInterface;
+
+    let app = TestApp::spawn().await?;
+    let editor = TestUser::create(&app.dm, "editor").await?;
+    let editor_client = http_client_build()?;
+    app.admin_login_as(&editor_client, &editor).await?;
+    let import_page_get = || {
+        editor_client
+            .get(format!("http://{}/admin/subscribers/import", app.addr))
+            .send()
+    };
+    assert_eq!(import_page_get().await?.status(), StatusCode::OK);
+
+    // A demotion takes effect on the next request.
+    sqlx::query("UPDATE users SET role = 'viewer' WHERE user_id = $1")
+        .bind(editor.user_id)
+        .execute(app.dm.db())
+        .await?;
+    assert_eq!(import_page_get().await?.status(), StatusCode::FORBIDDEN);
+
+    // Even if the session is missing from the index, it doesn't outlive the deactivation.
+    let _: i64 = app
+        .app_state
+        .redis_manager
+        .get_pool()
+        .del(format!("admin_sessions:{}", editor.user_id))
+        .await?;
+    app.admin_login().await?;
+    let resp = app
+        .admin_post(&format!("/users/{}/deactivate", editor.user_id), ())
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/users");
+    let resp = editor_client
+        .get(format!("http://{}/admin/dashboard", app.addr))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/login");
+    assert_eq!(import_page_get().await?.status(), StatusCode::SEE_OTHER);
+
+    Ok(())
+}
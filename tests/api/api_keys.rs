@@ -0,0 +1,194 @@
+use anyhow::Result;
+use mailomat::web::csrf::CSRF_HEADER;
+use reqwest::{header, StatusCode};
+use serde_json::{json, Value};
+use wiremock::{matchers::any, Mock, ResponseTemplate};
+
+use crate::helpers::{api_key_create, assert_resp_redir_to, http_client_build, TestApp, TestUser};
+
+fn news_body() -> Value {
+    json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+async fn news_post_bearer(app: &TestApp, key: &str) -> Result<reqwest::Response> {
+    Ok(http_client_build()?
+        .post(format!("http://{}/api/news", app.addr))
+        .header(header::AUTHORIZATION, format!("Bearer {key}"))
+        .json(&news_body())
+        .send()
+        .await?)
+}
+
+async fn subscribers_export_bearer(app: &TestApp, key: &str) -> Result<reqwest::Response> {
+    Ok(http_client_build()?
+        .get(format!(
+            "http://{}/api/subscribers/export?format=ndjson",
+            app.addr
+        ))
+        .header(header::AUTHORIZATION, format!("Bearer {key}"))
+        .send()
+        .await?)
+}
+
+#[tokio::test]
+async fn api_key_publishes_news_until_revoked() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.admin_login().await?;
+
+    let key = api_key_create(
+        &app,
+        &app.http_client,
+        &[("name", "deploy"), ("scope", "news:publish")],
+    )
+    .await?;
+    assert!(key.starts_with("mlm_"));
+
+    // The key isn't stored, only its hash.
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM api_keys WHERE key_hash = $1")
+        .bind(&key)
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(stored, 0);
+    let html = app.admin_get("/api-keys").await?.text().await?;
+    assert!(html.contains(&key[..12]));
+    assert!(!html.contains(&key));
+
+    let resp = news_post_bearer(&app, &key).await?;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    let last_used: Option<chrono::DateTime<chrono::Utc>> =
+        sqlx::query_scalar("SELECT last_used_at FROM api_keys")
+            .fetch_one(app.dm.db())
+            .await?;
+    assert!(last_used.is_some());
+
+    // The key doesn't have the scope to read the subscribers.
+    let resp = subscribers_export_bearer(&app, &key).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let api_key_id: uuid::Uuid = sqlx::query_scalar("SELECT api_key_id FROM api_keys")
+        .fetch_one(app.dm.db())
+        .await?;
+    let resp = app
+        .admin_post(&format!("/api-keys/{api_key_id}/revoke"), ())
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/api-keys");
+    let html = app.admin_get("/api-keys").await?.text().await?;
+    assert!(html.contains("The API key deploy was revoked."));
+
+    let resp = news_post_bearer(&app, &key).await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // Only the own keys can be revoked.
+    let resp = app
+        .admin_post(&format!("/api-keys/{}/revoke", uuid::Uuid::new_v4()), ())
+        .await?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_and_expired_api_keys_are_rejected() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+
+    let resp = news_post_bearer(&app, "not-a-key").await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = news_post_bearer(&app, &format!("mlm_{}", "A".repeat(43))).await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let key = api_key_create(
+        &app,
+        &app.http_client,
+        &[
+            ("name", "short lived"),
+            ("scope", "subscribers:read"),
+            ("expires_in_days", "1"),
+        ],
+    )
+    .await?;
+    let resp = subscribers_export_bearer(&app, &key).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    sqlx::query("UPDATE api_keys SET expires_at = now() - interval '1 minute'")
+        .execute(app.dm.db())
+        .await?;
+    let resp = subscribers_export_bearer(&app, &key).await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // The subscribers can't be read with a password.
+    let resp = http_client_build()?
+        .get(format!("http://{}/api/subscribers/export", app.addr))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_key_scopes_are_limited_by_the_role() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let subscriber = app.subscriber_confirmed_create().await?;
+    let viewer = TestUser::create(&app.dm, "viewer").await?;
+    let viewer_client = http_client_build()?;
+    app.admin_login_as(&viewer_client, &viewer).await?;
+
+    let html = viewer_client
+        .get(format!("http://{}/admin/api-keys", app.addr))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(!html.contains("news:publish"));
+    let resp = viewer_client
+        .post(format!("http://{}/admin/api-keys", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&viewer_client).await?)
+        .form(&[("name", "publish"), ("scope", "news:publish")])
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/api-keys");
+    let html = viewer_client
+        .get(format!("http://{}/admin/api-keys", app.addr))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains("Your role can&#x27;t use the news:publish scope!"));
+
+    let key = api_key_create(
+        &app,
+        &viewer_client,
+        &[("name", "export"), ("scope", "subscribers:read")],
+    )
+    .await?;
+    let body = subscribers_export_bearer(&app, &key).await?.text().await?;
+    let subscribers = body
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<Value>, _>>()?;
+    assert_eq!(subscribers.len(), 1);
+    assert_eq!(subscribers[0]["email"], subscriber.email.as_ref());
+    assert_eq!(subscribers[0]["status"], "confirmed");
+
+    // The keys of a deactivated user stop working.
+    sqlx::query("UPDATE users SET deactivated_at = now() WHERE user_id = $1")
+        .bind(viewer.user_id)
+        .execute(app.dm.db())
+        .await?;
+    let resp = subscribers_export_bearer(&app, &key).await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    Ok(())
+}
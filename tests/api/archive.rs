@@ -0,0 +1,70 @@
+use anyhow::Result;
+use chrono::Utc;
+use reqwest::StatusCode;
+use uuid::Uuid;
+
+use crate::helpers::TestApp;
+
+async fn insert_issue(app: &TestApp, title: &str, html_content: &str) -> Result<Uuid> {
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query(
+        r#"INSERT INTO newsletter_issues (newsletter_issue_id, title, text_content, html_content, published_at)
+        VALUES ($1, $2, 'text', $3, $4)"#,
+    )
+    .bind(newsletter_issue_id)
+    .bind(title)
+    .bind(html_content)
+    .bind(Utc::now())
+    .execute(app.dm.db())
+    .await?;
+
+    Ok(newsletter_issue_id)
+}
+
+#[tokio::test]
+async fn archive_lists_published_issues() -> Result<()> {
+    let app = TestApp::spawn().await?;
+
+    let body = app.archive_get("/archive").await?.text().await?;
+    assert!(body.contains("No newsletters were sent yet."));
+
+    let id = insert_issue(&app, "First issue", "<p>Hello</p>").await?;
+    let body = app.archive_get("/archive").await?.text().await?;
+    assert!(body.contains("First issue"));
+    assert!(body.contains(&format!("/archive/{id}")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn archive_issue_html_is_sanitized() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let id = insert_issue(
+        &app,
+        "Sanitized issue",
+        r#"<p onclick="steal()">Hello <b>there</b></p><script>alert("xss")</script>"#,
+    )
+    .await?;
+
+    let res = app.archive_get(&format!("/archive/{id}")).await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = res.text().await?;
+    assert!(body.contains("Sanitized issue"));
+    assert!(body.contains("Hello <b>there</b>"));
+    assert!(!body.contains("<script>"));
+    assert!(!body.contains("onclick"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn archive_unknown_issue_returns_404() -> Result<()> {
+    let app = TestApp::spawn().await?;
+
+    let res = app
+        .archive_get(&format!("/archive/{}", Uuid::new_v4()))
+        .await?;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
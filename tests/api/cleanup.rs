@@ -0,0 +1,132 @@
+use anyhow::Result;
+use mailomat::cleanup_worker::{try_execute_cleanup, CleanupOutcome};
+use reqwest::StatusCode;
+use uuid::Uuid;
+
+use crate::helpers::TestApp;
+
+#[tokio::test]
+async fn cleanup_deletes_long_expired_tokens() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let (_, long_expired) = app.subscriber_unconfirmed_create().await?;
+    app.subscriber_unconfirmed_create().await?;
+
+    sqlx::query(
+        r#"UPDATE subscription_tokens SET created_at = now() - interval '10 days'
+        WHERE subscriber_id = (SELECT id FROM subscriptions WHERE email = $1)"#,
+    )
+    .bind(long_expired.email.as_ref())
+    .execute(app.dm.db())
+    .await?;
+
+    let outcome = try_execute_cleanup(&app.app_state).await?;
+    assert_eq!(
+        outcome,
+        CleanupOutcome {
+            expired_tokens: 1,
+            purged_subscribers: 0,
+            pruned_login_events: 0,
+            pruned_idempotency_keys: 0
+        }
+    );
+
+    let n_tokens: i64 = sqlx::query_scalar("SELECT count(*) FROM subscription_tokens")
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(n_tokens, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn expired_links_are_still_gone_after_the_cleanup() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let (confirm_link, _) = app.subscriber_unconfirmed_create().await?;
+
+    sqlx::query("UPDATE subscription_tokens SET created_at = now() - interval '2 days'")
+        .execute(app.dm.db())
+        .await?;
+    let outcome = try_execute_cleanup(&app.app_state).await?;
+    assert_eq!(outcome.expired_tokens, 0);
+
+    let resp = app.http_client.get(confirm_link.html).send().await?;
+    assert_eq!(resp.status(), StatusCode::GONE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn cleanup_purges_stale_unconfirmed_subscribers() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let (_, stale) = app.subscriber_unconfirmed_create().await?;
+    let (_, recently_resent) = app.subscriber_unconfirmed_create().await?;
+    let confirmed = app.subscriber_confirmed_create().await?;
+
+    // Every subscriber subscribed a long time ago, but one of them was recently sent a new confirmation email.
+    sqlx::query("UPDATE subscriptions SET subscribed_at = now() - interval '30 days'")
+        .execute(app.dm.db())
+        .await?;
+    sqlx::query(
+        r#"UPDATE confirmation_emails_sent SET sent_at = now() - interval '30 days'
+        WHERE subscriber_id <> (SELECT id FROM subscriptions WHERE email = $1)"#,
+    )
+    .bind(recently_resent.email.as_ref())
+    .execute(app.dm.db())
+    .await?;
+
+    let stale_id: Uuid = sqlx::query_scalar("SELECT id FROM subscriptions WHERE email = $1")
+        .bind(stale.email.as_ref())
+        .fetch_one(app.dm.db())
+        .await?;
+
+    let outcome = try_execute_cleanup(&app.app_state).await?;
+    assert_eq!(outcome.purged_subscribers, 1);
+
+    // The history outlives the subscriber, without the personal data.
+    let events: Vec<(Option<String>, Option<String>)> =
+        sqlx::query_as("SELECT ip, user_agent FROM subscription_events WHERE subscriber_id = $1")
+            .bind(stale_id)
+            .fetch_all(app.dm.db())
+            .await?;
+    assert!(!events.is_empty());
+    assert!(events
+        .iter()
+        .all(|(ip, user_agent)| ip.is_none() && user_agent.is_none()));
+
+    let emails: Vec<String> = sqlx::query_scalar("SELECT email FROM subscriptions ORDER BY email")
+        .fetch_all(app.dm.db())
+        .await?;
+    assert!(!emails.contains(&stale.email.as_ref().to_owned()));
+    assert!(emails.contains(&recently_resent.email.as_ref().to_owned()));
+    assert!(emails.contains(&confirmed.email.as_ref().to_owned()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn cleanup_prunes_expired_idempotency_keys() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let expired_key = Uuid::new_v4().to_string();
+    let fresh_key = Uuid::new_v4().to_string();
+    for key in [&expired_key, &fresh_key] {
+        let res = app.api_news_post_idempotent(key).await?;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+    }
+
+    sqlx::query(
+        "UPDATE idempotency SET created_at = now() - interval '2 days' WHERE idempotency_key = $1",
+    )
+    .bind(&expired_key)
+    .execute(app.dm.db())
+    .await?;
+
+    let outcome = try_execute_cleanup(&app.app_state).await?;
+    assert_eq!(outcome.pruned_idempotency_keys, 1);
+
+    let keys: Vec<String> = sqlx::query_scalar("SELECT idempotency_key FROM idempotency")
+        .fetch_all(app.dm.db())
+        .await?;
+    assert_eq!(keys, vec![fresh_key]);
+
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use anyhow::{Context, Result};
+use mailomat::{
+    utils::{b64_encode, b64u_encode},
+    web::{csrf::CSRF_HEADER, FLASH_ERROR_MSG, SESSION_COOKIE_NAME},
+};
+use rand::{rng, RngCore};
+use reqwest::{header, redirect, Client, ClientBuilder, StatusCode};
+use secrecy::SecretString;
+use tower_cookies::{cookie::CookieJar, Cookie, Key};
+
+use crate::helpers::{assert_resp_redir_to, http_client_build, TestApp};
+
+/// A random cookie secret and the key made from it.
+fn cookie_secret() -> (String, Key) {
+    let mut secret = [0u8; 64];
+    rng().fill_bytes(&mut secret);
+    (b64_encode(secret), Key::from(&secret))
+}
+
+/// Spawns an app that signs with a new key and still accepts the returned old one.
+async fn spawn_rotated() -> Result<(TestApp, Key, Key)> {
+    let (new_secret, new_key) = cookie_secret();
+    let (old_secret, old_key) = cookie_secret();
+    let app = TestApp::spawn_with(|c| {
+        c.net_config.cookie_secret_b64enc = SecretString::from(new_secret);
+        c.net_config.previous_cookie_secrets_b64enc = vec![SecretString::from(old_secret)];
+    })
+    .await?;
+    Ok((app, new_key, old_key))
+}
+
+/// A client without a cookie store, so the test controls the cookies it sends.
+fn client_without_cookies() -> Result<Client> {
+    Ok(ClientBuilder::new()
+        .redirect(redirect::Policy::none())
+        .build()?)
+}
+
+fn cookie_sign(key: &Key, name: &str, value: &str) -> Result<String> {
+    let mut jar = CookieJar::new();
+    jar.signed_mut(key)
+        .add(Cookie::new(name.to_string(), value.to_string()));
+    Ok(format!(
+        "{name}={}",
+        jar.get(name).context("signed cookie")?.value()
+    ))
+}
+
+/// Returns the verified value of the cookie set in the response.
+fn set_cookie_verify(resp: &reqwest::Response, key: &Key, name: &str) -> Option<String> {
+    resp.headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|val| Cookie::parse(val.to_str().ok()?.to_string()).ok())
+        .filter(|cookie| cookie.name() == name)
+        .find_map(|cookie| {
+            let mut jar = CookieJar::new();
+            jar.add_original(cookie);
+            jar.signed(key).get(name).map(|c| c.value().to_string())
+        })
+}
+
+#[tokio::test]
+async fn session_signed_with_a_previous_key_is_accepted_and_resigned() -> Result<()> {
+    let (app, new_key, old_key) = spawn_rotated().await?;
+    let client = client_without_cookies()?;
+
+    // The login needs the session with the CSRF token, so it's done by a client that keeps the cookies.
+    let login_client = http_client_build()?;
+    let resp = login_client
+        .post(format!("http://{}/login", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&login_client).await?)
+        .form(&serde_json::json!({
+            "username": app.test_user.username,
+            "password": app.test_user.password,
+        }))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+    let session_id = set_cookie_verify(&resp, &new_key, SESSION_COOKIE_NAME)
+        .context("the session cookie isn't signed with the primary key")?;
+
+    // The same session, signed with the old key.
+    let resp = client
+        .get(format!("http://{}/admin/dashboard", app.addr))
+        .header(
+            header::COOKIE,
+            cookie_sign(&old_key, SESSION_COOKIE_NAME, &session_id)?,
+        )
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        set_cookie_verify(&resp, &new_key, SESSION_COOKIE_NAME),
+        Some(session_id.clone())
+    );
+
+    // Keys that aren't listed aren't accepted.
+    let (_, unknown_key) = cookie_secret();
+    let resp = client
+        .get(format!("http://{}/admin/dashboard", app.addr))
+        .header(
+            header::COOKIE,
+            cookie_sign(&unknown_key, SESSION_COOKIE_NAME, &session_id)?,
+        )
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn flash_message_signed_with_a_previous_key_is_shown() -> Result<()> {
+    let (app, _, old_key) = spawn_rotated().await?;
+    let message = "Signed with the old key!";
+
+    let html = client_without_cookies()?
+        .get(format!("http://{}/login", app.addr))
+        .header(
+            header::COOKIE,
+            cookie_sign(&old_key, FLASH_ERROR_MSG, &b64u_encode(message))?,
+        )
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains(message));
+
+    Ok(())
+}
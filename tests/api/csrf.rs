@@ -0,0 +1,198 @@
+use anyhow::Result;
+use mailomat::web::{csrf::CSRF_HEADER, ClientError};
+use reqwest::{header, StatusCode};
+use serde_json::{json, Value};
+
+use crate::helpers::{assert_resp_redir_to, http_client_build, TestApp};
+
+fn login_form(app: &TestApp) -> Value {
+    json!({
+        "username": app.test_user.username,
+        "password": app.test_user.password,
+    })
+}
+
+#[tokio::test]
+async fn login_without_a_valid_csrf_token_is_forbidden() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let client = http_client_build()?;
+    let url = format!("http://{}/login", app.addr);
+    let expected_err = ClientError::CsrfCheckFailed.to_string();
+
+    // The browser has a token, the request doesn't.
+    app.csrf_token(&client).await?;
+    let resp = client.post(&url).form(&login_form(&app)).send().await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body: Value = resp.json().await?;
+    assert_eq!(body["error"]["message"], expected_err);
+
+    // The token of another browser.
+    let other_token = app.csrf_token(&http_client_build()?).await?;
+    let resp = client
+        .post(&url)
+        .header(CSRF_HEADER, other_token)
+        .form(&login_form(&app))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Nothing got through to the dashboard.
+    let resp = client
+        .get(format!("http://{}/admin/dashboard", app.addr))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn login_form_field_with_the_csrf_token_is_accepted() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let client = http_client_build()?;
+    let token = app.csrf_token(&client).await?;
+
+    // Like a browser submitting the login form, the token is in the hidden field.
+    let resp = client
+        .post(format!("http://{}/login", app.addr))
+        .form(&json!({
+            "csrf_token": token,
+            "username": app.test_user.username,
+            "password": app.test_user.password,
+        }))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+
+    // The browser keeps its token after the login.
+    let html = app.admin_dashboard_get().await?.text().await?;
+    assert!(!html.contains(&token));
+    let html = client
+        .get(format!("http://{}/admin/dashboard", app.addr))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains(&token));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn forms_left_open_longer_than_the_session_expiry_can_be_posted() -> Result<()> {
+    let app = TestApp::spawn_with(|c| c.session_config.expiry_secs = 1).await?;
+    let client = http_client_build()?;
+    let token = app.csrf_token(&client).await?;
+
+    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    let resp = client
+        .post(format!("http://{}/login", app.addr))
+        .form(&json!({
+            "csrf_token": token,
+            "username": app.test_user.username,
+            "password": app.test_user.password,
+        }))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn form_post_without_the_csrf_cookie_goes_back_to_the_form() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let client = http_client_build()?;
+
+    let resp = client
+        .post(format!("http://{}/login", app.addr))
+        .form(&login_form(&app))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/login");
+    let html = client
+        .get(format!("http://{}/login", app.addr))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains("The form has expired, please submit it again."));
+
+    // The form shown again has a token that works.
+    let token = app.csrf_token(&client).await?;
+    let resp = client
+        .post(format!("http://{}/login", app.addr))
+        .header(CSRF_HEADER, token)
+        .form(&login_form(&app))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+
+    // The forms opened from an email link go back to the link.
+    let resp = http_client_build()?
+        .post(format!("http://{}/signup", app.addr))
+        .header(
+            header::REFERER,
+            format!("http://{}/signup?token=abc", app.addr),
+        )
+        .form(&json!({ "csrf_token": "abc" }))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/signup?token=abc");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn form_post_from_another_origin_is_forbidden() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let client = http_client_build()?;
+    let token = app.csrf_token(&client).await?;
+
+    for (name, value) in [
+        (header::ORIGIN, "https://evil.example".to_string()),
+        (header::ORIGIN, "null".to_string()),
+        (header::REFERER, "https://evil.example/login".to_string()),
+    ] {
+        let resp = client
+            .post(format!("http://{}/login", app.addr))
+            .header(CSRF_HEADER, &token)
+            .header(name, value)
+            .form(&login_form(&app))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    // The origin of the app itself.
+    let resp = client
+        .post(format!("http://{}/login", app.addr))
+        .header(CSRF_HEADER, &token)
+        .header(header::ORIGIN, format!("http://{}", app.addr))
+        .form(&login_form(&app))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_forms_require_the_csrf_token() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+
+    let resp = app
+        .http_client
+        .post(format!("http://{}/admin/logout", app.addr))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = app.admin_dashboard_get().await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = app.admin_post("/logout", ()).await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    Ok(())
+}
@@ -17,6 +17,8 @@ async fn healthcheck_ok() -> Result<()> {
         .await?;
 
     assert!(res.status() == StatusCode::OK, "Healthcheck FAILED!");
+    let body: serde_json::Value = res.json().await?;
+    assert_eq!(body["email_circuit"], "closed");
 
     Ok(())
 }
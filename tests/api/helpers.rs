@@ -4,15 +4,17 @@ use anyhow::{Context, Result};
 use fake::Fake;
 use linkify::LinkKind;
 use mailomat::{
-    config::{get_or_init_config, AppConfig},
+    config::{get_or_init_config, AppConfig, EmailProvider},
     database::DbManager,
+    issue_delivery_worker::{try_execute_task, ExecutionOutcome},
     web::{
         auth::password,
+        csrf::CSRF_HEADER,
         types::{DeserSubscriber, ValidSubscriber},
     },
-    App,
+    App, AppState,
 };
-use reqwest::{redirect, Client, ClientBuilder};
+use reqwest::{redirect, Client, ClientBuilder, StatusCode};
 use secrecy::{ExposeSecret, SecretString};
 use serde_json::{json, Value};
 use sqlx::{postgres::PgPoolOptions, Connection, PgConnection};
@@ -33,20 +35,55 @@ pub struct TestApp {
     pub http_client: Client,
     pub addr: SocketAddr,
     pub dm: DbManager,
+    pub app_state: AppState,
     pub email_server: MockServer,
     pub test_user: TestUser,
 }
 
 #[derive(Clone)]
 pub struct TestUser {
+    pub user_id: Uuid,
     pub username: String,
     pub password: String,
 }
 
+impl TestUser {
+    /// Inserts a user with a random username and password and the given role.
+    pub async fn create(dm: &DbManager, role: &str) -> Result<Self> {
+        let user_id = Uuid::new_v4();
+        let username = Uuid::new_v4().to_string();
+        let password = Uuid::new_v4().to_string();
+        let password_hash =
+            password::hash_new_to_string_async(SecretString::from(password.clone())).await?;
+
+        sqlx::query(
+            r#"INSERT INTO users (user_id, username, password_hash, role)
+        VALUES ($1, $2, $3, $4)"#,
+        )
+        .bind(user_id)
+        .bind(&username)
+        .bind(password_hash.expose_secret())
+        .bind(role)
+        .execute(dm.db())
+        .await?;
+
+        Ok(TestUser {
+            user_id,
+            username,
+            password,
+        })
+    }
+}
+
 impl TestApp {
     /// A helper function that tries to spawn a separate thread to serve our app
     /// returning the *socket address* on which it is listening.
     pub async fn spawn() -> Result<Self> {
+        Self::spawn_with(|_| {}).await
+    }
+
+    /// Like `spawn`, but lets the test change the config before the app is built.
+    pub async fn spawn_with(configure: impl FnOnce(&mut AppConfig)) -> Result<Self> {
         init_test_subscriber();
 
         // A mock server to stand-in for Postmark API
@@ -60,42 +97,33 @@ impl TestApp {
             // Trying to bind port 0 will trigger an OS scan for an available port
             // which will then be bound to the application.
             c.net_config.app_port = 0;
+            c.email_config.provider = EmailProvider::Postmark;
             c.email_config.url = email_server.uri();
+            // Retries are covered by the email client unit tests.
+            c.email_config.retry.max_retries = 0;
+            // All the tests log in from the same address and share Redis.
+            c.login_throttle_config.ip_lockout_after_failures = i64::MAX;
+            configure(&mut c);
             c
         };
 
         test_database_create_migrate(&config).await?;
 
         let app = App::build_from_config(config).await?;
-        let username = Uuid::new_v4().to_string();
-        let password = Uuid::new_v4().to_string();
-        let password_hash =
-            password::hash_new_to_string_async(SecretString::from(password.clone())).await?;
-
         // Add a test user
-        sqlx::query(
-            r#"INSERT INTO users (user_id, username, password_hash)
-        VALUES ($1, $2, $3)"#,
-        )
-        .bind(Uuid::new_v4())
-        .bind(&username)
-        .bind(password_hash.expose_secret())
-        .execute(app.app_state.database_mgr.db())
-        .await?;
+        let test_user = TestUser::create(&app.app_state.database_mgr, "owner").await?;
 
         // Build a TestApp
         let addr = app.listener.local_addr()?;
         let dm = app.app_state.database_mgr.clone();
-        let http_client = ClientBuilder::new()
-            .redirect(redirect::Policy::none())
-            .cookie_store(true)
-            .build()?;
+        let app_state = app.app_state.clone();
+        let http_client = http_client_build()?;
 
-        let test_user = TestUser { username, password };
         let test_app = TestApp {
             http_client,
             addr,
             dm,
+            app_state,
             email_server,
             test_user,
         };
@@ -158,10 +186,79 @@ impl TestApp {
         Ok(res)
     }
 
+    /// Sends an authorized post request to api/news with the provided `Idempotency-Key` header.
+    pub async fn api_news_post_idempotent(
+        &self,
+        idempotency_key: &str,
+    ) -> Result<reqwest::Response> {
+        let newsletter_req_body = json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        });
+
+        let test_user = &self.test_user;
+        let res = self
+            .http_client
+            .post(format!("http://{}/api/news", &self.addr))
+            .basic_auth(test_user.username.clone(), Some(test_user.password.clone()))
+            .header("Idempotency-Key", idempotency_key)
+            .json(&newsletter_req_body)
+            .send()
+            .await?;
+
+        Ok(res)
+    }
+
+    /// Executes the delivery tasks until the `issue_delivery_queue` is empty.
+    pub async fn dispatch_all_pending_emails(&self) -> Result<()> {
+        loop {
+            if let ExecutionOutcome::EmptyQueue = try_execute_task(&self.app_state).await? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until the email API received the number of requests, the emails sent in the background
+    /// arrive after the response.
+    pub async fn email_requests_wait(&self, n: usize) -> Result<Vec<wiremock::Request>> {
+        for _ in 0..100 {
+            let requests = self
+                .email_server
+                .received_requests()
+                .await
+                .context("request recording is disabled")?;
+            if requests.len() >= n {
+                return Ok(requests);
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        anyhow::bail!("the email API didn't receive {n} requests")
+    }
+
     /// Extract confirmation links embedded in the request to the email API.
     pub fn confirmation_link_get(&self, email_req: &wiremock::Request) -> Result<ConfirmationLink> {
         let body: Value = serde_json::from_slice(&email_req.body)?;
+        self.confirmation_link_from_message(&body)
+    }
 
+    /// Extract the confirmation links of every message in a batch request to the email API.
+    pub fn batch_confirmation_links_get(
+        &self,
+        batch_req: &wiremock::Request,
+    ) -> Result<Vec<ConfirmationLink>> {
+        let body: Value = serde_json::from_slice(&batch_req.body)?;
+        body.as_array()
+            .context("Batch body is not an array")?
+            .iter()
+            .map(|message| self.confirmation_link_from_message(message))
+            .collect()
+    }
+
+    fn confirmation_link_from_message(&self, body: &Value) -> Result<ConfirmationLink> {
         let get_link = |s: &str| {
             let links: Vec<_> = linkify::LinkFinder::new()
                 .links(s)
@@ -181,6 +278,36 @@ impl TestApp {
         Ok(ConfirmationLink { html, plain_text })
     }
 
+    /// Extract the unsubscribe links from the `List-Unsubscribe` headers of a batch request to the email API.
+    pub fn unsubscribe_links_get(
+        &self,
+        batch_req: &wiremock::Request,
+    ) -> Result<Vec<reqwest::Url>> {
+        let body: Value = serde_json::from_slice(&batch_req.body)?;
+        let messages = body.as_array().context("Batch body is not an array")?;
+
+        messages
+            .iter()
+            .map(|message| {
+                let header = message["Headers"]
+                    .as_array()
+                    .context("No headers in message")?
+                    .iter()
+                    .find(|h| h["Name"] == "List-Unsubscribe")
+                    .context("No List-Unsubscribe header")?;
+                let raw_link = header["Value"]
+                    .as_str()
+                    .context("Invalid header value")?
+                    .trim_start_matches('<')
+                    .trim_end_matches('>');
+                let mut link = reqwest::Url::parse(raw_link)?;
+                assert_eq!(link.host_str(), Some("127.0.0.1"));
+                link.set_port(Some(self.addr.port())).unwrap();
+                Ok(link)
+            })
+            .collect()
+    }
+
     /// Create new subscriber with a random name and a matching email:
     /// Name Surname, name_surname@email_provider.com
     /// Returns confirmation links required to confirm this subscriber and the subscriber's info.
@@ -189,7 +316,8 @@ impl TestApp {
     ) -> Result<(ConfirmationLink, ValidSubscriber)> {
         let name: String = fake::faker::name::en::Name().fake();
         let email_provider: String = fake::faker::internet::en::FreeEmailProvider().fake();
-        let email = name.to_lowercase().replace(" ", "_") + "@" + &email_provider;
+        // Without the apostrophes of names like O'Keefe, the pages escape them and wouldn't contain the address.
+        let email = name.to_lowercase().replace(" ", "_").replace('\'', "") + "@" + &email_provider;
 
         let body = json!({
             "name": name,
@@ -235,10 +363,27 @@ impl TestApp {
         Ok(self
             .http_client
             .post(format!("http://{}/login", self.addr))
+            .header(CSRF_HEADER, self.csrf_token(&self.http_client).await?)
             .form(&body)
             .send()
             .await?)
     }
+
+    /// Returns the CSRF token of the session of the given http client, taken from the hidden field of the login form.
+    pub async fn csrf_token(&self, http_client: &Client) -> Result<String> {
+        let html = http_client
+            .get(format!("http://{}/login", self.addr))
+            .send()
+            .await?
+            .text()
+            .await?;
+        let token = html
+            .split(r#"name="csrf_token" value=""#)
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .context("no CSRF token in the login form")?;
+        Ok(token.to_string())
+    }
     /// Sends a get request to /login and returns the HTML body string.
     pub async fn login_get_html(&self) -> Result<String> {
         Ok(self
@@ -250,6 +395,64 @@ impl TestApp {
             .await?)
     }
 
+    pub async fn archive_get(&self, path: &str) -> Result<reqwest::Response> {
+        let res = self
+            .http_client
+            .get(format!("http://{}{path}", self.addr))
+            .send()
+            .await?;
+        Ok(res)
+    }
+
+    /// Logs in as the test user, the session cookie is kept in the cookie store of the http_client.
+    pub async fn admin_login(&self) -> Result<()> {
+        self.admin_login_with(&self.http_client).await
+    }
+
+    /// Logs in as the test user with the given http client, so the client gets a session of its own.
+    pub async fn admin_login_with(&self, http_client: &Client) -> Result<()> {
+        self.admin_login_as(http_client, &self.test_user).await
+    }
+
+    /// Logs in as the given user with the given http client.
+    pub async fn admin_login_as(&self, http_client: &Client, user: &TestUser) -> Result<()> {
+        let resp = http_client
+            .post(format!("http://{}/login", self.addr))
+            .header(CSRF_HEADER, self.csrf_token(http_client).await?)
+            .form(&serde_json::json!({
+                "username": user.username,
+                "password": user.password
+            }))
+            .send()
+            .await?;
+        assert_resp_redir_to(&resp, "/admin/dashboard");
+        Ok(())
+    }
+
+    /// Sends a get request to the given path under /admin.
+    pub async fn admin_get(&self, path: &str) -> Result<reqwest::Response> {
+        Ok(self
+            .http_client
+            .get(format!("http://{}/admin{path}", self.addr))
+            .send()
+            .await?)
+    }
+
+    /// Sends a post request to the given path under /admin serializing the body into `form`.
+    pub async fn admin_post(
+        &self,
+        path: &str,
+        body: impl serde::Serialize,
+    ) -> Result<reqwest::Response> {
+        Ok(self
+            .http_client
+            .post(format!("http://{}/admin{path}", self.addr))
+            .header(CSRF_HEADER, self.csrf_token(&self.http_client).await?)
+            .form(&body)
+            .send()
+            .await?)
+    }
+
     pub async fn admin_dashboard_get(&self) -> Result<reqwest::Response> {
         let req = self
             .http_client
@@ -324,6 +527,58 @@ pub async fn api_news_post_appless(
     Ok(res)
 }
 
+/// Responds to the batch requests like Postmark does when every message was accepted.
+pub struct PostmarkBatchResponder;
+
+impl wiremock::Respond for PostmarkBatchResponder {
+    fn respond(&self, request: &wiremock::Request) -> ResponseTemplate {
+        let messages: Vec<Value> = serde_json::from_slice(&request.body).unwrap_or_default();
+        let results = messages
+            .iter()
+            .map(|message| {
+                json!({
+                    "ErrorCode": 0,
+                    "Message": "OK",
+                    "MessageID": Uuid::new_v4(),
+                    "To": message["To"],
+                })
+            })
+            .collect::<Vec<_>>();
+        ResponseTemplate::new(200).set_body_json(results)
+    }
+}
+
+/// Creates an API key as the admin logged in with the client, returns the key shown on the page.
+pub async fn api_key_create(
+    app: &TestApp,
+    client: &Client,
+    form: &[(&str, &str)],
+) -> Result<String> {
+    let resp = client
+        .post(format!("http://{}/admin/api-keys", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(client).await?)
+        .form(form)
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let html = resp.text().await?;
+    let key = html
+        .split(r#"<code id="api-key">"#)
+        .nth(1)
+        .and_then(|rest| rest.split("</code>").next())
+        .context("no API key on the page")?;
+    Ok(key.to_string())
+}
+
+/// Builds an http client that doesn't follow redirects and keeps the cookies, like a browser would.
+/// Every client has its own cookie store, so it has its own session.
+pub fn http_client_build() -> Result<Client> {
+    Ok(ClientBuilder::new()
+        .redirect(redirect::Policy::none())
+        .cookie_store(true)
+        .build()?)
+}
+
 /// A helper that ASSERTS that the response contains SEE_OTHER status code (303) and the provided
 /// redirection location matches the one in the response.
 pub fn assert_resp_redir_to(resp: &reqwest::Response, location: &str) {
@@ -1,7 +1,12 @@
 use anyhow::Result;
-use mailomat::web::ClientError;
+use argon2::{
+    password_hash::{PasswordHasher, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use mailomat::web::{auth::password, ClientError};
+use secrecy::SecretString;
 
-use crate::helpers::{assert_resp_redir_to, TestApp};
+use crate::helpers::{assert_resp_redir_to, http_client_build, TestApp};
 
 #[tokio::test]
 async fn login_error_messaging_works() -> Result<()> {
@@ -9,8 +14,9 @@ async fn login_error_messaging_works() -> Result<()> {
 
     // post@login for invalid user - contains cookie that is stored in cookie_store on the
     // http_client and a redirect back to login
+    // A new username each run, so the failures of the earlier runs don't throttle it.
     let invalid_login_form = serde_json::json!({
-        "username": "invalid",
+        "username": uuid::Uuid::new_v4().to_string(),
         "password": "invalid"
     });
     let expected_err_str = ClientError::UsernameOrPasswordInvalid.to_string();
@@ -56,3 +62,38 @@ async fn admin_dashboard_redirect_to_login_no_auth() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn login_upgrades_an_outdated_password_hash() -> Result<()> {
+    let app = TestApp::spawn().await?;
+
+    // A hash made with weaker parameters and without the pepper.
+    let salt = SaltString::encode_b64(uuid::Uuid::new_v4().as_bytes())?;
+    let old_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(8192, 1, 1, None)?,
+    )
+    .hash_password(app.test_user.password.as_bytes(), &salt)?
+    .to_string();
+    sqlx::query("UPDATE users SET password_hash = $1 WHERE user_id = $2")
+        .bind(&old_hash)
+        .bind(app.test_user.user_id)
+        .execute(app.dm.db())
+        .await?;
+
+    app.admin_login().await?;
+    let new_hash: String = sqlx::query_scalar("SELECT password_hash FROM users WHERE user_id = $1")
+        .bind(app.test_user.user_id)
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_ne!(new_hash, old_hash);
+    assert!(!password::needs_rehash(&SecretString::from(
+        new_hash.as_str()
+    )));
+
+    // The password still works with the new hash.
+    app.admin_login_with(&http_client_build()?).await?;
+
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use anyhow::{Context, Result};
+use mailomat::web::csrf::CSRF_HEADER;
+use reqwest::{header, Client, StatusCode};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::helpers::{assert_resp_redir_to, http_client_build, TestApp, TestUser};
+
+async fn login_post(
+    app: &TestApp,
+    client: &Client,
+    username: &str,
+    password: &str,
+) -> Result<reqwest::Response> {
+    Ok(client
+        .post(format!("http://{}/login", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(client).await?)
+        .form(&json!({ "username": username, "password": password }))
+        .send()
+        .await?)
+}
+
+async fn news_post_basic(
+    app: &TestApp,
+    username: &str,
+    password: &str,
+) -> Result<reqwest::Response> {
+    Ok(http_client_build()?
+        .post(format!("http://{}/api/news", app.addr))
+        .basic_auth(username, Some(password))
+        .json(&json!({
+            "title": "Title",
+            "content": { "text": "Hello", "html": "<p>Hello</p>" }
+        }))
+        .send()
+        .await?)
+}
+
+fn retry_after(resp: &reqwest::Response) -> Result<u64> {
+    Ok(resp
+        .headers()
+        .get(header::RETRY_AFTER)
+        .context("no Retry-After header")?
+        .to_str()?
+        .parse()?)
+}
+
+/// A random private address, so the tests don't share the IP counters.
+fn random_ip() -> String {
+    let bytes = Uuid::new_v4().into_bytes();
+    format!("10.{}.{}.{}", bytes[0], bytes[1], bytes[2])
+}
+
+#[tokio::test]
+async fn repeated_failures_delay_the_next_attempt() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let user = &app.test_user;
+
+    for _ in 0..3 {
+        let resp = login_post(&app, &app.http_client, &user.username, "wrong").await?;
+        assert_resp_redir_to(&resp, "/login");
+        assert!(resp.headers().get(header::RETRY_AFTER).is_none());
+    }
+
+    // Even the right password has to wait, without being checked.
+    let resp = login_post(&app, &app.http_client, &user.username, &user.password).await?;
+    assert_resp_redir_to(&resp, "/login");
+    assert!((1..=2).contains(&retry_after(&resp)?));
+    let html = app.login_get_html().await?;
+    assert!(html.contains("Too many failed login attempts, please try again in"));
+
+    let resp = news_post_basic(&app, &user.username, &user.password).await?;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!((1..=2).contains(&retry_after(&resp)?));
+
+    let failures: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM login_events WHERE kind = 'failure' AND username = $1",
+    )
+    .bind(&user.username)
+    .fetch_one(app.dm.db())
+    .await?;
+    assert_eq!(failures, 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn too_many_failures_lock_out_the_username() -> Result<()> {
+    let app = TestApp::spawn_with(|c| {
+        c.login_throttle_config.delay_after_failures = 100;
+        c.login_throttle_config.username_lockout_after_failures = 3;
+    })
+    .await?;
+    let user = &app.test_user;
+
+    for _ in 0..3 {
+        let resp = news_post_basic(&app, &user.username, "wrong").await?;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    let resp = news_post_basic(&app, &user.username, &user.password).await?;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    let secs = retry_after(&resp)?;
+    assert!(secs > 800 && secs <= 900);
+
+    // Another owner sees the lockout.
+    let owner = TestUser::create(&app.dm, "owner").await?;
+    let owner_client = http_client_build()?;
+    app.admin_login_as(&owner_client, &owner).await?;
+    let html = owner_client
+        .get(format!("http://{}/admin/login-events", app.addr))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains("username lockout"));
+    assert!(html.contains(&user.username));
+
+    // The other roles don't.
+    let editor = TestUser::create(&app.dm, "editor").await?;
+    let editor_client = http_client_build()?;
+    app.admin_login_as(&editor_client, &editor).await?;
+    let resp = editor_client
+        .get(format!("http://{}/admin/login-events", app.addr))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn too_many_failures_lock_out_the_client_ip() -> Result<()> {
+    let app = TestApp::spawn_with(|c| {
+        c.net_config.client_ip_header = Some("x-forwarded-for".into());
+        c.login_throttle_config.ip_lockout_after_failures = 2;
+    })
+    .await?;
+    let user = &app.test_user;
+    let ip = random_ip();
+
+    // Different usernames, so only the address is counted twice.
+    for _ in 0..2 {
+        let client = http_client_build()?;
+        let resp = client
+            .post(format!("http://{}/login", app.addr))
+            .header(CSRF_HEADER, app.csrf_token(&client).await?)
+            .header("x-forwarded-for", format!("{ip}, 10.0.0.1"))
+            .form(&json!({ "username": Uuid::new_v4().to_string(), "password": "wrong" }))
+            .send()
+            .await?;
+        assert_resp_redir_to(&resp, "/login");
+    }
+
+    let client = http_client_build()?;
+    let resp = client
+        .post(format!("http://{}/login", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&client).await?)
+        .header("x-forwarded-for", &ip)
+        .form(&json!({ "username": user.username, "password": user.password }))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/login");
+    assert!(retry_after(&resp)? > 0);
+    let ip_lockouts: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM login_events WHERE kind = 'ip_lockout' AND ip = $1",
+    )
+    .bind(&ip)
+    .fetch_one(app.dm.db())
+    .await?;
+    assert_eq!(ip_lockouts, 1);
+
+    // The same user can still log in from another address.
+    let client = http_client_build()?;
+    let resp = client
+        .post(format!("http://{}/login", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&client).await?)
+        .header("x-forwarded-for", random_ip())
+        .form(&json!({ "username": user.username, "password": user.password }))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn successful_login_resets_the_failures() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let user = &app.test_user;
+    let client = http_client_build()?;
+
+    for _ in 0..2 {
+        login_post(&app, &client, &user.username, "wrong").await?;
+    }
+    let resp = login_post(&app, &client, &user.username, &user.password).await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+
+    // Without the reset the 3rd failure would make the next login wait.
+    for _ in 0..2 {
+        login_post(&app, &client, &user.username, "wrong").await?;
+    }
+    let resp = login_post(&app, &client, &user.username, &user.password).await?;
+    assert_resp_redir_to(&resp, "/admin/dashboard");
+
+    Ok(())
+}
@@ -1,8 +1,26 @@
 //! Integration tests
 
+mod admin_issues;
+mod admin_password;
+mod admin_sessions;
+mod admin_subscribers;
+mod admin_totp;
+mod admin_users;
+mod api_keys;
+mod archive;
+mod cleanup;
+mod cookie_rotation;
+mod csrf;
 mod health_check;
 mod helpers;
 mod login;
+mod login_throttle;
 mod news;
+mod password_reset;
+mod privacy;
+mod subscribers_export;
+mod subscribers_import;
+mod subscription_events;
 mod subscriptions;
 mod subscriptions_confirm;
+mod unsubscribe;
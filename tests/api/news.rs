@@ -1,9 +1,12 @@
-use crate::helpers::{api_news_post_appless, TestApp};
+use crate::helpers::{api_news_post_appless, PostmarkBatchResponder, TestApp};
 use anyhow::Result;
+use mailomat::issue_delivery_worker::{try_execute_task, ExecutionOutcome};
+use serde_json::json;
+use std::time::Duration;
 use uuid::Uuid;
 use wiremock::{
     matchers::{any, method, path},
-    Mock, ResponseTemplate,
+    Mock, Respond, ResponseTemplate,
 };
 
 #[tokio::test]
@@ -18,7 +21,8 @@ async fn api_news_not_delivered_to_unconfirmed_subscribers() -> Result<()> {
         .await;
 
     let res = app.api_news_post().await?;
-    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await?;
 
     Ok(())
 }
@@ -51,7 +55,8 @@ async fn api_news_subscribers_with_invalid_emails_dont_get_news() -> Result<()>
         .await;
 
     let resp = app.api_news_post().await?.error_for_status()?;
-    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(resp.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await?;
 
     Ok(())
 }
@@ -63,14 +68,15 @@ async fn api_news_delivered_to_confirmed_subscriber() -> Result<()> {
 
     Mock::given(path("/email/batch"))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(PostmarkBatchResponder)
         // Will fail if no requests are received
         .expect(1)
         .mount(&app.email_server)
         .await;
 
     let res = app.api_news_post().await?;
-    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(res.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await?;
 
     Ok(())
 }
@@ -82,7 +88,7 @@ async fn api_news_delivered_to_confirmed_subscriber_without_blocking() -> Result
 
     Mock::given(path("/email/batch"))
         .and(method("POST"))
-        .respond_with(ResponseTemplate::new(200))
+        .respond_with(PostmarkBatchResponder)
         // Will fail if no requests are received
         .expect(32)
         .mount(&app.email_server)
@@ -99,8 +105,314 @@ async fn api_news_delivered_to_confirmed_subscriber_without_blocking() -> Result
 
     while let Some(res) = set.join_next().await {
         let res = res??;
-        assert_eq!(res.status().as_u16(), 200);
+        assert_eq!(res.status().as_u16(), 202);
     }
+    app.dispatch_all_pending_emails().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_news_issue_is_persisted() -> Result<()> {
+    let app = TestApp::spawn().await?;
+
+    let res = app.api_news_post().await?;
+    assert_eq!(res.status().as_u16(), 202);
+
+    let title: String = sqlx::query_scalar("SELECT title FROM newsletter_issues")
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(title, "Newsletter title");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_news_issue_records_author_and_recipient_counts() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.subscriber_confirmed_create().await?;
+    app.subscriber_confirmed_create().await?;
+    app.subscriber_unconfirmed_create().await?;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.api_news_post().await?;
+    assert_eq!(res.status().as_u16(), 202);
+    app.dispatch_all_pending_emails().await?;
+
+    let (author, n_recipients, n_delivered, n_failed): (String, i32, i32, i32) = sqlx::query_as(
+        r#"SELECT u.username, i.n_recipients, i.n_delivered, i.n_failed
+        FROM newsletter_issues i JOIN users u ON u.user_id = i.author_user_id"#,
+    )
+    .fetch_one(app.dm.db())
+    .await?;
+    assert_eq!(author, app.test_user.username);
+    assert_eq!(n_recipients, 2);
+    assert_eq!(n_delivered, 2);
+    assert_eq!(n_failed, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_news_failed_delivery_is_kept_in_queue_for_retry() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.subscriber_confirmed_create().await?;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.api_news_post().await?;
+    assert_eq!(res.status().as_u16(), 202);
+
+    let out = try_execute_task(&app.app_state).await;
+    assert!(out.is_err());
+
+    let (n_retries, is_delayed): (i16, bool) =
+        sqlx::query_as("SELECT n_retries, execute_after > now() FROM issue_delivery_queue")
+            .fetch_one(app.dm.db())
+            .await?;
+    assert_eq!(n_retries, 1);
+    assert!(is_delayed);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_news_tasks_are_leased_without_locks_while_sending() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.subscriber_confirmed_create().await?;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(|req: &wiremock::Request| {
+            PostmarkBatchResponder
+                .respond(req)
+                .set_delay(Duration::from_secs(2))
+        })
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.api_news_post().await?;
+    assert_eq!(res.status().as_u16(), 202);
+
+    let app_state = app.app_state.clone();
+    let sending = tokio::spawn(async move { try_execute_task(&app_state).await });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // The task isn't locked while the provider is answering, the other workers skip it because of its lease.
+    let mut transaction = app.dm.db().begin().await?;
+    let leased: bool = sqlx::query_scalar(
+        "SELECT execute_after > now() FROM issue_delivery_queue FOR UPDATE NOWAIT",
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    transaction.rollback().await?;
+    assert!(leased);
+    let outcome = try_execute_task(&app.app_state).await?;
+    assert!(matches!(outcome, ExecutionOutcome::EmptyQueue));
+
+    sending.await??;
+    let n_queued: i64 = sqlx::query_scalar("SELECT count(*) FROM issue_delivery_queue")
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(n_queued, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_news_rejected_recepients_are_dropped_from_queue() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.subscriber_confirmed_create().await?;
+    let inactive = app.subscriber_confirmed_create().await?;
+    let inactive_email = inactive.email.as_ref().to_owned();
+    let invalid = app.subscriber_confirmed_create().await?;
+    let invalid_email = invalid.email.as_ref().to_owned();
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(move |req: &wiremock::Request| {
+            let messages: Vec<serde_json::Value> = serde_json::from_slice(&req.body).unwrap();
+            let results = messages
+                .iter()
+                .map(|m| {
+                    if m["To"] == inactive_email.as_str() {
+                        json!({ "ErrorCode": 406, "Message": "Inactive recipient" })
+                    } else if m["To"] == invalid_email.as_str() {
+                        json!({ "ErrorCode": 300, "Message": "Invalid email request" })
+                    } else {
+                        json!({ "ErrorCode": 0, "Message": "OK", "MessageID": "id" })
+                    }
+                })
+                .collect::<Vec<_>>();
+            ResponseTemplate::new(200).set_body_json(results)
+        })
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.api_news_post().await?;
+    assert_eq!(res.status().as_u16(), 202);
+
+    try_execute_task(&app.app_state).await?;
+
+    let n_queued: i64 = sqlx::query_scalar("SELECT count(*) FROM issue_delivery_queue")
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(n_queued, 0);
+    let (n_delivered, n_failed): (i32, i32) =
+        sqlx::query_as("SELECT n_delivered, n_failed FROM newsletter_issues")
+            .fetch_one(app.dm.db())
+            .await?;
+    assert_eq!((n_delivered, n_failed), (1, 2));
+
+    let mut bounced: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT s.email FROM subscription_events e
+        JOIN subscriptions s ON s.id = e.subscriber_id
+        WHERE e.kind = 'bounced' AND e.source = 'delivery'
+        "#,
+    )
+    .fetch_all(app.dm.db())
+    .await?;
+    bounced.sort();
+    let mut expected = vec![
+        inactive.email.as_ref().to_owned(),
+        invalid.email.as_ref().to_owned(),
+    ];
+    expected.sort();
+    assert_eq!(bounced, expected);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_news_publishing_is_idempotent() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.subscriber_confirmed_create().await?;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let idempotency_key = Uuid::new_v4().to_string();
+    for _ in 0..2 {
+        let res = app.api_news_post_idempotent(&idempotency_key).await?;
+        assert_eq!(res.status().as_u16(), 202);
+    }
+    app.dispatch_all_pending_emails().await?;
+
+    let n_issues: i64 = sqlx::query_scalar("SELECT count(*) FROM newsletter_issues")
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(n_issues, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_news_request_in_progress_with_same_key_rejected() -> Result<()> {
+    use chrono::Utc;
+
+    let app = TestApp::spawn().await?;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    // Simulate a request that claimed the key but hasn't finished yet.
+    sqlx::query(
+        r#"
+        INSERT INTO idempotency (user_id, idempotency_key, created_at)
+        SELECT user_id, $2, $3 FROM users WHERE username = $1
+    "#,
+    )
+    .bind(&app.test_user.username)
+    .bind(&idempotency_key)
+    .bind(Utc::now())
+    .execute(app.dm.db())
+    .await?;
+
+    let res = app.api_news_post_idempotent(&idempotency_key).await?;
+    assert_eq!(res.status().as_u16(), 409);
+
+    let n_issues: i64 = sqlx::query_scalar("SELECT count(*) FROM newsletter_issues")
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(n_issues, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_news_abandoned_request_key_can_be_claimed_again() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    // Simulate a request that claimed the key and never finished, e.g. the server crashed.
+    sqlx::query(
+        r#"
+        INSERT INTO idempotency (user_id, idempotency_key, created_at)
+        SELECT user_id, $2, now() - interval '1 hour' FROM users WHERE username = $1
+    "#,
+    )
+    .bind(&app.test_user.username)
+    .bind(&idempotency_key)
+    .execute(app.dm.db())
+    .await?;
+
+    let res = app.api_news_post_idempotent(&idempotency_key).await?;
+    assert_eq!(res.status().as_u16(), 202);
+
+    // The response is saved again, so the retries get it replayed.
+    let res = app.api_news_post_idempotent(&idempotency_key).await?;
+    assert_eq!(res.status().as_u16(), 202);
+    let n_issues: i64 = sqlx::query_scalar("SELECT count(*) FROM newsletter_issues")
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(n_issues, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_news_expired_key_is_processed_as_a_new_request() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    let res = app.api_news_post_idempotent(&idempotency_key).await?;
+    assert_eq!(res.status().as_u16(), 202);
+    sqlx::query("UPDATE idempotency SET created_at = now() - interval '2 days'")
+        .execute(app.dm.db())
+        .await?;
+
+    let res = app.api_news_post_idempotent(&idempotency_key).await?;
+    assert_eq!(res.status().as_u16(), 202);
+    let n_issues: i64 = sqlx::query_scalar("SELECT count(*) FROM newsletter_issues")
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(n_issues, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_news_invalid_idempotency_key_400() -> Result<()> {
+    let app = TestApp::spawn().await?;
+
+    let res = app.api_news_post_idempotent(&"a".repeat(64)).await?;
+    assert_eq!(res.status().as_u16(), 400);
 
     Ok(())
 }
@@ -0,0 +1,227 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use mailomat::web::csrf::CSRF_HEADER;
+use reqwest::StatusCode;
+use serde_json::json;
+use wiremock::{matchers::path, Mock, ResponseTemplate};
+
+use crate::helpers::{assert_resp_redir_to, http_client_build, TestApp};
+
+const NEW_PASSWORD: &str = "a brand new password 42";
+const USER_EMAIL: &str = "admin@example.com";
+
+async fn user_email_set(app: &TestApp) -> Result<()> {
+    sqlx::query("UPDATE users SET email = $2 WHERE username = $1")
+        .bind(&app.test_user.username)
+        .bind(USER_EMAIL)
+        .execute(app.dm.db())
+        .await?;
+    Ok(())
+}
+
+async fn forgot_password_post(app: &TestApp, username: &str) -> Result<reqwest::Response> {
+    Ok(app
+        .http_client
+        .post(format!("http://{}/login/forgot", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&app.http_client).await?)
+        .form(&json!({ "username": username }))
+        .send()
+        .await?)
+}
+
+/// Extracts the reset token from the link in the reset email.
+fn reset_token_get(app: &TestApp, email_req: &wiremock::Request) -> Result<String> {
+    let links = app.confirmation_link_get(email_req)?;
+    assert_eq!(links.html, links.plain_text);
+    assert_eq!(links.html.path(), "/login/reset");
+    let token = links
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "token")
+        .context("no token in the reset link")?
+        .1
+        .into_owned();
+    Ok(token)
+}
+
+async fn reset_page_get(app: &TestApp, token: &str) -> Result<reqwest::Response> {
+    Ok(app
+        .http_client
+        .get(format!("http://{}/login/reset", app.addr))
+        .query(&[("token", token)])
+        .send()
+        .await?)
+}
+
+async fn reset_password_post(
+    app: &TestApp,
+    token: &str,
+    new_password: &str,
+    new_password_check: &str,
+) -> Result<reqwest::Response> {
+    Ok(app
+        .http_client
+        .post(format!("http://{}/login/reset", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&app.http_client).await?)
+        .form(&json!({
+            "token": token,
+            "new_password": new_password,
+            "new_password_check": new_password_check,
+        }))
+        .send()
+        .await?)
+}
+
+async fn login_status(app: &TestApp, password: &str) -> Result<String> {
+    let client = http_client_build()?;
+    let resp = client
+        .post(format!("http://{}/login", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&client).await?)
+        .form(&json!({ "username": app.test_user.username, "password": password }))
+        .send()
+        .await?;
+    let location = resp
+        .headers()
+        .get("Location")
+        .context("missing location header")?
+        .to_str()?;
+    Ok(location.to_string())
+}
+
+#[tokio::test]
+async fn password_reset_link_resets_the_password_once() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    user_email_set(&app).await?;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let other_client = http_client_build()?;
+    app.admin_login_with(&other_client).await?;
+
+    let resp = forgot_password_post(&app, &app.test_user.username).await?;
+    assert_resp_redir_to(&resp, "/login");
+    assert!(app
+        .login_get_html()
+        .await?
+        .contains("a password reset link was sent to it."));
+
+    let email_req = &app.email_requests_wait(1).await?[0];
+    let token = reset_token_get(&app, email_req)?;
+
+    let resp = reset_page_get(&app, &token).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // A rejected form doesn't use up the link.
+    let resp = reset_password_post(&app, &token, NEW_PASSWORD, "another password 42").await?;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    let html = reset_page_get(&app, &token).await?.text().await?;
+    assert!(html.contains("The new passwords don&#x27;t match!"));
+
+    let resp = reset_password_post(&app, &token, NEW_PASSWORD, NEW_PASSWORD).await?;
+    assert_resp_redir_to(&resp, "/login");
+    assert!(app
+        .login_get_html()
+        .await?
+        .contains("Your password was reset"));
+
+    assert_eq!(login_status(&app, NEW_PASSWORD).await?, "/admin/dashboard");
+    assert_eq!(login_status(&app, &app.test_user.password).await?, "/login");
+
+    // The existing sessions are logged out.
+    let resp = other_client
+        .get(format!("http://{}/admin/dashboard", app.addr))
+        .send()
+        .await?;
+    assert_resp_redir_to(&resp, "/login");
+
+    // The link can only be used once.
+    let resp = reset_page_get(&app, &token).await?;
+    assert_eq!(resp.status(), StatusCode::GONE);
+    let resp = reset_password_post(&app, &token, NEW_PASSWORD, NEW_PASSWORD).await?;
+    assert_eq!(resp.status(), StatusCode::GONE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn forgot_password_responds_the_same_for_unknown_users() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Neither an unknown user nor a user without an email address get an email.
+    for username in ["nobody-has-this-name", app.test_user.username.as_str()] {
+        let resp = forgot_password_post(&app, username).await?;
+        assert_resp_redir_to(&resp, "/login");
+        assert!(app
+            .login_get_html()
+            .await?
+            .contains("a password reset link was sent to it."));
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn expired_or_invalid_reset_links_are_rejected() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    user_email_set(&app).await?;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    forgot_password_post(&app, &app.test_user.username).await?;
+    let email_req = &app.email_requests_wait(1).await?[0];
+    let token = reset_token_get(&app, email_req)?;
+
+    sqlx::query("UPDATE password_reset_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(app.dm.db())
+        .await?;
+    let resp = reset_page_get(&app, &token).await?;
+    assert_eq!(resp.status(), StatusCode::GONE);
+    let resp = reset_password_post(&app, &token, NEW_PASSWORD, NEW_PASSWORD).await?;
+    assert_eq!(resp.status(), StatusCode::GONE);
+    assert_eq!(login_status(&app, NEW_PASSWORD).await?, "/login");
+
+    let resp = reset_page_get(&app, "not-a-token").await?;
+    assert_eq!(resp.status(), StatusCode::GONE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_can_set_the_email_address() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+
+    let resp = app
+        .admin_post("/email", json!({ "email": "not an email" }))
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/password");
+    let html = app.admin_get("/password").await?.text().await?;
+    assert!(html.contains("The email address is invalid!"));
+
+    let resp = app
+        .admin_post("/email", json!({ "email": USER_EMAIL }))
+        .await?;
+    assert_resp_redir_to(&resp, "/admin/password");
+    let html = app.admin_get("/password").await?.text().await?;
+    assert!(html.contains("Your email address was changed."));
+    assert!(html.contains(USER_EMAIL));
+
+    let email: Option<String> = sqlx::query_scalar("SELECT email FROM users WHERE username = $1")
+        .bind(&app.test_user.username)
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(email.as_deref(), Some(USER_EMAIL));
+
+    Ok(())
+}
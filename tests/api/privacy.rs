@@ -0,0 +1,248 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use mailomat::web::{
+    csrf::CSRF_HEADER,
+    types::{DataRequestToken, UnsubscribeToken},
+};
+use reqwest::StatusCode;
+use secrecy::ExposeSecret;
+use serde_json::{json, Value};
+use uuid::Uuid;
+use wiremock::{matchers::path, Mock, ResponseTemplate};
+
+use crate::helpers::{assert_resp_redir_to, TestApp};
+
+async fn data_request_post(app: &TestApp, email: &str) -> Result<reqwest::Response> {
+    Ok(app
+        .http_client
+        .post(format!("http://{}/privacy", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&app.http_client).await?)
+        .form(&json!({ "email": email }))
+        .send()
+        .await?)
+}
+
+/// Requests the link for the address and returns the token from the link in the email.
+async fn data_request_token_get(app: &TestApp, email: &str) -> Result<String> {
+    let n_before = app
+        .email_server
+        .received_requests()
+        .await
+        .context("request recording is disabled")?
+        .len();
+    let resp = data_request_post(app, email).await?;
+    assert_resp_redir_to(&resp, "/privacy");
+
+    let requests = app.email_requests_wait(n_before + 1).await?;
+    let links = app.confirmation_link_get(&requests[n_before])?;
+    assert_eq!(links.html, links.plain_text);
+    assert_eq!(links.html.path(), "/privacy/data");
+    let token = links
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "token")
+        .context("no token in the link")?
+        .1
+        .into_owned();
+    Ok(token)
+}
+
+async fn data_page_get(app: &TestApp, token: &str) -> Result<reqwest::Response> {
+    Ok(app
+        .http_client
+        .get(format!("http://{}/privacy/data", app.addr))
+        .query(&[("token", token)])
+        .send()
+        .await?)
+}
+
+async fn email_server_mount(app: &TestApp) {
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+}
+
+#[tokio::test]
+async fn data_request_link_downloads_the_subscriber_data() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let subscriber = app.subscriber_confirmed_create().await?;
+    email_server_mount(&app).await;
+
+    let token = data_request_token_get(&app, subscriber.email.as_ref()).await?;
+    let html = app
+        .http_client
+        .get(format!("http://{}/privacy", app.addr))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains("a link to access its data was sent to it"));
+    let html = data_page_get(&app, &token).await?.text().await?;
+    assert!(html.contains(subscriber.email.as_ref()));
+
+    let resp = app
+        .http_client
+        .get(format!("http://{}/privacy/data/export", app.addr))
+        .query(&[("token", &token)])
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers()[reqwest::header::CONTENT_DISPOSITION]
+        .to_str()?
+        .starts_with("attachment"));
+    let export: Value = resp.json().await?;
+    assert_eq!(export["subscriber"]["email"], subscriber.email.as_ref());
+    assert_eq!(export["subscriber"]["name"], subscriber.name.as_ref());
+    assert_eq!(export["subscriber"]["status"], "confirmed");
+    assert_eq!(
+        export["confirmation_emails_sent_at"]
+            .as_array()
+            .map(Vec::len),
+        Some(1)
+    );
+    assert_eq!(
+        export["data_request_emails_sent_at"]
+            .as_array()
+            .map(Vec::len),
+        Some(1)
+    );
+    assert_eq!(
+        export["events"]
+            .as_array()
+            .map(|events| events.iter().map(|e| e["kind"].clone()).collect::<Vec<_>>()),
+        Some(vec![
+            json!("subscribed"),
+            json!("confirmation_sent"),
+            json!("confirmed")
+        ])
+    );
+    // The confirmation token itself isn't exported.
+    let token_value: String =
+        sqlx::query_scalar("SELECT subscription_token FROM subscription_tokens")
+            .fetch_one(app.dm.db())
+            .await?;
+    assert!(!export.to_string().contains(&token_value));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn erasure_removes_the_subscriber_and_keeps_a_record_without_personal_data() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let (_, subscriber) = app.subscriber_unconfirmed_create().await?;
+    email_server_mount(&app).await;
+    let subscriber_id: Uuid = sqlx::query_scalar("SELECT id FROM subscriptions WHERE email = $1")
+        .bind(subscriber.email.as_ref())
+        .fetch_one(app.dm.db())
+        .await?;
+
+    let token = data_request_token_get(&app, subscriber.email.as_ref()).await?;
+    let resp = app
+        .http_client
+        .post(format!("http://{}/privacy/data/erase", app.addr))
+        .header(CSRF_HEADER, app.csrf_token(&app.http_client).await?)
+        .form(&json!({ "token": token }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.text().await?.contains("Your data has been erased."));
+
+    for table in [
+        "subscriptions",
+        "subscription_tokens",
+        "confirmation_emails_sent",
+        "data_request_emails_sent",
+    ] {
+        let n: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {table}"))
+            .fetch_one(app.dm.db())
+            .await?;
+        assert_eq!(n, 0, "{table}");
+    }
+    let erasure: Value = sqlx::query_scalar("SELECT to_jsonb(e) FROM data_erasures e")
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(erasure["subscriber_id"], subscriber_id.to_string());
+    assert!(!erasure.to_string().contains(subscriber.email.as_ref()));
+
+    // The link doesn't work anymore.
+    let resp = data_page_get(&app, &token).await?;
+    assert_eq!(resp.status(), StatusCode::GONE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn forged_and_expired_links_are_rejected() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let subscriber = app.subscriber_confirmed_create().await?;
+    let subscriber_id: Uuid = sqlx::query_scalar("SELECT id FROM subscriptions WHERE email = $1")
+        .bind(subscriber.email.as_ref())
+        .fetch_one(app.dm.db())
+        .await?;
+    let key = app.app_state.hmac_secret.expose_secret();
+
+    let expired = DataRequestToken::generate(
+        subscriber_id,
+        Utc::now() - chrono::Duration::minutes(1),
+        key,
+    );
+    let unsubscribe = UnsubscribeToken::generate(subscriber_id, key);
+    let other_key = DataRequestToken::generate(
+        subscriber_id,
+        Utc::now() + chrono::Duration::hours(1),
+        b"key",
+    );
+    for token in [
+        expired.as_str(),
+        unsubscribe.as_str(),
+        other_key.as_str(),
+        "not-a-token",
+    ] {
+        let resp = data_page_get(&app, token).await?;
+        assert_eq!(resp.status(), StatusCode::GONE, "{token}");
+        let resp = app
+            .http_client
+            .post(format!("http://{}/privacy/data/erase", app.addr))
+            .header(CSRF_HEADER, app.csrf_token(&app.http_client).await?)
+            .form(&json!({ "token": token }))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::GONE, "{token}");
+    }
+    let n: i64 = sqlx::query_scalar("SELECT count(*) FROM subscriptions")
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(n, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn data_request_emails_are_only_sent_to_subscribers_and_limited() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let subscriber = app.subscriber_confirmed_create().await?;
+    email_server_mount(&app).await;
+
+    // The response doesn't tell the unknown addresses apart.
+    let resp = data_request_post(&app, "nobody@example.com").await?;
+    assert_resp_redir_to(&resp, "/privacy");
+    for _ in 0..4 {
+        let resp = data_request_post(&app, subscriber.email.as_ref()).await?;
+        assert_resp_redir_to(&resp, "/privacy");
+    }
+
+    // The confirmation email, then 3 of the 4 data request emails.
+    app.email_requests_wait(4).await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let requests = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap_or_default();
+    assert_eq!(requests.len(), 4);
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use anyhow::Result;
+use reqwest::{header, StatusCode};
+use serde_json::Value;
+
+use crate::helpers::{api_key_create, http_client_build, TestApp};
+
+/// Inserts `count` confirmed subscribers a minute apart, and one that unsubscribed on 2025-01-01.
+async fn subscribers_insert(app: &TestApp, count: i32) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        SELECT gen_random_uuid(), 'reader' || n || '@example.com', 'Reader, No. ' || n,
+            now() - make_interval(mins => $1 - n), 'confirmed'
+        FROM generate_series(1, $1) AS n
+        "#,
+    )
+    .bind(count)
+    .execute(app.dm.db())
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES (gen_random_uuid(), 'gone@example.com', 'Gone', '2025-01-01T12:00:00Z', 'unsubscribed')
+        "#,
+    )
+    .execute(app.dm.db())
+    .await?;
+    Ok(())
+}
+
+async fn export_get(app: &TestApp, key: &str, query: &[(&str, &str)]) -> Result<reqwest::Response> {
+    Ok(http_client_build()?
+        .get(format!("http://{}/api/subscribers/export", app.addr))
+        .query(query)
+        .header(header::AUTHORIZATION, format!("Bearer {key}"))
+        .send()
+        .await?)
+}
+
+async fn read_key_create(app: &TestApp) -> Result<String> {
+    app.admin_login().await?;
+    api_key_create(
+        app,
+        &app.http_client,
+        &[("name", "accounting"), ("scope", "subscribers:read")],
+    )
+    .await
+}
+
+#[tokio::test]
+async fn export_streams_all_the_subscribers_as_csv() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    // More than one chunk of the body.
+    subscribers_insert(&app, 1_000).await?;
+    let key = read_key_create(&app).await?;
+
+    let resp = export_get(&app, &key, &[]).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers()[header::CONTENT_TYPE]
+        .to_str()?
+        .starts_with("text/csv"));
+    assert!(resp.headers()[header::CONTENT_DISPOSITION]
+        .to_str()?
+        .ends_with(".csv\""));
+
+    let csv = resp.text().await?;
+    let lines = csv.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines[0],
+        "id,email,name,status,subscribed_at,consent_source"
+    );
+    assert_eq!(lines.len(), 1 + 1_001);
+    // The oldest first.
+    assert!(lines[1].contains("gone@example.com"));
+    assert!(lines[2].contains(r#"reader1@example.com,"Reader, No. 1",confirmed,"#));
+    assert!(lines[1_001].contains("reader1000@example.com"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn export_can_be_filtered_and_sent_as_ndjson() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    subscribers_insert(&app, 3).await?;
+    let key = read_key_create(&app).await?;
+
+    let resp = export_get(&app, &key, &[("format", "ndjson"), ("status", "confirmed")]).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers()[header::CONTENT_TYPE].to_str()?,
+        "application/x-ndjson"
+    );
+    let subscribers = resp
+        .text()
+        .await?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<Value>, _>>()?;
+    let emails = subscribers
+        .iter()
+        .map(|sub| sub["email"].as_str().unwrap_or_default())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        emails,
+        vec![
+            "reader1@example.com",
+            "reader2@example.com",
+            "reader3@example.com"
+        ]
+    );
+
+    // The days are inclusive.
+    let resp = export_get(&app, &key, &[("from", "2025-01-01"), ("to", "2025-01-01")]).await?;
+    let csv = resp.text().await?;
+    assert_eq!(csv.lines().count(), 2);
+    assert!(csv.contains("gone@example.com"));
+
+    for query in [
+        ("format", "xlsx"),
+        ("status", "gone"),
+        ("from", "yesterday"),
+    ] {
+        let resp = export_get(&app, &key, &[query]).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{query:?}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn export_requires_the_subscribers_read_scope() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+    let publish_key = api_key_create(
+        &app,
+        &app.http_client,
+        &[("name", "deploy"), ("scope", "news:publish")],
+    )
+    .await?;
+
+    let resp = http_client_build()?
+        .get(format!("http://{}/api/subscribers/export", app.addr))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = export_get(&app, &publish_key, &[]).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    Ok(())
+}
@@ -0,0 +1,373 @@
+use anyhow::Result;
+use reqwest::{header, Client, StatusCode};
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::{
+    api_key_create, http_client_build, PostmarkBatchResponder, TestApp, TestUser,
+};
+
+const BOUNDARY: &str = "mailomat-import-boundary";
+
+/// A `multipart/form-data` body like the one the upload form sends, the file is the last field.
+fn multipart_body(fields: &[(&str, &str)], csv: &str) -> String {
+    let mut body = String::new();
+    for (name, value) in fields {
+        body.push_str(&format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+        ));
+    }
+    body.push_str(&format!(
+        "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"subscribers.csv\"\r\n\
+        Content-Type: text/csv\r\n\r\n{csv}\r\n--{BOUNDARY}--\r\n"
+    ));
+    body
+}
+
+/// Uploads the file on the import page as the admin logged in with the client.
+async fn admin_import_post(
+    app: &TestApp,
+    client: &Client,
+    fields: &[(&str, &str)],
+    csv: &str,
+) -> Result<reqwest::Response> {
+    let token = app.csrf_token(client).await?;
+    Ok(client
+        .post(format!(
+            "http://{}/admin/subscribers/import?csrf_token={token}",
+            app.addr
+        ))
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={BOUNDARY}"),
+        )
+        .body(multipart_body(fields, csv))
+        .send()
+        .await?)
+}
+
+/// The rows of the report as `(row, email, outcome)`, the quoted fields have no line breaks in the tests.
+fn report_rows(report: &str) -> Vec<(String, String, String)> {
+    report
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let (mut fields, mut field, mut quoted) = (Vec::new(), String::new(), false);
+            for ch in line.chars() {
+                match ch {
+                    '"' => quoted = !quoted,
+                    ',' if !quoted => fields.push(std::mem::take(&mut field)),
+                    ch => field.push(ch),
+                }
+            }
+            fields.push(field);
+            (fields[0].clone(), fields[1].clone(), fields[3].clone())
+        })
+        .collect()
+}
+
+fn row(n: &str, email: &str, outcome: &str) -> (String, String, String) {
+    (n.to_string(), email.to_string(), outcome.to_string())
+}
+
+async fn subscriber_get(app: &TestApp, email: &str) -> Result<Option<(String, Option<String>)>> {
+    Ok(
+        sqlx::query_as("SELECT status, consent_source FROM subscriptions WHERE email = $1")
+            .bind(email)
+            .fetch_optional(app.dm.db())
+            .await?,
+    )
+}
+
+#[tokio::test]
+async fn import_as_confirmed_reports_every_row() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let existing = app.subscriber_unconfirmed_create().await?.1;
+    // No confirmation emails for the confirmed subscribers.
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    app.admin_login().await?;
+
+    let csv = format!(
+        "\u{feff}email,name\r\n\
+        ursula@example.com,Ursula Le Guin\n\
+        not-an-email,Nobody\n\
+        ursula@example.com,Ursula Again\n\
+        {},{}\n\
+        \n\
+        octavia@example.com,\"Butler, Octavia\"\n",
+        existing.email.as_ref(),
+        existing.name.as_ref(),
+    );
+    let resp = admin_import_post(
+        &app,
+        &app.http_client,
+        &[
+            ("status", "confirmed"),
+            ("consent_source", "Signup sheet at the 2024 book fair"),
+        ],
+        &csv,
+    )
+    .await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers()[header::CONTENT_TYPE]
+        .to_str()?
+        .starts_with("text/csv"));
+    assert!(resp.headers()[header::CONTENT_DISPOSITION]
+        .to_str()?
+        .starts_with("attachment; filename=\"subscribers-import-"));
+
+    let report = resp.text().await?;
+    assert!(report.starts_with("row,email,name,outcome,reason\r\n"));
+    assert_eq!(
+        report_rows(&report),
+        vec![
+            row("2", "ursula@example.com", "accepted"),
+            row("3", "not-an-email", "invalid"),
+            row("4", "ursula@example.com", "skipped"),
+            row("5", existing.email.as_ref(), "skipped"),
+            row("6", "octavia@example.com", "accepted"),
+        ]
+    );
+    assert!(report.contains("the address is already in the list (pending confirmation)"));
+
+    assert_eq!(
+        subscriber_get(&app, "ursula@example.com").await?,
+        Some((
+            "confirmed".to_string(),
+            Some("Signup sheet at the 2024 book fair".to_string())
+        ))
+    );
+    // The existing subscriber wasn't changed.
+    assert_eq!(
+        subscriber_get(&app, existing.email.as_ref()).await?,
+        Some(("pending_confirmation".to_string(), None))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn import_as_pending_queues_the_confirmation_emails() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Without a header the name comes first.
+    let csv = "Ursula Le Guin,ursula@example.com\nOctavia Butler,octavia@example.com";
+    let resp = admin_import_post(
+        &app,
+        &app.http_client,
+        &[("status", "pending_confirmation"), ("consent_source", "")],
+        csv,
+    )
+    .await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let report = resp.text().await?;
+    assert_eq!(
+        report_rows(&report),
+        vec![
+            row("1", "ursula@example.com", "accepted"),
+            row("2", "octavia@example.com", "accepted"),
+        ]
+    );
+    assert!(report.contains("the confirmation email was queued"));
+
+    // Both emails are sent in one batch.
+    let batch_req = &app.email_requests_wait(1).await?[0];
+    let links = app.batch_confirmation_links_get(batch_req)?;
+    assert_eq!(links.len(), 2);
+    app.http_client
+        .get(links[0].html.clone())
+        .send()
+        .await?
+        .error_for_status()?;
+    assert_eq!(
+        subscriber_get(&app, "ursula@example.com").await?,
+        Some(("confirmed".to_string(), None))
+    );
+    assert_eq!(
+        subscriber_get(&app, "octavia@example.com")
+            .await?
+            .map(|(status, _)| status),
+        Some("pending_confirmation".to_string())
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn rows_are_imported_when_the_confirmation_emails_fail() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+    Mock::given(path("/email/batch"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    let resp = admin_import_post(
+        &app,
+        &app.http_client,
+        &[("status", "pending_confirmation")],
+        "name,email\nUrsula Le Guin,ursula@example.com\n",
+    )
+    .await?;
+    assert_eq!(
+        report_rows(&resp.text().await?),
+        vec![row("2", "ursula@example.com", "accepted")]
+    );
+    app.email_requests_wait(1).await?;
+    // The subscriber can get a new email by subscribing again.
+    assert_eq!(
+        subscriber_get(&app, "ursula@example.com")
+            .await?
+            .map(|(status, _)| status),
+        Some("pending_confirmation".to_string())
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_import_options_are_rejected() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+    let csv = "ursula@example.com,Ursula Le Guin\n";
+
+    // The confirmed subscribers need a consent source.
+    let resp = admin_import_post(
+        &app,
+        &app.http_client,
+        &[("status", "confirmed"), ("consent_source", " ")],
+        csv,
+    )
+    .await?;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = admin_import_post(&app, &app.http_client, &[], csv).await?;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    // A header without a name column.
+    let resp = admin_import_post(
+        &app,
+        &app.http_client,
+        &[("status", "pending_confirmation")],
+        "email,full name\nursula@example.com,Ursula Le Guin\n",
+    )
+    .await?;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let subscribers: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM subscriptions")
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(subscribers, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn import_requires_an_editor_and_the_csrf_token() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let fields = [("status", "confirmed"), ("consent_source", "Signup sheet")];
+    let csv = "name,email\nUrsula Le Guin,ursula@example.com\n";
+
+    let viewer = TestUser::create(&app.dm, "viewer").await?;
+    let viewer_client = http_client_build()?;
+    app.admin_login_as(&viewer_client, &viewer).await?;
+    let resp = viewer_client
+        .get(format!("http://{}/admin/subscribers/import", app.addr))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = admin_import_post(&app, &viewer_client, &fields, csv).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    app.admin_login().await?;
+    let html = app.admin_get("/subscribers/import").await?.text().await?;
+    assert!(html.contains(r#"enctype="multipart/form-data""#));
+    // Without the token in the query.
+    let resp = app
+        .http_client
+        .post(format!("http://{}/admin/subscribers/import", app.addr))
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={BOUNDARY}"),
+        )
+        .body(multipart_body(&fields, csv))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(subscriber_get(&app, "ursula@example.com").await?, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_imports_with_the_subscribers_write_scope() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.admin_login().await?;
+    let read_key = api_key_create(
+        &app,
+        &app.http_client,
+        &[("name", "export"), ("scope", "subscribers:read")],
+    )
+    .await?;
+    let write_key = api_key_create(
+        &app,
+        &app.http_client,
+        &[("name", "crm"), ("scope", "subscribers:write")],
+    )
+    .await?;
+    let import_post = |key: String| {
+        let url = format!("http://{}/api/subscribers/import", app.addr);
+        async move {
+            let resp = http_client_build()?
+                .post(url)
+                .query(&[("status", "confirmed"), ("consent_source", "CRM export")])
+                .header(header::AUTHORIZATION, format!("Bearer {key}"))
+                .header(header::CONTENT_TYPE, "text/csv")
+                .body("name,email\nUrsula Le Guin,ursula@example.com\nNobody,nobody\n")
+                .send()
+                .await?;
+            Ok::<_, anyhow::Error>(resp)
+        }
+    };
+
+    let resp = import_post(read_key).await?;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(subscriber_get(&app, "ursula@example.com").await?, None);
+
+    let resp = import_post(write_key.clone()).await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        report_rows(&resp.text().await?),
+        vec![
+            row("2", "ursula@example.com", "accepted"),
+            row("3", "nobody", "invalid"),
+        ]
+    );
+    assert_eq!(
+        subscriber_get(&app, "ursula@example.com").await?,
+        Some(("confirmed".to_string(), Some("CRM export".to_string())))
+    );
+
+    // Importing the same file again changes nothing.
+    let resp = import_post(write_key).await?;
+    assert_eq!(
+        report_rows(&resp.text().await?),
+        vec![
+            row("2", "ursula@example.com", "skipped"),
+            row("3", "nobody", "invalid"),
+        ]
+    );
+
+    Ok(())
+}
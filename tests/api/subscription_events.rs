@@ -0,0 +1,160 @@
+use anyhow::{Context, Result};
+use mailomat::web::REQUEST_ID_HEADER;
+use reqwest::{header, StatusCode};
+use serde_json::json;
+use uuid::Uuid;
+use wiremock::{matchers::path, Mock, ResponseTemplate};
+
+use crate::helpers::TestApp;
+
+#[derive(Debug, sqlx::FromRow)]
+struct Event {
+    kind: String,
+    source: String,
+    request_id: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+async fn events_get(app: &TestApp, subscriber_id: Uuid) -> Result<Vec<Event>> {
+    Ok(sqlx::query_as(
+        r#"
+        SELECT kind, source, request_id, ip, user_agent FROM subscription_events
+        WHERE subscriber_id = $1
+        ORDER BY occurred_at
+        "#,
+    )
+    .bind(subscriber_id)
+    .fetch_all(app.dm.db())
+    .await?)
+}
+
+async fn subscriber_id_get(app: &TestApp, email: &str) -> Result<Uuid> {
+    Ok(
+        sqlx::query_scalar("SELECT id FROM subscriptions WHERE email = $1")
+            .bind(email)
+            .fetch_one(app.dm.db())
+            .await?,
+    )
+}
+
+fn kinds(events: &[Event]) -> Vec<&str> {
+    events.iter().map(|event| event.kind.as_str()).collect()
+}
+
+#[tokio::test]
+async fn subscribing_and_confirming_are_recorded_with_the_request() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let resp = app
+        .http_client
+        .post(format!("http://{}/api/subscribe", app.addr))
+        .header(header::USER_AGENT, "Mozilla/5.0 (Newsletter Test)")
+        .json(&json!({ "name": "Ursula Le Guin", "email": "ursula@example.com" }))
+        .send()
+        .await?
+        .error_for_status()?;
+    let request_id = resp.headers()[REQUEST_ID_HEADER].to_str()?.to_string();
+    let email_req = &app
+        .email_server
+        .received_requests()
+        .await
+        .context("request recording is disabled")?[0];
+    let links = app.confirmation_link_get(email_req)?;
+    // Only the first click confirms the subscriber.
+    for _ in 0..2 {
+        app.http_client
+            .get(links.html.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+
+    let subscriber_id = subscriber_id_get(&app, "ursula@example.com").await?;
+    let events = events_get(&app, subscriber_id).await?;
+    assert_eq!(
+        kinds(&events),
+        vec!["subscribed", "confirmation_sent", "confirmed"]
+    );
+    let subscribed = &events[0];
+    assert_eq!(subscribed.source, "api");
+    assert_eq!(subscribed.request_id.as_deref(), Some(request_id.as_str()));
+    assert_eq!(subscribed.ip.as_deref(), Some("127.0.0.1"));
+    assert_eq!(
+        subscribed.user_agent.as_deref(),
+        Some("Mozilla/5.0 (Newsletter Test)")
+    );
+    let confirmed = &events[2];
+    assert_eq!(confirmed.source, "confirmation_link");
+    assert!(confirmed.request_id.is_some());
+    assert_ne!(confirmed.request_id, subscribed.request_id);
+    assert_eq!(confirmed.user_agent, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admins_see_the_history_and_deleting_keeps_it_without_personal_data() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let subscriber = app.subscriber_confirmed_create().await?;
+    let subscriber_id = subscriber_id_get(&app, subscriber.email.as_ref()).await?;
+    app.admin_login().await?;
+
+    // Unsubscribing twice is recorded once.
+    for _ in 0..2 {
+        app.admin_post(&format!("/subscribers/{subscriber_id}/unsubscribe"), ())
+            .await?;
+    }
+    let events = events_get(&app, subscriber_id).await?;
+    assert_eq!(
+        kinds(&events),
+        vec![
+            "subscribed",
+            "confirmation_sent",
+            "confirmed",
+            "unsubscribed"
+        ]
+    );
+    assert_eq!(events[3].source, "admin");
+
+    let html = app.admin_get("/subscribers").await?.text().await?;
+    assert!(html.contains(&format!("/admin/subscribers/{subscriber_id}/events")));
+    let html = app
+        .admin_get(&format!("/subscribers/{subscriber_id}/events"))
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains(subscriber.email.as_ref()));
+    assert!(html.contains("confirmation link"));
+    assert!(html.contains("127.0.0.1"));
+
+    app.admin_post(&format!("/subscribers/{subscriber_id}/delete"), ())
+        .await?;
+    let events = events_get(&app, subscriber_id).await?;
+    assert_eq!(events.len(), 5);
+    assert_eq!(events[4].kind, "erased");
+    assert!(events
+        .iter()
+        .all(|event| event.ip.is_none() && event.user_agent.is_none()));
+
+    // The history is still there after the subscriber is gone.
+    let html = app
+        .admin_get(&format!("/subscribers/{subscriber_id}/events"))
+        .await?
+        .text()
+        .await?;
+    assert!(html.contains("was deleted"));
+    assert!(!html.contains(subscriber.email.as_ref()));
+    assert!(!html.contains("127.0.0.1"));
+
+    let resp = app
+        .admin_get(&format!("/subscribers/{}/events", Uuid::new_v4()))
+        .await?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
@@ -1,6 +1,7 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use reqwest::StatusCode;
 use serde_json::json;
+use uuid::Uuid;
 use wiremock::{
     matchers::{method, path},
     Mock, ResponseTemplate,
@@ -167,6 +168,188 @@ async fn api_subscribe_duplicated_subscription_still_returns_200() -> Result<()>
     Ok(())
 }
 
+#[tokio::test]
+async fn api_subscribe_pending_subscriber_gets_a_new_confirmation_email() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let (first_links, subscriber) = app.subscriber_unconfirmed_create().await?;
+    let body = json!({
+        "name": "Another Name",
+        "email": subscriber.email.as_ref(),
+    });
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.api_subscribe_post(&body).await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let email_req = &app
+        .email_server
+        .received_requests()
+        .await
+        .expect("Requests should be received")
+        .pop()
+        .expect("1 request is expected");
+    let email_body: serde_json::Value = serde_json::from_slice(&email_req.body)?;
+    assert!(email_body["HtmlBody"]
+        .as_str()
+        .is_some_and(|b| b.contains(subscriber.name.as_ref())));
+    let second_links = app.confirmation_link_get(email_req)?;
+    assert_ne!(first_links.html, second_links.html);
+
+    let res = app.http_client.get(second_links.html).send().await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let (status,): (String,) = sqlx::query_as("SELECT status FROM subscriptions WHERE email = $1")
+        .bind(subscriber.email.as_ref())
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(status, "confirmed");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_subscribe_pending_subscriber_gets_the_standard_response_when_the_email_fails(
+) -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let (_, subscriber) = app.subscriber_unconfirmed_create().await?;
+    let body = json!({
+        "name": subscriber.name.as_ref(),
+        "email": subscriber.email.as_ref(),
+    });
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.api_subscribe_post(&body).await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.text().await?,
+        "If this email is not already subscribed, you will receive a confirmation email shortly."
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_subscribe_unsubscribed_subscriber_can_subscribe_again() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let subscriber = app.subscriber_confirmed_create().await?;
+    let subscriber_id: Uuid = sqlx::query_scalar("SELECT id FROM subscriptions WHERE email = $1")
+        .bind(subscriber.email.as_ref())
+        .fetch_one(app.dm.db())
+        .await?;
+    app.admin_login().await?;
+    app.admin_post(&format!("/subscribers/{subscriber_id}/unsubscribe"), ())
+        .await?;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let body = json!({
+        "name": "Another Name",
+        "email": subscriber.email.as_ref(),
+    });
+    let res = app.api_subscribe_post(&body).await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let status_get = || async {
+        sqlx::query_as::<_, (String, String)>(
+            "SELECT status, name FROM subscriptions WHERE id = $1",
+        )
+        .bind(subscriber_id)
+        .fetch_one(app.dm.db())
+        .await
+    };
+    assert_eq!(
+        status_get().await?,
+        ("pending_confirmation".into(), "Another Name".into())
+    );
+    let kinds: Vec<String> = sqlx::query_scalar(
+        "SELECT kind FROM subscription_events WHERE subscriber_id = $1 ORDER BY occurred_at",
+    )
+    .bind(subscriber_id)
+    .fetch_all(app.dm.db())
+    .await?;
+    assert_eq!(
+        &kinds[3..],
+        ["unsubscribed", "subscribed", "confirmation_sent"]
+    );
+
+    let requests = app
+        .email_server
+        .received_requests()
+        .await
+        .context("request recording is disabled")?;
+    let links = app.confirmation_link_get(requests.last().context("no email was sent")?)?;
+    app.http_client
+        .get(links.html)
+        .send()
+        .await?
+        .error_for_status()?;
+    assert_eq!(status_get().await?.0, "confirmed");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_subscribe_confirmation_emails_are_throttled() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let (_, subscriber) = app.subscriber_unconfirmed_create().await?;
+    let body = json!({
+        "name": subscriber.name.as_ref(),
+        "email": subscriber.email.as_ref(),
+    });
+
+    // Only 2 confirmation emails are allowed per day, including the first one.
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    for i in 0..3 {
+        let res = app.api_subscribe_post(&body).await?;
+        assert_eq!(res.status(), StatusCode::OK, "failed in iteration {i}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn api_subscribe_confirmed_subscriber_does_not_get_a_confirmation_email() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let subscriber = app.subscriber_confirmed_create().await?;
+    let body = json!({
+        "name": subscriber.name.as_ref(),
+        "email": subscriber.email.as_ref(),
+    });
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let res = app.api_subscribe_post(&body).await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn api_subscribe_sends_a_confirmation_email_for_valid_data() -> Result<()> {
     let app = TestApp::spawn().await?;
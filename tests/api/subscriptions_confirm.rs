@@ -129,3 +129,24 @@ async fn api_subscribe_confirm_invalid_sub_token_returns_400() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn api_subscribe_confirm_expired_token_returns_410() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let (confirm_link, subscriber) = app.subscriber_unconfirmed_create().await?;
+
+    sqlx::query("UPDATE subscription_tokens SET created_at = now() - interval '2 days'")
+        .execute(app.dm.db())
+        .await?;
+
+    let res = app.http_client.get(confirm_link.html).send().await?;
+    assert_eq!(res.status(), StatusCode::GONE);
+
+    let (status,): (String,) = sqlx::query_as("SELECT status FROM subscriptions WHERE email = $1")
+        .bind(subscriber.email.as_ref())
+        .fetch_one(app.dm.db())
+        .await?;
+    assert_eq!(status, "pending_confirmation");
+
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use anyhow::Result;
+use mailomat::web::types::UnsubscribeToken;
+use reqwest::{StatusCode, Url};
+use uuid::Uuid;
+use wiremock::{
+    matchers::{method, path},
+    Mock,
+};
+
+use crate::helpers::{PostmarkBatchResponder, TestApp};
+
+/// Publishes a newsletter to a single confirmed subscriber and returns their unsubscribe link.
+async fn unsubscribe_link_from_newsletter(app: &TestApp) -> Result<Url> {
+    app.subscriber_confirmed_create().await?;
+    newsletter_unsubscribe_link(app).await
+}
+
+/// Sends a newsletter to the only confirmed subscriber and returns the unsubscribe link in it.
+async fn newsletter_unsubscribe_link(app: &TestApp) -> Result<Url> {
+    let _mock_guard = Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder)
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    app.api_news_post().await?.error_for_status()?;
+    app.dispatch_all_pending_emails().await?;
+
+    let batch_req = app
+        .email_server
+        .received_requests()
+        .await
+        .expect("Requests should be received")
+        .pop()
+        .expect("1 request is expected");
+    let mut links = app.unsubscribe_links_get(&batch_req)?;
+    assert_eq!(links.len(), 1);
+
+    Ok(links.remove(0))
+}
+
+async fn subscriber_status(app: &TestApp) -> Result<String> {
+    let status = sqlx::query_scalar("SELECT status FROM subscriptions")
+        .fetch_one(app.dm.db())
+        .await?;
+    Ok(status)
+}
+
+#[tokio::test]
+async fn unsubscribe_link_shows_confirmation_page() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let link = unsubscribe_link_from_newsletter(&app).await?;
+
+    let resp = app.http_client.get(link).send().await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.text().await?.contains("<form"));
+
+    // Visiting the link alone doesn't unsubscribe
+    assert_eq!(subscriber_status(&app).await?, "confirmed");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unsubscribe_one_click_post_unsubscribes() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let link = unsubscribe_link_from_newsletter(&app).await?;
+
+    // RFC 8058 one-click request
+    let resp = app
+        .http_client
+        .post(link)
+        .form(&[("List-Unsubscribe", "One-Click")])
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(subscriber_status(&app).await?, "unsubscribed");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn old_confirmation_link_doesnt_subscribe_again() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let (confirmation_link, _) = app.subscriber_unconfirmed_create().await?;
+    app.http_client
+        .get(confirmation_link.html.clone())
+        .send()
+        .await?
+        .error_for_status()?;
+    let link = newsletter_unsubscribe_link(&app).await?;
+    app.http_client
+        .post(link)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    // The link hasn't expired yet, but it was sent for the subscription that ended.
+    let resp = app.http_client.get(confirmation_link.html).send().await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(subscriber_status(&app).await?, "unsubscribed");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unsubscribed_subscribers_dont_get_news() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    let link = unsubscribe_link_from_newsletter(&app).await?;
+
+    // Enqueue the issue before unsubscribing
+    app.api_news_post().await?.error_for_status()?;
+    app.http_client
+        .post(link)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Mock::given(path("/email/batch"))
+        .respond_with(PostmarkBatchResponder)
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    app.api_news_post().await?.error_for_status()?;
+    app.dispatch_all_pending_emails().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unsubscribe_forged_token_returns_401() -> Result<()> {
+    let app = TestApp::spawn().await?;
+    app.subscriber_confirmed_create().await?;
+    let subscriber_id: Uuid = sqlx::query_scalar("SELECT id FROM subscriptions")
+        .fetch_one(app.dm.db())
+        .await?;
+
+    let forged = UnsubscribeToken::generate(subscriber_id, b"not the secret");
+    let resp = app
+        .http_client
+        .post(format!(
+            "http://{}/api/subscribe/unsubscribe?token={}",
+            app.addr,
+            forged.as_str()
+        ))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(subscriber_status(&app).await?, "confirmed");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unsubscribe_malformed_token_returns_400() -> Result<()> {
+    let app = TestApp::spawn().await?;
+
+    for query in ["", "?token=", "?token=abc", "?token=čaša"] {
+        let resp = app
+            .http_client
+            .get(format!(
+                "http://{}/api/subscribe/unsubscribe{query}",
+                app.addr
+            ))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "query: {query}");
+    }
+
+    Ok(())
+}